@@ -1,15 +1,30 @@
-use log::{/*error, warn, info, debug,*/ trace, /*log, Level*/};
-use std::{collections::VecDeque, thread, sync::Mutex, time::Duration, ops::DerefMut};
-use crate::settings::app_settings::{Action, Settings};
+use log::{error, /*warn,*/ info, /*debug,*/ trace, /*log, Level*/};
+use std::{thread, time::Duration};
+use crate::settings::app_settings::Settings;
+use crate::app_logger::{finish_capture, start_capture};
+use crate::cancel;
 use crate::dispatch::dispatch;
+use crate::jobs::{self, jobs_file};
+use crate::notify;
 
+/**
+Start running the queued jobs in the background, one at a time.
+Jobs left running by a previous run of the app are marked interrupted first.
+*/
 pub fn start_consumer(settings: Settings)
 {
+    match jobs::interrupt_running(&jobs_file(&settings))
+    {
+        Ok(0) => {},
+        Ok(n) => {info!("Marked {} jobs that were running when the app stopped as interrupted", n);},
+        Err(e) => {error!("Couldn't check the job queue {} for interrupted jobs -- Error: {}", jobs_file(&settings).to_string_lossy(), e);}
+    }
     thread::spawn(|| { consumer(settings); });
 }
 
 pub fn consumer(settings: Settings)
 {
+    let jobs_file = jobs_file(&settings);
     let mut first_iter = true;
     loop{
         /* Wait a few seconds between iterations.
@@ -26,42 +41,37 @@ pub fn consumer(settings: Settings)
 
         trace!("Iterating periodic update loop");
 
-        let action: Option<Action> = match ACTION_QUEUE.try_lock()
+        let job = match jobs::start_next(&jobs_file)
         {
-            Ok(mut guard_for_queue) => match CURRENT_ACTION.try_lock()
-            {
-                Ok(mut guard_for_current_action) =>
-                {
-                    let next_action = guard_for_queue.deref_mut().pop_front();
-                    *guard_for_current_action = next_action.clone();
-                    next_action
-                },
-                Err(_) => {continue;}
-            },
-            Err(_) => {continue;}
+            Ok(Some(j)) => j,
+            Ok(None) => {continue;},
+            Err(e) => {
+                error!("Couldn't read the job queue {} -- Error: {}", jobs_file.to_string_lossy(), e);
+                continue;
+            }
         };
-        if let Some(a) = action
+        info!("Starting job {}: {}", job.id, job.describe());
+        let oneoff_settings = Settings{
+            action: job.action.clone(),
+            ..settings.clone()
+        };
+        cancel::start_job(job.id);
+        start_capture();
+        let results = dispatch(&oneoff_settings);
+        let log = finish_capture();
+        let cancelled = cancel::cancelled();
+        cancel::end_job();
+        match jobs::finish(&jobs_file, job.id, &results, &log, cancelled)
         {
-            let oneoff_settings = Settings{
-                startup: settings.startup.clone(),
-                sources: settings.sources.clone(),
-                mysql: settings.mysql.clone(),
-                action: a,
-                dropbox: settings.dropbox.clone(),
-                gdrive: settings.gdrive.clone()
-            };
-            dispatch(&oneoff_settings);
-            if let Ok(mut g) = CURRENT_ACTION.try_lock()
-            {
-                *g = None;
-            }
+            Ok(()) if cancelled => {info!("Cancelled job {}", job.id);},
+            Ok(()) => {info!("Finished job {}", job.id);},
+            Err(e) => {error!("Couldn't record the outcome of job {} -- Error: {}", job.id, e);}
+        }
+        match jobs::get(&jobs_file, job.id)
+        {
+            Ok(Some(finished)) => {notify::job_finished(&settings, &finished);},
+            Ok(_) => {},
+            Err(e) => {error!("Couldn't read job {} back to notify of how it went -- Error: {}", job.id, e);}
         }
-        
     }
 }
-
-lazy_static!
-{
-    pub static ref ACTION_QUEUE: Mutex<VecDeque<Action>> = Mutex::new(VecDeque::<Action>::new());
-    pub static ref CURRENT_ACTION: Mutex<Option<Action>> = Mutex::new(None);
-}
\ No newline at end of file
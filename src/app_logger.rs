@@ -1,5 +1,5 @@
-use std::fs;
-use log::LevelFilter;
+use std::{cell::{Cell, RefCell}, collections::VecDeque, fs};
+use log::{Level, LevelFilter, Log, Metadata, Record};
 use log4rs::append::console::{ConsoleAppender, Target};
 use log4rs::append::file::FileAppender;
 use log4rs::encode::pattern::PatternEncoder;
@@ -44,5 +44,91 @@ pub fn setup_logger(settings: &Settings)
         .logger(log4rs::config::Logger::builder().appender("cmdlogger"   ).additive(false).build("cmdlog",    LevelFilter::Info))
         .build(log4rs::config::Root::builder().appender("stdout").appender("main").build(LevelFilter::Info))
         .expect("Couldn't build logger setup.");
-    log4rs::init_config(logger_setup).expect("Couldn't initialize logger.");
+    let logger = log4rs::Logger::new(logger_setup);
+    log::set_max_level(logger.max_log_level());
+    log::set_boxed_logger(Box::new(CapturingLogger{inner: logger})).expect("Couldn't initialize logger.");
+}
+
+/** How many of the most recent lines a capture keeps */
+const CAPTURE_LINES: usize = 200;
+
+thread_local!
+{
+    static ERROR_COUNT: Cell<u64> = const { Cell::new(0) };
+    static CAPTURE: RefCell<Option<VecDeque<String>>> = const { RefCell::new(None) };
+}
+
+/**
+Passes everything on to log4rs, while keeping track of what each thread logs: how many errors, and while capturing, the most recent lines.
+That's how a job knows whether each of its steps went well, and gets its log excerpt.
+*/
+struct CapturingLogger
+{
+    inner: log4rs::Logger
+}
+
+impl Log for CapturingLogger
+{
+    fn enabled(&self, metadata: &Metadata) -> bool
+    {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record)
+    {
+        if self.inner.enabled(record.metadata())
+        {
+            if record.level() == Level::Error
+            {
+                ERROR_COUNT.with(|count| count.set(count.get() + 1));
+            }
+            // the full commands can contain passwords, so they only go to their own log file
+            if record.target() != "cmdlog"
+            {
+                CAPTURE.with(|capture| {
+                    if let Some(lines) = capture.borrow_mut().as_mut()
+                    {
+                        lines.push_back(format!("{} {} {} - {}", chrono::Utc::now().format("%Y-%m-%d %H:%M:%S"), record.level(), record.target(), record.args()));
+                        if lines.len() > CAPTURE_LINES
+                        {
+                            lines.pop_front();
+                        }
+                    }
+                });
+            }
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self)
+    {
+        self.inner.flush();
+    }
+}
+
+/**
+Get how many errors the current thread has logged so far. Compare it before and after doing something to see whether that went wrong.
+*/
+pub fn error_count() -> u64
+{
+    ERROR_COUNT.with(|count| count.get())
+}
+
+/**
+Start keeping the most recent lines logged by the current thread, discarding any capture already in progress.
+*/
+pub fn start_capture()
+{
+    CAPTURE.with(|capture| *capture.borrow_mut() = Some(VecDeque::new()));
+}
+
+/**
+Stop capturing what the current thread logs.
+
+# Returns
+The most recent lines logged since start_capture, oldest first.
+*/
+pub fn finish_capture() -> Vec<String>
+{
+    CAPTURE.with(|capture| capture.borrow_mut().take()).map(Vec::from).unwrap_or_default()
 }
\ No newline at end of file
@@ -129,7 +129,7 @@ mod tests
         {
             let backoff_min: f32 = calculate_backoff_series(1.0, 2.0, 5, 600.0, 6000.0, 0.0).iter().sum();
             let backoff_jitter: f32 = calculate_backoff_series(1.0, 2.0, 5, 600.0, 6000.0, 1.0).iter().sum();
-            let backoff_max: f32 = vec!(1.0, 4.0, 16.0, 64.0, 256.0).iter().sum();
+            let backoff_max: f32 = [1.0, 4.0, 16.0, 64.0, 256.0].iter().sum();
             assert!(backoff_min <= backoff_jitter && backoff_jitter <= backoff_max);
         }
     }
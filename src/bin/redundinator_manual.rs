@@ -1,4 +1,4 @@
-use redundinator::{dispatch::dispatch, settings::app_settings::Settings, app_logger::setup_logger};
+use redundinator::{dispatch::dispatch, notify, settings::app_settings::Settings, app_logger::setup_logger};
 
 /**
 The command line manual interface to actions in Redundinator.
@@ -11,5 +11,16 @@ fn main()
 
     let settings = Settings::load();
     setup_logger(&settings);
-    dispatch(&settings);
+    let results = dispatch(&settings);
+    // notifying of testing the notifiers or hashing a password would only be noise
+    if !settings.action.test_notifiers && !settings.action.hash_password
+    {
+        notify::run_finished(&settings, &results);
+    }
+    notify::wait_for_sending();
+    // so cron and scripts can tell it failed
+    if results.iter().any(|r| !r.ok)
+    {
+        std::process::exit(1);
+    }
 }
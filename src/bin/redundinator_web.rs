@@ -1,13 +1,13 @@
-use actix_web::{web, web::Data, App, HttpServer};
-use log::{/*error, warn,*/ info, /*debug, trace, log, Level*/};
+use actix_web::{middleware::from_fn, web, web::Data, App, HttpServer};
+use log::{error, warn, info, /*debug, trace, log, Level*/};
 
-use redundinator::{action_queue, resources::pages, settings::app_settings::Settings, app_logger::setup_logger};
+use redundinator::{action_queue, notify, scheduler, resources::{api, auth, events, metrics, pages}, settings::app_settings::Settings, app_logger::setup_logger};
 
 /**
 Start the web interface for Redundinator
 
 # Returns
-Result, but only when actix-web fails to bind to the port we want to use for HTTP.
+Result, but only when actix-web fails to bind to the port we want to use for HTTP or to load the TLS certificate.
 */
 #[actix_rt::main]
 async fn main() -> std::io::Result<()>
@@ -19,22 +19,64 @@ async fn main() -> std::io::Result<()>
     let settings = Settings::load();
     setup_logger(&settings);
 
+    if settings.web.allow_anonymous
+    {
+        warn!("allow_anonymous is set, anyone who can reach the web interface can use it without logging in.");
+    }else if !auth::has_logins(&settings.web)
+    {
+        // still start, so scheduled backups keep running and the page says what to do
+        error!("{} Until then it only shows these instructions.", auth::setup_instructions(&settings.startup.config_file));
+    }
+
     info!("Starting Redundinator action queue consumer.");
     action_queue::start_consumer(settings.clone());
+    scheduler::start(settings.clone());
+    notify::start(settings.clone());
 
-    info!("Starting Redundinator web interface on {}", settings.startup.listen_addr);
+    let https = !settings.web.tls_cert_file.is_empty();
+    info!("Starting Redundinator web interface on {} with {}", settings.startup.listen_addr, if https {"HTTPS"} else {"HTTP"});
 
     //Start the HTTP server
     let settings_clone = settings.clone();
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(Data::new(settings_clone.clone()))
+            .wrap(from_fn(auth::require_login))   // every request has to be logged in
             .route("/", web::get().to(pages::index))   // request for root: this delivers the dashboard
             .route("/action", web::post().to(pages::action))   // action request page
+            .route("/search", web::get().to(pages::search))   // catalog search page
+            .route("/jobs", web::get().to(pages::jobs))   // job queue and history
+            .route("/jobs/{id}", web::get().to(pages::job))   // details of one job
+            .route("/jobs/{id}/{command}", web::post().to(pages::manage_job))   // reorder, remove or cancel a job
+            .route("/events", web::get().to(events::events))   // live progress and log lines for the pages
+            .route("/metrics", web::get().to(metrics::metrics))   // for Prometheus
+            .service(web::scope("/api/v1")   // JSON API, described by /api/v1/openapi.json
+                .app_data(web::JsonConfig::default().error_handler(api::json_error))
+                .app_data(web::PathConfig::default().error_handler(api::path_error))
+                .app_data(web::QueryConfig::default().error_handler(api::query_error))
+                .route("/sources", web::get().to(api::sources))
+                .route("/sources/{name}/exports", web::get().to(api::source_exports))
+                .route("/storage", web::get().to(api::storage))
+                .route("/queue", web::get().to(api::queue))
+                .route("/progress", web::get().to(api::progress))
+                .route("/jobs", web::get().to(api::jobs))
+                .route("/jobs", web::post().to(api::enqueue))
+                .route("/jobs/{id}", web::get().to(api::job))
+                .route("/openapi.json", web::get().to(api::openapi))
+                .default_service(web::route().to(api::notfound)))
             .default_service(web::route().to(pages::notfound))  // where to go when nothing else matches
-    })
-    .bind(settings.startup.listen_addr)?
-    .run()
-    .await
+    });
+    let server = match https
+    {
+        true => {
+            let tls = auth::tls_config(&settings.web).map_err(|e| {
+                error!("Couldn't load the TLS certificate {} and key {} -- Error: {}", settings.web.tls_cert_file, settings.web.tls_key_file, e);
+                e
+            })?;
+            server.bind_rustls_0_23(&settings.startup.listen_addr, tls)?
+        },
+        false => server.bind(&settings.startup.listen_addr)?
+    };
+    server.run().await
 }
 
@@ -0,0 +1,131 @@
+use std::{io, sync::atomic::{AtomicI64, Ordering}, thread, time::Duration};
+
+/*
+The job running in the web interface's queue can be cancelled from the web interface's own threads.
+Whatever the job is doing checks in regularly: commands run with shell::run are killed along with everything they started,
+and loops over files, parts and upload chunks stop with an error. The job's remaining steps are then skipped.
+Outside the queue, e.g. in redundinator_manual, nothing is ever cancelled.
+*/
+
+/** Id of the job the queue is running, 0 when none is */
+static RUNNING_JOB: AtomicI64 = AtomicI64::new(0);
+/** Id of the job cancellation was requested for, 0 when none */
+static CANCELLED_JOB: AtomicI64 = AtomicI64::new(0);
+
+/** How often waits check whether the job was cancelled */
+pub const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/**
+Note that the queue is starting a job, which can be cancelled until `end_job` is called.
+*/
+pub fn start_job(id: i64)
+{
+    CANCELLED_JOB.store(0, Ordering::SeqCst);
+    RUNNING_JOB.store(id, Ordering::SeqCst);
+}
+
+/**
+Note that the queue is done with the job it was running.
+*/
+pub fn end_job()
+{
+    RUNNING_JOB.store(0, Ordering::SeqCst);
+    CANCELLED_JOB.store(0, Ordering::SeqCst);
+}
+
+/**
+Ask for a job to be cancelled.
+
+# Returns
+bool for whether the job is the one running. If it isn't, nothing happens.
+*/
+pub fn request(id: i64) -> bool
+{
+    if id == 0 || RUNNING_JOB.load(Ordering::SeqCst) != id
+    {
+        return false;
+    }
+    // if the job ends right now this is harmless, the next job has a different id
+    CANCELLED_JOB.store(id, Ordering::SeqCst);
+    true
+}
+
+/**
+Whether the job running on this process was cancelled.
+*/
+pub fn cancelled() -> bool
+{
+    let running = RUNNING_JOB.load(Ordering::SeqCst);
+    running != 0 && CANCELLED_JOB.load(Ordering::SeqCst) == running
+}
+
+/**
+Whether a job is running in the queue on this process, as opposed to e.g. redundinator_manual.
+*/
+pub fn in_job() -> bool
+{
+    RUNNING_JOB.load(Ordering::SeqCst) != 0
+}
+
+/**
+Id of the job running in the queue on this process, if any.
+*/
+pub fn running_job() -> Option<i64>
+{
+    match RUNNING_JOB.load(Ordering::SeqCst)
+    {
+        0 => None,
+        id => Some(id)
+    }
+}
+
+/**
+Stop what's being done if the job was cancelled.
+
+# Returns
+An error of kind Interrupted if the job was cancelled.
+*/
+pub fn check() -> io::Result<()>
+{
+    match cancelled()
+    {
+        true => Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled")),
+        false => Ok(())
+    }
+}
+
+/**
+Sleep, waking up early if the job is cancelled.
+*/
+pub fn sleep(duration: Duration)
+{
+    let mut left = duration;
+    while !left.is_zero() && !cancelled()
+    {
+        let nap = left.min(POLL_INTERVAL);
+        thread::sleep(nap);
+        left -= nap;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn only_the_running_job_is_cancelled()
+    {
+        assert!(!request(7));
+        start_job(7);
+        assert!(!request(8));
+        assert!(!cancelled() && check().is_ok());
+        assert!(request(7));
+        assert!(cancelled());
+        assert_eq!(check().unwrap_err().kind(), io::ErrorKind::Interrupted);
+        end_job();
+        start_job(8);
+        assert!(!cancelled());
+        end_job();
+    }
+}
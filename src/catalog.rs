@@ -0,0 +1,642 @@
+use glob::{MatchOptions, Pattern};
+use log::{error, warn, info/*, debug, trace, log, Level*/};
+use regex::Regex;
+use sha2::Sha256;
+use sqlite::{Connection, State, Value};
+use std::{collections::{BTreeMap, HashMap}, fs, io, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use crate::checksum::file_digest;
+use crate::resources::format_timestamp;
+use crate::settings::app_settings::Settings;
+use crate::snapshot::{list_snapshots, snapshot_dir};
+
+/*
+The catalog is a sqlite database next to the tokens file, recording every regular file in every snapshot and export:
+
+    files(source, kind, generation, path, size, mtime, sha256, part)
+
+`kind` is "snapshot" or "export" and `generation` is the timestamp of the snapshot or export.
+Paths are relative to the snapshot, the same as inside exports and for the include setting, e.g. _home_/alice/notes.txt.
+`part` is only set for exports: the number of the last part file the file is in.
+
+Snapshots are cataloged after each sync and exports after each export, and their rows are deleted when prune deletes them.
+*/
+
+/**
+What a generation in the catalog is.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind
+{
+    Snapshot,
+    Export
+}
+
+impl Kind
+{
+    fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            Kind::Snapshot => "snapshot",
+            Kind::Export => "export"
+        }
+    }
+}
+
+/**
+One file in a snapshot or export.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatalogEntry
+{
+    pub path: String,
+    pub size: u64,
+    /** Modification time, in seconds since the epoch */
+    pub mtime: i64,
+    /** Hex encoded SHA-256 of the contents. None when it isn't known, e.g. for exports of the legacy single-copy layout. */
+    pub sha256: Option<String>,
+    /**
+    Number of the last export part the file is in, as in the part filenames, so the parts up to this one are enough to extract it.
+    Where exactly the compressor puts a file is only checked every so often, so this can be a part or two later than the actual one.
+    */
+    pub part: Option<u32>
+}
+
+/**
+One version of a file found by a search: a path with a given size and modification time, and every generation it is in.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileVersion
+{
+    pub source: String,
+    pub path: String,
+    pub size: u64,
+    pub mtime: i64,
+    pub sha256: Option<String>,
+    /** Timestamps of the snapshots containing this version, oldest first */
+    pub snapshots: Vec<i64>,
+    /** Timestamps of the exports containing this version and the last part it's in, oldest first */
+    pub exports: Vec<(i64, Option<u32>)>
+}
+
+/**
+What to search the catalog for.
+*/
+pub enum Matcher
+{
+    Glob(Pattern),
+    Regex(Regex)
+}
+
+impl Matcher
+{
+    /**
+    # Arguments
+    * `pattern` - A glob, or a regex if `regex` is set.
+      A glob without a `/` is matched against the filename, e.g. "*.pdf", otherwise against the whole path, e.g. "_home_/alice/report.pdf".
+      A regex matches if it matches anywhere in the path.
+
+    # Returns
+    The matcher, or a description of what's wrong with the pattern.
+    */
+    pub fn parse(pattern: &str, regex: bool) -> Result<Matcher, String>
+    {
+        if pattern.trim().is_empty()
+        {
+            return Err(String::from("empty search pattern"));
+        }
+        if regex
+        {
+            return Regex::new(pattern).map(Matcher::Regex).map_err(|e| format!("invalid regex {pattern}: {e}"));
+        }
+        Pattern::new(pattern.trim().trim_start_matches("./")).map(Matcher::Glob).map_err(|e| format!("invalid glob {pattern}: {e}"))
+    }
+
+    pub fn matches(&self, path: &str) -> bool
+    {
+        match self
+        {
+            Matcher::Glob(pattern) => {
+                let options = MatchOptions{case_sensitive: true, require_literal_separator: true, require_literal_leading_dot: false};
+                if pattern.as_str().contains('/')
+                {
+                    pattern.matches_with(path, options)
+                }else{
+                    pattern.matches_with(path.rsplit('/').next().unwrap_or(path), options)
+                }
+            },
+            Matcher::Regex(regex) => regex.is_match(path)
+        }
+    }
+}
+
+/**
+Location of the catalog: catalog.db in the same directory as the tokens file.
+*/
+pub fn catalog_file(settings: &Settings) -> PathBuf
+{
+    Path::new(&settings.startup.tokens_file).with_file_name("catalog.db")
+}
+
+/**
+Record the files of a generation, replacing whatever was recorded for it before.
+
+# Arguments
+* `catalog_file` - Location of the catalog, from catalog_file()
+* `generation` - Timestamp of the snapshot or export
+
+# Returns
+- On success, Ok(())
+- On failure, a sqlite::Error. Nothing is recorded then.
+
+# Examples
+```
+use redundinator::{catalog::{add_generation, search, CatalogEntry, Kind, Matcher}, testing::fixtures::Fixture};
+
+let catalog_file = Fixture::blank("catalog.db");
+let entry = CatalogEntry{path: String::from("_home_/alice/notes.txt"), size: 5, mtime: 1700000000, sha256: None, part: Some(0)};
+add_generation(catalog_file.to_path(), "laptop", Kind::Export, 1700000100, &[entry]).unwrap();
+let found = search(catalog_file.to_path(), "", &Matcher::parse("*.txt", false).unwrap()).unwrap();
+assert_eq!(found[0].exports, vec!((1700000100, Some(0))));
+```
+*/
+pub fn add_generation(catalog_file: &Path, source_name: &str, kind: Kind, generation: i64, entries: &[CatalogEntry]) -> Result<(), sqlite::Error>
+{
+    let connection = connect(catalog_file)?;
+    connection.execute("BEGIN")?;
+    let result = delete_rows(&connection, source_name, kind, generation).and_then(|_| {
+        let insert_query = "INSERT INTO files (source, kind, generation, path, size, mtime, sha256, part) VALUES (:source, :kind, :generation, :path, :size, :mtime, :sha256, :part)";
+        let mut insert_stmt = connection.prepare(insert_query)?;
+        for entry in entries
+        {
+            insert_stmt.reset()?;
+            insert_stmt.bind::<&[(_, Value)]>(&[
+                (":source", source_name.into()),
+                (":kind", kind.as_str().into()),
+                (":generation", generation.into()),
+                (":path", entry.path.as_str().into()),
+                (":size", i64::try_from(entry.size).unwrap_or(i64::MAX).into()),
+                (":mtime", entry.mtime.into()),
+                (":sha256", entry.sha256.clone().into()),
+                (":part", entry.part.map(i64::from).into())
+            ])?;
+            while State::Row == insert_stmt.next()? {}
+        }
+        Ok(())
+    });
+    match result
+    {
+        Ok(()) => connection.execute("COMMIT"),
+        Err(e) => {
+            connection.execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
+
+/**
+Forget everything recorded for a generation.
+
+# Returns
+- On success, Ok(())
+- On failure, a sqlite::Error
+*/
+pub fn remove_generation(catalog_file: &Path, source_name: &str, kind: Kind, generation: i64) -> Result<(), sqlite::Error>
+{
+    let connection = connect(catalog_file)?;
+    delete_rows(&connection, source_name, kind, generation)
+}
+
+/**
+Get the timestamps of the generations of a source that are in the catalog, oldest first.
+*/
+pub fn generations(catalog_file: &Path, source_name: &str, kind: Kind) -> Result<Vec<i64>, sqlite::Error>
+{
+    let connection = connect(catalog_file)?;
+    let select_query = "SELECT DISTINCT generation FROM files WHERE source = :source AND kind = :kind ORDER BY generation";
+    let mut select_stmt = connection.prepare(select_query)?;
+    select_stmt.bind::<&[(_, Value)]>(&[(":source", source_name.into()), (":kind", kind.as_str().into())])?;
+    let mut timestamps = Vec::new();
+    while let State::Row = select_stmt.next()?
+    {
+        timestamps.push(select_stmt.read::<i64, _>("generation")?);
+    }
+    Ok(timestamps)
+}
+
+/**
+Get the files recorded for a generation.
+
+# Returns
+The entries by path.
+*/
+pub fn generation_entries(catalog_file: &Path, source_name: &str, kind: Kind, generation: i64) -> Result<HashMap<String, CatalogEntry>, sqlite::Error>
+{
+    let connection = connect(catalog_file)?;
+    let select_query = "SELECT path, size, mtime, sha256, part FROM files WHERE source = :source AND kind = :kind AND generation = :generation";
+    let mut select_stmt = connection.prepare(select_query)?;
+    select_stmt.bind::<&[(_, Value)]>(&[
+        (":source", source_name.into()),
+        (":kind", kind.as_str().into()),
+        (":generation", generation.into())
+    ])?;
+    let mut entries = HashMap::new();
+    while let State::Row = select_stmt.next()?
+    {
+        let entry = read_entry(&select_stmt)?;
+        entries.insert(entry.path.clone(), entry);
+    }
+    Ok(entries)
+}
+
+/**
+Find the files in the catalog whose paths match.
+
+# Arguments
+* `source_name` - Only search this source. When blank, search all.
+
+# Returns
+Each version of each matching file, ordered by source, path and modification time.
+*/
+pub fn search(catalog_file: &Path, source_name: &str, matcher: &Matcher) -> Result<Vec<FileVersion>, sqlite::Error>
+{
+    let connection = connect(catalog_file)?;
+    let select_query = "SELECT source, kind, generation, path, size, mtime, sha256, part FROM files WHERE :source = '' OR source = :source";
+    let mut select_stmt = connection.prepare(select_query)?;
+    select_stmt.bind((":source", source_name))?;
+
+    let mut matched_paths: HashMap<String, bool> = HashMap::new();
+    let mut versions: BTreeMap<(String, String, i64, u64), FileVersion> = BTreeMap::new();
+    while let State::Row = select_stmt.next()?
+    {
+        let path = select_stmt.read::<String, _>("path")?;
+        let is_match = *matched_paths.entry(path.clone()).or_insert_with(|| matcher.matches(&path));
+        if !is_match {continue;}
+
+        let source = select_stmt.read::<String, _>("source")?;
+        let entry = read_entry(&select_stmt)?;
+        let version = versions.entry((source.clone(), path.clone(), entry.mtime, entry.size)).or_insert_with(|| FileVersion{
+            source,
+            path,
+            size: entry.size,
+            mtime: entry.mtime,
+            sha256: None,
+            snapshots: Vec::new(),
+            exports: Vec::new()
+        });
+        if version.sha256.is_none()
+        {
+            version.sha256 = entry.sha256;
+        }
+        let generation = select_stmt.read::<i64, _>("generation")?;
+        if select_stmt.read::<String, _>("kind")? == Kind::Export.as_str()
+        {
+            version.exports.push((generation, entry.part));
+        }else{
+            version.snapshots.push(generation);
+        }
+    }
+    let mut versions = versions.into_values().collect::<Vec<FileVersion>>();
+    for version in &mut versions
+    {
+        version.snapshots.sort_unstable();
+        version.exports.sort_unstable();
+    }
+    Ok(versions)
+}
+
+/**
+Catalog the files of a snapshot, hashing each one.
+
+Files with the same size and modification time as in the previously cataloged snapshot are assumed unchanged and keep their hash,
+which skips reading most of a snapshot since rsync only transfers what changed.
+
+# Returns
+bool for whether it succeeded. Failures are logged.
+*/
+pub fn catalog_snapshot(source_name: &str, timestamp: i64, settings: &Settings) -> bool
+{
+    info!("Cataloging snapshot {} of source: {}", timestamp, source_name);
+    let catalog_file = catalog_file(settings);
+    let previous = match generations(&catalog_file, source_name, Kind::Snapshot)
+        .and_then(|g| match g.into_iter().rev().find(|g| *g < timestamp)
+        {
+            Some(p) => generation_entries(&catalog_file, source_name, Kind::Snapshot, p),
+            None => Ok(HashMap::new())
+        })
+    {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("Couldn't read the previous snapshot of source {} from the catalog, every file will be hashed -- Error: {}", source_name, e);
+            HashMap::new()
+        }
+    };
+
+    let dir = snapshot_dir(&settings.startup.storage_dir, source_name, timestamp);
+    let mut entries = Vec::new();
+    let mut hashed: u64 = 0;
+    let walked = walk_files(&dir, Path::new(""), &mut |path, rel, meta| {
+        let rel = rel.to_string_lossy().into_owned();
+        let modified = mtime(meta);
+        let sha256 = match previous.get(&rel)
+        {
+            Some(p) if p.size == meta.len() && p.mtime == modified && p.sha256.is_some() => p.sha256.clone(),
+            _ => {
+                hashed += 1;
+                match file_digest::<Sha256>(path)
+                {
+                    Ok(h) => Some(h),
+                    Err(e) => {
+                        warn!("Couldn't hash {} for the catalog -- Error: {}", path.to_string_lossy(), e);
+                        None
+                    }
+                }
+            }
+        };
+        entries.push(CatalogEntry{path: rel, size: meta.len(), mtime: modified, sha256, part: None});
+    });
+    if let Err(e) = walked
+    {
+        error!("Couldn't read snapshot {} of source {} to catalog it -- Error: {}", timestamp, source_name, e);
+        return false;
+    }
+    match add_generation(&catalog_file, source_name, Kind::Snapshot, timestamp, &entries)
+    {
+        Ok(()) => {
+            info!("Cataloged snapshot {} of source {}: {} files, {} of them hashed", timestamp, source_name, entries.len(), hashed);
+            true
+        },
+        Err(e) => {
+            error!("Couldn't write snapshot {} of source {} to the catalog {} -- Error: {}", timestamp, source_name, catalog_file.to_string_lossy(), e);
+            false
+        }
+    }
+}
+
+/**
+Catalog the files of an export.
+
+The hashes are taken from the catalog of the snapshot the export was made from, for the files that are unchanged there.
+
+# Arguments
+* `snapshot_timestamp` - The snapshot the export was made from, if any
+* `entries` - The files in the export, with their parts
+
+# Returns
+bool for whether it succeeded. Failures are logged.
+*/
+pub fn catalog_export(source_name: &str, timestamp: i64, snapshot_timestamp: Option<i64>, mut entries: Vec<CatalogEntry>, settings: &Settings) -> bool
+{
+    let catalog_file = catalog_file(settings);
+    if let Some(snapshot_timestamp) = snapshot_timestamp
+    {
+        match generation_entries(&catalog_file, source_name, Kind::Snapshot, snapshot_timestamp)
+        {
+            Ok(snapshot) => {
+                for entry in &mut entries
+                {
+                    if let Some(s) = snapshot.get(&entry.path).filter(|s| s.size == entry.size && s.mtime == entry.mtime)
+                    {
+                        entry.sha256.clone_from(&s.sha256);
+                    }
+                }
+            },
+            Err(e) => {warn!("Couldn't read snapshot {} of source {} from the catalog, the export will be cataloged without hashes -- Error: {}", snapshot_timestamp, source_name, e);}
+        }
+    }
+    match add_generation(&catalog_file, source_name, Kind::Export, timestamp, &entries)
+    {
+        Ok(()) => {
+            info!("Cataloged export {} of source {}: {} files", timestamp, source_name, entries.len());
+            true
+        },
+        Err(e) => {
+            error!("Couldn't write export {} of source {} to the catalog {} -- Error: {}", timestamp, source_name, catalog_file.to_string_lossy(), e);
+            false
+        }
+    }
+}
+
+/**
+Catalog the snapshots of a source that aren't in the catalog yet, e.g. ones taken before the catalog existed.
+*/
+pub fn catalog_missing(source_name: &str, settings: &Settings)
+{
+    let cataloged = match generations(&catalog_file(settings), source_name, Kind::Snapshot)
+    {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Couldn't read the catalog {} -- Error: {}", catalog_file(settings).to_string_lossy(), e);
+            return;
+        }
+    };
+    let missing = list_snapshots(&settings.startup.storage_dir, source_name).into_iter().filter(|ts| !cataloged.contains(ts)).collect::<Vec<i64>>();
+    info!("Source {} has {} snapshots that aren't cataloged yet", source_name, missing.len());
+    for timestamp in missing
+    {
+        catalog_snapshot(source_name, timestamp, settings);
+    }
+}
+
+/**
+Forget a deleted generation, logging any failure.
+*/
+pub fn forget(source_name: &str, kind: Kind, generation: i64, settings: &Settings)
+{
+    if let Err(e) = remove_generation(&catalog_file(settings), source_name, kind, generation)
+    {
+        error!("Couldn't remove {} {} of source {} from the catalog -- Error: {}", kind.as_str(), generation, source_name, e);
+    }
+}
+
+/**
+Search the catalog for the pattern in the action settings and log what was found.
+*/
+pub fn find(settings: &Settings)
+{
+    let matcher = match Matcher::parse(&settings.action.search, settings.action.search_regex)
+    {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Can't search the catalog: {}", e);
+            return;
+        }
+    };
+    let versions = match search(&catalog_file(settings), &settings.action.source, &matcher)
+    {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Couldn't search the catalog {} -- Error: {}", catalog_file(settings).to_string_lossy(), e);
+            return;
+        }
+    };
+    info!("Found {} versions of files matching {}", versions.len(), settings.action.search);
+    for version in versions
+    {
+        info!("{}", describe(&version));
+    }
+}
+
+/**
+Describe a version of a file and where it can be found, in one line.
+*/
+pub fn describe(version: &FileVersion) -> String
+{
+    let snapshots = version.snapshots.iter().map(|ts| ts.to_string()).collect::<Vec<String>>().join(", ");
+    let exports = version.exports.iter().map(|(ts, part)| match part
+    {
+        Some(p) => format!("{ts} (part {p:04})"),
+        None => ts.to_string()
+    }).collect::<Vec<String>>().join(", ");
+    format!("{}: {} -- {} bytes, modified {}, sha256 {} -- snapshots: {} -- exports: {}",
+        version.source,
+        version.path,
+        version.size,
+        format_timestamp(version.mtime),
+        version.sha256.as_deref().unwrap_or("unknown"),
+        if snapshots.is_empty() {"none"} else {&snapshots},
+        if exports.is_empty() {"none"} else {&exports}
+    )
+}
+
+/**
+Modification time of a file in seconds since the epoch, 0 when it's not available.
+*/
+pub fn mtime(meta: &fs::Metadata) -> i64
+{
+    meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .and_then(|d| i64::try_from(d.as_secs()).ok())
+        .unwrap_or(0)
+}
+
+/**
+Call a function for every regular file in a directory, recursively and in name order. Symlinks aren't followed.
+
+# Arguments
+* `root` - Directory the relative paths are relative to
+* `rel` - Directory to walk, relative to `root`
+* `f` - Called with the full path, the path relative to `root`, and the metadata of each file
+*/
+fn walk_files<F: FnMut(&Path, &Path, &fs::Metadata)>(root: &Path, rel: &Path, f: &mut F) -> io::Result<()>
+{
+    let mut entries = fs::read_dir(root.join(rel))?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries
+    {
+        let rel_path = rel.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir()
+        {
+            walk_files(root, &rel_path, f)?;
+        }else if file_type.is_file()
+        {
+            f(&entry.path(), &rel_path, &entry.metadata()?);
+        }
+    }
+    Ok(())
+}
+
+fn read_entry(stmt: &sqlite::Statement) -> Result<CatalogEntry, sqlite::Error>
+{
+    Ok(CatalogEntry{
+        path: stmt.read::<String, _>("path")?,
+        size: u64::try_from(stmt.read::<i64, _>("size")?).unwrap_or(0),
+        mtime: stmt.read::<i64, _>("mtime")?,
+        sha256: stmt.read::<Option<String>, _>("sha256")?,
+        part: stmt.read::<Option<i64>, _>("part")?.and_then(|p| u32::try_from(p).ok())
+    })
+}
+
+fn delete_rows(connection: &Connection, source_name: &str, kind: Kind, generation: i64) -> Result<(), sqlite::Error>
+{
+    let delete_query = "DELETE FROM files WHERE source = :source AND kind = :kind AND generation = :generation";
+    let mut delete_stmt = connection.prepare(delete_query)?;
+    delete_stmt.bind::<&[(_, Value)]>(&[
+        (":source", source_name.into()),
+        (":kind", kind.as_str().into()),
+        (":generation", generation.into())
+    ])?;
+    while State::Row == delete_stmt.next()? {}
+    Ok(())
+}
+
+/**
+Establish a sqlite connection to the catalog.
+
+Also creates the file if it doesn't exist, and inside the file, creates the table if it doesn't exist.
+
+# Returns
+- On success, a Connection
+- On failure, a sqlite::Error
+*/
+fn connect(catalog_file: &Path) -> Result<Connection, sqlite::Error>
+{
+    let connection = sqlite::open(catalog_file)?;
+    connection.execute("
+        CREATE TABLE IF NOT EXISTS files (
+            source TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            generation INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            sha256 TEXT,
+            part INTEGER,
+            PRIMARY KEY (source, kind, generation, path)
+        );
+        CREATE INDEX IF NOT EXISTS files_by_path ON files (source, path);
+    ")?;
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn entry(path: &str, size: u64, mtime: i64, part: Option<u32>) -> CatalogEntry
+    {
+        CatalogEntry{path: path.to_string(), size, mtime, sha256: Some(format!("hash of {path} at {mtime}")), part}
+    }
+
+    #[test]
+    fn matching_paths()
+    {
+        let by_name = Matcher::parse("*.pdf", false).unwrap();
+        assert!(by_name.matches("_home_/alice/report.pdf"));
+        assert!(!by_name.matches("_home_/alice/report.pdf.txt"));
+        let by_path = Matcher::parse("_home_/*/report.pdf", false).unwrap();
+        assert!(by_path.matches("_home_/alice/report.pdf"));
+        assert!(!by_path.matches("_home_/alice/old/report.pdf"));
+        let regex = Matcher::parse("alice/.*\\.pdf$", true).unwrap();
+        assert!(regex.matches("_home_/alice/old/report.pdf"));
+        assert!(Matcher::parse("(", true).is_err());
+        assert!(Matcher::parse(" ", false).is_err());
+    }
+
+    #[test]
+    fn versions_across_generations()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog_file = dir.path().join("catalog.db");
+        add_generation(&catalog_file, "laptop", Kind::Snapshot, 100, &[entry("docs/a.txt", 1, 10, None), entry("docs/b.txt", 2, 10, None)]).unwrap();
+        add_generation(&catalog_file, "laptop", Kind::Snapshot, 200, &[entry("docs/a.txt", 3, 20, None)]).unwrap();
+        add_generation(&catalog_file, "laptop", Kind::Export, 150, &[entry("docs/a.txt", 1, 10, Some(2))]).unwrap();
+        add_generation(&catalog_file, "server", Kind::Snapshot, 100, &[entry("docs/a.txt", 1, 10, None)]).unwrap();
+
+        let found = search(&catalog_file, "laptop", &Matcher::parse("a.txt", false).unwrap()).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].mtime, found[0].snapshots.clone(), found[0].exports.clone()), (10, vec!(100), vec!((150, Some(2)))));
+        assert_eq!((found[1].mtime, found[1].snapshots.clone(), found[1].exports.clone()), (20, vec!(200), vec!()));
+        assert_eq!(search(&catalog_file, "", &Matcher::parse("docs/*", false).unwrap()).unwrap().len(), 4);
+
+        // recording a generation again replaces it, and removing it leaves the others
+        add_generation(&catalog_file, "laptop", Kind::Snapshot, 100, &[entry("docs/a.txt", 1, 10, None)]).unwrap();
+        assert_eq!(generation_entries(&catalog_file, "laptop", Kind::Snapshot, 100).unwrap().len(), 1);
+        remove_generation(&catalog_file, "laptop", Kind::Snapshot, 100).unwrap();
+        assert_eq!(generations(&catalog_file, "laptop", Kind::Snapshot).unwrap(), vec!(200));
+        assert_eq!(generations(&catalog_file, "server", Kind::Snapshot).unwrap(), vec!(100));
+    }
+}
@@ -0,0 +1,116 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::{fs::File, io::{self, Read}, path::Path};
+
+/** Dropbox hashes files in blocks of this size for its content_hash */
+pub const DROPBOX_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/**
+Hash each consecutive block of a file separately.
+
+# Arguments
+* `path` - File to hash
+* `block_size` - Size of each block in bytes. The last block may be shorter.
+
+# Returns
+The digest of each block in order. An empty file has no blocks.
+*/
+pub fn block_digests<D: Digest>(path: &Path, block_size: u64) -> io::Result<Vec<Vec<u8>>>
+{
+    let block_size = u64::max(block_size, 1);
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut digests = Vec::new();
+    loop
+    {
+        let mut hasher = D::new();
+        let mut in_block: u64 = 0;
+        while in_block < block_size
+        {
+            let want = usize::try_from(u64::min(block_size - in_block, BUFFER_SIZE as u64)).unwrap_or(BUFFER_SIZE);
+            let read = file.read(&mut buffer[..want])?;
+            if read == 0 {break;}
+            hasher.update(&buffer[..read]);
+            in_block += read as u64;
+        }
+        if in_block == 0
+        {
+            return Ok(digests);
+        }
+        digests.push(hasher.finalize().to_vec());
+        if in_block < block_size
+        {
+            return Ok(digests);
+        }
+    }
+}
+
+/**
+Hash a whole file.
+
+# Returns
+The hex encoded digest.
+*/
+pub fn file_digest<D: Digest>(path: &Path) -> io::Result<String>
+{
+    let digest = match block_digests::<D>(path, u64::MAX)?.pop()
+    {
+        Some(d) => d,
+        None => D::digest(b"").to_vec()
+    };
+    Ok(hex::encode(digest))
+}
+
+/**
+Compute Dropbox's content_hash of a file: the SHA-256 of the concatenated SHA-256 hashes of each 4MiB block.
+*/
+pub fn dropbox_content_hash(path: &Path) -> io::Result<String>
+{
+    let blocks = block_digests::<Sha256>(path, DROPBOX_BLOCK_SIZE)?;
+    Ok(hex::encode(Sha256::digest(blocks.concat())))
+}
+
+/**
+Compute the ETag S3 gives an object uploaded in multiple parts: the MD5 of the concatenated MD5 hashes of each part, followed by -{number of parts}.
+
+# Arguments
+* `part_size` - Size of the parts the object was uploaded in
+*/
+pub fn s3_multipart_etag(path: &Path, part_size: u64) -> io::Result<String>
+{
+    let parts = block_digests::<Md5>(path, part_size)?;
+    Ok(format!("{}-{}", hex::encode(Md5::digest(parts.concat())), parts.len()))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn hashes_of_blocks()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        let data: Vec<u8> = (0..=255).cycle().take(10000).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        assert_eq!(file_digest::<Sha256>(&path).unwrap(), hex::encode(Sha256::digest(&data)));
+        // less than a block, so it's the hash of the hash
+        assert_eq!(dropbox_content_hash(&path).unwrap(), hex::encode(Sha256::digest(Sha256::digest(&data))));
+
+        let part_md5s = [Md5::digest(&data[..4096]), Md5::digest(&data[4096..8192]), Md5::digest(&data[8192..])].concat();
+        assert_eq!(s3_multipart_etag(&path, 4096).unwrap(), format!("{}-3", hex::encode(Md5::digest(part_md5s))));
+    }
+
+    #[test]
+    fn empty_file()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty");
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(file_digest::<Md5>(&path).unwrap(), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(dropbox_content_hash(&path).unwrap(), hex::encode(Sha256::digest(b"")));
+    }
+}
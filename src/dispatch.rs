@@ -1,14 +1,70 @@
-use log::{error, /*warn, */info/*, debug, trace, log, Level*/};
-use std::collections::HashMap;
+use log::{error, warn, info/*, debug, trace, log, Level*/};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Instant};
 
-use crate::{upload::{dropbox::{dropbox_up, dropbox_auth}, gdrive::gdrive_up}, export::{export, unexport}, mysql, rsync::sync, settings::app_settings::{Settings, Source}};
+use crate::{app_logger::error_count, cancel, metrics, resources::auth, upload, catalog, export::{export, list_exports, unexport}, jobs, mysql, notify, retention::prune, rsync::{sync, RsyncOutcome, SyncStats}, restore::{list_remote, restore}, settings::app_settings::{Settings, Source}, verify::verify};
+
+/**
+How one step of a dispatch went.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct SourceResult
+{
+    /** What was done, e.g. "sync" or "upload to s3" */
+    pub action: String,
+    /** The source it was done for, blank for steps that aren't about one source */
+    pub source: String,
+    /** Whether it succeeded without logging any errors */
+    pub ok: bool,
+    /** When it started, as a unix timestamp */
+    #[serde(default)]
+    pub started_at: i64,
+    #[serde(default)]
+    pub duration_secs: f64,
+    /** Bytes it synced, exported, uploaded or downloaded */
+    #[serde(default)]
+    pub bytes: u64,
+    /** Exit code of the commands it ran, e.g. rsync for a sync, the highest if there were several. None if it didn't run any */
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    /** The hostname or IP the source was reached at, for syncs */
+    #[serde(default)]
+    pub host: Option<String>,
+    /** How rsync went, for syncs. The worst if it ran several times */
+    #[serde(default)]
+    pub rsync_outcome: Option<RsyncOutcome>,
+    /** Files rsync had trouble with, as it reported them */
+    #[serde(default)]
+    pub file_errors: Vec<String>,
+    /** What rsync reported about each path, for syncs */
+    #[serde(default)]
+    pub sync_stats: Vec<SyncStats>,
+    /** Why the change guard stopped the sync, if it did */
+    #[serde(default)]
+    pub held_back: Option<String>
+}
+
+impl SourceResult
+{
+    /**
+    The result of a step that failed before it got going.
+    */
+    pub fn failed(action: &str, source_name: &str) -> SourceResult
+    {
+        SourceResult{action: action.to_string(), source: source_name.to_string(), ok: false, started_at: chrono::Utc::now().timestamp(), ..Default::default()}
+    }
+}
 
 /**
 Do all of the actions specified in the "action" section of the configuration in a sensible order once then terminate.
 This handles everything necessary when calling redundinator_manual on the command line.
+
+# Returns
+How each step went, in the order they were done.
 */
-pub fn dispatch(settings: &Settings)
+pub fn dispatch(settings: &Settings) -> Vec<SourceResult>
 {
+    let mut results = Vec::new();
     let sources: HashMap<String,Source> = if settings.action.source.is_empty()
     {
         settings.sources.clone()
@@ -19,16 +75,31 @@ pub fn dispatch(settings: &Settings)
             None => {
                 let sources_list = settings.sources.keys().cloned().collect::<Vec<String>>().join(",");
                 error!("active source {} not found in sources list ({})", settings.action.source, sources_list);
-                return;
+                results.push(SourceResult::failed("select source", &settings.action.source));
+                return results;
             }
         }
     };
     let sources_list = sources.keys().cloned().collect::<Vec<String>>().join(",");
 
-    if settings.action.auth_dropbox
+    if settings.action.hash_password
+    {
+        step(settings, &mut results, "hash_password", "", auth::print_password_hash);
+    }
+
+    if settings.action.test_notifiers
+    {
+        info!("Sending a test notification through every notifier");
+        step(settings, &mut results, "test_notifiers", "", || notify::send_test(settings));
+    }
+
+    if settings.action.auth_upload
     {
-        info!("Running auth for Dropbox");
-        dropbox_auth(settings);
+        for (target_name, setup) in upload::selected_targets(settings)
+        {
+            info!("Running auth for upload target: {}", target_name);
+            step(settings, &mut results, &format!("auth_upload to {target_name}"), "", || {upload::auth(&target_name, &setup, settings); true});
+        }
     }
 
     if settings.action.sync
@@ -36,59 +107,172 @@ pub fn dispatch(settings: &Settings)
         info!("Running sync for hosts: {}", sources_list);
         for source in &sources
         {
-            sync(source, settings);
+            step(settings, &mut results, "sync", source.0, || {sync(source, settings); true});
         }
     }
 
     if settings.action.mysql_dump
     {
         info!("Running mysql dump for localhost");
-        mysql::dump(settings);
+        step(settings, &mut results, "mysql_dump", "", || {mysql::dump(settings); true});
     }
 
     if settings.action.export
     {
         info!("Running export for hosts: {}", sources_list);
-        for source in &sources
+        for name in sources.keys()
         {
-            let (name, _) = source;
-            export(name, settings);
+            step(settings, &mut results, "export", name, || {export(name, settings); true});
+        }
+    }
+
+    if settings.action.catalog
+    {
+        info!("Running catalog for hosts: {}", sources_list);
+        for name in sources.keys()
+        {
+            step(settings, &mut results, "catalog", name, || {catalog::catalog_missing(name, settings); true});
+        }
+    }
+
+    if !settings.action.search.is_empty()
+    {
+        step(settings, &mut results, "search", &settings.action.source, || {catalog::find(settings); true});
+    }
+
+    if settings.action.list_exports
+    {
+        for name in sources.keys()
+        {
+            step(settings, &mut results, "list_exports", name, || {list_exports(name, settings); true});
+        }
+    }
+
+    if settings.action.list_remote
+    {
+        for (target_name, target) in upload::connect_selected(settings)
+        {
+            info!("Listing exports at {}", target_name);
+            step(settings, &mut results, &format!("list_remote at {target_name}"), &settings.action.source, || {list_remote(target.as_ref(), &settings.action.source); true});
+        }
+    }
+
+    if settings.action.restore
+    {
+        info!("Running restore for hosts: {}", sources_list);
+        let targets = upload::connect_selected(settings);
+        for name in sources.keys()
+        {
+            step(settings, &mut results, "restore", name, || restore(name, settings, &targets));
         }
     }
 
     if settings.action.unexport
     {
         info!("Running unexport for hosts: {}", sources_list);
-        for source in &sources
+        for name in sources.keys()
         {
-            let (name, _) = source;
-            unexport(name, settings);
+            step(settings, &mut results, "unexport", name, || {unexport(name, settings); true});
         }
     }
 
-    if settings.action.upload_dropbox
+    if settings.action.upload
     {
-        info!("Running dropbox upload for hosts: {}", sources_list);
-        for source in &sources
+        for (target_name, setup) in upload::selected_targets(settings)
         {
-            let (name, _) = source;
-            dropbox_up(name, settings);
+            info!("Running upload to {} for hosts: {}", target_name, sources_list);
+            let action = format!("upload to {target_name}");
+            let target = match upload::connect(&target_name, &setup, settings)
+            {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("Couldn't connect to upload target {}, skipping it -- Error: {}", target_name, e);
+                    results.push(SourceResult::failed(&action, ""));
+                    continue;
+                }
+            };
+            for name in sources.keys()
+            {
+                let mut uploaded = true;
+                step(settings, &mut results, &action, name, || {uploaded = upload::upload_source(target.as_ref(), name, settings); uploaded});
+                if !uploaded
+                {
+                    info!("Systemic error encountered in upload to {}, not uploading any more sources to it", target_name);
+                    break;
+                }
+            }
         }
     }
 
-    if settings.action.upload_gdrive
+    if settings.action.verify
     {
-        info!("Running Google Drive upload for hosts: {}", sources_list);
-        for source in &sources
+        info!("Running verify for hosts: {}", sources_list);
+        let targets = upload::connect_selected(settings);
+        let mut failed = Vec::new();
+        for name in sources.keys()
         {
-            let (name, _) = source;
-            if !gdrive_up(name, settings)
+            let mut verified = true;
+            step(settings, &mut results, "verify", name, || {verified = verify(name, settings, &targets); verified});
+            if !verified
             {
-                info!("Systemic error encountered in upload, not uploading any more sources");
-                break;
+                failed.push(name.clone());
             }
         }
+        if !failed.is_empty()
+        {
+            error!("Verify found problems with the exports of: {}", failed.join(","));
+        }
+    }
+
+    if settings.action.prune
+    {
+        info!("Running retention for hosts: {}", sources_list);
+        for name in sources.keys()
+        {
+            step(settings, &mut results, "prune", name, || {prune(name, settings, settings.action.dry_run); true});
+        }
     }
     
     info!("Redundinator completed all actions.");
+    results
+}
+
+/**
+Do one step of a dispatch and record how it went, in the results and in the metrics. It failed if it says so, or if it logged any errors.
+Once the running job is cancelled, the step that was cut off counts as failed, and the remaining steps are skipped and not recorded.
+
+# Arguments
+* `f` - Does the step and returns whether it succeeded
+*/
+fn step<F: FnOnce() -> bool>(settings: &Settings, results: &mut Vec<SourceResult>, action: &str, source_name: &str, f: F)
+{
+    if cancel::cancelled()
+    {
+        return;
+    }
+    let errors_before = error_count();
+    let started_at = chrono::Utc::now().timestamp();
+    let started = Instant::now();
+    metrics::take_notes();
+    let ok = f();
+    let notes = metrics::take_notes();
+    let result = SourceResult{
+        action: action.to_string(),
+        source: source_name.to_string(),
+        ok: ok && error_count() == errors_before && !cancel::cancelled(),
+        started_at,
+        duration_secs: started.elapsed().as_secs_f64(),
+        bytes: notes.bytes,
+        exit_code: notes.exit_code,
+        host: notes.host,
+        rsync_outcome: notes.rsync_outcome,
+        file_errors: notes.file_errors,
+        sync_stats: notes.sync_stats,
+        held_back: notes.held_back
+    };
+    if let Err(e) = metrics::record(&metrics::metrics_file(settings), &result)
+    {
+        warn!("Couldn't record how {} went in the metrics -- Error: {}", jobs::describe_result(&result), e);
+    }
+    results.push(result);
 }
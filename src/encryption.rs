@@ -0,0 +1,298 @@
+use age::{secrecy::SecretString, stream::{StreamReader, StreamWriter}, Decryptor, Encryptor, Identity, IdentityFile, Recipient};
+use std::{fs, io::{self, BufRead, BufReader, Read, Write}, path::Path, str::FromStr};
+
+use crate::settings::app_settings::Encryption;
+
+/*
+Exports can be encrypted with age (https://age-encryption.org), so they can be read with the age command line tool as well.
+The encryption is applied to the compressed stream before it's split into parts, and the parts keep their usual names.
+Encrypted exports are recognized by the age header at the start of the first part, so unexport doesn't need to be told.
+
+Exports are encrypted to public keys, so the host making them never needs a private key.
+Only restoring needs the matching private key, from the identity file.
+*/
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+/** age encrypts in chunks of 64KiB plus a 16 byte tag, so at most this much is held back before being written out */
+pub const CHUNK_SIZE: u64 = 64 * 1024 + 16;
+
+/**
+Check whether any encryption is configured.
+*/
+pub fn is_enabled(config: &Encryption) -> bool
+{
+    !config.recipients.is_empty() || !config.passphrase.is_empty()
+}
+
+/**
+Check whether a key to decrypt exports is configured.
+*/
+pub fn can_decrypt(config: &Encryption) -> bool
+{
+    !config.passphrase.is_empty() || !config.identity_file.is_empty()
+}
+
+/**
+Check whether a file starts with an age header, i.e. is the first part of an encrypted export.
+*/
+pub fn starts_encrypted(path: &Path) -> io::Result<bool>
+{
+    let mut start = Vec::with_capacity(AGE_MAGIC.len());
+    fs::File::open(path)?.take(AGE_MAGIC.len() as u64).read_to_end(&mut start)?;
+    Ok(start == AGE_MAGIC)
+}
+
+/**
+Describe how exports are being encrypted, e.g. for logs and manifests.
+*/
+pub fn describe(config: &Encryption) -> String
+{
+    if !config.passphrase.is_empty()
+    {
+        String::from("age (passphrase)")
+    }else if !config.recipients.is_empty()
+    {
+        format!("age ({} recipients)", config.recipients.len())
+    }else{
+        String::from("none")
+    }
+}
+
+/**
+Parse one recipient: an age public key (age1...) or an SSH public key (ssh-ed25519 ... or ssh-rsa ...).
+*/
+fn parse_recipient(recipient: &str) -> Result<Box<dyn Recipient + Send>, String>
+{
+    let recipient = recipient.trim();
+    if recipient.starts_with("ssh-")
+    {
+        match age::ssh::Recipient::from_str(recipient)
+        {
+            Ok(r) => Ok(Box::new(r)),
+            Err(e) => Err(format!("Invalid SSH recipient {recipient}: {e:?}"))
+        }
+    }else{
+        match age::x25519::Recipient::from_str(recipient)
+        {
+            Ok(r) => Ok(Box::new(r)),
+            Err(e) => Err(format!("Invalid age recipient {recipient}: {e}"))
+        }
+    }
+}
+
+/**
+Set up encryption according to the configuration.
+
+# Returns
+An encryptor, None if encryption isn't configured, or a description of what's wrong with the configuration.
+*/
+pub fn encryptor(config: &Encryption) -> Result<Option<Encryptor>, String>
+{
+    if !config.passphrase.is_empty()
+    {
+        if !config.recipients.is_empty()
+        {
+            return Err(String::from("Encryption can use recipients or a passphrase, not both"));
+        }
+        return Ok(Some(Encryptor::with_user_passphrase(SecretString::from(config.passphrase.clone()))));
+    }
+    if config.recipients.is_empty()
+    {
+        return Ok(None);
+    }
+    let recipients = config.recipients.iter().map(|r| parse_recipient(r)).collect::<Result<Vec<_>, String>>()?;
+    match Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as &dyn Recipient))
+    {
+        Ok(e) => Ok(Some(e)),
+        Err(e) => Err(format!("Couldn't set up encryption: {e}"))
+    }
+}
+
+/**
+A writer that encrypts what goes through it, or passes it through unchanged when encryption isn't configured.
+*/
+pub enum EncryptingWriter<W: Write>
+{
+    Plain(W),
+    Encrypted(StreamWriter<W>)
+}
+
+impl<W: Write> EncryptingWriter<W>
+{
+    pub fn new(inner: W, encryptor: Option<Encryptor>) -> io::Result<EncryptingWriter<W>>
+    {
+        Ok(match encryptor
+        {
+            Some(e) => EncryptingWriter::Encrypted(e.wrap_output(inner)?),
+            None => EncryptingWriter::Plain(inner)
+        })
+    }
+
+    /**
+    Write out the end of the encrypted stream, which is required for it to be readable.
+
+    # Returns
+    The inner writer.
+    */
+    pub fn finish(self) -> io::Result<W>
+    {
+        match self
+        {
+            EncryptingWriter::Plain(w) => Ok(w),
+            EncryptingWriter::Encrypted(w) => w.finish()
+        }
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        match self
+        {
+            EncryptingWriter::Plain(w) => w.write(buf),
+            EncryptingWriter::Encrypted(w) => w.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        match self
+        {
+            EncryptingWriter::Plain(w) => w.flush(),
+            EncryptingWriter::Encrypted(w) => w.flush()
+        }
+    }
+}
+
+/**
+A reader that decrypts what comes through it, if it was encrypted.
+*/
+pub enum DecryptingReader<R: Read>
+{
+    Plain(BufReader<R>),
+    Encrypted(StreamReader<BufReader<R>>)
+}
+
+impl<R: Read> DecryptingReader<R>
+{
+    /**
+    Look at the start of a stream to see if it's encrypted and if so, set up decryption using the passphrase or identity file from the configuration.
+    */
+    pub fn new(inner: R, config: &Encryption) -> io::Result<DecryptingReader<R>>
+    {
+        let mut reader = BufReader::new(inner);
+        if !reader.fill_buf()?.starts_with(AGE_MAGIC)
+        {
+            return Ok(DecryptingReader::Plain(reader));
+        }
+
+        let decryptor = Decryptor::new_buffered(reader).map_err(|e| io::Error::other(format!("Couldn't read encryption header: {e}")))?;
+        let identities: Vec<Box<dyn Identity>> = if decryptor.is_scrypt()
+        {
+            if config.passphrase.is_empty()
+            {
+                return Err(io::Error::other("Export is encrypted with a passphrase, but none is configured"));
+            }
+            vec!(Box::new(age::scrypt::Identity::new(SecretString::from(config.passphrase.clone()))))
+        }else{
+            load_identities(&config.identity_file)?
+        };
+        match decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+        {
+            Ok(r) => Ok(DecryptingReader::Encrypted(r)),
+            Err(e) => Err(io::Error::other(format!("Couldn't decrypt export: {e}")))
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool
+    {
+        matches!(self, DecryptingReader::Encrypted(_))
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        match self
+        {
+            DecryptingReader::Plain(r) => r.read(buf),
+            DecryptingReader::Encrypted(r) => r.read(buf)
+        }
+    }
+}
+
+/**
+Load the private keys for decryption from an age identity file, or an unencrypted SSH private key.
+*/
+fn load_identities(identity_file: &str) -> io::Result<Vec<Box<dyn Identity>>>
+{
+    if identity_file.is_empty()
+    {
+        return Err(io::Error::other("Export is encrypted, but no identity file is configured to decrypt it"));
+    }
+    let contents = fs::read(identity_file)?;
+    if contents.windows(b"PRIVATE KEY-----".len()).any(|w| w == b"PRIVATE KEY-----")
+    {
+        return match age::ssh::Identity::from_buffer(&contents[..], Some(identity_file.to_string()))?
+        {
+            identity @ age::ssh::Identity::Unencrypted(_) => Ok(vec!(Box::new(identity))),
+            age::ssh::Identity::Encrypted(_) => Err(io::Error::other(format!("SSH key {identity_file} is protected with a passphrase, which isn't supported"))),
+            age::ssh::Identity::Unsupported(k) => Err(io::Error::other(format!("SSH key {identity_file} is of an unsupported type: {k:?}")))
+        };
+    }
+    match IdentityFile::from_buffer(&contents[..])?.into_identities()
+    {
+        Ok(identities) => Ok(identities),
+        Err(e) => Err(io::Error::other(format!("Couldn't load identities from {identity_file}: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    fn round_trip(config: &Encryption, data: &[u8]) -> (Vec<u8>, bool)
+    {
+        let mut writer = EncryptingWriter::new(Vec::new(), encryptor(config).unwrap()).unwrap();
+        writer.write_all(data).unwrap();
+        let encrypted = writer.finish().unwrap();
+        let mut reader = DecryptingReader::new(&encrypted[..], config).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        (decrypted, reader.is_encrypted())
+    }
+
+    #[test]
+    fn recipient_round_trip()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = age::x25519::Identity::generate();
+        let identity_path = dir.path().join("identity.txt");
+        fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+        let config = Encryption{
+            recipients: vec!(identity.to_public().to_string()),
+            passphrase: String::new(),
+            identity_file: identity_path.to_string_lossy().into_owned()
+        };
+        assert_eq!(round_trip(&config, b"some backup data"), (b"some backup data".to_vec(), true));
+    }
+
+    #[test]
+    fn unencrypted_passes_through()
+    {
+        let config = Encryption{recipients: vec!(), passphrase: String::new(), identity_file: String::new()};
+        assert_eq!(round_trip(&config, b"some backup data"), (b"some backup data".to_vec(), false));
+    }
+
+    #[test]
+    fn recipients_and_passphrase_conflict()
+    {
+        let identity = age::x25519::Identity::generate();
+        let config = Encryption{recipients: vec!(identity.to_public().to_string()), passphrase: String::from("secret"), identity_file: String::new()};
+        assert!(encryptor(&config).is_err());
+    }
+}
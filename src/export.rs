@@ -1,90 +1,687 @@
-use log::{error, /*warn, */info/*, debug, trace, log, Level*/};
-use run_script::ScriptOptions;
-use std::fs;
+use glob::{MatchOptions, Pattern};
+use log::{error, warn, info/*, debug, trace, log, Level*/};
+use std::{fs, io::{self, Write}, path::{Component, Path, PathBuf}, sync::{Arc, atomic::{AtomicU64, Ordering}}};
 
-use crate::latest_export_ts;
-use crate::settings::app_settings::Settings;
+use crate::{cancel, export_files, export_timestamps};
+use crate::catalog::{self, CatalogEntry};
+use crate::encryption::{self, DecryptingReader, EncryptingWriter};
+use crate::manifest::{Manifest, ManifestPart};
+use crate::metrics;
+use crate::parts::{PartsReader, SplitWriter, WrittenPart};
+use crate::progress;
+use crate::settings::app_settings::{Export, Settings};
+use crate::snapshot::{export_base, latest_snapshot};
+use crate::usage::content_size;
 
+const MIB: u64 = 1024 * 1024;
+/** Most uncompressed data to add to an archive between flushes of the compressor, which settle which part the files are in */
+const PART_CHECKPOINT_INTERVAL: u64 = 64 * MIB;
+
+/**
+Export the latest snapshot of a source to the export directory as a tar archive compressed with zstd, optionally encrypted with age, split into parts.
+
+The parts are written to a temporary directory first and only moved into place when the whole archive was written,
+so an export that fails partway (e.g. because a file couldn't be read) never leaves parts behind that look like a finished export.
+The manifest is moved into place last. Then the files in the export are added to the catalog.
+*/
 pub fn export(source_name: &str, settings: &Settings)
 {
-    info!("Beginning export (tar+zstd|split) for source: {}", source_name);
+    info!("Beginning export (tar+zstd) for source: {}", source_name);
 
     let now = chrono::Utc::now().timestamp();
-    let export_path = &settings.startup.export_dir;
-    let source = format!(r#"{}/sources/{source_name}"#, settings.startup.storage_dir);
-    let dest = format!(r#"{export_path}/{source_name}_{now}"#);
+    let export_path = PathBuf::from(&settings.startup.export_dir);
+    let base = match export_base(&settings.startup.storage_dir, source_name)
+    {
+        Some(b) => b,
+        None => {
+            error!("Nothing to export for source: {} -- it has no complete snapshot", source_name);
+            return;
+        }
+    };
+    let source = match fs::canonicalize(&base)
+    {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Nothing to export for source: {} -- Error: {}", source_name, e);
+            return;
+        }
+    };
+    // the export base resolves to the snapshot's own directory, named after its timestamp
+    let snapshot_timestamp = latest_snapshot(&settings.startup.storage_dir, source_name)
+        .and_then(|_| source.file_name()?.to_str()?.parse::<i64>().ok());
+    let prefix = format!("{source_name}_{now}.tar.zst.");
+    let staging = export_path.join(format!("{source_name}_{now}.partial"));
 
-    if let Err(e) = fs::create_dir_all(export_path)
+    remove_stale_staging(source_name, &export_path);
+    if let Err(e) = fs::create_dir_all(&staging)
     {
         error!("Couldn't create directory for export destination. Error: {}", e);
         return;
     }
 
-    let cmd_export = format!(r#"tar --zstd -C {source} -cf - . | split --numeric-suffixes --bytes=100G --suffix-length=4 - "{dest}.tar.zst.""#);
-    info!(target: "cmdlog", "{}", cmd_export);
-    match run_script::run(&cmd_export, &Vec::new(), &ScriptOptions::new())
-    {
-        Ok(v) => {
-            let (code, stdout, stderr) = v;
-            if code != 0
+    progress::start(&format!("export for {source_name}"), Some(content_size(&source)));
+    let archived = write_archive(&source, &staging, &prefix, &settings.export);
+    progress::finish();
+    let result = archived.and_then(|summary| {
+        let manifest = Manifest{
+            source: source_name.to_string(),
+            timestamp: now,
+            snapshot_timestamp,
+            parts: summary.parts.iter().map(|p| ManifestPart{
+                name: p.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+                size: p.size,
+                sha256: p.sha256.clone()
+            }).collect(),
+            file_count: summary.file_count,
+            uncompressed_size: summary.uncompressed_size,
+            part_size_mib: settings.export.part_size_mib,
+            compression_level: settings.export.compression_level,
+            encryption: encryption::describe(&settings.export.encryption),
+            encryption_recipients: settings.export.encryption.recipients.clone(),
+            redundinator_version: env!("CARGO_PKG_VERSION").to_string()
+        };
+        let staged_manifest = manifest.write(&staging)?;
+        for part in &summary.parts
+        {
+            if let Some(name) = part.path.file_name()
             {
-                error!("export (tar+zstd|split) returned nonzero exit code! Command: {} -- Exit Code: {} -- stdout: {} -- stderr: {}",
-                    cmd_export,
-                    code,
-                    stdout,
-                    stderr
-                );
+                fs::rename(&part.path, export_path.join(name))?;
             }
+        }
+        fs::rename(&staged_manifest, export_path.join(Manifest::file_name(source_name, now)))?;
+        Ok((manifest, summary.entries))
+    });
+    match result
+    {
+        Ok((m, entries)) => {
+            metrics::transferred(m.parts.iter().map(|p| p.size).sum());
+            info!("Completed export (tar+zstd) for source: {} -- {} files, {} bytes in {} parts, encryption: {}", source_name, m.file_count, m.uncompressed_size, m.parts.len(), m.encryption);
+            catalog::catalog_export(source_name, now, snapshot_timestamp, entries, settings);
         },
-        Err(e) => {
-            error!("Failed to run export (tar+zstd|split)! Command: {} -- Error: {}", cmd_export, e);
+        Err(_) if cancel::cancelled() => {warn!("Export (tar+zstd) for source {} was cancelled", source_name);},
+        Err(e) => {error!("Export (tar+zstd) failed for source: {} -- Error: {}", source_name, e);}
+    }
+    if let Err(e) = fs::remove_dir_all(&staging)
+    {
+        error!("Couldn't remove temporary export directory {} -- Error: {}", staging.to_string_lossy(), e);
+    }
+}
+
+/**
+Remove the temporary directories left behind by exports of a source that crashed or were killed, with whatever parts they had written.
+*/
+fn remove_stale_staging(source_name: &str, export_path: &Path)
+{
+    let entries = match fs::read_dir(export_path)
+    {
+        Ok(e) => e,
+        Err(_) => {return;} // nothing exported yet
+    };
+    for entry in entries.filter_map(Result::ok)
+    {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let stale = name.strip_prefix(&format!("{source_name}_"))
+            .and_then(|rest| rest.strip_suffix(".partial"))
+            .is_some_and(|timestamp| timestamp.parse::<i64>().is_ok());
+        if !stale || !entry.path().is_dir()
+        {
+            continue;
+        }
+        match fs::remove_dir_all(entry.path())
+        {
+            Ok(()) => {warn!("Removed temporary directory of an export that didn't finish: {}", entry.path().to_string_lossy());},
+            Err(e) => {error!("Couldn't remove temporary directory of an export that didn't finish: {} -- Error: {}", entry.path().to_string_lossy(), e);}
         }
     }
-    info!("Completed export (tar+zstd|split) for source: {}", source_name);
 }
 
-pub fn unexport(source_name: &str, settings: &Settings)
+/**
+What went into an archive written by write_archive.
+*/
+struct ArchiveSummary
+{
+    parts: Vec<WrittenPart>,
+    file_count: u64,
+    uncompressed_size: u64,
+    /** The regular files, with the part each is in */
+    entries: Vec<CatalogEntry>
+}
+
+/**
+Write a directory into a tar+zstd archive split into parts, encrypting it if configured.
+
+# Arguments
+* `source` - Directory to archive. Its contents are stored with paths relative to it.
+* `dest_dir` - Directory to write the parts in
+* `prefix` - Filename of the parts before the part number
+* `config` - Part size, compression and encryption settings
+
+# Returns
+The parts, in order, and what was archived, or the first error encountered.
+*/
+fn write_archive(source: &Path, dest_dir: &Path, prefix: &str, config: &Export) -> io::Result<ArchiveSummary>
+{
+    let encryptor = encryption::encryptor(&config.encryption).map_err(io::Error::other)?;
+    let split = SplitWriter::new(dest_dir, prefix, config.part_size_mib * MIB);
+    let position = split.position();
+    let encrypted = EncryptingWriter::new(split, encryptor)?;
+
+    let mut encoder = zstd::stream::write::Encoder::new(encrypted, config.compression_level)?;
+    encoder.include_checksum(true)?;
+    let threads = match config.threads
+    {
+        0 => std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1),
+        n => n
+    };
+    if threads > 1
+    {
+        encoder.multithread(threads)?;
+    }
+
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+    builder.append_dir(".", source)?;
+    let mut summary = ArchiveSummary{parts: Vec::new(), file_count: 0, uncompressed_size: 0, entries: Vec::new()};
+    let mut tracker = PartTracker::new(position, config.part_size_mib * MIB, encryption::is_enabled(&config.encryption));
+    append_tree(&mut builder, source, Path::new(""), &mut summary, &mut tracker)?;
+    let encoder = builder.into_inner()?;
+    let encrypted = encoder.finish()?;
+    let split = encrypted.finish()?;
+    summary.parts = split.finish()?;
+    let last_part = u32::try_from(summary.parts.len().saturating_sub(1)).unwrap_or(u32::MAX);
+    tracker.settle(&mut summary.entries, last_part);
+    for entry in &mut summary.entries
+    {
+        entry.part = entry.part.map(|p| u32::min(p, last_part));
+    }
+    Ok(summary)
+}
+
+/**
+Works out which part each file of an archive is in.
+
+The compressor holds on to some of its input before writing it out, so the position in the output says little about where the file just added will end up.
+Instead the compressor is flushed every so often, after which every file added since the previous flush is known to be in the part
+being written or an earlier one. That part is what gets recorded: the parts up to it are enough to extract the file.
+*/
+struct PartTracker
+{
+    position: Arc<AtomicU64>,
+    part_size: u64,
+    /** What the encryption may still be holding on to after a flush: one chunk */
+    slack: u64,
+    flush_every: u64,
+    since_flush: u64,
+    /** Index of the first entry whose part isn't known yet */
+    unsettled: usize
+}
+
+impl PartTracker
+{
+    /**
+    # Arguments
+    * `position` - Counter of the bytes written to the parts, from SplitWriter::position
+    * `part_size` - Size of each part in bytes
+    * `encrypted` - Whether the archive is encrypted
+    */
+    fn new(position: Arc<AtomicU64>, part_size: u64, encrypted: bool) -> PartTracker
+    {
+        let part_size = u64::max(part_size, 1);
+        PartTracker{
+            position,
+            part_size,
+            slack: if encrypted {encryption::CHUNK_SIZE} else {0},
+            flush_every: u64::min(part_size, PART_CHECKPOINT_INTERVAL),
+            since_flush: 0,
+            unsettled: 0
+        }
+    }
+
+    /**
+    Note that an entry of the given size was added to the archive, flushing it if it's time to.
+    */
+    fn added<W: Write>(&mut self, builder: &mut tar::Builder<W>, size: u64, entries: &mut [CatalogEntry]) -> io::Result<()>
+    {
+        self.since_flush += size;
+        if self.since_flush >= self.flush_every
+        {
+            builder.get_mut().flush()?;
+            let written = self.position.load(Ordering::Relaxed) + self.slack;
+            self.settle(entries, u32::try_from(written / self.part_size).unwrap_or(u32::MAX));
+        }
+        Ok(())
+    }
+
+    /**
+    Record the part of every entry added since the last time.
+    */
+    fn settle(&mut self, entries: &mut [CatalogEntry], part: u32)
+    {
+        for entry in entries.iter_mut().skip(self.unsettled)
+        {
+            entry.part = Some(part);
+        }
+        self.unsettled = entries.len();
+        self.since_flush = 0;
+    }
+}
+
+/**
+Add everything in a directory to an archive, recursively and in name order, recording the regular files and their sizes.
+Sockets are skipped, as tar does, since they can't be archived.
+
+# Arguments
+* `root` - Directory the archive's paths are relative to
+* `rel` - Directory to add, relative to `root`
+* `tracker` - Works out the part each file is in
+*/
+fn append_tree<W: Write>(builder: &mut tar::Builder<W>, root: &Path, rel: &Path, summary: &mut ArchiveSummary, tracker: &mut PartTracker) -> io::Result<()>
+{
+    let mut entries = fs::read_dir(root.join(rel))?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries
+    {
+        cancel::check()?;
+        let path = entry.path();
+        let rel_path = rel.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if is_socket(&file_type)
+        {
+            warn!("Skipping socket in export: {}", path.to_string_lossy());
+            continue;
+        }
+        if file_type.is_file()
+        {
+            progress::next_file(&rel_path.to_string_lossy());
+        }
+        builder.append_path_with_name(&path, &rel_path)?;
+        if file_type.is_dir()
+        {
+            append_tree(builder, root, &rel_path, summary, tracker)?;
+        }else if file_type.is_file()
+        {
+            let meta = entry.metadata()?;
+            summary.file_count += 1;
+            summary.uncompressed_size += meta.len();
+            summary.entries.push(CatalogEntry{
+                path: rel_path.to_string_lossy().into_owned(),
+                size: meta.len(),
+                mtime: catalog::mtime(&meta),
+                sha256: None,
+                part: None
+            });
+            progress::file_position(meta.len());
+            tracker.added(builder, meta.len(), &mut summary.entries)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_family = "unix")]
+fn is_socket(file_type: &fs::FileType) -> bool
 {
-    info!("Beginning unexport (cat|untar+zstd) for source: {}", source_name);
+    std::os::unix::fs::FileTypeExt::is_socket(file_type)
+}
 
-    let target_timestamp = match latest_export_ts(source_name, &settings.startup.export_dir)
+#[cfg(not(target_family = "unix"))]
+fn is_socket(_file_type: &fs::FileType) -> bool
+{
+    false
+}
+
+/**
+Extract an export of a source in the export dir into `{unexport_dir}/sources/{source}/`.
+Which export is picked by the timestamp or generations_ago in the action settings, the latest by default.
+*/
+pub fn unexport(source_name: &str, settings: &Settings)
+{
+    let timestamps = export_timestamps(source_name, &settings.startup.export_dir);
+    if timestamps.is_empty()
+    {
+        info!("Nothing to unexport.");
+        return;
+    }
+    let target_timestamp = match select_export(&timestamps, settings.action.timestamp, settings.action.generations_ago)
     {
         Some(t) => t,
         None =>{
-            info!("Nothing to unexport.");
+            error!("No such export of source {} -- there are {}: {}", source_name, timestamps.len(), timestamps.iter().map(|t| t.to_string()).collect::<Vec<String>>().join(","));
             return;
         }
     };
+    unexport_to(source_name, target_timestamp, &default_unexport_dest(source_name, settings), settings);
+}
+
+/**
+Pick one export out of the ones available.
+
+# Arguments
+* `timestamps` - The exports available, oldest first
+* `timestamp` - The exact export wanted, or 0 to go by `generations_ago`
+* `generations_ago` - How many exports back from the newest: 0 for the newest, 1 for the one before it, and so on
+
+# Returns
+The timestamp of the export, or None if there's no such export.
+*/
+pub fn select_export(timestamps: &[i64], timestamp: i64, generations_ago: u32) -> Option<i64>
+{
+    if timestamp != 0
+    {
+        return timestamps.iter().find(|t| **t == timestamp).copied();
+    }
+    timestamps.iter().rev().nth(generations_ago as usize).copied()
+}
+
+/**
+Log the exports of a source in the export dir, newest first.
+*/
+pub fn list_exports(source_name: &str, settings: &Settings)
+{
+    let export_dir = &settings.startup.export_dir;
+    let timestamps = export_timestamps(source_name, export_dir);
+    info!("{} exports of source {}", timestamps.len(), source_name);
+    for (generations_ago, timestamp) in timestamps.iter().rev().enumerate()
+    {
+        let time = chrono::DateTime::from_timestamp(*timestamp, 0).map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string()).unwrap_or_default();
+        let parts = export_files(source_name, *timestamp, export_dir);
+        let size: u64 = parts.iter().filter_map(|p| fs::metadata(p).ok()).map(|m| m.len()).sum();
+        let contents = match crate::manifest::load(source_name, *timestamp, export_dir)
+        {
+            Ok(Some(m)) => format!("{} files, {} bytes uncompressed", m.file_count, m.uncompressed_size),
+            Ok(None) => String::from("no manifest"),
+            Err(e) => format!("unreadable manifest: {e}")
+        };
+        info!("  {} ({}) generations_ago {} -- {} parts, {} bytes, {}", timestamp, time, generations_ago, parts.len(), size, contents);
+    }
+}
+
+/**
+Where unexport extracts a source to unless told otherwise.
+*/
+pub fn default_unexport_dest(source_name: &str, settings: &Settings) -> PathBuf
+{
+    PathBuf::from(format!(r#"{}/sources/{source_name}/"#, settings.startup.unexport_dir))
+}
 
-    let export_path = &settings.startup.export_dir;
-    let source = format!(r#"{export_path}/{source_name}_{target_timestamp}.tar.zst."#);
-    let dest = format!(r#"{}/sources/{source_name}/"#, settings.startup.unexport_dir);
-    
-    if let Err(e) = fs::create_dir_all(&dest)
+/**
+Extract one export of a source in the export dir, or only the paths in it matching the include setting of the action.
+
+# Arguments
+* `timestamp` - Which export of the source
+* `dest` - Directory to extract into. Created if it doesn't exist.
+
+# Returns
+bool for whether it succeeded. Failures are logged.
+*/
+pub fn unexport_to(source_name: &str, timestamp: i64, dest: &Path, settings: &Settings) -> bool
+{
+    info!("Beginning unexport (untar+zstd) for source: {} export: {} into: {}", source_name, timestamp, dest.to_string_lossy());
+
+    let parts = export_files(source_name, timestamp, &settings.startup.export_dir);
+    if parts.is_empty()
+    {
+        error!("Export {} of source {} isn't in the export dir", timestamp, source_name);
+        return false;
+    }
+    let filter = match PathFilter::parse(&settings.action.include)
+    {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Invalid include pattern -- Error: {}", e);
+            return false;
+        }
+    };
+    if let Err(e) = fs::create_dir_all(dest)
     {
         error!("Couldn't create directory for export destination. Error: {}", e);
-        return;
+        return false;
     }
 
-    let cmd_unexport = format!(r#"cat {source}* | tar --zstd -C {dest} -xf -"#);
-    info!(target: "cmdlog", "{}", cmd_unexport);
-    match run_script::run(&cmd_unexport, &Vec::new(), &ScriptOptions::new())
+    // exports with a manifest were written in sorted order, which lets extraction of plain paths stop early
+    let sorted = crate::manifest::manifest_path(source_name, timestamp, &settings.startup.export_dir).is_some();
+    match read_archive(&parts, dest, &settings.export, filter.as_ref(), sorted)
     {
-        Ok(v) => {
-            let (code, stdout, stderr) = v;
-            if code != 0
-            {
-                error!("unexport (cat|untar+zstd) returned nonzero exit code! Command: {} -- Exit Code: {} -- stdout: {} -- stderr: {}",
-                    cmd_unexport,
-                    code,
-                    stdout,
-                    stderr
-                );
-            }
+        Ok(extracted) => {
+            info!("Completed unexport for source: {} -- {} entries extracted", source_name, extracted);
+            true
         },
         Err(e) => {
-            error!("Failed to run export (cat|untar+zstd)! Command: {} -- Error: {}", cmd_unexport, e);
+            error!("Unexport (untar+zstd) failed for source: {} -- Error: {}", source_name, e);
+            false
+        }
+    }
+}
+
+/**
+Whether extracted files can be given the owners they had, which takes root.
+*/
+#[cfg(target_family = "unix")]
+fn can_set_ownership() -> bool
+{
+    nix::unistd::geteuid().is_root()
+}
+
+#[cfg(not(target_family = "unix"))]
+fn can_set_ownership() -> bool
+{
+    false
+}
+
+/**
+Which paths in an archive to extract, from a comma separated list of glob patterns.
+
+Patterns are matched against paths as stored in the archive, relative to the snapshot, e.g. `_home_/alice` or `_etc_/hosts`.
+`*` and `?` don't match across `/`, but a `**` path component matches any number of directories. When a directory matches, everything in it is included.
+*/
+pub struct PathFilter
+{
+    patterns: Vec<Pattern>,
+    /** The patterns as paths, if none of them has any wildcards */
+    literals: Option<Vec<PathBuf>>
+}
+
+const PATH_MATCH_OPTIONS: MatchOptions = MatchOptions{case_sensitive: true, require_literal_separator: true, require_literal_leading_dot: false};
+
+impl PathFilter
+{
+    /**
+    # Returns
+    The filter, None if the list is blank, or an error for an invalid pattern.
+    */
+    pub fn parse(include: &str) -> Result<Option<PathFilter>, String>
+    {
+        let specs: Vec<&str> = include.split(',').map(|p| p.trim().trim_start_matches("./").trim_end_matches('/')).filter(|p| !p.is_empty()).collect();
+        if specs.is_empty()
+        {
+            return Ok(None);
+        }
+        let patterns = specs.iter().map(|p| Pattern::new(p).map_err(|e| format!("{p}: {e}"))).collect::<Result<Vec<Pattern>, String>>()?;
+        let literals = if specs.iter().all(|p| !p.contains(['*', '?', '[']))
+        {
+            Some(specs.iter().map(PathBuf::from).collect())
+        }else{
+            None
+        };
+        Ok(Some(PathFilter{patterns, literals}))
+    }
+
+    /**
+    Check whether a path in the archive, or a directory it's in, matches.
+    */
+    pub fn matches(&self, path: &Path) -> bool
+    {
+        path.ancestors().filter(|a| !a.as_os_str().is_empty()).any(|a| self.patterns.iter().any(|p| p.matches_path_with(a, PATH_MATCH_OPTIONS)))
+    }
+
+    /**
+    For an archive in sorted order, check whether nothing after this path can match any more.
+    */
+    pub fn is_past(&self, path: &Path) -> bool
+    {
+        match &self.literals
+        {
+            Some(literals) => literals.iter().all(|l| path > l.as_path() && !path.starts_with(l)),
+            None => false
+        }
+    }
+}
+
+/**
+Get a path from an archive without any leading `./`, the way PathFilter expects it.
+*/
+fn archive_path(path: &Path) -> PathBuf
+{
+    path.components().filter(|c| *c != Component::CurDir).collect()
+}
+
+/**
+Extract a tar+zstd archive split into parts, decrypting it if it's encrypted.
+
+Permissions and modification times are restored, and so is ownership when running as root.
+
+# Arguments
+* `parts` - The part files, in order
+* `dest` - Directory to extract into
+* `config` - Export settings, for the keys to decrypt with
+* `filter` - Only extract the paths matching this, or everything if None
+* `sorted` - Whether the archive's paths are in sorted order, so reading can stop once the filter can't match anything further on
+
+# Returns
+The number of entries extracted.
+*/
+fn read_archive<P: AsRef<Path>>(parts: &[P], dest: &Path, config: &Export, filter: Option<&PathFilter>, sorted: bool) -> io::Result<u64>
+{
+    let decrypted = DecryptingReader::new(PartsReader::new(parts), &config.encryption)?;
+    let decoder = zstd::stream::read::Decoder::new(decrypted)?;
+    let mut archive = tar::Archive::new(decoder);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_ownerships(can_set_ownership());
+    archive.set_overwrite(true);
+
+    // like tar::Archive::unpack, directories are done last so writing their contents doesn't change their modification times
+    let mut directories = Vec::new();
+    let mut extracted = 0;
+    for entry in archive.entries()?
+    {
+        cancel::check()?;
+        let mut entry = entry?;
+        let path = archive_path(&entry.path()?);
+        if let Some(f) = filter
+        {
+            if !f.matches(&path)
+            {
+                if sorted && f.is_past(&path)
+                {
+                    break;
+                }
+                continue;
+            }
+        }
+        if entry.header().entry_type() == tar::EntryType::Directory
+        {
+            directories.push(entry);
+        }else{
+            entry.unpack_in(dest)?;
+        }
+        extracted += 1;
+    }
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut dir in directories
+    {
+        dir.unpack_in(dest)?;
+    }
+    Ok(extracted)
+}
+
+/**
+Read through a whole tar+zstd archive split into parts without extracting it, to check that it's readable and intact.
+zstd and age both check the integrity of what they read, so this fails on any corruption.
+
+# Arguments
+* `parts` - The part files, in order
+* `config` - Export settings, for the keys to decrypt with
+
+# Returns
+The number of regular files in the archive and their total size.
+*/
+pub fn test_archive<P: AsRef<Path>>(parts: &[P], config: &Export) -> io::Result<(u64, u64)>
+{
+    let decrypted = DecryptingReader::new(PartsReader::new(parts), &config.encryption)?;
+    let decoder = zstd::stream::read::Decoder::new(decrypted)?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut file_count = 0;
+    let mut total_size = 0;
+    for entry in archive.entries()?
+    {
+        cancel::check()?;
+        let mut entry = entry?;
+        let size = io::copy(&mut entry, &mut io::sink())?;
+        if entry.header().entry_type().is_file()
+        {
+            file_count += 1;
+            total_size += size;
+        }
+    }
+    // tar stops at its end marker, so read whatever follows to reach the checksums at the end of the stream
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok((file_count, total_size))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn selecting_exports()
+    {
+        let timestamps = [100, 200, 300];
+        assert_eq!(select_export(&timestamps, 0, 0), Some(300));
+        assert_eq!(select_export(&timestamps, 0, 2), Some(100));
+        assert_eq!(select_export(&timestamps, 0, 3), None);
+        assert_eq!(select_export(&timestamps, 200, 2), Some(200));
+        assert_eq!(select_export(&timestamps, 250, 0), None);
+    }
+
+    #[test]
+    fn filtering_paths()
+    {
+        let filter = PathFilter::parse("_home_/alice/*.txt, ./_etc_/").unwrap().unwrap();
+        assert!(filter.matches(Path::new("_home_/alice/notes.txt")));
+        assert!(!filter.matches(Path::new("_home_/alice/deep/notes.txt")));
+        assert!(filter.matches(Path::new("_etc_/ssh/sshd_config")));
+        assert!(!filter.matches(Path::new("_home_/bob/notes.txt")));
+        assert!(!filter.is_past(Path::new("_zzz_")));
+
+        let literal = PathFilter::parse("_etc_/ssh,_home_").unwrap().unwrap();
+        assert!(!literal.is_past(Path::new("_etc_/passwd")));
+        assert!(!literal.is_past(Path::new("_home_/alice/x")));
+        assert!(literal.is_past(Path::new("_var_")));
+
+        assert!(PathFilter::parse(" , ").unwrap().is_none());
+        assert!(PathFilter::parse("[").is_err());
+    }
+
+    #[test]
+    fn removes_stale_staging()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        for staging in ["laptop_100.partial", "laptop_tower_200.partial", "desktop_300.partial"]
+        {
+            fs::create_dir_all(dir.path().join(staging).join("part")).unwrap();
         }
+        fs::write(dir.path().join("laptop_100.tar.zst.0000"), "").unwrap();
+        remove_stale_staging("laptop", dir.path());
+        let mut left = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect::<Vec<String>>();
+        left.sort();
+        assert_eq!(left, vec!("desktop_300.partial", "laptop_100.tar.zst.0000", "laptop_tower_200.partial"));
     }
-    info!("Completed unexport for source: {}", source_name);
-}
\ No newline at end of file
+
+    #[test]
+    fn archive_paths_without_dot()
+    {
+        assert_eq!(archive_path(Path::new("./_etc_/hosts")), PathBuf::from("_etc_/hosts"));
+        assert_eq!(archive_path(Path::new(".")), PathBuf::new());
+    }
+}
@@ -0,0 +1,229 @@
+use log::{error, /*warn, info, debug, trace, log, Level*/};
+use run_script::{types::IoOptions, ScriptOptions};
+use std::{collections::HashMap, path::Path};
+
+use crate::cancel;
+use crate::settings::app_settings::{Hook, SshCreds, Source, SyncMethod};
+use crate::shell::shell_and_log;
+
+/*
+Hooks are commands run around the sync of a source, to quiesce what's being backed up: stop a game server, snapshot a VM, flush a database,
+and start things again afterwards. Pre-hooks run in order before the sync, and the first one that fails skips it.
+Post-hooks all run after it, however it went, even if a pre-hook failed or the job was cancelled.
+
+A hook runs on this machine, or with `remote` on the source over ssh with the source's credentials. It's stopped with `timeout` when it runs too long.
+Each one gets environment variables describing the sync:
+- REDUNDINATOR_HOOK: "pre" or "post"
+- REDUNDINATOR_SOURCE and REDUNDINATOR_HOST: the source's name, and the address it's synced from
+- REDUNDINATOR_PATHS: the source's paths, one per line
+- REDUNDINATOR_SNAPSHOT: the directory the snapshot is synced into, on this machine
+- REDUNDINATOR_JOB: the id of the job, when it runs from the queue
+- REDUNDINATOR_RESULT: for post-hooks, "ok", "failed", "skipped" when a pre-hook failed, or "cancelled"
+*/
+
+/** How long a hook may run when it doesn't set its own timeout */
+const DEFAULT_TIMEOUT_SECS: u64 = 10 * 60;
+/** How long a hook that timed out gets to exit after being asked to, before it's killed outright */
+const KILL_AFTER_SECS: u64 = 10;
+/** Exit code of `timeout` when the command ran too long */
+const TIMED_OUT: i32 = 124;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage
+{
+    Pre,
+    Post
+}
+
+impl Stage
+{
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            Stage::Pre  => "pre",
+            Stage::Post => "post"
+        }
+    }
+}
+
+/**
+The environment variables describing a sync, for its hooks.
+
+# Arguments
+* `snapshot` - Directory the sync goes into
+*/
+pub fn environment(name: &str, source: &Source, host: &str, snapshot: &Path) -> HashMap<String, String>
+{
+    [
+        ("REDUNDINATOR_SOURCE", name.to_string()),
+        ("REDUNDINATOR_HOST", host.to_string()),
+        ("REDUNDINATOR_PATHS", source.paths.join("\n")),
+        ("REDUNDINATOR_SNAPSHOT", snapshot.to_string_lossy().into_owned()),
+        ("REDUNDINATOR_JOB", cancel::running_job().map(|id| id.to_string()).unwrap_or_default())
+    ].into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+}
+
+/**
+Run the source's hooks for a stage. Pre-hooks stop at the first one that fails, post-hooks all run.
+
+# Arguments
+* `env` - Environment variables describing the sync, from `environment`
+
+# Returns
+bool for whether they all succeeded.
+*/
+pub fn run(stage: Stage, name: &str, source: &Source, host: &str, env: &HashMap<String, String>) -> bool
+{
+    let hooks = match stage
+    {
+        Stage::Pre => &source.pre_hooks,
+        Stage::Post => &source.post_hooks
+    };
+    let mut env = env.clone();
+    env.insert(String::from("REDUNDINATOR_HOOK"), stage.as_str().to_string());
+    let mut all_ok = true;
+    for (i, hook) in hooks.iter().enumerate()
+    {
+        let purpose = format!("{}-hook {} of {}", stage.as_str(), i + 1, hooks.len());
+        if !run_hook(hook, &purpose, name, source, host, &env)
+        {
+            all_ok = false;
+            if stage == Stage::Pre
+            {
+                break;
+            }
+        }
+    }
+    all_ok
+}
+
+/**
+Run one hook, with its output going to the logs.
+
+# Returns
+bool for whether it succeeded.
+*/
+fn run_hook(hook: &Hook, purpose: &str, name: &str, source: &Source, host: &str, env: &HashMap<String, String>) -> bool
+{
+    let cmd = match hook_command(hook, source, host, env)
+    {
+        Some(c) => c,
+        None => {
+            error!("Failed: {} Source: {} -- Error: remote hooks need the source to be synced with RsyncSsh", purpose, name);
+            return false;
+        }
+    };
+    // ssh would otherwise read what's meant for us
+    let options = ScriptOptions{input_redirection: IoOptions::Null, env_vars: Some(env.clone()), ..ScriptOptions::new()};
+    match shell_and_log(cmd, &options, purpose, name, true)
+    {
+        Some(0) => true,
+        Some(TIMED_OUT) if timeout_secs(hook) > 0 => {
+            error!("{} ran for longer than its timeout of {} seconds and was stopped. Source: {}", purpose, timeout_secs(hook), name);
+            false
+        },
+        _ => false
+    }
+}
+
+fn timeout_secs(hook: &Hook) -> u64
+{
+    hook.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)
+}
+
+/**
+Make the command that runs a hook, here or on the source, within its timeout.
+Run here, the environment variables are passed to the command as it's run. Over ssh, they're set at the start of the command.
+
+# Returns
+The command, or None if the hook is remote but the source isn't synced over ssh.
+*/
+fn hook_command(hook: &Hook, source: &Source, host: &str, env: &HashMap<String, String>) -> Option<String>
+{
+    let command = if hook.remote
+    {
+        let setup = match &source.method
+        {
+            SyncMethod::RsyncSsh(setup) => setup,
+            _ => {return None;}
+        };
+        let mut vars = env.iter().map(|(k, v)| format!("{k}={}", quote(v))).collect::<Vec<String>>();
+        vars.sort();
+        let remote = format!("{} sh -c {}", vars.join(" "), quote(&hook.command));
+        match &setup.creds
+        {
+            SshCreds::Key(creds) => format!(r#"ssh -i {} -p {} {}@{} {}"#, creds.keyfile_path, setup.port, creds.username, host, quote(&remote)),
+            SshCreds::Password(creds) => format!(r#"sshpass -p "{}" ssh -p {} {}@{} {}"#, creds.password, setup.port, creds.username, host, quote(&remote))
+        }
+    }else{
+        format!("sh -c {}", quote(&hook.command))
+    };
+    Some(match timeout_secs(hook)
+    {
+        0 => command,
+        secs => format!("timeout -k {KILL_AFTER_SECS} {secs} {command}")
+    })
+}
+
+/**
+Quote a string so the shell takes it as one word, as it is.
+*/
+fn quote(s: &str) -> String
+{
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::settings::app_settings::{RsyncSshSetup, SshCredsKey};
+
+    fn source(method: SyncMethod, pre_hooks: Vec<Hook>, post_hooks: Vec<Hook>) -> Source
+    {
+        Source{
+            hostname: String::from("laptop"),
+            paths: vec!(String::from("/home/"), String::from("/srv/game server/")),
+            method,
+            pre_hooks,
+            post_hooks,
+            ..Default::default()
+        }
+    }
+
+    fn hook(command: &str, remote: bool, timeout_secs: Option<u64>) -> Hook
+    {
+        Hook{command: command.to_string(), remote, timeout_secs}
+    }
+
+    #[test]
+    fn hook_commands()
+    {
+        let ssh = SyncMethod::RsyncSsh(RsyncSshSetup{port: 2222, remote_path_to_rsync_binary: None, creds: SshCreds::Key(SshCredsKey{username: String::from("backup"), keyfile_path: String::from("/keys/laptop")})});
+        let env = [("REDUNDINATOR_SOURCE", "laptop"), ("REDUNDINATOR_HOOK", "pre")].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(hook_command(&hook("systemctl stop game", true, None), &source(ssh, Vec::new(), Vec::new()), "10.8.0.11", &env).unwrap(),
+            r#"timeout -k 10 600 ssh -i /keys/laptop -p 2222 backup@10.8.0.11 'REDUNDINATOR_HOOK='\''pre'\'' REDUNDINATOR_SOURCE='\''laptop'\'' sh -c '\''systemctl stop game'\'''"#);
+        assert_eq!(hook_command(&hook("echo 'it''s'", false, Some(0)), &source(SyncMethod::RsyncLocal, Vec::new(), Vec::new()), "localhost", &env).unwrap(),
+            r#"sh -c 'echo '\''it'\'''\''s'\'''"#);
+        assert!(hook_command(&hook("true", true, None), &source(SyncMethod::RsyncLocal, Vec::new(), Vec::new()), "localhost", &env).is_none());
+    }
+
+    #[test]
+    fn runs_hooks()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let record = format!(r#"echo "$REDUNDINATOR_HOOK $REDUNDINATOR_SOURCE $REDUNDINATOR_RESULT" >> '{}'"#, out.to_string_lossy());
+        let source = source(SyncMethod::RsyncLocal,
+            vec!(hook(&record, false, None), hook("exit 3", false, None), hook(&record, false, None)),
+            vec!(hook("sleep 5", false, Some(1)), hook(&record, false, None)));
+        let mut env = environment("laptop", &source, "localhost", dir.path());
+        assert_eq!(env["REDUNDINATOR_PATHS"], "/home/\n/srv/game server/");
+
+        assert!(!run(Stage::Pre, "laptop", &source, "localhost", &env));
+        env.insert(String::from("REDUNDINATOR_RESULT"), String::from("skipped"));
+        assert!(!run(Stage::Post, "laptop", &source, "localhost", &env));
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "pre laptop \npost laptop skipped\n");
+    }
+}
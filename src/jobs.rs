@@ -0,0 +1,654 @@
+use serde::{Deserialize, Serialize};
+use sqlite::{Connection, State, Statement, Value};
+use std::{fmt, path::{Path, PathBuf}};
+
+use crate::dispatch::SourceResult;
+use crate::settings::app_settings::{Action, Settings};
+
+/*
+Actions requested through the web interface are queued as jobs in a sqlite database next to the tokens file,
+so the queue survives restarts and there's a record of everything that ran:
+
+    jobs(id, action, status, requested_at, started_at, finished_at, outcome, results, log, schedule, position)
+
+`action` is the requested Action and `results` the SourceResults of running it, both as JSON. Times are unix timestamps.
+`schedule` is the id of the schedule that queued the job, or null when it was requested by hand.
+Queued jobs are run in order of `position`, which starts out as the order they were requested in and changes when they're moved up or down the queue.
+A queued job that's removed, or a running job that's cancelled, is marked cancelled.
+A job still marked as running when the app starts was cut off by the restart, and is marked interrupted.
+*/
+
+/**
+Where a job is at.
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus
+{
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Interrupted,
+    Cancelled
+}
+
+impl JobStatus
+{
+    fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            JobStatus::Queued      => "queued",
+            JobStatus::Running     => "running",
+            JobStatus::Succeeded   => "succeeded",
+            JobStatus::Failed      => "failed",
+            JobStatus::Interrupted => "interrupted",
+            JobStatus::Cancelled   => "cancelled"
+        }
+    }
+
+    fn parse(status: &str) -> JobStatus
+    {
+        match status
+        {
+            "queued"    => JobStatus::Queued,
+            "running"   => JobStatus::Running,
+            "succeeded" => JobStatus::Succeeded,
+            "failed"    => JobStatus::Failed,
+            "cancelled" => JobStatus::Cancelled,
+            _           => JobStatus::Interrupted
+        }
+    }
+}
+
+impl fmt::Display for JobStatus
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/**
+One requested run of an action.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Job
+{
+    pub id: i64,
+    pub action: Action,
+    pub status: JobStatus,
+    pub requested_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    /** Summary of how it went, blank until it's finished */
+    pub outcome: String,
+    /** How each step went for each source */
+    pub results: Vec<SourceResult>,
+    /** The last lines logged while it ran */
+    pub log: String,
+    /** Id of the schedule that queued it, None when it was requested by hand */
+    pub schedule: Option<String>
+}
+
+impl Job
+{
+    /**
+    Describe what the job does in a few words, e.g. "sync, export for laptop".
+    */
+    pub fn describe(&self) -> String
+    {
+        let a = &self.action;
+        let flags = [
+            (a.auth_upload, "auth_upload"), (a.sync, "sync"), (a.mysql_dump, "mysql_dump"), (a.export, "export"), (a.catalog, "catalog"),
+            (!a.search.is_empty(), "search"), (a.list_exports, "list_exports"), (a.list_remote, "list_remote"), (a.restore, "restore"),
+            (a.unexport, "unexport"), (a.upload, "upload"), (a.verify, "verify"), (a.prune, "prune")
+        ];
+        let actions = flags.iter().filter(|(set, _)| *set).map(|(_, name)| *name).collect::<Vec<&str>>().join(", ");
+        let actions = if actions.is_empty() {String::from("nothing")} else {actions};
+        let source = if a.source.is_empty() {String::from("all sources")} else {a.source.clone()};
+        let target = if a.upload_target.is_empty() {String::new()} else {format!(" at {}", a.upload_target)};
+        let dry_run = if a.dry_run {" (dry run)"} else {""};
+        let approved = if a.approve_changes {" (changes approved)"} else {""};
+        let schedule = match &self.schedule
+        {
+            Some(s) => format!(" (scheduled: {s})"),
+            None => String::new()
+        };
+        format!("{actions} for {source}{target}{dry_run}{approved}{schedule}")
+    }
+}
+
+/**
+Location of the jobs database: jobs.db in the same directory as the tokens file.
+*/
+pub fn jobs_file(settings: &Settings) -> PathBuf
+{
+    Path::new(&settings.startup.tokens_file).with_file_name("jobs.db")
+}
+
+/**
+Add a job to the end of the queue.
+
+# Returns
+- On success, the id of the new job
+- On failure, a sqlite::Error
+*/
+pub fn enqueue(jobs_file: &Path, action: &Action) -> Result<i64, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    insert(&connection, action, None)
+}
+
+/**
+Add a job for a schedule to the end of the queue, unless the schedule's previous job is still queued or running.
+
+# Arguments
+* `schedule` - Id of the schedule
+
+# Returns
+- On success, the id of the new job, or None if it was skipped
+- On failure, a sqlite::Error
+*/
+pub fn enqueue_scheduled(jobs_file: &Path, action: &Action, schedule: &str) -> Result<Option<i64>, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    connection.execute("BEGIN IMMEDIATE")?;
+    let result = pending_for_schedule(&connection, schedule).and_then(|pending| match pending
+    {
+        true => Ok(None),
+        false => insert(&connection, action, Some(schedule)).map(Some)
+    });
+    match result
+    {
+        Ok(id) => {
+            connection.execute("COMMIT")?;
+            Ok(id)
+        },
+        Err(e) => {
+            connection.execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
+
+/**
+Take the next job off the queue, marking it as running.
+
+# Returns
+- The job, or None when the queue is empty
+- On failure, a sqlite::Error
+*/
+pub fn start_next(jobs_file: &Path) -> Result<Option<Job>, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    // so the job can't be removed or taken by something else between choosing it and marking it running
+    connection.execute("BEGIN IMMEDIATE")?;
+    let result = take_next(&connection);
+    match result
+    {
+        Ok(job) => {
+            connection.execute("COMMIT")?;
+            Ok(job)
+        },
+        Err(e) => {
+            connection.execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
+
+fn take_next(connection: &Connection) -> Result<Option<Job>, sqlite::Error>
+{
+    let select_query = format!("SELECT * FROM jobs WHERE status = '{}' ORDER BY position, id LIMIT 1", JobStatus::Queued.as_str());
+    loop
+    {
+        let mut select_stmt = connection.prepare(&select_query)?;
+        let mut job = match select_stmt.next()?
+        {
+            State::Row => read_job(&select_stmt)?,
+            State::Done => {return Ok(None);}
+        };
+        drop(select_stmt);
+        job.status = JobStatus::Running;
+        job.started_at = Some(chrono::Utc::now().timestamp());
+        let update_query = "UPDATE jobs SET status = :status, started_at = :started_at WHERE id = :id AND status = :queued";
+        let mut update_stmt = connection.prepare(update_query)?;
+        update_stmt.bind::<&[(_, Value)]>(&[
+            (":status", job.status.as_str().into()),
+            (":started_at", job.started_at.into()),
+            (":id", job.id.into()),
+            (":queued", JobStatus::Queued.as_str().into())
+        ])?;
+        while State::Row == update_stmt.next()? {}
+        if connection.change_count() > 0
+        {
+            return Ok(Some(job));
+        }
+        // it stopped being queued in the meantime, so try the next one
+    }
+}
+
+/**
+Record how a job went.
+
+# Arguments
+* `log` - The lines logged while it ran
+* `cancelled` - Whether it was cancelled partway
+*/
+pub fn finish(jobs_file: &Path, id: i64, results: &[SourceResult], log: &[String], cancelled: bool) -> Result<(), sqlite::Error>
+{
+    let (status, outcome) = conclude(results, cancelled);
+    let connection = connect(jobs_file)?;
+    let update_query = "UPDATE jobs SET status = :status, finished_at = :finished_at, outcome = :outcome, results = :results, log = :log WHERE id = :id";
+    let mut update_stmt = connection.prepare(update_query)?;
+    update_stmt.bind::<&[(_, Value)]>(&[
+        (":status", status.as_str().into()),
+        (":finished_at", chrono::Utc::now().timestamp().into()),
+        (":outcome", outcome.into()),
+        (":results", to_json(&results).into()),
+        (":log", log.join("\n").into()),
+        (":id", id.into())
+    ])?;
+    while State::Row == update_stmt.next()? {}
+    Ok(())
+}
+
+/**
+Work out how a run of some actions went from how each step did.
+
+# Arguments
+* `cancelled` - Whether it was cancelled partway
+
+# Returns
+Its status, and a summary of how it went, e.g. "1 of 3 steps failed: sync for laptop".
+*/
+pub fn conclude(results: &[SourceResult], cancelled: bool) -> (JobStatus, String)
+{
+    let failed = results.iter().filter(|r| !r.ok).collect::<Vec<&SourceResult>>();
+    let status = match (cancelled, failed.is_empty())
+    {
+        (true, _) => JobStatus::Cancelled,
+        (false, true) => JobStatus::Succeeded,
+        (false, false) => JobStatus::Failed
+    };
+    let outcome = match (cancelled, failed.len())
+    {
+        (true, _) => results.last().map(|r| format!("cancelled during {}", describe_result(r))).unwrap_or_else(|| String::from("cancelled")),
+        (false, 0) => format!("{} steps succeeded", results.len()),
+        (false, _) => format!("{} of {} steps failed: {}", failed.len(), results.len(), failed.iter().map(|r| describe_result(r)).collect::<Vec<String>>().join(", "))
+    };
+    (status, outcome)
+}
+
+/**
+Mark the jobs that were left running as interrupted. Call this when starting up, before running any jobs.
+
+# Returns
+- On success, how many jobs were interrupted
+- On failure, a sqlite::Error
+*/
+pub fn interrupt_running(jobs_file: &Path) -> Result<usize, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    let update_query = "UPDATE jobs SET status = :interrupted, finished_at = :now, outcome = 'interrupted by a restart' WHERE status = :running";
+    let mut update_stmt = connection.prepare(update_query)?;
+    update_stmt.bind::<&[(_, Value)]>(&[
+        (":interrupted", JobStatus::Interrupted.as_str().into()),
+        (":now", chrono::Utc::now().timestamp().into()),
+        (":running", JobStatus::Running.as_str().into())
+    ])?;
+    while State::Row == update_stmt.next()? {}
+    Ok(connection.change_count())
+}
+
+/**
+Take a job off the queue before it starts, marking it as cancelled.
+
+# Returns
+- On success, whether the job was in the queue
+- On failure, a sqlite::Error
+*/
+pub fn remove(jobs_file: &Path, id: i64) -> Result<bool, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    let update_query = "UPDATE jobs SET status = :cancelled, finished_at = :now, outcome = 'removed from the queue' WHERE id = :id AND status = :queued";
+    let mut update_stmt = connection.prepare(update_query)?;
+    update_stmt.bind::<&[(_, Value)]>(&[
+        (":cancelled", JobStatus::Cancelled.as_str().into()),
+        (":now", chrono::Utc::now().timestamp().into()),
+        (":id", id.into()),
+        (":queued", JobStatus::Queued.as_str().into())
+    ])?;
+    while State::Row == update_stmt.next()? {}
+    Ok(connection.change_count() > 0)
+}
+
+/**
+Move a queued job one place up or down the queue, by swapping it with its neighbour.
+
+# Arguments
+* `earlier` - true to move it towards the front of the queue, false towards the back
+
+# Returns
+- On success, whether it moved. It doesn't if it isn't queued or is already at that end of the queue.
+- On failure, a sqlite::Error
+*/
+pub fn move_queued(jobs_file: &Path, id: i64, earlier: bool) -> Result<bool, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    connection.execute("BEGIN IMMEDIATE")?;
+    let result = swap_with_neighbour(&connection, id, earlier);
+    match result
+    {
+        Ok(moved) => {
+            connection.execute("COMMIT")?;
+            Ok(moved)
+        },
+        Err(e) => {
+            connection.execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
+
+/**
+Get one job.
+*/
+pub fn get(jobs_file: &Path, id: i64) -> Result<Option<Job>, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    let mut select_stmt = connection.prepare("SELECT * FROM jobs WHERE id = :id")?;
+    select_stmt.bind((":id", id))?;
+    match select_stmt.next()?
+    {
+        State::Row => Ok(Some(read_job(&select_stmt)?)),
+        State::Done => Ok(None)
+    }
+}
+
+/**
+Get the jobs waiting in the queue, in the order they'll run.
+*/
+pub fn queued(jobs_file: &Path) -> Result<Vec<Job>, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    let select_query = format!("SELECT * FROM jobs WHERE status = '{}' ORDER BY position, id", JobStatus::Queued.as_str());
+    let jobs = read_jobs(connection.prepare(select_query)?)?;
+    Ok(jobs)
+}
+
+/**
+Get the job that's running, if any.
+*/
+pub fn running(jobs_file: &Path) -> Result<Option<Job>, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    let select_query = format!("SELECT * FROM jobs WHERE status = '{}' ORDER BY id DESC LIMIT 1", JobStatus::Running.as_str());
+    let jobs = read_jobs(connection.prepare(select_query)?)?;
+    Ok(jobs.into_iter().next())
+}
+
+/**
+Get the jobs that have started, newest first.
+
+# Arguments
+* `limit` - The most jobs to get
+*/
+pub fn history(jobs_file: &Path, limit: usize) -> Result<Vec<Job>, sqlite::Error>
+{
+    let connection = connect(jobs_file)?;
+    let select_query = format!("SELECT * FROM jobs WHERE status != '{}' ORDER BY id DESC LIMIT :limit", JobStatus::Queued.as_str());
+    let mut select_stmt = connection.prepare(select_query)?;
+    select_stmt.bind((":limit", i64::try_from(limit).unwrap_or(i64::MAX)))?;
+    let jobs = read_jobs(select_stmt)?;
+    Ok(jobs)
+}
+
+/**
+Describe a step of a job in a few words, e.g. "upload to s3 for laptop".
+*/
+pub fn describe_result(result: &SourceResult) -> String
+{
+    if result.source.is_empty()
+    {
+        result.action.clone()
+    }else{
+        format!("{} for {}", result.action, result.source)
+    }
+}
+
+fn insert(connection: &Connection, action: &Action, schedule: Option<&str>) -> Result<i64, sqlite::Error>
+{
+    let insert_query = "INSERT INTO jobs (action, status, requested_at, outcome, results, log, schedule, position)
+        VALUES (:action, :status, :requested_at, '', '[]', '', :schedule, (SELECT COALESCE(MAX(position), 0) + 1 FROM jobs))";
+    let mut insert_stmt = connection.prepare(insert_query)?;
+    insert_stmt.bind::<&[(_, Value)]>(&[
+        (":action", to_json(action).into()),
+        (":status", JobStatus::Queued.as_str().into()),
+        (":requested_at", chrono::Utc::now().timestamp().into()),
+        (":schedule", schedule.into())
+    ])?;
+    while State::Row == insert_stmt.next()? {}
+    let mut id_stmt = connection.prepare("SELECT last_insert_rowid() AS id")?;
+    id_stmt.next()?;
+    id_stmt.read::<i64, _>("id")
+}
+
+fn pending_for_schedule(connection: &Connection, schedule: &str) -> Result<bool, sqlite::Error>
+{
+    let select_query = "SELECT COUNT(*) AS pending FROM jobs WHERE schedule = :schedule AND status IN (:queued, :running)";
+    let mut select_stmt = connection.prepare(select_query)?;
+    select_stmt.bind::<&[(_, Value)]>(&[
+        (":schedule", schedule.into()),
+        (":queued", JobStatus::Queued.as_str().into()),
+        (":running", JobStatus::Running.as_str().into())
+    ])?;
+    select_stmt.next()?;
+    Ok(select_stmt.read::<i64, _>("pending")? > 0)
+}
+
+fn swap_with_neighbour(connection: &Connection, id: i64, earlier: bool) -> Result<bool, sqlite::Error>
+{
+    let mut position_stmt = connection.prepare("SELECT position FROM jobs WHERE id = :id AND status = :queued")?;
+    position_stmt.bind::<&[(_, Value)]>(&[(":id", id.into()), (":queued", JobStatus::Queued.as_str().into())])?;
+    let position = match position_stmt.next()?
+    {
+        State::Row => position_stmt.read::<i64, _>("position")?,
+        State::Done => {return Ok(false);}
+    };
+    let neighbour_query = match earlier
+    {
+        true => "SELECT id, position FROM jobs WHERE status = :queued AND position < :position ORDER BY position DESC LIMIT 1",
+        false => "SELECT id, position FROM jobs WHERE status = :queued AND position > :position ORDER BY position LIMIT 1"
+    };
+    let mut neighbour_stmt = connection.prepare(neighbour_query)?;
+    neighbour_stmt.bind::<&[(_, Value)]>(&[(":queued", JobStatus::Queued.as_str().into()), (":position", position.into())])?;
+    let (neighbour_id, neighbour_position) = match neighbour_stmt.next()?
+    {
+        State::Row => (neighbour_stmt.read::<i64, _>("id")?, neighbour_stmt.read::<i64, _>("position")?),
+        State::Done => {return Ok(false);}
+    };
+    let mut update_stmt = connection.prepare("UPDATE jobs SET position = :position WHERE id = :id")?;
+    for (job_id, new_position) in [(id, neighbour_position), (neighbour_id, position)]
+    {
+        update_stmt.reset()?;
+        update_stmt.bind::<&[(_, Value)]>(&[(":position", new_position.into()), (":id", job_id.into())])?;
+        while State::Row == update_stmt.next()? {}
+    }
+    Ok(true)
+}
+
+fn read_jobs(mut stmt: Statement) -> Result<Vec<Job>, sqlite::Error>
+{
+    let mut jobs = Vec::new();
+    while let State::Row = stmt.next()?
+    {
+        jobs.push(read_job(&stmt)?);
+    }
+    Ok(jobs)
+}
+
+fn read_job(stmt: &Statement) -> Result<Job, sqlite::Error>
+{
+    Ok(Job{
+        id: stmt.read::<i64, _>("id")?,
+        action: from_json(&stmt.read::<String, _>("action")?)?,
+        status: JobStatus::parse(&stmt.read::<String, _>("status")?),
+        requested_at: stmt.read::<i64, _>("requested_at")?,
+        started_at: stmt.read::<Option<i64>, _>("started_at")?,
+        finished_at: stmt.read::<Option<i64>, _>("finished_at")?,
+        outcome: stmt.read::<String, _>("outcome")?,
+        results: from_json(&stmt.read::<String, _>("results")?)?,
+        log: stmt.read::<String, _>("log")?,
+        schedule: stmt.read::<Option<String>, _>("schedule")?
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> String
+{
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(json: &str) -> Result<T, sqlite::Error>
+{
+    serde_json::from_str(json).map_err(|e| sqlite::Error{code: None, message: Some(format!("invalid JSON in jobs database: {e}"))})
+}
+
+/**
+Establish a sqlite connection to the jobs database.
+
+Also creates the file if it doesn't exist, and inside the file, creates the table if it doesn't exist.
+The web interface and the queue consumer use it at the same time, so a connection waits a while for the other to finish writing.
+
+# Returns
+- On success, a Connection
+- On failure, a sqlite::Error
+*/
+fn connect(jobs_file: &Path) -> Result<Connection, sqlite::Error>
+{
+    let mut connection = sqlite::open(jobs_file)?;
+    connection.set_busy_timeout(5000)?;
+    connection.execute("
+        CREATE TABLE IF NOT EXISTS jobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            action TEXT NOT NULL,
+            status TEXT NOT NULL,
+            requested_at INTEGER NOT NULL,
+            started_at INTEGER,
+            finished_at INTEGER,
+            outcome TEXT NOT NULL,
+            results TEXT NOT NULL,
+            log TEXT NOT NULL,
+            schedule TEXT,
+            position INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE INDEX IF NOT EXISTS jobs_by_status ON jobs (status, id);
+    ")?;
+    // databases from before schedules and reordering existed don't have those columns yet
+    let mut columns_stmt = connection.prepare("PRAGMA table_info(jobs)")?;
+    let mut columns = Vec::new();
+    while let State::Row = columns_stmt.next()?
+    {
+        columns.push(columns_stmt.read::<String, _>("name")?);
+    }
+    drop(columns_stmt);
+    if !columns.iter().any(|c| c == "schedule")
+    {
+        connection.execute("ALTER TABLE jobs ADD COLUMN schedule TEXT")?;
+    }
+    if !columns.iter().any(|c| c == "position")
+    {
+        connection.execute("ALTER TABLE jobs ADD COLUMN position INTEGER NOT NULL DEFAULT 0; UPDATE jobs SET position = id;")?;
+    }
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn queue_and_history()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs_file = dir.path().join("jobs.db");
+        let first = enqueue(&jobs_file, &Action{sync: true, source: String::from("laptop"), ..Default::default()}).unwrap();
+        let second = enqueue(&jobs_file, &Action{export: true, ..Default::default()}).unwrap();
+        assert_eq!(queued(&jobs_file).unwrap().iter().map(|j| j.id).collect::<Vec<i64>>(), vec!(first, second));
+
+        let job = start_next(&jobs_file).unwrap().unwrap();
+        assert_eq!((job.id, job.status, job.describe()), (first, JobStatus::Running, String::from("sync for laptop")));
+        assert_eq!(running(&jobs_file).unwrap().map(|j| j.id), Some(first));
+        let results = vec!(SourceResult::failed("sync", "laptop"));
+        finish(&jobs_file, first, &results, &[String::from("it broke")], false).unwrap();
+        let job = get(&jobs_file, first).unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.results, results);
+        assert_eq!(job.outcome, "1 of 1 steps failed: sync for laptop");
+        assert_eq!(job.log, "it broke");
+
+        // a job running when the app stops is interrupted, the queued ones stay queued
+        start_next(&jobs_file).unwrap().unwrap();
+        enqueue(&jobs_file, &Action{verify: true, ..Default::default()}).unwrap();
+        assert_eq!(interrupt_running(&jobs_file).unwrap(), 1);
+        assert_eq!(get(&jobs_file, second).unwrap().unwrap().status, JobStatus::Interrupted);
+        assert_eq!(queued(&jobs_file).unwrap().len(), 1);
+        assert_eq!(history(&jobs_file, 10).unwrap().iter().map(|j| j.id).collect::<Vec<i64>>(), vec!(second, first));
+
+        // actions queued by older versions lack newer fields
+        assert!(from_json::<Action>(r#"{"sync": true, "source": "laptop"}"#).unwrap().sync);
+    }
+
+    #[test]
+    fn scheduled_runs_wait_for_the_previous_one()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs_file = dir.path().join("jobs.db");
+        let action = Action{sync: true, ..Default::default()};
+        let first = enqueue_scheduled(&jobs_file, &action, "nightly").unwrap();
+        assert!(first.is_some());
+        assert_eq!(enqueue_scheduled(&jobs_file, &action, "nightly").unwrap(), None);
+        assert!(enqueue_scheduled(&jobs_file, &action, "weekly").unwrap().is_some());
+
+        let job = start_next(&jobs_file).unwrap().unwrap();
+        assert_eq!(job.schedule.as_deref(), Some("nightly"));
+        assert_eq!(enqueue_scheduled(&jobs_file, &action, "nightly").unwrap(), None);
+        finish(&jobs_file, job.id, &[], &[], false).unwrap();
+        assert!(enqueue_scheduled(&jobs_file, &action, "nightly").unwrap().is_some());
+    }
+
+    #[test]
+    fn reorder_remove_and_cancel()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let jobs_file = dir.path().join("jobs.db");
+        let ids = (0..3).map(|_| enqueue(&jobs_file, &Action{sync: true, ..Default::default()}).unwrap()).collect::<Vec<i64>>();
+        let order = || queued(&jobs_file).unwrap().iter().map(|j| j.id).collect::<Vec<i64>>();
+        assert!(move_queued(&jobs_file, ids[2], true).unwrap());
+        assert_eq!(order(), vec!(ids[0], ids[2], ids[1]));
+        assert!(move_queued(&jobs_file, ids[2], true).unwrap());
+        assert!(!move_queued(&jobs_file, ids[2], true).unwrap());
+        assert!(move_queued(&jobs_file, ids[0], false).unwrap());
+        assert_eq!(order(), vec!(ids[2], ids[1], ids[0]));
+        // new jobs go to the back
+        let last = enqueue(&jobs_file, &Action{export: true, ..Default::default()}).unwrap();
+        assert_eq!(order().last(), Some(&last));
+
+        assert!(remove(&jobs_file, ids[1]).unwrap());
+        assert!(!remove(&jobs_file, ids[1]).unwrap());
+        assert_eq!(get(&jobs_file, ids[1]).unwrap().unwrap().status, JobStatus::Cancelled);
+        assert_eq!(order(), vec!(ids[2], ids[0], last));
+
+        let job = start_next(&jobs_file).unwrap().unwrap();
+        assert_eq!(job.id, ids[2]);
+        assert!(!remove(&jobs_file, job.id).unwrap());
+        assert!(!move_queued(&jobs_file, job.id, false).unwrap());
+        let results = vec!(SourceResult::failed("sync", "laptop"));
+        finish(&jobs_file, job.id, &results, &[], true).unwrap();
+        let job = get(&jobs_file, job.id).unwrap().unwrap();
+        assert_eq!((job.status, job.outcome.as_str()), (JobStatus::Cancelled, "cancelled during sync for laptop"));
+    }
+}
@@ -1,6 +1,5 @@
 extern crate clap;
 extern crate enquote;
-#[macro_use]
 extern crate lazy_static;
 extern crate serde;
 extern crate uuid;
@@ -8,35 +7,66 @@ extern crate uuid;
 pub mod action_queue;
 pub mod app_logger;
 pub mod backoff;
+pub mod cancel;
+pub mod catalog;
+pub mod checksum;
 pub mod dispatch;
+pub mod encryption;
 pub mod export;
+pub mod hooks;
+pub mod jobs;
+pub mod manifest;
+pub mod metrics;
 pub mod mysql;
+pub mod notify;
+pub mod parts;
+pub mod progress;
 pub mod resources;
+pub mod restore;
+pub mod retention;
 pub mod rsync;
+pub mod scheduler;
 pub mod settings;
 pub mod shell;
+pub mod snapshot;
 pub mod testing;
 pub mod tokens;
 pub mod upload;
+pub mod usage;
+pub mod verify;
 
 use glob::glob;
 use lazy_static::lazy_static;
 use log::{error, /*warn, info, debug, trace, log, Level*/};
 use regex::Regex;
 
+/**
+Get the timestamp of the newest export of a source.
+
+# Returns
+The timestamp, or None if there are no exports of the source in the export dir.
+*/
 pub fn latest_export_ts(name: &str, export_path: &str) -> Option<i64>
+{
+    export_timestamps(name, export_path).last().copied()
+}
+
+/**
+Get the timestamps of all the exports of a source in the export dir, oldest first.
+*/
+pub fn export_timestamps(name: &str, export_path: &str) -> Vec<i64>
 {
     let glob_str = format!("{export_path}/{name}_*.tar.zst.*");
-    let mut latest: Option<i64> = None;
     let matches = match glob(&glob_str)
     {
         Ok(v) => v,
         Err(e) =>
         {
             error!("Failed to process glob: {} -- Error: {}", glob_str, e);
-            return None;
+            return vec!();
         }
     };
+    let mut timestamps: Vec<i64> = Vec::new();
     for entry in matches.filter_map(Result::ok)
     {
         let path = entry.display().to_string();
@@ -50,21 +80,35 @@ pub fn latest_export_ts(name: &str, export_path: &str) -> Option<i64>
             Ok(t) => t,
             Err(e) => {error!("Timestamp in filename didn't fit in an i64: {}", e); continue;}
         };
-        match latest
-        {
-            None => {latest = Some(timestamp);}
-            Some(lat) => {
-                if timestamp > lat {latest = Some(timestamp);}
-            }
-        }
+        timestamps.push(timestamp);
     }
+    timestamps.sort_unstable();
+    timestamps.dedup();
+    timestamps
+}
 
-    latest
+/**
+Get the paths of all the files making up one export of a source, in order.
+*/
+pub fn export_files(name: &str, timestamp: i64, export_path: &str) -> Vec<String>
+{
+    let glob_str = format!("{export_path}/{name}_{timestamp}.tar.zst.*");
+    let mut files: Vec<String> = match glob(&glob_str)
+    {
+        Ok(v) => v,
+        Err(e) =>
+        {
+            error!("Failed to process glob: {} -- Error: {}", glob_str, e);
+            return vec!();
+        }
+    }.filter_map(Result::ok).map(|f| f.display().to_string()).collect();
+    files.sort();
+    files
 }
 
 pub fn new_tokio_runtime() -> Result<tokio::runtime::Runtime, std::io::Error>
 {
-    return tokio::runtime::Builder::new_current_thread().enable_all().build();
+    tokio::runtime::Builder::new_current_thread().enable_all().build()
 }
 
 lazy_static!{
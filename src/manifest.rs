@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::{Path, PathBuf}};
+
+/*
+Each export gets a manifest next to its parts:
+
+    {name}_{timestamp}.tar.zst.0000
+    {name}_{timestamp}.tar.zst.0001
+    {name}_{timestamp}.manifest.json
+
+The manifest is only written once all the parts are in place, and uploaded after all of them, so an export with a manifest is known to be complete.
+It lists the size and SHA-256 of each part, which is what copies of the export (local or at an upload target) are checked against.
+Exports made by older versions have no manifest.
+*/
+
+/**
+Metadata about one export.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Manifest
+{
+    pub source: String,
+    /** When the export was made, as used in its filenames */
+    pub timestamp: i64,
+    /** Timestamp of the snapshot the export was made from, None for the legacy single-copy layout */
+    pub snapshot_timestamp: Option<i64>,
+    pub parts: Vec<ManifestPart>,
+    /** Number of regular files in the archive */
+    pub file_count: u64,
+    /** Total size of the regular files in the archive, before compression */
+    pub uncompressed_size: u64,
+    pub part_size_mib: u64,
+    pub compression_level: i32,
+    /** As given by encryption::describe */
+    pub encryption: String,
+    /** Public keys the export was encrypted to, if any */
+    pub encryption_recipients: Vec<String>,
+    pub redundinator_version: String
+}
+
+/**
+One part file of an export.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestPart
+{
+    pub name: String,
+    pub size: u64,
+    /** Hex encoded SHA-256 of the part's contents */
+    pub sha256: String
+}
+
+impl Manifest
+{
+    /**
+    Filename of the manifest of an export.
+    */
+    pub fn file_name(source_name: &str, timestamp: i64) -> String
+    {
+        format!("{source_name}_{timestamp}.manifest.json")
+    }
+
+    /**
+    Write the manifest into a directory, replacing it atomically if it's already there.
+
+    # Returns
+    The path of the manifest.
+    */
+    pub fn write(&self, dir: &Path) -> io::Result<PathBuf>
+    {
+        let path = dir.join(Manifest::file_name(&self.source, self.timestamp));
+        let temp_path = dir.join(format!("{}.tmp", Manifest::file_name(&self.source, self.timestamp)));
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(&temp_path, json)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(path)
+    }
+
+    /**
+    Read a manifest file.
+    */
+    pub fn read(path: &Path) -> io::Result<Manifest>
+    {
+        let json = fs::read(path)?;
+        serde_json::from_slice(&json).map_err(|e| io::Error::other(format!("Invalid manifest {}: {e}", path.to_string_lossy())))
+    }
+
+    /**
+    Look up a part by its filename.
+    */
+    pub fn part(&self, name: &str) -> Option<&ManifestPart>
+    {
+        self.parts.iter().find(|p| p.name == name)
+    }
+}
+
+/**
+Get the path of the manifest of an export, if it has one.
+*/
+pub fn manifest_path(source_name: &str, timestamp: i64, export_path: &str) -> Option<PathBuf>
+{
+    let path = Path::new(export_path).join(Manifest::file_name(source_name, timestamp));
+    if path.is_file() {Some(path)} else {None}
+}
+
+/**
+Load the manifest of an export.
+
+# Returns
+The manifest, None if the export has none, or an error if it couldn't be read.
+*/
+pub fn load(source_name: &str, timestamp: i64, export_path: &str) -> io::Result<Option<Manifest>>
+{
+    match manifest_path(source_name, timestamp, export_path)
+    {
+        Some(path) => Manifest::read(&path).map(Some),
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn write_and_read()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = Manifest{
+            source: String::from("web"),
+            timestamp: 1700000000,
+            snapshot_timestamp: Some(1699990000),
+            parts: vec!(ManifestPart{name: String::from("web_1700000000.tar.zst.0000"), size: 3, sha256: String::from("abc")}),
+            file_count: 2,
+            uncompressed_size: 10,
+            part_size_mib: 1,
+            compression_level: 3,
+            encryption: String::from("none"),
+            encryption_recipients: vec!(),
+            redundinator_version: String::from("0.0.0")
+        };
+        let path = manifest.write(dir.path()).unwrap();
+        assert_eq!(path.file_name().unwrap(), "web_1700000000.manifest.json");
+        let export_path = dir.path().to_string_lossy();
+        assert_eq!(load("web", 1700000000, &export_path).unwrap(), Some(manifest.clone()));
+        assert_eq!(load("web", 1700000001, &export_path).unwrap(), None);
+        assert_eq!(manifest.part("web_1700000000.tar.zst.0000").map(|p| p.size), Some(3));
+    }
+}
@@ -0,0 +1,265 @@
+use lazy_static::lazy_static;
+use sqlite::{Connection, State, Value};
+use std::{path::{Path, PathBuf}, sync::Mutex};
+
+use crate::dispatch::SourceResult;
+use crate::rsync::{RsyncOutcome, SyncStats};
+use crate::settings::app_settings::Settings;
+
+/*
+Numbers about how backups are going, for the /metrics page of the web interface to give to Prometheus.
+How each step of a dispatch went is kept in a sqlite database next to the tokens file, so they survive restarts and include steps run from the command line:
+
+    steps(source, action, runs, failures, consecutive_failures, last_run_at, last_ok, last_success_at, last_duration_secs, last_bytes, last_exit_code, bytes_total)
+
+There's a row for each action and source, e.g. "sync" and "laptop". Steps that aren't about one source have a blank source.
+
+While a step runs, whatever it does notes how many bytes it copied and the exit codes of the commands it ran here, and the dispatch collects them when the step is done.
+Syncs also note the address they reached the source at, how rsync went, the files it had trouble with, its stats, and why they were held back if they were.
+Those go in the job's results but not the metrics.
+*/
+
+/**
+What a step has noted while running.
+*/
+#[derive(Default, Debug, PartialEq)]
+pub struct StepNotes
+{
+    pub bytes: u64,
+    pub exit_code: Option<i32>,
+    pub host: Option<String>,
+    pub rsync_outcome: Option<RsyncOutcome>,
+    pub file_errors: Vec<String>,
+    pub sync_stats: Vec<SyncStats>,
+    pub held_back: Option<String>
+}
+
+/** The most file errors kept for a step, so a sync with thousands of them doesn't bloat the job */
+pub const MAX_FILE_ERRORS: usize = 100;
+
+lazy_static!{
+    static ref NOTES: Mutex<StepNotes> = Mutex::new(StepNotes::default());
+}
+
+/**
+Note that the running step copied some bytes: synced, exported, uploaded or downloaded them.
+*/
+pub fn transferred(bytes: u64)
+{
+    NOTES.lock().expect("Step notes lock is poisoned").bytes += bytes;
+}
+
+/**
+Note the exit code of a command the running step ran. If it runs several, the highest is kept, since 0 is success.
+*/
+pub fn exit_code(code: i32)
+{
+    let mut notes = NOTES.lock().expect("Step notes lock is poisoned");
+    notes.exit_code = Some(notes.exit_code.map_or(code, |c| c.max(code)));
+}
+
+/**
+Note the hostname or IP the running step reached its source at.
+*/
+pub fn host(host: &str)
+{
+    NOTES.lock().expect("Step notes lock is poisoned").host = Some(host.to_string());
+}
+
+/**
+Note how an rsync the running step ran went, and the files it had trouble with. If it runs several, the worst outcome is kept.
+*/
+pub fn rsync_outcome(outcome: RsyncOutcome, file_errors: Vec<String>)
+{
+    let mut notes = NOTES.lock().expect("Step notes lock is poisoned");
+    notes.rsync_outcome = notes.rsync_outcome.max(Some(outcome));
+    let room = MAX_FILE_ERRORS.saturating_sub(notes.file_errors.len());
+    notes.file_errors.extend(file_errors.into_iter().take(room));
+}
+
+/**
+Note what rsync reported about syncing one of the source's paths.
+*/
+pub fn sync_stats(stats: SyncStats)
+{
+    NOTES.lock().expect("Step notes lock is poisoned").sync_stats.push(stats);
+}
+
+/**
+Note that the change guard stopped the running step's sync, and why.
+*/
+pub fn held_back(reason: &str)
+{
+    NOTES.lock().expect("Step notes lock is poisoned").held_back = Some(reason.to_string());
+}
+
+/**
+Get what the step that just finished noted, and start over for the next one.
+*/
+pub fn take_notes() -> StepNotes
+{
+    std::mem::take(&mut *NOTES.lock().expect("Step notes lock is poisoned"))
+}
+
+/**
+How the steps for one action and source have gone.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepStats
+{
+    pub source: String,
+    pub action: String,
+    pub runs: u64,
+    pub failures: u64,
+    /** Failures since the last success */
+    pub consecutive_failures: u64,
+    pub last_run_at: i64,
+    pub last_ok: bool,
+    /** None if it's never succeeded */
+    pub last_success_at: Option<i64>,
+    pub last_duration_secs: f64,
+    pub last_bytes: u64,
+    pub last_exit_code: Option<i32>,
+    pub bytes_total: u64
+}
+
+pub fn metrics_file(settings: &Settings) -> PathBuf
+{
+    Path::new(&settings.startup.tokens_file).with_file_name("metrics.db")
+}
+
+/**
+Add how a step went to the stats for its action and source.
+*/
+pub fn record(metrics_file: &Path, result: &SourceResult) -> Result<(), sqlite::Error>
+{
+    let connection = connect(metrics_file)?;
+    let upsert_query = "
+        INSERT INTO steps (source, action, runs, failures, consecutive_failures, last_run_at, last_ok, last_success_at, last_duration_secs, last_bytes, last_exit_code, bytes_total)
+        VALUES (:source, :action, 1, :failed, :failed, :run_at, :ok, :success_at, :duration, :bytes, :exit_code, :bytes)
+        ON CONFLICT (source, action) DO UPDATE SET
+            runs = runs + 1,
+            failures = failures + :failed,
+            consecutive_failures = CASE WHEN :ok THEN 0 ELSE consecutive_failures + 1 END,
+            last_run_at = :run_at,
+            last_ok = :ok,
+            last_success_at = COALESCE(:success_at, last_success_at),
+            last_duration_secs = :duration,
+            last_bytes = :bytes,
+            last_exit_code = :exit_code,
+            bytes_total = bytes_total + :bytes";
+    let mut upsert_stmt = connection.prepare(upsert_query)?;
+    upsert_stmt.bind::<&[(_, Value)]>(&[
+        (":source", result.source.as_str().into()),
+        (":action", result.action.as_str().into()),
+        (":failed", i64::from(!result.ok).into()),
+        (":run_at", result.started_at.into()),
+        (":ok", i64::from(result.ok).into()),
+        (":success_at", if result.ok {result.started_at.into()} else {Value::Null}),
+        (":duration", result.duration_secs.into()),
+        (":bytes", (result.bytes as i64).into()),
+        (":exit_code", result.exit_code.map(|c| Value::Integer(c.into())).unwrap_or(Value::Null))
+    ])?;
+    while State::Row == upsert_stmt.next()? {}
+    Ok(())
+}
+
+/**
+Get the stats of every action and source that's been run, by source and then action.
+*/
+pub fn all_stats(metrics_file: &Path) -> Result<Vec<StepStats>, sqlite::Error>
+{
+    let connection = connect(metrics_file)?;
+    let mut stmt = connection.prepare("SELECT * FROM steps ORDER BY source, action")?;
+    let mut stats = Vec::new();
+    while let State::Row = stmt.next()?
+    {
+        stats.push(StepStats{
+            source: stmt.read::<String, _>("source")?,
+            action: stmt.read::<String, _>("action")?,
+            runs: stmt.read::<i64, _>("runs")? as u64,
+            failures: stmt.read::<i64, _>("failures")? as u64,
+            consecutive_failures: stmt.read::<i64, _>("consecutive_failures")? as u64,
+            last_run_at: stmt.read::<i64, _>("last_run_at")?,
+            last_ok: stmt.read::<i64, _>("last_ok")? != 0,
+            last_success_at: stmt.read::<Option<i64>, _>("last_success_at")?,
+            last_duration_secs: stmt.read::<f64, _>("last_duration_secs")?,
+            last_bytes: stmt.read::<i64, _>("last_bytes")? as u64,
+            last_exit_code: stmt.read::<Option<i64>, _>("last_exit_code")?.map(|c| c as i32),
+            bytes_total: stmt.read::<i64, _>("bytes_total")? as u64
+        });
+    }
+    Ok(stats)
+}
+
+/**
+Establish a sqlite connection to the metrics database, creating it and the table if they don't exist.
+*/
+fn connect(metrics_file: &Path) -> Result<Connection, sqlite::Error>
+{
+    let mut connection = sqlite::open(metrics_file)?;
+    connection.set_busy_timeout(5000)?;
+    connection.execute("
+        CREATE TABLE IF NOT EXISTS steps (
+            source TEXT NOT NULL,
+            action TEXT NOT NULL,
+            runs INTEGER NOT NULL,
+            failures INTEGER NOT NULL,
+            consecutive_failures INTEGER NOT NULL,
+            last_run_at INTEGER NOT NULL,
+            last_ok INTEGER NOT NULL,
+            last_success_at INTEGER,
+            last_duration_secs REAL NOT NULL,
+            last_bytes INTEGER NOT NULL,
+            last_exit_code INTEGER,
+            bytes_total INTEGER NOT NULL,
+            PRIMARY KEY (source, action)
+        );
+    ")?;
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn steps_add_up()
+    {
+        let dir = std::env::temp_dir().join(format!("redundinator_metrics_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("metrics.db");
+        let _ = std::fs::remove_file(&file);
+
+        let step = |started_at: i64, ok: bool, bytes: u64, exit_code: Option<i32>| SourceResult{
+            action: String::from("sync"), source: String::from("laptop"), ok, started_at, duration_secs: 2.5, bytes, exit_code, ..Default::default()
+        };
+        record(&file, &step(100, true, 10, Some(0))).unwrap();
+        record(&file, &step(200, false, 5, Some(23))).unwrap();
+        record(&file, &step(300, false, 0, None)).unwrap();
+        let stats = all_stats(&file).unwrap();
+        assert_eq!(stats, vec!(StepStats{
+            source: String::from("laptop"), action: String::from("sync"), runs: 3, failures: 2, consecutive_failures: 2,
+            last_run_at: 300, last_ok: false, last_success_at: Some(100), last_duration_secs: 2.5, last_bytes: 0, last_exit_code: None, bytes_total: 15
+        }));
+        record(&file, &step(400, true, 1, Some(24))).unwrap();
+        let stats = &all_stats(&file).unwrap()[0];
+        assert_eq!((stats.consecutive_failures, stats.last_success_at, stats.last_exit_code), (0, Some(400), Some(24)));
+
+        exit_code(0);
+        exit_code(23);
+        exit_code(0);
+        transferred(7);
+        transferred(8);
+        host("laptop-wifi");
+        rsync_outcome(RsyncOutcome::PartialReal, vec!(String::from("one")));
+        rsync_outcome(RsyncOutcome::Success, vec!(String::from("two")));
+        assert_eq!(take_notes(), StepNotes{
+            bytes: 15, exit_code: Some(23), host: Some(String::from("laptop-wifi")), rsync_outcome: Some(RsyncOutcome::PartialReal), file_errors: vec!(String::from("one"), String::from("two")), ..Default::default()
+        });
+        assert_eq!(take_notes(), StepNotes::default());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
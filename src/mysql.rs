@@ -2,9 +2,9 @@ use std::io::Write;
 use std::fs;
 use std::fs::File;
 use log::{error, /*warn, */info/*, debug, trace, log, Level*/};
-use run_script::ScriptOptions;
 
 use crate::settings::app_settings::{Mysql, Settings};
+use crate::shell;
 
 fn mysqldump_cnf(mysql_settings: &Mysql) -> String
 {
@@ -37,6 +37,6 @@ pub fn dump(settings: &Settings)
     //run mysqldump
     let cmd = format!(r#"/usr/bin/mysqldump --defaults-file="{cnf_location}" -u root --all-databases > {dump_location}"#);
     info!(target: "cmdlog", "{}", cmd);
-    let cmdo = match run_script::run(&cmd, &Vec::new(), &ScriptOptions::new()) {Ok(v) => format!("{}<br/>{}", v.1, v.2), Err(e) => format!("Error: {e}")};
+    let cmdo = match shell::run(&cmd) {Ok(v) => format!("{}<br/>{}", v.1, v.2), Err(e) => format!("Error: {e}")};
     info!("Completed mysql dump: {}", cmdo);
 }
\ No newline at end of file
@@ -0,0 +1,698 @@
+use lazy_static::lazy_static;
+use lettre::{message::header::ContentType, transport::smtp::authentication::Credentials, Message, SmtpTransport, Transport};
+use log::{error, warn, info/*, debug, trace, log, Level*/};
+use regex::{Captures, Regex};
+use serde::Serialize;
+use serde_json::json;
+use std::{collections::{BTreeMap, HashMap}, sync::Mutex, thread, time::Duration};
+
+use crate::dispatch::SourceResult;
+use crate::jobs::{conclude, Job, JobStatus};
+use crate::metrics::{all_stats, metrics_file};
+use crate::new_tokio_runtime;
+use crate::progress::{format_bytes, format_duration};
+use crate::resources::format_timestamp;
+use crate::rsync::RsyncOutcome;
+use crate::settings::app_settings::{Email, Notifier, NotifyMethod, Push, Settings, Webhook};
+use crate::snapshot::latest_snapshot;
+
+/*
+Notifications go out through the notifiers in the config: email, a JSON webhook, or ntfy or Gotify push notifications.
+Each notifier picks which events it gets:
+- "success" and "failure" when a job from the queue, or a run from the command line, finishes. Cancelled jobs aren't notified of.
+- "stale" when a source hasn't had a successful sync in `stale_after_days`. The web interface checks every hour,
+  and notifies of each stale source again every day until it's backed up.
+- "held_back" when the change guard stops a sync because it would delete or modify too much, until an operator approves it.
+
+The title and message are templates: {placeholders} in them are filled in from the event, and ones that aren't known are left as they are.
+Sending runs in the background, and a notifier that can't be reached only gets an error in the log.
+*/
+
+/** How long to wait for a mail server or web server before giving up on a notification */
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+/** How often the web interface checks for stale sources */
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/** How long before a source that's still stale is notified of again */
+const STALE_RENOTIFY_SECS: i64 = 24 * 60 * 60;
+const DEFAULT_TITLE: &str = "{headline}";
+const DEFAULT_MESSAGE: &str = "{details}";
+
+lazy_static!{
+    /** Notifications being sent in the background */
+    static ref SENDING: Mutex<Vec<thread::JoinHandle<()>>> = Mutex::new(Vec::new());
+    static ref PLACEHOLDER_REGEX: Regex = Regex::new(r"\{([a-z_]+)\}").expect("Error in regex for template placeholders");
+}
+
+/**
+What's being notified of.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event
+{
+    Success,
+    Failure,
+    Stale,
+    HeldBack,
+    /** Sent to every notifier by the test_notifiers action */
+    Test
+}
+
+impl Event
+{
+    /** The events notifiers can choose, by the names used in the config */
+    pub const NAMES: [&'static str; 4] = ["success", "failure", "stale", "held_back"];
+
+    pub fn as_str(&self) -> &'static str
+    {
+        match self
+        {
+            Event::Success  => "success",
+            Event::Failure  => "failure",
+            Event::Stale    => "stale",
+            Event::HeldBack => "held_back",
+            Event::Test     => "test"
+        }
+    }
+}
+
+/**
+A source that hasn't been backed up in a while.
+*/
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct StaleSource
+{
+    pub source: String,
+    /** When it last synced successfully, None if never */
+    pub last_sync: Option<i64>
+}
+
+/**
+Something to notify of, before it's put into each notifier's templates.
+*/
+struct Occurrence
+{
+    event: Event,
+    /** The values of the placeholders */
+    vars: BTreeMap<&'static str, String>,
+    /** Sent along by webhooks, as is */
+    data: serde_json::Value
+}
+
+/**
+Start checking for stale sources in the background, if that's set up. Also warns of anything wrong with the notifiers.
+*/
+pub fn start(settings: Settings)
+{
+    for (name, notifier) in &settings.notifications.notifiers
+    {
+        if let Some(unknown) = notifier.events.iter().find(|e| !Event::NAMES.contains(&e.as_str()))
+        {
+            error!("Notifier {} has unknown event {}, the events are: {}", name, unknown, Event::NAMES.join(", "));
+        }
+    }
+    if settings.notifications.stale_after_days == 0
+    {
+        return;
+    }
+    if !settings.notifications.notifiers.values().any(|n| n.events.iter().any(|e| e == "stale"))
+    {
+        warn!("stale_after_days is set, but no notifier is set to notify of stale sources");
+        return;
+    }
+    info!("Checking for sources with no sync in {} days every hour", settings.notifications.stale_after_days);
+    thread::spawn(move || {
+        let mut notified: HashMap<String, i64> = HashMap::new();
+        loop
+        {
+            let now = chrono::Utc::now().timestamp();
+            let stale = stale_sources(&settings, now);
+            // sources that were backed up since can be notified of straight away if they go stale again
+            notified.retain(|source, _| stale.iter().any(|s| &s.source == source));
+            let due = stale.into_iter()
+                .filter(|s| notified.get(&s.source).is_none_or(|at| now - at >= STALE_RENOTIFY_SECS))
+                .collect::<Vec<StaleSource>>();
+            if !due.is_empty()
+            {
+                for s in &due
+                {
+                    notified.insert(s.source.clone(), now);
+                }
+                send(&settings, &stale_occurrence(&due, now));
+            }
+            thread::sleep(STALE_CHECK_INTERVAL);
+        }
+    });
+}
+
+/**
+Notify of a job that's finished, in the background. Runs from the command line are notified of as jobs with id 0.
+*/
+pub fn job_finished(settings: &Settings, job: &Job)
+{
+    let event = match job.status
+    {
+        JobStatus::Succeeded => Event::Success,
+        JobStatus::Failed | JobStatus::Interrupted => Event::Failure,
+        _ => {return;}
+    };
+    if !settings.notifications.notifiers.values().any(|n| n.events.iter().any(|e| e == event.as_str()))
+    {
+        return;
+    }
+    send_in_background(settings, job_occurrence(event, job));
+}
+
+/**
+Notify of a run of the actions from the command line that's finished, like a job from the queue, in the background.
+*/
+pub fn run_finished(settings: &Settings, results: &[SourceResult])
+{
+    if results.is_empty()
+    {
+        return;
+    }
+    let (status, outcome) = conclude(results, false);
+    let now = chrono::Utc::now().timestamp();
+    let job = Job{id: 0, action: settings.action.clone(), status, requested_at: now, started_at: None, finished_at: Some(now), outcome, results: results.to_vec(), log: String::new(), schedule: None};
+    job_finished(settings, &job);
+}
+
+/**
+Wait for the notifications being sent in the background, so they aren't lost when the process exits.
+*/
+pub fn wait_for_sending()
+{
+    let sending = std::mem::take(&mut *SENDING.lock().expect("Notification sending lock is poisoned"));
+    for handle in sending
+    {
+        let _ = handle.join();
+    }
+}
+
+fn send_in_background(settings: &Settings, occurrence: Occurrence)
+{
+    let settings = settings.clone();
+    let handle = thread::spawn(move || { send(&settings, &occurrence); });
+    let mut sending = SENDING.lock().expect("Notification sending lock is poisoned");
+    sending.retain(|h| !h.is_finished());
+    sending.push(handle);
+}
+
+/**
+Notify that the change guard stopped a sync, in the background.
+*/
+pub fn changes_held_back(settings: &Settings, source_name: &str, reason: &str)
+{
+    if !settings.notifications.notifiers.values().any(|n| n.events.iter().any(|e| e == Event::HeldBack.as_str()))
+    {
+        return;
+    }
+    let mut vars = base_vars(Event::HeldBack);
+    vars.insert("headline", format!("Sync of {source_name} on {} held back: it would change too much", vars["host"]));
+    let details = format!("{source_name}: {reason}.\n\nThe latest snapshot is left as it was. If the changes are expected, approve them by syncing {source_name} with approve_changes.");
+    vars.insert("details", details.clone());
+    vars.insert("summary", details);
+    vars.insert("sources", source_name.to_string());
+    send_in_background(settings, Occurrence{event: Event::HeldBack, vars, data: json!({"source": source_name, "reason": reason})});
+}
+
+/**
+Send a test notification through every notifier, to check they're set up right.
+
+# Returns
+bool for whether they all sent it.
+*/
+pub fn send_test(settings: &Settings) -> bool
+{
+    if settings.notifications.notifiers.is_empty()
+    {
+        warn!("There are no notifiers in the config to test");
+        return false;
+    }
+    let mut vars = base_vars(Event::Test);
+    vars.insert("headline", format!("Test notification from redundinator on {}", vars["host"]));
+    vars.insert("details", String::from("If you're reading this, notifications work."));
+    send(settings, &Occurrence{event: Event::Test, vars, data: json!({})})
+}
+
+/**
+Find the sources that haven't had a successful sync in stale_after_days.
+The metrics know when each source last synced successfully. Sources synced before there were metrics go by their latest snapshot.
+*/
+pub fn stale_sources(settings: &Settings, now: i64) -> Vec<StaleSource>
+{
+    let stats = match all_stats(&metrics_file(settings))
+    {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Couldn't read the metrics to check for stale sources -- Error: {}", e);
+            return Vec::new();
+        }
+    };
+    let limit = i64::from(settings.notifications.stale_after_days) * 24 * 60 * 60;
+    let mut names = settings.sources.keys().cloned().collect::<Vec<String>>();
+    names.sort();
+    names.into_iter().filter_map(|source| {
+        let last_sync = match stats.iter().find(|s| s.source == source && s.action == "sync")
+        {
+            Some(s) => s.last_success_at,
+            None => latest_snapshot(&settings.startup.storage_dir, &source)
+        };
+        last_sync.is_none_or(|t| now - t >= limit).then_some(StaleSource{source, last_sync})
+    }).collect()
+}
+
+/**
+Send to each notifier that wants the event.
+
+# Returns
+bool for whether they all sent it.
+*/
+fn send(settings: &Settings, occurrence: &Occurrence) -> bool
+{
+    let mut names = settings.notifications.notifiers.keys().collect::<Vec<&String>>();
+    names.sort();
+    let mut all_sent = true;
+    for name in names
+    {
+        let notifier = &settings.notifications.notifiers[name];
+        if occurrence.event != Event::Test && !notifier.events.iter().any(|e| e == occurrence.event.as_str())
+        {
+            continue;
+        }
+        match deliver(notifier, occurrence)
+        {
+            Ok(()) => {info!("Sent {} notification through {}", occurrence.event.as_str(), name);},
+            Err(e) => {
+                error!("Couldn't send {} notification through {} -- Error: {}", occurrence.event.as_str(), name, settings.redact(&e));
+                all_sent = false;
+            }
+        }
+    }
+    all_sent
+}
+
+fn deliver(notifier: &Notifier, occurrence: &Occurrence) -> Result<(), String>
+{
+    let template = |t: &str, default: &str| fill(if t.is_empty() {default} else {t}, &occurrence.vars);
+    let title = template(&notifier.title, DEFAULT_TITLE);
+    let message = template(&notifier.message, DEFAULT_MESSAGE);
+    match &notifier.method
+    {
+        NotifyMethod::Email(email) => send_email(email, &title, &message),
+        NotifyMethod::Webhook(webhook) => send_webhook(webhook, occurrence, &title, &message),
+        NotifyMethod::Ntfy(push) => send_ntfy(push, occurrence.event, &title, &message),
+        NotifyMethod::Gotify(push) => send_gotify(push, &title, &message)
+    }
+}
+
+/**
+Fill in the {placeholders} in a template. Ones that aren't known are left as they are.
+*/
+fn fill(template: &str, vars: &BTreeMap<&'static str, String>) -> String
+{
+    PLACEHOLDER_REGEX.replace_all(template, |caps: &Captures| {
+        vars.get(&caps[1]).cloned().unwrap_or_else(|| caps[0].to_string())
+    }).into_owned()
+}
+
+/**
+The placeholders every event has.
+*/
+fn base_vars(event: Event) -> BTreeMap<&'static str, String>
+{
+    let now = chrono::Utc::now().timestamp();
+    vec![
+        ("event", event.as_str().to_string()),
+        ("host", hostname()),
+        ("time", format_timestamp(now))
+    ].into_iter().collect()
+}
+
+fn job_occurrence(event: Event, job: &Job) -> Occurrence
+{
+    let mut vars = base_vars(event);
+    let mut sources: Vec<String> = Vec::new();
+    let mut failed_sources: Vec<String> = Vec::new();
+    for r in job.results.iter().filter(|r| !r.source.is_empty())
+    {
+        if !sources.contains(&r.source)
+        {
+            sources.push(r.source.clone());
+        }
+        if !r.ok && !failed_sources.contains(&r.source)
+        {
+            failed_sources.push(r.source.clone());
+        }
+    }
+    let verb = if event == Event::Success {"succeeded"} else {"failed"};
+    let name = if job.id == 0 {String::from("Backup")} else {format!("Backup job {}", job.id)};
+    vars.insert("headline", format!("{name} {verb} on {}: {}", vars["host"], job.describe()));
+    vars.insert("details", format!("{}\n\n{}", job_summary(job), job.outcome));
+    vars.insert("job", if job.id == 0 {String::new()} else {job.id.to_string()});
+    vars.insert("what", job.describe());
+    vars.insert("status", job.status.to_string());
+    vars.insert("outcome", job.outcome.clone());
+    vars.insert("summary", job_summary(job));
+    vars.insert("sources", sources.join(", "));
+    vars.insert("failed_sources", failed_sources.join(", "));
+    let data = json!({
+        "job": {
+            "id": (job.id != 0).then_some(job.id),
+            "what": job.describe(),
+            "status": job.status,
+            "outcome": job.outcome,
+            "results": job.results
+        }
+    });
+    Occurrence{event, vars, data}
+}
+
+fn stale_occurrence(stale: &[StaleSource], now: i64) -> Occurrence
+{
+    let mut vars = base_vars(Event::Stale);
+    let names = stale.iter().map(|s| s.source.clone()).collect::<Vec<String>>().join(", ");
+    let details = stale.iter().map(|s| match s.last_sync
+    {
+        Some(t) => format!("{}: last synced {} ({} days ago)", s.source, format_timestamp(t), (now - t) / (24 * 60 * 60)),
+        None => format!("{}: never synced", s.source)
+    }).collect::<Vec<String>>().join("\n");
+    vars.insert("headline", format!("Backups on {} are stale for {names}", vars["host"]));
+    vars.insert("details", details.clone());
+    vars.insert("summary", details);
+    vars.insert("sources", names);
+    Occurrence{event: Event::Stale, vars, data: json!({"stale_sources": stale})}
+}
+
+/**
+How a job went for each source, a line per source, e.g. "laptop: sync ok (2m 3s, 1.2 GiB), upload to s3 FAILED".
+Steps that aren't about one source go on a line of their own at the end.
+*/
+fn job_summary(job: &Job) -> String
+{
+    let mut by_source: Vec<(String, Vec<String>)> = Vec::new();
+    for r in &job.results
+    {
+        let mut details = vec!(format_duration(r.duration_secs.round() as u64));
+        if r.bytes > 0
+        {
+            details.push(format_bytes(r.bytes));
+        }
+        if let Some(code) = r.exit_code.filter(|c| *c != 0)
+        {
+            details.push(format!("exit code {code}"));
+        }
+        if let Some(outcome) = r.rsync_outcome.filter(|o| *o != RsyncOutcome::Success)
+        {
+            details.push(format!("rsync {}", outcome.describe()));
+        }
+        if let Some(host) = &r.host
+        {
+            details.push(format!("via {host}"));
+        }
+        if let Some(reason) = &r.held_back
+        {
+            details.push(format!("held back: {reason}"));
+        }
+        let step = format!("{} {} ({})", r.action, if r.ok {"ok"} else {"FAILED"}, details.join(", "));
+        match by_source.iter_mut().find(|(s, _)| *s == r.source)
+        {
+            Some((_, steps)) => steps.push(step),
+            None => by_source.push((r.source.clone(), vec!(step)))
+        }
+    }
+    // steps not about one source last
+    by_source.sort_by_key(|(s, _)| s.is_empty());
+    match by_source.is_empty()
+    {
+        true => String::from("Nothing was done."),
+        false => by_source.iter().map(|(s, steps)| format!("{}: {}", if s.is_empty() {"other"} else {s}, steps.join(", "))).collect::<Vec<String>>().join("\n")
+    }
+}
+
+fn send_email(email: &Email, title: &str, message: &str) -> Result<(), String>
+{
+    let mut builder = Message::builder()
+        .from(email.from.parse().map_err(|e| format!("invalid from address {}: {e}", email.from))?)
+        .subject(title);
+    for to in &email.to
+    {
+        builder = builder.to(to.parse().map_err(|e| format!("invalid to address {to}: {e}"))?);
+    }
+    let message = builder.header(ContentType::TEXT_PLAIN).body(message.to_string()).map_err(|e| e.to_string())?;
+    let transport = match email.security.as_str()
+    {
+        "starttls" => SmtpTransport::starttls_relay(&email.smtp_host).map_err(|e| e.to_string())?,
+        "tls" => SmtpTransport::relay(&email.smtp_host).map_err(|e| e.to_string())?,
+        "none" => SmtpTransport::builder_dangerous(&email.smtp_host),
+        other => {return Err(format!(r#"unknown security "{other}", it should be "starttls", "tls" or "none""#));}
+    };
+    let mut transport = transport.port(email.smtp_port).timeout(Some(SEND_TIMEOUT));
+    if !email.username.is_empty()
+    {
+        transport = transport.credentials(Credentials::new(email.username.clone(), email.password.clone()));
+    }
+    transport.build().send(&message).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn send_webhook(webhook: &Webhook, occurrence: &Occurrence, title: &str, message: &str) -> Result<(), String>
+{
+    let mut body = json!({
+        "event": occurrence.event.as_str(),
+        "host": occurrence.vars["host"],
+        "time": chrono::Utc::now().timestamp(),
+        "title": title,
+        "message": message
+    });
+    if let (Some(body), Some(data)) = (body.as_object_mut(), occurrence.data.as_object())
+    {
+        body.extend(data.clone());
+    }
+    post(&webhook.url, |client| {
+        let request = client.post(&webhook.url).json(&body);
+        webhook.headers.iter().fold(request, |request, (name, value)| request.header(name, value))
+    })
+}
+
+fn send_ntfy(push: &Push, event: Event, title: &str, message: &str) -> Result<(), String>
+{
+    let tags = match event
+    {
+        Event::Success => "white_check_mark",
+        Event::Failure => "x",
+        Event::Stale => "warning",
+        Event::HeldBack => "rotating_light",
+        Event::Test => "bell"
+    };
+    post(&push.url, |client| {
+        let mut request = client.post(&push.url).header("Title", title).header("Tags", tags).body(message.to_string());
+        if push.priority > 0
+        {
+            request = request.header("Priority", push.priority.to_string());
+        }
+        if !push.token.is_empty()
+        {
+            request = request.bearer_auth(&push.token);
+        }
+        request
+    })
+}
+
+fn send_gotify(push: &Push, title: &str, message: &str) -> Result<(), String>
+{
+    let url = format!("{}/message", push.url.trim_end_matches('/'));
+    let mut body = json!({"title": title, "message": message});
+    if push.priority > 0
+    {
+        body["priority"] = json!(push.priority);
+    }
+    post(&url, |client| client.post(&url).header("X-Gotify-Key", &push.token).json(&body))
+}
+
+/**
+Send an HTTP request and check that it worked.
+
+# Arguments
+* `build` - Makes the request with the client it's given
+*/
+fn post<F: FnOnce(&reqwest::Client) -> reqwest::RequestBuilder>(url: &str, build: F) -> Result<(), String>
+{
+    let runtime = new_tokio_runtime().map_err(|e| format!("couldn't start the HTTP client: {e}"))?;
+    let client = reqwest::Client::builder().timeout(SEND_TIMEOUT).build().map_err(|e| e.to_string())?;
+    let request = build(&client);
+    runtime.block_on(async {
+        let response = request.send().await.map_err(|e| format!("couldn't reach {url}: {e}"))?;
+        match response.status().is_success()
+        {
+            true => Ok(()),
+            false => Err(format!("{url} responded with HTTP status {}", response.status()))
+        }
+    })
+}
+
+#[cfg(target_family = "unix")]
+fn hostname() -> String
+{
+    nix::unistd::gethostname().ok().and_then(|h| h.into_string().ok()).unwrap_or_else(|| String::from("unknown host"))
+}
+
+#[cfg(not(target_family = "unix"))]
+fn hostname() -> String
+{
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("unknown host"))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::dispatch::SourceResult;
+    use crate::settings::app_settings::Action;
+    use std::{io::{BufRead, BufReader, Read, Write}, net::TcpListener};
+
+    fn job() -> Job
+    {
+        let step = |action: &str, source: &str, ok: bool, bytes: u64, exit_code: Option<i32>| SourceResult{
+            action: action.to_string(), source: source.to_string(), ok, started_at: 0, duration_secs: 123.4, bytes, exit_code, ..Default::default()
+        };
+        Job{
+            id: 7,
+            action: Action{sync: true, upload: true, ..Default::default()},
+            status: JobStatus::Failed,
+            requested_at: 0,
+            started_at: Some(0),
+            finished_at: Some(200),
+            outcome: String::from("1 of 3 steps failed: sync for desktop"),
+            results: vec!(
+                step("sync", "laptop", true, 3 * 1024 * 1024, Some(0)),
+                step("sync", "desktop", false, 0, Some(23)),
+                step("upload to s3", "laptop", true, 0, None)
+            ),
+            log: String::new(),
+            schedule: None
+        }
+    }
+
+    #[test]
+    fn templates_are_filled()
+    {
+        let occurrence = job_occurrence(Event::Failure, &job());
+        assert_eq!(fill("Job {job} {status} for {failed_sources}, {unknown}", &occurrence.vars), "Job 7 failed for desktop, {unknown}");
+        assert_eq!(occurrence.vars["summary"], "laptop: sync ok (2m 3s, 3.0 MiB), upload to s3 ok (2m 3s)\ndesktop: sync FAILED (2m 3s, exit code 23)");
+        assert!(occurrence.vars["headline"].starts_with("Backup job 7 failed on "));
+
+        let stale = stale_occurrence(&[StaleSource{source: String::from("client3"), last_sync: Some(0)}, StaleSource{source: String::from("client4"), last_sync: None}], 8 * 24 * 60 * 60);
+        assert_eq!(stale.vars["details"], format!("client3: last synced {} (8 days ago)\nclient4: never synced", format_timestamp(0)));
+    }
+
+    /**
+    Accept one HTTP request, answer it with 200 OK, and return its head and body.
+    */
+    fn http_listener() -> (String, thread::JoinHandle<(String, String)>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        (url, thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n")
+            {
+                reader.read_line(&mut head).unwrap();
+            }
+            let length = head.lines().find_map(|l| l.to_lowercase().strip_prefix("content-length: ").map(|n| n.parse::<usize>().unwrap())).unwrap_or(0);
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+            (head, String::from_utf8(body).unwrap())
+        }))
+    }
+
+    /**
+    Accept one SMTP session and return the message sent in it.
+    */
+    fn smtp_sink() -> (u16, thread::JoinHandle<String>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (port, thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 sink ESMTP\r\n").unwrap();
+            let mut data = String::new();
+            let mut in_data = false;
+            loop
+            {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0
+                {
+                    break;
+                }
+                if in_data
+                {
+                    if line == ".\r\n"
+                    {
+                        in_data = false;
+                        stream.write_all(b"250 queued\r\n").unwrap();
+                    }else{
+                        data += &line;
+                    }
+                    continue;
+                }
+                let reply: &[u8] = match line.get(..4).unwrap_or("").to_uppercase().as_str()
+                {
+                    "EHLO" => b"250 sink\r\n",
+                    "DATA" => {in_data = true; b"354 go ahead\r\n"},
+                    "QUIT" => {stream.write_all(b"221 bye\r\n").unwrap(); break;},
+                    _ => b"250 ok\r\n"
+                };
+                stream.write_all(reply).unwrap();
+            }
+            data
+        }))
+    }
+
+    #[test]
+    fn notifiers_send()
+    {
+        let occurrence = job_occurrence(Event::Failure, &job());
+        let notifier = |method: NotifyMethod| Notifier{events: vec!(String::from("failure")), title: String::from("Job {job} {status}"), message: String::new(), method};
+
+        let (url, request) = http_listener();
+        let webhook = Webhook{url: format!("{url}/hook"), headers: vec![(String::from("X-Secret"), String::from("s3cret"))].into_iter().collect()};
+        deliver(&notifier(NotifyMethod::Webhook(webhook)), &occurrence).unwrap();
+        let (head, body) = request.join().unwrap();
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(head.to_lowercase().contains("x-secret: s3cret\r\n"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!((body["event"].as_str(), body["title"].as_str(), body["job"]["id"].as_i64()), (Some("failure"), Some("Job 7 failed"), Some(7)));
+
+        let (url, request) = http_listener();
+        deliver(&notifier(NotifyMethod::Ntfy(Push{url: format!("{url}/backups"), token: String::from("tk"), priority: 4})), &occurrence).unwrap();
+        let (head, body) = request.join().unwrap();
+        assert!(head.starts_with("POST /backups HTTP/1.1\r\n"));
+        assert!(head.contains("title: Job 7 failed\r\n") && head.contains("priority: 4\r\n") && head.contains("authorization: Bearer tk\r\n"));
+        assert!(body.contains("desktop: sync FAILED"));
+
+        let (url, request) = http_listener();
+        deliver(&notifier(NotifyMethod::Gotify(Push{url: format!("{url}/"), token: String::from("app"), priority: 0})), &occurrence).unwrap();
+        let (head, body) = request.join().unwrap();
+        assert!(head.starts_with("POST /message HTTP/1.1\r\n") && head.contains("x-gotify-key: app\r\n"));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["title"], "Job 7 failed");
+
+        let (port, session) = smtp_sink();
+        let email = Email{
+            smtp_host: String::from("127.0.0.1"), smtp_port: port, security: String::from("none"), username: String::new(), password: String::new(),
+            from: String::from("redundinator@example.com"), to: vec!(String::from("admin@example.com"))
+        };
+        deliver(&notifier(NotifyMethod::Email(email)), &occurrence).unwrap();
+        let data = session.join().unwrap();
+        assert!(data.contains("Subject: Job 7 failed\r\n"));
+        assert!(data.contains("To: admin@example.com\r\n"));
+        assert!(data.contains("desktop: sync FAILED"));
+
+        // nothing listening
+        let unreachable = Push{url: String::from("http://127.0.0.1:9/x"), token: String::new(), priority: 0};
+        assert!(deliver(&notifier(NotifyMethod::Ntfy(unreachable)), &occurrence).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use sha2::{Digest, Sha256};
+use std::{collections::VecDeque, fs::File, io::{self, BufReader, BufWriter, Read, Write}, path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicU64, Ordering}}};
+
+/*
+Exports are one stream of data split across numbered part files, so each file stays a manageable size for cloud storage:
+
+    {name}_{timestamp}.tar.zst.0000
+    {name}_{timestamp}.tar.zst.0001
+    ...
+
+Concatenating the parts in order gives back the whole stream. This is the same layout `split --numeric-suffixes --suffix-length=4` produces,
+which is what older versions used, so their exports can still be read.
+*/
+
+/**
+A part file written by SplitWriter.
+*/
+#[derive(Clone, Debug)]
+pub struct WrittenPart
+{
+    pub path: PathBuf,
+    pub size: u64,
+    /** Hex encoded SHA-256 of the part's contents */
+    pub sha256: String
+}
+
+/**
+Writes a stream into numbered part files of at most `part_size` bytes each, hashing each part along the way.
+Part files are only created when there is data for them.
+*/
+pub struct SplitWriter
+{
+    dir: PathBuf,
+    prefix: String,
+    part_size: u64,
+    current: Option<BufWriter<File>>,
+    hasher: Sha256,
+    written_in_part: u64,
+    written_total: Arc<AtomicU64>,
+    parts: Vec<WrittenPart>
+}
+
+impl SplitWriter
+{
+    /**
+    # Arguments
+    * `dir` - Directory to create the parts in
+    * `prefix` - Filename of the parts before the part number
+    * `part_size` - Maximum size of each part in bytes
+    */
+    pub fn new(dir: &Path, prefix: &str, part_size: u64) -> SplitWriter
+    {
+        SplitWriter{
+            dir: dir.to_path_buf(),
+            prefix: prefix.to_string(),
+            part_size: u64::max(part_size, 1),
+            current: None,
+            hasher: Sha256::new(),
+            written_in_part: 0,
+            written_total: Arc::new(AtomicU64::new(0)),
+            parts: Vec::new()
+        }
+    }
+
+    /**
+    Get a counter of all the bytes written so far, which can still be read once the writer is wrapped in others.
+    Dividing it by the part size gives the number of the part being written.
+    */
+    pub fn position(&self) -> Arc<AtomicU64>
+    {
+        Arc::clone(&self.written_total)
+    }
+
+    /**
+    Flush and close the last part.
+
+    # Returns
+    All the parts written, in order.
+    */
+    pub fn finish(mut self) -> io::Result<Vec<WrittenPart>>
+    {
+        self.close_part()?;
+        Ok(self.parts)
+    }
+
+    fn close_part(&mut self) -> io::Result<()>
+    {
+        if let Some(mut part) = self.current.take()
+        {
+            part.flush()?;
+            part.get_ref().sync_all()?;
+            let hasher = std::mem::take(&mut self.hasher);
+            if let Some(written) = self.parts.last_mut()
+            {
+                written.size = self.written_in_part;
+                written.sha256 = hex::encode(hasher.finalize());
+            }
+        }
+        Ok(())
+    }
+
+    fn next_part(&mut self) -> io::Result<()>
+    {
+        self.close_part()?;
+        let path = self.dir.join(format!("{}{:04}", self.prefix, self.parts.len()));
+        self.current = Some(BufWriter::new(File::create(&path)?));
+        self.parts.push(WrittenPart{path, size: 0, sha256: String::new()});
+        self.written_in_part = 0;
+        Ok(())
+    }
+}
+
+impl Write for SplitWriter
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        if buf.is_empty() {return Ok(0);}
+        if self.current.is_none() || self.written_in_part >= self.part_size
+        {
+            self.next_part()?;
+        }
+        let room = usize::try_from(self.part_size - self.written_in_part).unwrap_or(usize::MAX);
+        let len = usize::min(room, buf.len());
+        let written = match self.current.as_mut()
+        {
+            Some(part) => part.write(&buf[..len])?,
+            None => {return Err(io::Error::other("no part file open"));}
+        };
+        self.hasher.update(&buf[..written]);
+        self.written_in_part += written as u64;
+        self.written_total.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        match self.current.as_mut()
+        {
+            Some(part) => part.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+/**
+Reads numbered part files one after another as a single stream.
+*/
+pub struct PartsReader
+{
+    paths: VecDeque<PathBuf>,
+    current: Option<BufReader<File>>
+}
+
+impl PartsReader
+{
+    /**
+    # Arguments
+    * `paths` - The part files, in order
+    */
+    pub fn new<P: AsRef<Path>>(paths: &[P]) -> PartsReader
+    {
+        PartsReader{paths: paths.iter().map(|p| p.as_ref().to_path_buf()).collect(), current: None}
+    }
+}
+
+impl Read for PartsReader
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        if buf.is_empty() {return Ok(0);}
+        loop
+        {
+            let current = match self.current.as_mut()
+            {
+                Some(c) => c,
+                None => match self.paths.pop_front()
+                {
+                    Some(path) => self.current.insert(BufReader::new(File::open(path)?)),
+                    None => {return Ok(0);}
+                }
+            };
+            let read = current.read(buf)?;
+            if read > 0 {return Ok(read);}
+            self.current = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn split_and_rejoin()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..=255).cycle().take(10000).collect();
+
+        let mut writer = SplitWriter::new(dir.path(), "test.", 4096);
+        let position = writer.position();
+        writer.write_all(&data).unwrap();
+        assert_eq!(position.load(Ordering::Relaxed), 10000);
+        let parts = writer.finish().unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[2].path.file_name().unwrap(), "test.0002");
+        assert_eq!(parts[0].size, 4096);
+        assert_eq!(parts[2].size, 10000 - 2 * 4096);
+        assert_eq!(std::fs::metadata(&parts[2].path).unwrap().len(), parts[2].size);
+        assert_eq!(parts[1].sha256, hex::encode(Sha256::digest(&data[4096..8192])));
+
+        let paths: Vec<PathBuf> = parts.iter().map(|p| p.path.clone()).collect();
+        let mut rejoined = Vec::new();
+        PartsReader::new(&paths).read_to_end(&mut rejoined).unwrap();
+        assert_eq!(rejoined, data);
+    }
+
+    #[test]
+    fn no_data_no_parts()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let parts = SplitWriter::new(dir.path(), "test.", 4096).finish().unwrap();
+        assert!(parts.is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+use crate::cancel;
+
+/*
+How far along the task being done is: a sync, export, upload or download. Whatever does the task reports how many bytes are done as it goes,
+and the web interface shows it while a job runs. There's only ever one task at a time, since jobs run one at a time.
+When run from the command line, progress is printed to stderr every so often instead.
+*/
+
+/** How long to measure over before working out a new rate */
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+/** How much each new measurement of the rate counts, against the previous ones */
+const RATE_SMOOTHING: f64 = 0.3;
+/** How often progress is printed when run from the command line */
+const PRINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/**
+Progress of one task.
+*/
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Progress
+{
+    /** What's being done, e.g. "sync for laptop" */
+    pub task: String,
+    /** The job doing it, if it's run by the queue */
+    pub job: Option<i64>,
+    pub bytes_done: u64,
+    /** None until it's known */
+    pub bytes_total: Option<u64>,
+    /** Bytes per second, recently */
+    pub rate: Option<f64>,
+    /** Seconds until it's done, at the recent rate */
+    pub eta_secs: Option<u64>,
+    /** The file being worked on, blank if none in particular */
+    pub current_file: String
+}
+
+/**
+Keeps track of how a task is doing, and how fast.
+*/
+struct Meter
+{
+    progress: Progress,
+    /** Bytes done before the current file */
+    base: u64,
+    /** When the rate was last measured, and the bytes done then */
+    sample: (Instant, u64),
+    printed: Instant
+}
+
+impl Meter
+{
+    fn new(task: &str, total: Option<u64>, now: Instant) -> Meter
+    {
+        Meter{
+            progress: Progress{task: task.to_string(), job: cancel::running_job(), bytes_done: 0, bytes_total: total, rate: None, eta_secs: None, current_file: String::new()},
+            base: 0,
+            sample: (now, 0),
+            printed: now
+        }
+    }
+
+    /**
+    Set how many bytes are done altogether, and work out the rate and ETA.
+    */
+    fn set_done(&mut self, done: u64, now: Instant)
+    {
+        self.progress.bytes_done = done;
+        let (then, done_then) = self.sample;
+        let elapsed = now.saturating_duration_since(then);
+        if elapsed >= RATE_INTERVAL
+        {
+            let rate = done.saturating_sub(done_then) as f64 / elapsed.as_secs_f64();
+            self.progress.rate = Some(match self.progress.rate
+            {
+                Some(r) => r + (rate - r) * RATE_SMOOTHING,
+                None => rate
+            });
+            self.sample = (now, done);
+        }
+        self.progress.eta_secs = match (self.progress.bytes_total, self.progress.rate)
+        {
+            (Some(total), Some(rate)) if rate > 0.0 => Some((total.saturating_sub(done) as f64 / rate).ceil() as u64),
+            _ => None
+        };
+    }
+}
+
+lazy_static!{
+    static ref CURRENT: Mutex<Option<Meter>> = Mutex::new(None);
+}
+
+fn with_meter<F: FnOnce(&mut Meter, Instant)>(f: F)
+{
+    let now = Instant::now();
+    let mut current = CURRENT.lock().expect("Progress lock is poisoned");
+    if let Some(meter) = current.as_mut()
+    {
+        f(meter, now);
+        if !cancel::in_job() && now.saturating_duration_since(meter.printed) >= PRINT_INTERVAL
+        {
+            meter.printed = now;
+            eprintln!("{}", describe(&meter.progress));
+        }
+    }
+}
+
+/**
+Start keeping track of a task, replacing any task that wasn't finished.
+
+# Arguments
+* `task` - What's being done, e.g. "export for laptop"
+* `total` - How many bytes there are to do, if known
+*/
+pub fn start(task: &str, total: Option<u64>)
+{
+    *CURRENT.lock().expect("Progress lock is poisoned") = Some(Meter::new(task, total, Instant::now()));
+}
+
+/**
+Note that the task is done.
+
+# Returns
+Where it got to, if it was started.
+*/
+pub fn finish() -> Option<Progress>
+{
+    CURRENT.lock().expect("Progress lock is poisoned").take().map(|m| m.progress)
+}
+
+/**
+Move on to the next file of the task. What was done of the previous file counts towards the total.
+*/
+pub fn next_file(name: &str)
+{
+    with_meter(|meter, _| {
+        meter.base = meter.progress.bytes_done;
+        meter.progress.current_file = name.to_string();
+    });
+}
+
+/**
+Note how far along the current file is, in bytes from its start.
+*/
+pub fn file_position(bytes: u64)
+{
+    with_meter(|meter, now| {
+        let base = meter.base;
+        meter.set_done(base + bytes, now);
+    });
+}
+
+/**
+Note how far along the whole task is, for things that report that instead of going file by file.
+
+# Arguments
+* `total` - The total if it's known better now, or None to keep the one there is
+*/
+pub fn overall(done: u64, total: Option<u64>)
+{
+    with_meter(|meter, now| {
+        meter.base = done;
+        if total.is_some()
+        {
+            meter.progress.bytes_total = total;
+        }
+        meter.set_done(done, now);
+    });
+}
+
+/**
+Get the progress of the task being done, if any.
+*/
+pub fn current() -> Option<Progress>
+{
+    CURRENT.lock().expect("Progress lock is poisoned").as_ref().map(|m| m.progress.clone())
+}
+
+/**
+Describe progress in a line, e.g. "upload to s3 for laptop: 1.2 GiB of 4.0 GiB (30%), 12.5 MiB/s, 4m 2s left -- laptop_1700000000.tar.zst.002"
+*/
+pub fn describe(progress: &Progress) -> String
+{
+    let mut line = format!("{}: {}", progress.task, format_bytes(progress.bytes_done));
+    if let Some(total) = progress.bytes_total
+    {
+        line += &format!(" of {}", format_bytes(total));
+        if let Some(percent) = (progress.bytes_done.min(total) * 100).checked_div(total)
+        {
+            line += &format!(" ({percent}%)");
+        }
+    }
+    if let Some(rate) = progress.rate
+    {
+        line += &format!(", {}/s", format_bytes(rate as u64));
+    }
+    if let Some(eta) = progress.eta_secs
+    {
+        line += &format!(", {} left", format_duration(eta));
+    }
+    if !progress.current_file.is_empty()
+    {
+        line += &format!(" -- {}", progress.current_file);
+    }
+    line
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String
+{
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024
+    {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1
+    {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+pub(crate) fn format_duration(secs: u64) -> String
+{
+    match secs
+    {
+        s if s >= 3600 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m {}s", s / 60, s % 60),
+        s => format!("{s}s")
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn rate_and_eta()
+    {
+        let start = Instant::now();
+        let mut meter = Meter::new("export for laptop", Some(10_000), start);
+        meter.set_done(500, start + Duration::from_millis(500));
+        assert_eq!((meter.progress.rate, meter.progress.eta_secs), (None, None));
+        meter.set_done(1000, start + Duration::from_secs(1));
+        assert_eq!((meter.progress.rate, meter.progress.eta_secs), (Some(1000.0), Some(9)));
+        // the rate changes gradually
+        meter.set_done(3000, start + Duration::from_secs(2));
+        assert_eq!(meter.progress.rate, Some(1300.0));
+        assert_eq!(meter.progress.eta_secs, Some(6));
+        meter.progress.current_file = String::from("a/b");
+        assert_eq!(describe(&meter.progress), "export for laptop: 2.9 KiB of 9.8 KiB (30%), 1.3 KiB/s, 6s left -- a/b");
+    }
+}
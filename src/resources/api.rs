@@ -0,0 +1,371 @@
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, error::{InternalError, JsonPayloadError, PathError, QueryPayloadError}, http::{header, StatusCode}, web};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+
+use crate::{export_files, export_timestamps};
+use crate::jobs::{self, Job};
+use crate::manifest;
+use crate::progress;
+use crate::settings::app_settings::{Action, Settings, SyncMethod};
+use crate::snapshot::{latest_snapshot, list_snapshots};
+use crate::usage::{filesystem_space, source_usage, FilesystemSpace, SourceUsage};
+
+/*
+The JSON API, under /api/v1. Everything it returns is JSON, errors included: {"error": "what went wrong"} with a 4xx or 5xx status.
+openapi.json next to this file describes it, and is served at /api/v1/openapi.json. Keep the two in step.
+*/
+
+/** The OpenAPI description of the API */
+const OPENAPI: &str = include_str!("openapi.json");
+/** How many jobs the job history gives when no limit is asked for */
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+/**
+What the API tells about a source. Connection details and credentials are left out.
+*/
+#[derive(Serialize)]
+struct SourceInfo
+{
+    name: String,
+    hostname: String,
+    fallback_hostnames: Vec<String>,
+    /** Rsyncd, RsyncSsh or RsyncLocal */
+    method: &'static str,
+    paths: Vec<String>,
+    snapshots: usize,
+    latest_snapshot: Option<i64>,
+    exports: usize,
+    latest_export: Option<i64>
+}
+
+/**
+A job, with a description of what it does. Secrets are replaced in what it shows.
+*/
+#[derive(Serialize)]
+struct JobInfo
+{
+    #[serde(flatten)]
+    job: Job,
+    description: String
+}
+
+impl JobInfo
+{
+    fn new(mut job: Job, settings: &Settings) -> JobInfo
+    {
+        job.action = job.action.redacted();
+        job.log = settings.redact(&job.log);
+        JobInfo{description: job.describe(), job}
+    }
+}
+
+/**
+One export of a source in the export dir.
+*/
+#[derive(Serialize)]
+struct ExportInfo
+{
+    timestamp: i64,
+    /** 0 for the newest export, 1 for the one before it, and so on */
+    generations_ago: usize,
+    parts: usize,
+    /** Size of the parts */
+    size: u64,
+    /** The rest is from the manifest, and missing for exports without one */
+    snapshot_timestamp: Option<i64>,
+    file_count: Option<u64>,
+    uncompressed_size: Option<u64>,
+    encryption: Option<String>
+}
+
+#[derive(Serialize)]
+struct DirInfo
+{
+    path: String,
+    /** None when it can't be determined */
+    filesystem: Option<FilesystemSpace>
+}
+
+#[derive(Serialize)]
+struct StorageInfo
+{
+    storage_dir: DirInfo,
+    export_dir: DirInfo,
+    sources: Vec<SourceUsage>
+}
+
+/**
+A request to queue a job.
+*/
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnqueueRequest
+{
+    /** Names of the actions to do, as in Action::NAMES */
+    actions: Vec<String>,
+    /** Source to do them for, blank or missing for all */
+    #[serde(default)]
+    source: String,
+    /** Upload target to use, blank or missing for all */
+    #[serde(default)]
+    upload_target: String,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    approve_changes: bool,
+    #[serde(default)]
+    timestamp: i64,
+    #[serde(default)]
+    generations_ago: u32,
+    #[serde(default)]
+    include: String
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery
+{
+    limit: Option<usize>
+}
+
+/**
+Responds with a list of the configured sources and their snapshots and exports.
+*/
+pub async fn sources(settings: web::Data<Settings>) -> HttpResponse
+{
+    let mut names = settings.sources.keys().cloned().collect::<Vec<String>>();
+    names.sort();
+    let list = names.into_iter().map(|name| {
+        let source = &settings.sources[&name];
+        let exports = export_timestamps(&name, &settings.startup.export_dir);
+        SourceInfo{
+            hostname: source.hostname.clone(),
+            fallback_hostnames: source.fallback_hostnames.clone(),
+            method: match source.method
+            {
+                SyncMethod::Rsyncd(_) => "Rsyncd",
+                SyncMethod::RsyncSsh(_) => "RsyncSsh",
+                SyncMethod::RsyncLocal => "RsyncLocal"
+            },
+            paths: source.paths.clone(),
+            snapshots: list_snapshots(&settings.startup.storage_dir, &name).len(),
+            latest_snapshot: latest_snapshot(&settings.startup.storage_dir, &name),
+            exports: exports.len(),
+            latest_export: exports.last().copied(),
+            name
+        }
+    }).collect::<Vec<SourceInfo>>();
+    json(StatusCode::OK, &list)
+}
+
+/**
+Responds with the exports of a source in the export dir, newest first, or HTTP 404 Not Found for an unknown source.
+*/
+pub async fn source_exports(settings: web::Data<Settings>, name: web::Path<String>) -> HttpResponse
+{
+    if !settings.sources.contains_key(name.as_str())
+    {
+        return error(StatusCode::NOT_FOUND, &format!("no source named {name}"));
+    }
+    let export_dir = &settings.startup.export_dir;
+    let list = export_timestamps(&name, export_dir).into_iter().rev().enumerate().map(|(generations_ago, timestamp)| {
+        let files = export_files(&name, timestamp, export_dir);
+        let m = manifest::load(&name, timestamp, export_dir).ok().flatten();
+        ExportInfo{
+            timestamp,
+            generations_ago,
+            parts: files.len(),
+            size: files.iter().filter_map(|f| std::fs::metadata(f).ok()).map(|m| m.len()).sum(),
+            snapshot_timestamp: m.as_ref().and_then(|m| m.snapshot_timestamp),
+            file_count: m.as_ref().map(|m| m.file_count),
+            uncompressed_size: m.as_ref().map(|m| m.uncompressed_size),
+            encryption: m.map(|m| m.encryption)
+        }
+    }).collect::<Vec<ExportInfo>>();
+    json(StatusCode::OK, &list)
+}
+
+/**
+Responds with how much space each source takes up, and how much is left where the backups are stored.
+This reads through every snapshot, so it can take a while.
+*/
+pub async fn storage(settings: web::Data<Settings>) -> HttpResponse
+{
+    let settings = settings.into_inner();
+    let result = web::block(move || {
+        let mut names = settings.sources.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        let dir_info = |path: &str| DirInfo{path: path.to_string(), filesystem: filesystem_space(Path::new(path))};
+        StorageInfo{
+            storage_dir: dir_info(&settings.startup.storage_dir),
+            export_dir: dir_info(&settings.startup.export_dir),
+            sources: names.iter().map(|name| source_usage(name, &settings)).collect()
+        }
+    }).await;
+    match result
+    {
+        Ok(info) => json(StatusCode::OK, &info),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+    }
+}
+
+/**
+Queues a job. Responds with HTTP 201 Created and the job, or HTTP 400 Bad Request if there's anything wrong with the request.
+*/
+pub async fn enqueue(settings: web::Data<Settings>, req: web::Json<EnqueueRequest>) -> HttpResponse
+{
+    if req.actions.is_empty()
+    {
+        return error(StatusCode::BAD_REQUEST, "no actions given");
+    }
+    let mut action = Action{
+        source: req.source.clone(),
+        upload_target: req.upload_target.clone(),
+        dry_run: req.dry_run,
+        approve_changes: req.approve_changes,
+        timestamp: req.timestamp,
+        generations_ago: req.generations_ago,
+        include: req.include.clone(),
+        ..Default::default()
+    };
+    if let Some(unknown) = req.actions.iter().find(|name| !action.enable(name))
+    {
+        return error(StatusCode::BAD_REQUEST, &format!("unknown action {unknown}, the actions are: {}", Action::NAMES.join(", ")));
+    }
+    if !req.source.is_empty() && !settings.sources.contains_key(&req.source)
+    {
+        return error(StatusCode::BAD_REQUEST, &format!("no source named {}", req.source));
+    }
+    if !req.upload_target.is_empty() && !settings.upload_targets.contains_key(&req.upload_target)
+    {
+        return error(StatusCode::BAD_REQUEST, &format!("no upload target named {}", req.upload_target));
+    }
+
+    let jobs_file = jobs::jobs_file(&settings);
+    match jobs::enqueue(&jobs_file, &action).and_then(|id| jobs::get(&jobs_file, id))
+    {
+        Ok(Some(job)) => HttpResponseBuilder::new(StatusCode::CREATED)
+            .insert_header((header::LOCATION, format!("/api/v1/jobs/{}", job.id)))
+            .json(JobInfo::new(job, &settings)),
+        Ok(None) => error(StatusCode::INTERNAL_SERVER_ERROR, "the job disappeared right after it was queued"),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+    }
+}
+
+/**
+Responds with the running job, if any, and the queued jobs in the order they'll run.
+*/
+pub async fn queue(settings: web::Data<Settings>) -> HttpResponse
+{
+    let jobs_file = jobs::jobs_file(&settings);
+    match jobs::running(&jobs_file).and_then(|r| Ok((r, jobs::queued(&jobs_file)?)))
+    {
+        Ok((running, queued)) => json(StatusCode::OK, &json!({
+            "running": running.map(|j| JobInfo::new(j, &settings)),
+            "queued": queued.into_iter().map(|j| JobInfo::new(j, &settings)).collect::<Vec<JobInfo>>()
+        })),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+    }
+}
+
+/**
+Responds with the progress of the task being done, e.g. a sync or upload, or null if nothing is being done.
+*/
+pub async fn progress() -> HttpResponse
+{
+    json(StatusCode::OK, &progress::current())
+}
+
+/**
+Responds with the jobs that have started, newest first, with their results.
+*/
+pub async fn jobs(settings: web::Data<Settings>, query: web::Query<HistoryQuery>) -> HttpResponse
+{
+    match jobs::history(&jobs::jobs_file(&settings), query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT))
+    {
+        Ok(history) => json(StatusCode::OK, &history.into_iter().map(|j| JobInfo::new(j, &settings)).collect::<Vec<JobInfo>>()),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+    }
+}
+
+/**
+Responds with one job, or HTTP 404 Not Found if there's no such job.
+*/
+pub async fn job(settings: web::Data<Settings>, id: web::Path<i64>) -> HttpResponse
+{
+    match jobs::get(&jobs::jobs_file(&settings), *id)
+    {
+        Ok(Some(job)) => json(StatusCode::OK, &JobInfo::new(job, &settings)),
+        Ok(None) => error(StatusCode::NOT_FOUND, &format!("no job {id}")),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+    }
+}
+
+/**
+Responds with the OpenAPI description of the API.
+*/
+pub async fn openapi() -> HttpResponse
+{
+    HttpResponseBuilder::new(StatusCode::OK)
+        .insert_header((header::CONTENT_TYPE, "application/json"))
+        .body(OPENAPI)
+}
+
+/**
+Responds to requests for anything else under the API with HTTP 404 Not Found.
+*/
+pub async fn notfound() -> HttpResponse
+{
+    error(StatusCode::NOT_FOUND, "no such API endpoint")
+}
+
+/**
+Turns a request body that isn't valid JSON for the endpoint into HTTP 400 Bad Request, in the same form as the API's other errors.
+*/
+pub fn json_error(e: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error
+{
+    let message = e.to_string();
+    InternalError::from_response(e, error(StatusCode::BAD_REQUEST, &message)).into()
+}
+
+/**
+Turns a path that doesn't fit the endpoint, like a job id that isn't a number, into HTTP 404 Not Found.
+*/
+pub fn path_error(e: PathError, _req: &HttpRequest) -> actix_web::Error
+{
+    let message = e.to_string();
+    InternalError::from_response(e, error(StatusCode::NOT_FOUND, &message)).into()
+}
+
+/**
+Turns an invalid query string into HTTP 400 Bad Request.
+*/
+pub fn query_error(e: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error
+{
+    let message = e.to_string();
+    InternalError::from_response(e, error(StatusCode::BAD_REQUEST, &message)).into()
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> HttpResponse
+{
+    HttpResponseBuilder::new(status).json(value)
+}
+
+pub(super) fn error(status: StatusCode, message: &str) -> HttpResponse
+{
+    json(status, &json!({"error": message}))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn openapi_matches_the_actions()
+    {
+        let doc: serde_json::Value = serde_json::from_str(OPENAPI).unwrap();
+        let names = doc["components"]["schemas"]["ActionName"]["enum"].as_array().unwrap().iter().map(|n| n.as_str().unwrap()).collect::<Vec<&str>>();
+        assert_eq!(names, Action::NAMES);
+    }
+}
@@ -0,0 +1,349 @@
+use actix_web::{body::MessageBody, dev::{ServiceRequest, ServiceResponse}, http::{header, header::HeaderMap, Method, StatusCode}, middleware::Next, web, Error, HttpResponse, HttpResponseBuilder};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use lazy_static::lazy_static;
+use log::{error, warn/*, info, debug, trace, log, Level*/};
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, fs::File, io, io::BufReader, sync::Mutex};
+
+use crate::settings::app_settings::{Settings, Web};
+
+use super::{api, escape_html, html_construct};
+
+/*
+Every request to the web interface has to be logged in, unless allow_anonymous is set.
+People log in with HTTP basic auth, which browsers ask for by themselves, and scripts using the API can send an API token instead.
+Since browsers send basic auth along with requests other sites make them do, anything but GET or HEAD from another origin is refused.
+Without any users or tokens configured, no one could log in, so every request gets instructions for adding a user instead.
+*/
+
+/** What browsers show when asking for a login */
+const REALM: &str = "Redundinator";
+
+lazy_static!{
+    /** Hashes of the username, password and password hash of logins that have been checked, so argon2 doesn't have to run on every request */
+    static ref VERIFIED: Mutex<HashSet<Vec<u8>>> = Mutex::new(HashSet::new());
+}
+
+/**
+What a request logs in with.
+*/
+#[derive(Debug, PartialEq)]
+enum Credentials
+{
+    Basic{username: String, password: String},
+    Bearer(String)
+}
+
+/**
+Middleware that refuses requests that aren't logged in with HTTP 401 Unauthorized, and cross-origin requests that change things with HTTP 403 Forbidden.
+Requests under /api get JSON errors like the rest of the API.
+*/
+pub async fn require_login(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error>
+{
+    let settings = req.app_data::<web::Data<Settings>>().expect("Settings are missing from the web app").clone();
+    if settings.web.allow_anonymous
+    {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+    if !has_logins(&settings.web)
+    {
+        let response = locked(req.path().starts_with("/api/"), &settings.startup.config_file);
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    let credentials = credentials(req.headers());
+    let logged_in = match credentials
+    {
+        Some(c) => web::block(move || authenticate(&settings.web, &c)).await?,
+        None => None
+    };
+    let refusal = match logged_in
+    {
+        None => {
+            if req.headers().contains_key(header::AUTHORIZATION)
+            {
+                let peer = req.connection_info().peer_addr().unwrap_or("unknown address").to_string();
+                warn!("Failed login to the web interface from {}", peer);
+            }
+            Some((StatusCode::UNAUTHORIZED, "log in to use this"))
+        },
+        Some(Login::User) if !safe_origin(req.method(), req.headers()) => Some((StatusCode::FORBIDDEN, "requests that change things have to come from this site")),
+        Some(_) => None
+    };
+    match refusal
+    {
+        Some((status, message)) => {
+            let response = refuse(req.path().starts_with("/api/"), status, message);
+            Ok(req.into_response(response).map_into_right_body())
+        },
+        None => next.call(req).await.map(ServiceResponse::map_into_left_body)
+    }
+}
+
+/**
+Who a request logged in as.
+*/
+#[derive(Debug, PartialEq)]
+enum Login
+{
+    /** One of the users, who could be using a browser */
+    User,
+    /** A script with an API token */
+    Token
+}
+
+/**
+Get the login sent with a request, if any.
+*/
+fn credentials(headers: &HeaderMap) -> Option<Credentials>
+{
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, rest) = value.trim().split_once(' ')?;
+    let rest = rest.trim();
+    if scheme.eq_ignore_ascii_case("basic")
+    {
+        let decoded = String::from_utf8(STANDARD.decode(rest).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some(Credentials::Basic{username: username.to_string(), password: password.to_string()})
+    }else if scheme.eq_ignore_ascii_case("bearer")
+    {
+        Some(Credentials::Bearer(rest.to_string()))
+    }else{
+        None
+    }
+}
+
+/**
+Check a login against the users and API tokens in the settings. This can take a moment the first time a user logs in, so don't run it on an async thread.
+
+# Returns
+Who logged in, or None if the login is wrong.
+*/
+fn authenticate(web: &Web, credentials: &Credentials) -> Option<Login>
+{
+    match credentials
+    {
+        Credentials::Basic{username, password} => {
+            let hash = web.users.get(username)?;
+            let key = Sha256::digest(format!("{username}\0{password}\0{hash}")).to_vec();
+            if VERIFIED.lock().expect("Login cache lock is poisoned").contains(&key)
+            {
+                return Some(Login::User);
+            }
+            let parsed = match PasswordHash::new(hash)
+            {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("The password hash of web interface user {} isn't valid, they can't log in -- Error: {}", username, e);
+                    return None;
+                }
+            };
+            Argon2::default().verify_password(password.as_bytes(), &parsed).ok()?;
+            VERIFIED.lock().expect("Login cache lock is poisoned").insert(key);
+            Some(Login::User)
+        },
+        Credentials::Bearer(token) => {
+            let hash = hex::encode(Sha256::digest(token.as_bytes()));
+            web.api_tokens.values().any(|h| h.trim().eq_ignore_ascii_case(&hash)).then_some(Login::Token)
+        }
+    }
+}
+
+/**
+Whether a request from a browser is safe to do: it only reads, or it comes from a page of this site.
+*/
+fn safe_origin(method: &Method, headers: &HeaderMap) -> bool
+{
+    if method == Method::GET || method == Method::HEAD
+    {
+        return true;
+    }
+    // browsers send Origin with every cross-origin POST, so a request without one didn't come from another site
+    let origin = match headers.get(header::ORIGIN).and_then(|o| o.to_str().ok())
+    {
+        Some(o) => o,
+        None => {return true;}
+    };
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok()).unwrap_or("");
+    origin.split_once("://").map(|(_, origin_host)| origin_host) == Some(host)
+}
+
+/**
+The response to a request that's refused: a JSON error for the API, or a page for the browser. Either way, browsers are asked to log in.
+*/
+fn refuse(api: bool, status: StatusCode, message: &str) -> HttpResponse
+{
+    let mut response = if api
+    {
+        api::error(status, message)
+    }else{
+        HttpResponseBuilder::new(status)
+            .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
+            .body(html_construct("Redundinator", "", &format!("{} -- {message}", status.canonical_reason().unwrap_or(""))))
+    };
+    if status == StatusCode::UNAUTHORIZED
+    {
+        let challenge = format!(r#"Basic realm="{REALM}", charset="UTF-8""#);
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_str(&challenge).expect("The login challenge isn't a valid header"));
+    }
+    response
+}
+
+/**
+Whether anyone could log in to the web interface with the settings.
+*/
+pub fn has_logins(web: &Web) -> bool
+{
+    !web.users.is_empty() || !web.api_tokens.is_empty()
+}
+
+/**
+How to add a user to the config so the web interface can be used.
+*/
+pub fn setup_instructions(config_file: &str) -> String
+{
+    format!(r#"No users or API tokens are configured, so no one can log in to the web interface. To add a user, run `redundinator_manual --hash_password`, type a password, and add the hash it prints to the web section of {config_file} as "web": {{"users": {{"<username>": "<hash>"}}}}, then restart Redundinator. If something in front of it already checks logins, set "web": {{"allow_anonymous": true}} instead."#)
+}
+
+/**
+The response to every request while there's no way to log in: how to add a user, with HTTP 503 Service Unavailable.
+*/
+fn locked(api: bool, config_file: &str) -> HttpResponse
+{
+    let instructions = setup_instructions(config_file);
+    if api
+    {
+        api::error(StatusCode::SERVICE_UNAVAILABLE, &instructions)
+    }else{
+        HttpResponseBuilder::new(StatusCode::SERVICE_UNAVAILABLE)
+            .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
+            .body(html_construct("Redundinator", "", &escape_html(&instructions)))
+    }
+}
+
+/**
+Ask for a password on stdin and print the hash of it, for adding a user to the web section of the config.
+
+# Returns
+bool for whether a hash was printed.
+*/
+pub fn print_password_hash() -> bool
+{
+    println!("Enter the password to hash:");
+    let mut password = String::new();
+    if let Err(e) = io::stdin().read_line(&mut password)
+    {
+        error!("Couldn't read the password -- Error: {}", e);
+        return false;
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty()
+    {
+        println!("Empty input, not hashing it");
+        return false;
+    }
+    match Argon2::default().hash_password(password.as_bytes(), &SaltString::generate(&mut rand::rngs::OsRng))
+    {
+        Ok(hash) => {
+            println!("{hash}");
+            true
+        },
+        Err(e) => {
+            error!("Couldn't hash the password -- Error: {}", e);
+            false
+        }
+    }
+}
+
+/**
+Load the certificate and key to serve HTTPS with.
+
+# Returns
+The TLS setup for the web server, or an error if the files can't be read or don't fit together.
+*/
+pub fn tls_config(web: &Web) -> io::Result<rustls::ServerConfig>
+{
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&web.tls_cert_file)?)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty()
+    {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no certificates in {}", web.tls_cert_file)));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&web.tls_key_file)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no private key in {}", web.tls_key_file)))?;
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+
+    fn web() -> Web
+    {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let hash = Argon2::default().hash_password(b"correct horse", &salt).unwrap().to_string();
+        Web{
+            users: vec![(String::from("alice"), hash)].into_iter().collect(),
+            api_tokens: vec![(String::from("cron"), hex::encode(Sha256::digest(b"s3cret-token")))].into_iter().collect(),
+            allow_anonymous: false,
+            tls_cert_file: String::new(),
+            tls_key_file: String::new()
+        }
+    }
+
+    #[test]
+    fn logins_are_checked()
+    {
+        let web = web();
+        let basic = |u: &str, p: &str| Credentials::Basic{username: u.to_string(), password: p.to_string()};
+        assert_eq!(authenticate(&web, &basic("alice", "correct horse")), Some(Login::User));
+        // again, from the cache
+        assert_eq!(authenticate(&web, &basic("alice", "correct horse")), Some(Login::User));
+        assert_eq!(authenticate(&web, &basic("alice", "wrong")), None);
+        assert_eq!(authenticate(&web, &basic("bob", "correct horse")), None);
+        assert_eq!(authenticate(&web, &Credentials::Bearer(String::from("s3cret-token"))), Some(Login::Token));
+        assert_eq!(authenticate(&web, &Credentials::Bearer(String::from("guess"))), None);
+    }
+
+    #[test]
+    fn locked_without_logins()
+    {
+        assert!(has_logins(&web()));
+        let web = Web{users: Default::default(), api_tokens: Default::default(), ..web()};
+        assert!(!has_logins(&web));
+        assert_eq!(locked(true, "/etc/redundinator/config.json").status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn credentials_are_parsed()
+    {
+        let mut headers = HeaderMap::new();
+        assert_eq!(credentials(&headers), None);
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", STANDARD.encode("alice:pass:word"))).unwrap());
+        assert_eq!(credentials(&headers), Some(Credentials::Basic{username: String::from("alice"), password: String::from("pass:word")}));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer abc"));
+        assert_eq!(credentials(&headers), Some(Credentials::Bearer(String::from("abc"))));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Digest abc"));
+        assert_eq!(credentials(&headers), None);
+    }
+
+    #[test]
+    fn cross_origin_posts_are_refused()
+    {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("backup.lan:8080"));
+        assert!(safe_origin(&Method::POST, &headers));
+        headers.insert(header::ORIGIN, HeaderValue::from_static("http://backup.lan:8080"));
+        assert!(safe_origin(&Method::POST, &headers));
+        headers.insert(header::ORIGIN, HeaderValue::from_static("https://evil.example"));
+        assert!(!safe_origin(&Method::POST, &headers));
+        assert!(safe_origin(&Method::GET, &headers));
+        headers.insert(header::ORIGIN, HeaderValue::from_static("null"));
+        assert!(!safe_origin(&Method::POST, &headers));
+    }
+}
@@ -0,0 +1,219 @@
+use actix_web::{http::header, web, HttpResponse};
+use futures::stream;
+use serde::Serialize;
+use std::{fs::File, io, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}, time::Duration};
+
+use crate::progress::{self, Progress};
+use crate::settings::app_settings::Settings;
+
+/*
+A stream of server-sent events for pages to show what's going on as it happens, without reloading.
+Each second it sends the progress of the task being done, if that changed, and any lines added to main.log.
+To start with it sends the last lines of main.log, so there's something to look at.
+*/
+
+/** How often to check for progress and new log lines */
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/** How many lines of main.log to send when the stream starts */
+const LOG_LINES_SHOWN: usize = 200;
+/** The most of main.log to read at once */
+const LOG_READ_LIMIT: u64 = 256 * 1024;
+
+/**
+Progress as it's sent to pages, with a description ready to show.
+*/
+#[derive(Serialize)]
+struct ProgressEvent
+{
+    #[serde(flatten)]
+    progress: Progress,
+    description: String
+}
+
+/**
+Where a stream of events is up to.
+*/
+struct Watch
+{
+    settings: web::Data<Settings>,
+    log_file: PathBuf,
+    /** How far into main.log has been sent, None before anything is */
+    log_position: Option<u64>,
+    /** The progress event sent last, to only send it again when it changes */
+    last_progress: Option<String>,
+    first: bool
+}
+
+/**
+Responds to requests for /events with a stream of server-sent events: `progress` events with the progress of the task being done as JSON,
+or null when there is none, and `log` events with new lines of main.log, secrets removed.
+*/
+pub async fn events(settings: web::Data<Settings>) -> HttpResponse
+{
+    let log_file = Path::new(&settings.startup.log_dir).join("main.log");
+    let watch = Watch{settings, log_file, log_position: None, last_progress: None, first: true};
+    let events = stream::unfold(watch, |mut watch| async move {
+        if !watch.first
+        {
+            actix_rt::time::sleep(POLL_INTERVAL).await;
+        }
+        watch.first = false;
+        let chunk = watch.poll();
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(chunk)), watch))
+    });
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // stop proxies like nginx from holding the events back
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events)
+}
+
+impl Watch
+{
+    /**
+    Check for progress and new log lines.
+
+    # Returns
+    The events to send, or a comment if there's nothing new, which lets the server notice when the page has gone away.
+    */
+    fn poll(&mut self) -> String
+    {
+        let mut out = String::new();
+
+        let progress = progress::current().map(|p| ProgressEvent{description: progress::describe(&p), progress: p});
+        let progress = serde_json::to_string(&progress).unwrap_or_else(|_| String::from("null"));
+        if self.last_progress.as_ref() != Some(&progress)
+        {
+            out += &sse_event("progress", &progress);
+            self.last_progress = Some(progress);
+        }
+
+        let lines = match self.log_position
+        {
+            None => tail(&self.log_file, LOG_LINES_SHOWN),
+            Some(position) => read_from(&self.log_file, position)
+        };
+        // if the log can't be read, it could be being rotated, so just try again next time
+        if let Ok((lines, position)) = lines
+        {
+            if !lines.is_empty()
+            {
+                out += &sse_event("log", &self.settings.redact(&lines));
+            }
+            self.log_position = Some(position);
+        }
+
+        if out.is_empty()
+        {
+            out += ": nothing new\n\n";
+        }
+        out
+    }
+}
+
+/**
+Script for pages that show the events: it fills in the elements with ids `progress` and `log`, if the page has them.
+*/
+pub(super) const LIVE_SCRIPT: &str = "<script>
+ addEventListener('DOMContentLoaded', () => {
+  const events = new EventSource('/events');
+  const progress = document.getElementById('progress');
+  const log = document.getElementById('log');
+  events.addEventListener('progress', e => {
+   const p = JSON.parse(e.data);
+   if(progress) progress.textContent = p ? p.description : 'Nothing running';
+  });
+  events.addEventListener('log', e => {
+   if(!log) return;
+   const follow = log.scrollTop + log.clientHeight >= log.scrollHeight - 5;
+   log.textContent = (log.textContent + e.data + '\\n').split('\\n').slice(-1000).join('\\n');
+   if(follow) log.scrollTop = log.scrollHeight;
+  });
+ });
+</script>
+<style>#log{max-height: 30em; overflow-y: auto;}</style>";
+
+/**
+Format a server-sent event. Each line of the data gets its own data field, browsers join them back up with newlines.
+*/
+fn sse_event(event: &str, data: &str) -> String
+{
+    let data = data.lines().map(|line| format!("data: {line}\n")).collect::<String>();
+    format!("event: {event}\n{data}\n")
+}
+
+/**
+Read the last lines of a file.
+
+# Returns
+The lines, and the position at the end of the last whole line to read on from.
+*/
+fn tail(path: &Path, max_lines: usize) -> io::Result<(String, u64)>
+{
+    let length = File::open(path)?.metadata()?.len();
+    let start = length.saturating_sub(LOG_READ_LIMIT);
+    let (text, position) = read_from(path, start)?;
+    // when starting in the middle of the file, the first line is only part of one
+    let text = match start
+    {
+        0 => text.as_str(),
+        _ => text.split_once('\n').map(|(_, rest)| rest).unwrap_or("")
+    };
+    let lines = text.lines().collect::<Vec<&str>>();
+    let lines = &lines[lines.len().saturating_sub(max_lines)..];
+    Ok((lines.iter().map(|line| format!("{line}\n")).collect(), position))
+}
+
+/**
+Read the whole lines of a file after a position. If the file is shorter than that, it's been replaced, so read it from the start.
+
+# Returns
+The lines, and the position at the end of the last whole line to read on from.
+*/
+fn read_from(path: &Path, position: u64) -> io::Result<(String, u64)>
+{
+    let mut file = File::open(path)?;
+    let position = if file.metadata()?.len() < position {0} else {position};
+    file.seek(SeekFrom::Start(position))?;
+    let mut buffer = Vec::new();
+    file.take(LOG_READ_LIMIT).read_to_end(&mut buffer)?;
+    // leave a line that's still being written for next time
+    let whole = match buffer.iter().rposition(|b| *b == b'\n')
+    {
+        Some(end) => end + 1,
+        None => 0
+    };
+    buffer.truncate(whole);
+    Ok((String::from_utf8_lossy(&buffer).into_owned(), position + whole as u64))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn log_is_followed()
+    {
+        let dir = std::env::temp_dir().join(format!("redundinator_events_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.log");
+        std::fs::write(&path, "one\ntwo\nthree\nfour").unwrap();
+
+        let (lines, position) = tail(&path, 2).unwrap();
+        assert_eq!((lines.as_str(), position), ("two\nthree\n", 14));
+        // the unfinished line comes once it's finished
+        File::options().append(true).open(&path).unwrap().write_all(b"\nfive\n").unwrap();
+        let (lines, position) = read_from(&path, position).unwrap();
+        assert_eq!((lines.as_str(), position), ("four\nfive\n", 24));
+        assert_eq!(read_from(&path, position).unwrap(), (String::new(), 24));
+        // a new log starts over
+        std::fs::write(&path, "six\n").unwrap();
+        assert_eq!(read_from(&path, position).unwrap(), (String::from("six\n"), 4));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(sse_event("log", "a\nb\n"), "event: log\ndata: a\ndata: b\n\n");
+    }
+}
@@ -0,0 +1,163 @@
+use actix_web::{http::{header, StatusCode}, web, HttpResponse, HttpResponseBuilder};
+use lazy_static::lazy_static;
+use std::{path::Path, sync::Mutex, time::{Duration, Instant}};
+
+use crate::{export_files, export_timestamps, latest_export_ts};
+use crate::jobs;
+use crate::metrics::{all_stats, metrics_file, StepStats};
+use crate::settings::app_settings::Settings;
+use crate::snapshot::{latest_snapshot, list_snapshots};
+use crate::usage::{filesystem_space, source_usage, SourceUsage};
+
+/*
+/metrics, in the text format Prometheus scrapes. It needs a login like every other page, so give Prometheus an API token as its bearer token.
+
+How each step went comes from the metrics database, which has every step since it was created, run from the queue or the command line.
+Snapshots, exports and the queue are looked at on every scrape. Working out the space each source takes up reads through every snapshot,
+so that's only done every so often.
+*/
+
+/** How long the space each source takes up is reused for before it's worked out again */
+const USAGE_MAX_AGE: Duration = Duration::from_secs(15 * 60);
+
+lazy_static!{
+    /** The space each source took up last time it was worked out, and when that was */
+    static ref USAGE: Mutex<Option<(Instant, Vec<SourceUsage>)>> = Mutex::new(None);
+}
+
+/**
+Responds with the metrics, for Prometheus.
+*/
+pub async fn metrics(settings: web::Data<Settings>) -> HttpResponse
+{
+    let settings = settings.into_inner();
+    let result = web::block(move || exposition(&settings).map_err(|e| e.to_string())).await;
+    match result.map_err(|e| e.to_string()).and_then(|r| r)
+    {
+        Ok(text) => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8"))
+            .body(text),
+        Err(e) => HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).body(format!("Couldn't read the metrics: {e}\n"))
+    }
+}
+
+/**
+Gather the metrics and write them out.
+*/
+fn exposition(settings: &Settings) -> Result<String, sqlite::Error>
+{
+    let mut out = String::new();
+
+    let stats = all_stats(&metrics_file(settings))?;
+    let step = |source: &str, action: &str| labels(&[("source", source), ("action", action)]);
+    let per_step = |f: &dyn Fn(&StepStats) -> Option<f64>| stats.iter().filter_map(|s| f(s).map(|v| (step(&s.source, &s.action), v))).collect::<Vec<(String, f64)>>();
+    family(&mut out, "redundinator_step_runs_total", "counter", "How many times the action has been done for the source", &per_step(&|s| Some(s.runs as f64)));
+    family(&mut out, "redundinator_step_failures_total", "counter", "How many times the action has failed for the source", &per_step(&|s| Some(s.failures as f64)));
+    family(&mut out, "redundinator_step_consecutive_failures", "gauge", "How many times in a row the action has failed for the source since it last succeeded", &per_step(&|s| Some(s.consecutive_failures as f64)));
+    family(&mut out, "redundinator_step_last_run_timestamp_seconds", "gauge", "When the action was last started for the source", &per_step(&|s| Some(s.last_run_at as f64)));
+    family(&mut out, "redundinator_step_last_success", "gauge", "Whether the last time the action was done for the source succeeded", &per_step(&|s| Some(if s.last_ok {1.0} else {0.0})));
+    family(&mut out, "redundinator_step_last_success_timestamp_seconds", "gauge", "When the action last started for the source and succeeded", &per_step(&|s| s.last_success_at.map(|t| t as f64)));
+    family(&mut out, "redundinator_step_last_duration_seconds", "gauge", "How long the action took the last time it was done for the source", &per_step(&|s| Some(s.last_duration_secs)));
+    family(&mut out, "redundinator_step_last_bytes", "gauge", "Bytes synced, exported, uploaded or downloaded the last time the action was done for the source", &per_step(&|s| Some(s.last_bytes as f64)));
+    family(&mut out, "redundinator_step_bytes_total", "counter", "Bytes synced, exported, uploaded or downloaded by the action for the source", &per_step(&|s| Some(s.bytes_total as f64)));
+    family(&mut out, "redundinator_step_last_exit_code", "gauge", "Exit code of the command the action ran the last time, e.g. rsync for a sync, the highest if it ran several", &per_step(&|s| s.last_exit_code.map(f64::from)));
+
+    let mut names = settings.sources.keys().cloned().collect::<Vec<String>>();
+    names.sort();
+    let storage_dir = &settings.startup.storage_dir;
+    let export_dir = &settings.startup.export_dir;
+    let per_source = |f: &dyn Fn(&str) -> Option<f64>| names.iter().filter_map(|n| f(n).map(|v| (labels(&[("source", n)]), v))).collect::<Vec<(String, f64)>>();
+    family(&mut out, "redundinator_snapshots", "gauge", "How many snapshots of the source there are", &per_source(&|n| Some(list_snapshots(storage_dir, n).len() as f64)));
+    family(&mut out, "redundinator_latest_snapshot_timestamp_seconds", "gauge", "When the latest snapshot of the source was taken", &per_source(&|n| latest_snapshot(storage_dir, n).map(|t| t as f64)));
+    family(&mut out, "redundinator_exports", "gauge", "How many exports of the source there are", &per_source(&|n| Some(export_timestamps(n, export_dir).len() as f64)));
+    family(&mut out, "redundinator_latest_export_timestamp_seconds", "gauge", "When the latest export of the source was made", &per_source(&|n| latest_export_ts(n, export_dir).map(|t| t as f64)));
+    family(&mut out, "redundinator_latest_export_size_bytes", "gauge", "Size of the parts of the latest export of the source", &per_source(&|n| latest_export_ts(n, export_dir).map(|ts| {
+        export_files(n, ts, export_dir).iter().filter_map(|f| std::fs::metadata(f).ok()).map(|m| m.len() as f64).sum()
+    })));
+
+    let usage = usage(settings);
+    let used = usage.iter().flat_map(|u| [
+        (labels(&[("source", &u.source), ("kind", "snapshots")]), u.snapshot_bytes as f64),
+        (labels(&[("source", &u.source), ("kind", "exports")]), u.export_bytes as f64)
+    ]).collect::<Vec<(String, f64)>>();
+    family(&mut out, "redundinator_storage_used_bytes", "gauge", "Space the source's snapshots or exports take up, counting files hard-linked between snapshots once. Updated every 15 minutes", &used);
+
+    let dirs = [("storage", storage_dir), ("export", export_dir)].iter()
+        .filter_map(|(kind, dir)| filesystem_space(Path::new(dir)).map(|space| (labels(&[("dir", kind)]), space)))
+        .collect::<Vec<_>>();
+    family(&mut out, "redundinator_filesystem_size_bytes", "gauge", "Size of the filesystem the storage or export dir is on", &dirs.iter().map(|(l, s)| (l.clone(), s.total_bytes as f64)).collect::<Vec<_>>());
+    family(&mut out, "redundinator_filesystem_free_bytes", "gauge", "Space left on the filesystem the storage or export dir is on", &dirs.iter().map(|(l, s)| (l.clone(), s.free_bytes as f64)).collect::<Vec<_>>());
+
+    let jobs_file = jobs::jobs_file(settings);
+    family(&mut out, "redundinator_queue_length", "gauge", "How many jobs are waiting to run", &[(String::new(), jobs::queued(&jobs_file)?.len() as f64)]);
+    family(&mut out, "redundinator_job_running", "gauge", "Whether a job is running", &[(String::new(), if jobs::running(&jobs_file)?.is_some() {1.0} else {0.0})]);
+
+    Ok(out)
+}
+
+/**
+The space each source takes up, worked out again if it's been a while.
+*/
+fn usage(settings: &Settings) -> Vec<SourceUsage>
+{
+    let mut cached = USAGE.lock().expect("Usage cache lock is poisoned");
+    match cached.as_ref()
+    {
+        Some((at, usage)) if at.elapsed() < USAGE_MAX_AGE => usage.clone(),
+        _ => {
+            let mut names = settings.sources.keys().cloned().collect::<Vec<String>>();
+            names.sort();
+            let usage = names.iter().map(|name| source_usage(name, settings)).collect::<Vec<SourceUsage>>();
+            *cached = Some((Instant::now(), usage.clone()));
+            usage
+        }
+    }
+}
+
+/**
+Write out a metric and its values.
+
+# Arguments
+* `samples` - The labels, as made by `labels`, and value of each sample
+*/
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)])
+{
+    *out += &format!("# HELP {name} {help}.\n# TYPE {name} {kind}\n");
+    for (labels, value) in samples
+    {
+        *out += &format!("{name}{labels} {value}\n");
+    }
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String
+{
+    let pairs = pairs.iter().map(|(name, value)| {
+        format!(r#"{name}="{}""#, value.replace('\\', r"\\").replace('"', r#"\""#).replace('\n', r"\n"))
+    }).collect::<Vec<String>>();
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn exposition_format()
+    {
+        let mut out = String::new();
+        family(&mut out, "redundinator_step_runs_total", "counter", "How many times", &[
+            (labels(&[("source", "laptop"), ("action", "upload to s3")]), 3.0),
+            (labels(&[("source", "odd \"one\"\\")]), 0.5)
+        ]);
+        family(&mut out, "redundinator_queue_length", "gauge", "Jobs waiting", &[(String::new(), 2.0)]);
+        assert_eq!(out, "# HELP redundinator_step_runs_total How many times.
+# TYPE redundinator_step_runs_total counter
+redundinator_step_runs_total{source=\"laptop\",action=\"upload to s3\"} 3
+redundinator_step_runs_total{source=\"odd \\\"one\\\"\\\\\"} 0.5
+# HELP redundinator_queue_length Jobs waiting.
+# TYPE redundinator_queue_length gauge
+redundinator_queue_length 2
+");
+    }
+}
@@ -1,8 +1,14 @@
+pub mod api;
+pub mod auth;
+pub mod events;
+pub mod metrics;
 pub mod pages;
 
 use run_script::ScriptOptions;
 use serde_json::json;
 
+use crate::settings::app_settings::Settings;
+
 /**
 Generates a complete HTML document given the elements that change between pages.
 This is where we define all the external static resources included in every page, and other HTML boilerplate.
@@ -32,11 +38,14 @@ fn html_construct(title: &str, head_extra: &str, body: &str) -> String
 </html>")
 }
 
-fn show_command(cmd: &str) -> String
+/**
+Run a command and show its output, with any secrets in it replaced. Command lines can contain passwords.
+*/
+fn show_command(cmd: &str, settings: &Settings) -> String
 {
     let cmdo = match run_script::run(cmd, &Vec::new(), &ScriptOptions::new())
     {
-        Ok(v) => format!("{}<br/>{}", v.1, v.2),
+        Ok(v) => format!("{}<br/>{}", escape_html(&settings.redact(&v.1)), escape_html(&settings.redact(&v.2))),
         Err(e) => format!("Error: {e}")
     };
     fieldset(cmd, &cmdo, true)
@@ -56,3 +65,17 @@ fn serde_to_string<T: serde::Serialize>(in_val: T) -> String
         Err(_) => "error".to_string()
     }
 }
+
+fn escape_html(text: &str) -> String
+{
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('\'', "&#39;").replace('"', "&quot;")
+}
+
+pub(crate) fn format_timestamp(timestamp: i64) -> String
+{
+    match chrono::DateTime::from_timestamp(timestamp, 0)
+    {
+        Some(t) => t.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => "invalid time".to_string()
+    }
+}
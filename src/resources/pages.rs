@@ -1,13 +1,36 @@
-use actix_web::{HttpResponse, http::header, http::StatusCode, web};
+use actix_web::{HttpRequest, HttpResponse, http::header, http::StatusCode, web};
 use actix_web::HttpResponseBuilder;
+use chrono::Local;
 /*use log::{error, warn, info, debug, trace, log, Level};*/
 use serde::{Deserialize, Serialize};
-use std::{ops::DerefMut};
+use std::collections::BTreeMap;
 
 use crate::settings::app_settings::{Action, Settings};
-use crate::action_queue::{ACTION_QUEUE, CURRENT_ACTION};
+use crate::cancel;
+use crate::catalog::{self, Matcher};
+use crate::export_timestamps;
+use crate::jobs::{self, Job, JobStatus};
+use crate::progress::{format_bytes, format_duration};
+use crate::rsync::{RsyncOutcome, SyncStats};
+use crate::scheduler;
+use crate::snapshot::{latest_snapshot, list_snapshots};
 
-use super::{fieldset, html_construct, serde_to_string, show_command};
+use super::{escape_html, events::LIVE_SCRIPT, fieldset, format_timestamp, html_construct, serde_to_string, show_command};
+
+/** The most file versions a search page shows */
+const SEARCH_RESULTS_SHOWN: usize = 1000;
+/** How many of the most recent jobs the main page shows */
+const INDEX_HISTORY_SHOWN: usize = 20;
+/** How many of the most recent jobs the jobs page shows */
+const HISTORY_SHOWN: usize = 500;
+/** How many of the most recent jobs the sync statistics on the main page come from */
+const STATS_HISTORY_JOBS: usize = 500;
+/** How many syncs of a path before the latest one make up how much it usually sends */
+const USUAL_RUNS: usize = 10;
+/** How many times its usual volume a path has to send for it to stand out */
+const UNUSUAL_FACTOR: f64 = 3.0;
+/** How many of the latest syncs of a path the trend shows */
+const TREND_RUNS: usize = 20;
 
 /**
 Responds to requests for the main page at the domain root.
@@ -17,6 +40,9 @@ HttpResponse containing the main page
 */
 pub async fn index(settings: web::Data<Settings>) -> HttpResponse
 {
+    let mut target_names = settings.upload_targets.keys().cloned().collect::<Vec<String>>();
+    target_names.sort();
+    let target_options = target_names.iter().map(|target_name| format!("<option>{target_name}</option>")).collect::<Vec<String>>().join("");
     let source_options = settings.sources.keys().map(|source_name| format!("<option>{source_name}</option>")).collect::<Vec<String>>().join("");
     let buttons = format!("
 <form method='post' action='action'>
@@ -25,12 +51,37 @@ pub async fn index(settings: web::Data<Settings>) -> HttpResponse
   <select name='action'>
    <option>sync</option>
    <option>mysql_dump</option>
-   <option>upload_dropbox</option>
-   <option>upload_gdrive</option>
+   <option>upload</option>
    <option>export</option>
    <option>unexport</option>
+   <option>list_exports</option>
+   <option>list_remote</option>
+   <option>restore</option>
+   <option>verify</option>
+   <option>prune</option>
+   <option>catalog</option>
   </select>
  </label>
+ <label>
+  <input type='checkbox' name='dry_run' value='1'/>
+  Dry run
+ </label>
+ <label>
+  <input type='checkbox' name='approve_changes' value='1'/>
+  Approve changes
+ </label>
+ <label>
+  Export Timestamp
+  <input type='text' name='timestamp' placeholder='newest'/>
+ </label>
+ <label>
+  Generations Ago
+  <input type='number' name='generations_ago' min='0' placeholder='0'/>
+ </label>
+ <label>
+  Include Paths
+  <input type='text' name='include' placeholder='all, or e.g. _home_/alice/**'/>
+ </label>
  <label>
   Active Source
   <select name='active_source'>
@@ -38,34 +89,66 @@ pub async fn index(settings: web::Data<Settings>) -> HttpResponse
    {source_options}
   </select>
  </label>
+ <label>
+  Upload Target
+  <select name='upload_target'>
+   <option value=''>All</option>
+   {target_options}
+  </select>
+ </label>
  <input type='submit'/>
 </form>");
     let buttons_block = fieldset("Request Action", &buttons, false);
+    let search_block = fieldset("Search Catalog", &search_form(&settings, "", false, ""), false);
 
-    let set_str = serde_to_string(&settings.sources);
+    let set_str = serde_to_string(&settings.redacted().sources);
     let config_block = fieldset("Hosts config", &set_str, true);
 
-    let current_action = match CURRENT_ACTION.lock()
+    let snapshots = settings.sources.keys().map(|source_name| {
+        let latest = latest_snapshot(&settings.startup.storage_dir, source_name);
+        let list = list_snapshots(&settings.startup.storage_dir, source_name).iter().rev().map(|ts| {
+            let marker = if Some(*ts) == latest {" (latest)"} else {""};
+            format!("  {ts}  {}{marker}", format_timestamp(*ts))
+        }).collect::<Vec<String>>();
+        format!("{source_name}: {} snapshots\n{}", list.len(), list.join("\n"))
+    }).collect::<Vec<String>>().join("\n");
+    let snapshots_block = fieldset("Snapshots", &snapshots, true);
+
+    let exports = settings.sources.keys().map(|source_name| {
+        let list = export_timestamps(source_name, &settings.startup.export_dir).iter().rev().enumerate().map(|(generations_ago, ts)| {
+            format!("  {ts}  {}  (generations_ago {generations_ago})", format_timestamp(*ts))
+        }).collect::<Vec<String>>();
+        format!("{source_name}: {} exports\n{}", list.len(), list.join("\n"))
+    }).collect::<Vec<String>>().join("\n");
+    let exports_block = fieldset("Exports", &exports, true);
+
+    let jobs_file = jobs::jobs_file(&settings);
+    let action_queue_block = match jobs::queued(&jobs_file)
     {
-        Ok(guard_for_action) => guard_for_action.clone(),
-        Err(_) => None
+        Ok(queued) => fieldset("Action Queue", &jobs_table(&queued), false),
+        Err(e) => fieldset("Action Queue", &format!("Error: {}", escape_html(&e.to_string())), true)
     };
-    let current_action_block = fieldset("Current Action", &serde_to_string(current_action), true);
-
-    let action_queue = match ACTION_QUEUE.lock()
+    let history_block = match jobs::history(&jobs_file, INDEX_HISTORY_SHOWN)
+    {
+        Ok(history) => fieldset("Job History", &format!("{}<a href='jobs'>All jobs</a>", jobs_table(&history)), false),
+        Err(e) => fieldset("Job History", &format!("Error: {}", escape_html(&e.to_string())), true)
+    };
+    let schedules_block = fieldset("Schedules", &schedules_table(&settings), false);
+    let sync_stats_block = match jobs::history(&jobs_file, STATS_HISTORY_JOBS)
     {
-        Ok(guard_for_queue) => Some(guard_for_queue.clone()),
-        Err(_) => None
+        Ok(history) => fieldset("Sync Statistics", &sync_stats_table(&history), false),
+        Err(e) => fieldset("Sync Statistics", &format!("Error: {}", escape_html(&e.to_string())), true)
     };
-    let action_queue_block = fieldset("Action Queue", &serde_to_string(action_queue), true);
 
     let cmdo = [
         "ps aux|grep redundinator",
         &format!("du -h --max-depth=1 {}/sources", settings.startup.storage_dir)
-    ].iter().map(|cmd| show_command(cmd)).collect::<Vec<String>>().join("");
+    ].iter().map(|cmd| show_command(cmd, &settings)).collect::<Vec<String>>().join("");
 
-    let body = format!("{buttons_block}{config_block}{current_action_block}{action_queue_block}{cmdo}");
-    let head = "";
+    let live_block = live_blocks();
+
+    let body = format!("{buttons_block}{live_block}{search_block}{config_block}{snapshots_block}{exports_block}{sync_stats_block}{action_queue_block}{history_block}{schedules_block}{cmdo}");
+    let head = LIVE_SCRIPT;
     let html = html_construct("Redundinator status", head, &body);
 
     HttpResponseBuilder::new(StatusCode::OK)
@@ -77,6 +160,12 @@ pub async fn index(settings: web::Data<Settings>) -> HttpResponse
 pub struct ActionRequest {
     action: String,
     active_source: String,
+    dry_run: Option<String>,
+    approve_changes: Option<String>,
+    upload_target: Option<String>,
+    timestamp: Option<String>,
+    generations_ago: Option<String>,
+    include: Option<String>,
 }
 
 /**
@@ -85,31 +174,44 @@ Responds to requests for the action page.
 # Returns
 HttpResponse containing the result of the action request
 */
-pub async fn action(req: web::Form<ActionRequest>) -> HttpResponse
+pub async fn action(settings: web::Data<Settings>, req: web::Form<ActionRequest>) -> HttpResponse
 {
     let user_action = Action
     {
         sync: req.action == "sync",
         mysql_dump: req.action == "mysql_dump",
-        auth_dropbox: req.action == "auth_dropbox",
-        upload_dropbox: req.action == "upload_dropbox",
-        upload_gdrive: req.action == "upload_gdrive",
+        auth_upload: false,
+        upload: req.action == "upload",
+        upload_target: req.upload_target.clone().unwrap_or_default(),
+        oauth_token: String::new(),
         source: req.active_source.clone(),
         export: req.action == "export",
-        unexport: req.action == "unexport"
+        unexport: req.action == "unexport",
+        list_remote: req.action == "list_remote",
+        restore: req.action == "restore",
+        list_exports: req.action == "list_exports",
+        timestamp: req.timestamp.as_deref().and_then(|t| t.trim().parse().ok()).unwrap_or(0),
+        generations_ago: req.generations_ago.as_deref().and_then(|g| g.trim().parse().ok()).unwrap_or(0),
+        include: req.include.clone().unwrap_or_default(),
+        restore_dest: String::new(),
+        catalog: req.action == "catalog",
+        search: String::new(),
+        search_regex: false,
+        verify: req.action == "verify",
+        prune: req.action == "prune",
+        dry_run: req.dry_run.is_some(),
+        approve_changes: req.approve_changes.is_some(),
+        hash_password: false,
+        test_notifiers: false
     };
-    let result = match ACTION_QUEUE.lock()
+    let result = match jobs::enqueue(&jobs::jobs_file(&settings), &user_action)
     {
-        Ok(mut guard_for_queue) => {
-            guard_for_queue.deref_mut().push_back(user_action);
-            true
-        },
-        Err(_) => false
+        Ok(id) => format!("queued as <a href='jobs/{id}'>job {id}</a>"),
+        Err(e) => format!("couldn't queue it: {}", escape_html(&e.to_string()))
     };
 
-
-    let deets = serde_to_string(req);
-    let body = format!("requested: {deets} -- status: {result}");
+    let deets = escape_html(&serde_to_string(req));
+    let body = format!("requested: {deets} -- status: {result}<br/><a href='/'>Return to Home</a>");
     let head = "";
     let html = html_construct("Redundinator status", head, &body);
 
@@ -118,6 +220,334 @@ pub async fn action(req: web::Form<ActionRequest>) -> HttpResponse
         .body(html)
 }
 
+/**
+Responds to requests for the job history page.
+
+# Returns
+HttpResponse containing the queue and every job that ran, newest first
+*/
+pub async fn jobs(settings: web::Data<Settings>) -> HttpResponse
+{
+    let jobs_file = jobs::jobs_file(&settings);
+    let body = match jobs::queued(&jobs_file).and_then(|q| Ok((q, jobs::history(&jobs_file, HISTORY_SHOWN)?)))
+    {
+        Ok((queued, history)) => format!("<a href='/'>Return to Home</a>{}{}",
+            fieldset("Action Queue", &jobs_table(&queued), false),
+            fieldset("Job History", &jobs_table(&history), false)),
+        Err(e) => format!("Error: {}", escape_html(&e.to_string()))
+    };
+    let html = html_construct("Jobs - Redundinator", "", &body);
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
+        .body(html)
+}
+
+/**
+Responds to requests for the page of one job.
+
+# Returns
+HttpResponse containing everything about the job, or HTTP 404 Not Found if there's no such job
+*/
+pub async fn job(settings: web::Data<Settings>, id: web::Path<i64>) -> HttpResponse
+{
+    let job = match jobs::get(&jobs::jobs_file(&settings), *id)
+    {
+        Ok(Some(j)) => j,
+        Ok(None) => {return notfound().await;},
+        Err(e) => {
+            return HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR)
+                .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
+                .body(html_construct("Job - Redundinator", "", &format!("Error: {}", escape_html(&e.to_string()))));
+        }
+    };
+    let time = |t: Option<i64>| t.map(format_timestamp).unwrap_or_default();
+    let summary = format!("<table>
+ <tr><th>Job</th><td>{}</td></tr>
+ <tr><th>What</th><td>{}</td></tr>
+ <tr><th>Status</th><td>{}</td></tr>
+ <tr><th>Requested</th><td>{}</td></tr>
+ <tr><th>Started</th><td>{}</td></tr>
+ <tr><th>Finished</th><td>{}</td></tr>
+ <tr><th>Outcome</th><td>{}</td></tr>
+</table>", job.id, escape_html(&job.describe()), job.status, format_timestamp(job.requested_at), time(job.started_at), time(job.finished_at), escape_html(&job.outcome));
+    let results = job.results.iter().map(|r| format!(" <tr><td>{}</td><td>{}</td><td>{}{}{}{}</td></tr>",
+        escape_html(&r.action), escape_html(&r.source), if r.ok {"ok"} else {"failed"},
+        r.rsync_outcome.filter(|o| *o != RsyncOutcome::Success).map(|o| format!(" (rsync {})", o.describe())).unwrap_or_default(),
+        r.host.as_ref().map(|h| format!(" via {}", escape_html(h))).unwrap_or_default(),
+        r.held_back.as_ref().map(|reason| format!(" - held back: {}{}", escape_html(reason), approve_button(&r.source))).unwrap_or_default()
+    )).collect::<Vec<String>>().join("\n");
+    let results = format!("<table>\n <tr><th>Action</th><th>Source</th><th>Result</th></tr>\n{results}\n</table>");
+    let file_errors = job.results.iter()
+        .flat_map(|r| r.file_errors.iter().map(move |e| format!("{}: {}", r.source, e)))
+        .collect::<Vec<String>>();
+
+    // while it runs, what it logs is only in main.log
+    let running = job.status == JobStatus::Running;
+    let body = format!("<a href='/jobs'>All jobs</a>{}{}{}{}{}{}{}",
+        job_controls(&job),
+        fieldset(&format!("Job {}", job.id), &summary, false),
+        if running {live_blocks()} else {String::new()},
+        fieldset("Results", &results, false),
+        if file_errors.is_empty() {String::new()} else {fieldset("File errors", &escape_html(&file_errors.join("\n")), true)},
+        if running {String::new()} else {fieldset("Log", &escape_html(&settings.redact(&job.log)), true)},
+        fieldset("Action", &escape_html(&serde_to_string(job.action.redacted())), true));
+    let html = html_construct(&format!("Job {} - Redundinator", job.id), if running {LIVE_SCRIPT} else {""}, &body);
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
+        .body(html)
+}
+
+/**
+A table of what the latest sync of each path of each source did, how much it sent compared to usual, and how that's been going lately.
+Paths that sent several times what they usually do are pointed out.
+
+# Arguments
+* `history` - Jobs, newest first, as they come from the job history
+*/
+fn sync_stats_table(history: &[Job]) -> String
+{
+    let mut runs: BTreeMap<(&str, &str), Vec<&SyncStats>> = BTreeMap::new();
+    for result in history.iter().rev().flat_map(|job| &job.results)
+    {
+        for stats in &result.sync_stats
+        {
+            runs.entry((&result.source, &stats.path)).or_default().push(stats);
+        }
+    }
+    if runs.is_empty()
+    {
+        return String::from("No syncs in the job history have statistics yet.");
+    }
+    let rows = runs.iter().map(|((source, path), runs)| {
+        let (latest, before) = runs.split_last().expect("Paths only have entries with runs");
+        let usual = median(&before.iter().rev().take(USUAL_RUNS).map(|s| s.literal_bytes).collect::<Vec<u64>>());
+        let sent = match usual
+        {
+            Some(u) if u > 0 && latest.literal_bytes as f64 >= u as f64 * UNUSUAL_FACTOR => {
+                format!("<strong>{} ({:.0}x usual)</strong>", format_bytes(latest.literal_bytes), latest.literal_bytes as f64 / u as f64)
+            },
+            _ => format_bytes(latest.literal_bytes)
+        };
+        let trend = sparkline(&runs.iter().rev().take(TREND_RUNS).rev().map(|s| s.literal_bytes).collect::<Vec<u64>>());
+        format!(" <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{sent}</td><td>{}</td><td>{:.1}</td><td>{}</td><td>{trend}</td></tr>",
+            escape_html(source), escape_html(path), format_timestamp(latest.started_at), latest.files, latest.created, latest.deleted, latest.transferred,
+            usual.map(format_bytes).unwrap_or_default(), latest.speedup, format_duration(latest.elapsed_secs.round() as u64)
+        )
+    }).collect::<Vec<String>>().join("\n");
+    format!("<table>
+ <tr><th>Source</th><th>Path</th><th>Last sync</th><th>Files</th><th>Created</th><th>Deleted</th><th>Transferred</th><th>Sent</th><th>Usually sends</th><th>Speedup</th><th>Took</th><th>Sent lately</th></tr>
+{rows}
+</table>")
+}
+
+fn median(values: &[u64]) -> Option<u64>
+{
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied()
+}
+
+/**
+Draw values as a line of bars, e.g. "▁▁▂▁█", scaled to the biggest.
+*/
+fn sparkline(values: &[u64]) -> String
+{
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().max().unwrap_or(0).max(1);
+    values.iter().map(|v| BARS[((*v as f64 / max as f64) * (BARS.len() - 1) as f64).round() as usize]).collect()
+}
+
+/**
+Places for the progress of the task being done and the end of main.log, which LIVE_SCRIPT keeps up to date.
+*/
+fn live_blocks() -> String
+{
+    fieldset("Progress", "<pre id='progress'>Connecting...</pre>", false) + &fieldset("Live log", "<pre id='log'></pre>", false)
+}
+
+/**
+A table of jobs, each linking to its page.
+*/
+fn jobs_table(jobs: &[Job]) -> String
+{
+    if jobs.is_empty()
+    {
+        return String::from("None");
+    }
+    let time = |t: Option<i64>| t.map(format_timestamp).unwrap_or_default();
+    let rows = jobs.iter().map(|job| format!(" <tr><td><a href='/jobs/{}'>{}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+        job.id, job.id,
+        escape_html(&job.describe()),
+        job.status,
+        format_timestamp(job.requested_at),
+        time(job.started_at),
+        time(job.finished_at),
+        escape_html(&job.outcome),
+        job_controls(job)
+    )).collect::<Vec<String>>().join("\n");
+    format!("<table>\n <tr><th>Job</th><th>What</th><th>Status</th><th>Requested</th><th>Started</th><th>Finished</th><th>Outcome</th><th></th></tr>\n{rows}\n</table>")
+}
+
+/**
+Buttons for managing a job: moving a queued job up or down the queue or removing it, or cancelling a running job.
+*/
+fn job_controls(job: &Job) -> String
+{
+    let button = |command: &str, label: &str| format!("<form method='post' action='/jobs/{}/{command}' style='display:inline'><button type='submit'>{label}</button></form>", job.id);
+    match job.status
+    {
+        JobStatus::Queued => format!("{}{}{}", button("up", "Up"), button("down", "Down"), button("remove", "Remove")),
+        JobStatus::Running => button("cancel", "Cancel"),
+        _ => String::new()
+    }
+}
+
+/**
+A button queueing a sync of the source that goes ahead with changes the change guard held back.
+*/
+fn approve_button(source: &str) -> String
+{
+    format!("<form method='post' action='/action' style='display:inline'>\
+<input type='hidden' name='action' value='sync'/>\
+<input type='hidden' name='active_source' value='{0}'/>\
+<input type='hidden' name='approve_changes' value='1'/>\
+<button type='submit'>Approve the changes and sync {0}</button></form>", escape_html(source))
+}
+
+/**
+Responds to requests to manage a job: `up`, `down` or `remove` for a queued job, and `cancel` for a running one (which removes a queued one).
+
+# Returns
+HttpResponse redirecting back to the page the request came from, or explaining why nothing was done
+*/
+pub async fn manage_job(settings: web::Data<Settings>, path: web::Path<(i64, String)>, request: HttpRequest) -> HttpResponse
+{
+    let (id, command) = path.into_inner();
+    let jobs_file = jobs::jobs_file(&settings);
+    let result = match command.as_str()
+    {
+        "up" => jobs::move_queued(&jobs_file, id, true).map(|done| (done, "it isn't queued, or is already first")),
+        "down" => jobs::move_queued(&jobs_file, id, false).map(|done| (done, "it isn't queued, or is already last")),
+        "remove" => jobs::remove(&jobs_file, id).map(|done| (done, "it isn't queued")),
+        "cancel" if cancel::request(id) => Ok((true, "")),
+        "cancel" => jobs::remove(&jobs_file, id).map(|done| (done, "it isn't queued or running")),
+        _ => {return notfound().await;}
+    };
+    let (status, message) = match result
+    {
+        Ok((true, _)) => {
+            let back = request.headers().get(header::REFERER).and_then(|r| r.to_str().ok()).unwrap_or("/jobs").to_string();
+            return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+                .insert_header((header::LOCATION, back))
+                .finish();
+        },
+        Ok((false, reason)) => (StatusCode::CONFLICT, format!("Couldn't {} job {}: {}", escape_html(&command), id, reason)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", escape_html(&e.to_string())))
+    };
+    let body = format!("{message}<br/><a href='/jobs'>All jobs</a>");
+
+    HttpResponseBuilder::new(status)
+        .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
+        .body(html_construct("Job - Redundinator", "", &body))
+}
+
+/**
+Make an HTML table of the configured schedules and when each next runs, followed by any that are invalid.
+*/
+fn schedules_table(settings: &Settings) -> String
+{
+    let (scheduled, problems) = scheduler::schedules(settings);
+    if scheduled.is_empty() && problems.is_empty()
+    {
+        return String::from("None");
+    }
+    let now = Local::now();
+    let rows = scheduled.iter().map(|s| format!(" <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+        escape_html(&s.id),
+        if s.source.is_empty() {String::from("All")} else {escape_html(&s.source)},
+        escape_html(&s.cron),
+        escape_html(&s.actions.join(", ")),
+        s.next_run(&now).map(|t| t.format("%Y-%m-%d %H:%M:%S %:z").to_string()).unwrap_or_else(|| String::from("never"))
+    )).collect::<Vec<String>>().join("\n");
+    let invalid = problems.iter().map(|p| format!("<br/>Invalid {}", escape_html(p))).collect::<Vec<String>>().join("");
+    format!("<table>\n <tr><th>Schedule</th><th>Source</th><th>Cron</th><th>Actions</th><th>Next Run</th></tr>\n{rows}\n</table>{invalid}")
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchRequest {
+    pattern: Option<String>,
+    regex: Option<String>,
+    active_source: Option<String>,
+}
+
+/**
+Responds to requests for the catalog search page.
+
+# Returns
+HttpResponse containing the versions of the files found, and where they are
+*/
+pub async fn search(settings: web::Data<Settings>, req: web::Query<SearchRequest>) -> HttpResponse
+{
+    let pattern = req.pattern.clone().unwrap_or_default();
+    let regex = req.regex.is_some();
+    let source = req.active_source.clone().unwrap_or_default();
+    let results = match Matcher::parse(&pattern, regex)
+    {
+        Ok(matcher) => match catalog::search(&catalog::catalog_file(&settings), &source, &matcher)
+        {
+            Ok(versions) => {
+                let shown = versions.iter().take(SEARCH_RESULTS_SHOWN).map(|v| escape_html(&catalog::describe(v))).collect::<Vec<String>>().join("\n");
+                let more = if versions.len() > SEARCH_RESULTS_SHOWN {format!("\n... showing the first {SEARCH_RESULTS_SHOWN}, narrow the search to see the rest")} else {String::new()};
+                format!("{} versions found\n{shown}{more}", versions.len())
+            },
+            Err(e) => format!("Error: {}", escape_html(&e.to_string()))
+        },
+        Err(e) => escape_html(&e)
+    };
+
+    let search_block = fieldset("Search Catalog", &search_form(&settings, &pattern, regex, &source), false);
+    let results_block = fieldset("Results", &results, true);
+    let body = format!("<a href='/'>Return to Home</a>{search_block}{results_block}");
+    let html = html_construct("Catalog search - Redundinator", "", &body);
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
+        .body(html)
+}
+
+/**
+The form for searching the catalog, filled in with the current search.
+*/
+fn search_form(settings: &Settings, pattern: &str, regex: bool, active_source: &str) -> String
+{
+    let source_options = settings.sources.keys().map(|source_name| {
+        let selected = if source_name == active_source {" selected"} else {""};
+        format!("<option{selected}>{}</option>", escape_html(source_name))
+    }).collect::<Vec<String>>().join("");
+    let checked = if regex {" checked"} else {""};
+    format!("
+<form method='get' action='search'>
+ <label>
+  Pattern
+  <input type='text' name='pattern' value='{}' placeholder='e.g. *.pdf or _home_/alice/notes.txt'/>
+ </label>
+ <label>
+  <input type='checkbox' name='regex' value='1'{checked}/>
+  Regex
+ </label>
+ <label>
+  Active Source
+  <select name='active_source'>
+   <option value=''>All</option>
+   {source_options}
+  </select>
+ </label>
+ <input type='submit' value='Search'/>
+</form>", escape_html(pattern))
+}
+
 /**
 Responds to requests that don't match anything we have.
 
@@ -131,4 +561,45 @@ pub async fn notfound() -> HttpResponse
     HttpResponseBuilder::new(StatusCode::NOT_FOUND)
         .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
         .body(html)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::dispatch::SourceResult;
+
+    #[test]
+    fn sync_stats_trends()
+    {
+        assert_eq!(sparkline(&[0, 10, 70, 35]), "▁▂█▅");
+        assert_eq!(sparkline(&[0, 0]), "▁▁");
+        assert_eq!(median(&[5, 1, 3]), Some(3));
+        assert_eq!(median(&[]), None);
+
+        let job = |id: i64, sent: u64| Job{
+            id,
+            action: Action::default(),
+            status: JobStatus::Succeeded,
+            requested_at: 0,
+            started_at: None,
+            finished_at: None,
+            outcome: String::new(),
+            results: vec!(SourceResult{
+                action: String::from("sync"),
+                source: String::from("laptop"),
+                ok: true,
+                sync_stats: vec!(SyncStats{path: String::from("/home/"), literal_bytes: sent, ..Default::default()}),
+                ..Default::default()
+            }),
+            log: String::new(),
+            schedule: None
+        };
+        // newest first, like the history
+        let history = vec!(job(4, 10240), job(3, 1024), job(2, 2048), job(1, 1024));
+        let table = sync_stats_table(&history);
+        assert!(table.contains("<td>laptop</td><td>/home/</td>"));
+        assert!(table.contains("<td><strong>10.0 KiB (10x usual)</strong></td><td>1.0 KiB</td>"));
+        assert!(table.contains("<td>▂▂▂█</td>"));
+        assert!(!sync_stats_table(&history[1..]).contains("usual)"));
+    }
+}
@@ -0,0 +1,400 @@
+use lazy_static::lazy_static;
+use log::{error, warn, info/*, debug, trace, log, Level*/};
+use regex::Regex;
+use sha2::Sha256;
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+use crate::{cancel, export_files};
+use crate::checksum::file_digest;
+use crate::export::{default_unexport_dest, select_export, unexport_to};
+use crate::manifest::{self, Manifest, ManifestPart};
+use crate::metrics;
+use crate::progress;
+use crate::settings::app_settings::Settings;
+use crate::upload::{RemoteFile, UploadError, UploadTarget};
+
+/*
+Restoring brings an export back from an upload target into the export dir, then unexports it from there as usual.
+
+The parts are downloaded into `{export_dir}/{source}_{timestamp}.download/` and only moved into the export dir
+once every one of them has been checked against the manifest, so a restore that's interrupted never leaves a partial export behind.
+Running it again continues the downloads where they stopped.
+*/
+
+/** How many times to try downloading a part before giving up on it, continuing where the previous try stopped */
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+lazy_static!{
+    static ref REMOTE_EXPORT_FILENAME_REGEX: Regex = Regex::new(r"^(?P<source>.+)_(?P<timestamp>\d+)\.(?:tar\.zst\.\d+|(?P<manifest>manifest\.json))$").expect("Error in regex for recognizing export filenames");
+}
+
+/**
+An export found at an upload target.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteExport
+{
+    pub source: String,
+    pub timestamp: i64,
+    /** The part files, in order */
+    pub parts: Vec<RemoteFile>,
+    pub has_manifest: bool
+}
+
+impl RemoteExport
+{
+    /** Total size of the parts */
+    pub fn size(&self) -> u64
+    {
+        self.parts.iter().map(|p| p.size).sum()
+    }
+}
+
+/**
+Group the files at an upload target into the exports they belong to. Files that aren't part of an export are ignored.
+
+# Returns
+The exports, by source and then oldest first.
+*/
+pub fn remote_exports(files: Vec<RemoteFile>) -> Vec<RemoteExport>
+{
+    let mut exports: BTreeMap<(String, i64), RemoteExport> = BTreeMap::new();
+    for file in files
+    {
+        let caps = match REMOTE_EXPORT_FILENAME_REGEX.captures(&file.name)
+        {
+            Some(c) => c,
+            None => {continue;}
+        };
+        let timestamp: i64 = match caps["timestamp"].parse()
+        {
+            Ok(t) => t,
+            Err(_) => {continue;}
+        };
+        let source = caps["source"].to_string();
+        let is_manifest = caps.name("manifest").is_some();
+        let export = exports.entry((source.clone(), timestamp)).or_insert_with(|| RemoteExport{source, timestamp, parts: Vec::new(), has_manifest: false});
+        if is_manifest
+        {
+            export.has_manifest = true;
+        }else{
+            export.parts.push(file);
+        }
+    }
+    exports.into_values().map(|mut e| {
+        e.parts.sort_by(|a, b| a.name.cmp(&b.name));
+        e
+    }).collect()
+}
+
+/**
+Pick the export to restore from the ones at a target.
+
+# Arguments
+* `exports` - Exports of one source, oldest first
+* `timestamp` - The export wanted, or 0 to go by `generations_ago`
+* `generations_ago` - How many exports back from the newest. Only complete exports (with a manifest) are counted, unless there are none.
+*/
+pub fn choose_export(exports: &[RemoteExport], timestamp: i64, generations_ago: u32) -> Option<&RemoteExport>
+{
+    let complete: Vec<i64> = exports.iter().filter(|e| e.has_manifest).map(|e| e.timestamp).collect();
+    let timestamps = if complete.is_empty() && timestamp == 0 {exports.iter().map(|e| e.timestamp).collect()} else {complete};
+    let chosen = if timestamp != 0 {timestamp} else {select_export(&timestamps, 0, generations_ago)?};
+    exports.iter().find(|e| e.timestamp == chosen)
+}
+
+/**
+Log the exports stored at an upload target.
+
+# Arguments
+* `source_name` - Only list this source's exports, or all of them if blank
+*/
+pub fn list_remote(target: &dyn UploadTarget, source_name: &str)
+{
+    let files = match target.list_remote()
+    {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Couldn't list files at {} -- Error: {}", target.name(), e);
+            return;
+        }
+    };
+    let exports: Vec<RemoteExport> = remote_exports(files).into_iter().filter(|e| source_name.is_empty() || e.source == source_name).collect();
+    info!("{} exports at {}", exports.len(), target.name());
+    for e in exports
+    {
+        let time = chrono::DateTime::from_timestamp(e.timestamp, 0).map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string()).unwrap_or_default();
+        let complete = if e.has_manifest {""} else {", no manifest"};
+        info!("  {} {} ({}) -- {} parts, {} bytes{}", e.source, e.timestamp, time, e.parts.len(), e.size(), complete);
+    }
+}
+
+/**
+Download an export of a source from the first upload target that has it, then unexport it.
+
+Which export is set by the timestamp in the action settings, and where it's extracted to by restore_dest:
+`{restore_dest}/{source}/` if set, otherwise the usual unexport destination.
+
+# Arguments
+* `targets` - Connected upload targets to look for the export at, in order, with their names
+
+# Returns
+bool for whether it succeeded. Failures are logged.
+*/
+pub fn restore(source_name: &str, settings: &Settings, targets: &[(String, Box<dyn UploadTarget>)]) -> bool
+{
+    info!("Beginning restore for source: {}", source_name);
+    let wanted = settings.action.timestamp;
+    for (target_name, target) in targets
+    {
+        let files = match target.list_remote()
+        {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Couldn't list files at {} -- Error: {}", target_name, e);
+                continue;
+            }
+        };
+        let exports: Vec<RemoteExport> = remote_exports(files).into_iter().filter(|e| e.source == source_name).collect();
+        let export = match choose_export(&exports, wanted, settings.action.generations_ago)
+        {
+            Some(e) => e,
+            None => {
+                info!("No export {}of source {} at {}", if wanted == 0 {String::new()} else {format!("{wanted} ")}, source_name, target_name);
+                continue;
+            }
+        };
+        info!("Restoring export {} of source {} from {}", export.timestamp, source_name, target_name);
+        if !download_export(target.as_ref(), export, settings)
+        {
+            if cancel::cancelled()
+            {
+                return false;
+            }
+            continue;
+        }
+        let dest = match settings.action.restore_dest.as_str()
+        {
+            "" => default_unexport_dest(source_name, settings),
+            d => PathBuf::from(d).join(source_name)
+        };
+        return unexport_to(source_name, export.timestamp, &dest, settings);
+    }
+    error!("Couldn't restore source {} from any upload target", source_name);
+    false
+}
+
+/**
+Download the parts and manifest of an export into the export dir, checking each part against the manifest.
+Exports without a manifest are checked against whatever hash the target reports instead.
+
+# Returns
+bool for whether the whole export is now in the export dir. Failures are logged.
+*/
+fn download_export(target: &dyn UploadTarget, export: &RemoteExport, settings: &Settings) -> bool
+{
+    let source_name = &export.source;
+    let export_dir = Path::new(&settings.startup.export_dir);
+    if let Ok(Some(m)) = manifest::load(source_name, export.timestamp, &settings.startup.export_dir)
+    {
+        if export_files(source_name, export.timestamp, &settings.startup.export_dir).len() == m.parts.len()
+        {
+            info!("Export {} of source {} is already in the export dir, not downloading it", export.timestamp, source_name);
+            return true;
+        }
+    }
+
+    let staging = export_dir.join(format!("{source_name}_{}.download", export.timestamp));
+    if let Err(e) = fs::create_dir_all(&staging)
+    {
+        error!("Couldn't create directory {} for downloads -- Error: {}", staging.to_string_lossy(), e);
+        return false;
+    }
+
+    let manifest_name = Manifest::file_name(source_name, export.timestamp);
+    let manifest = if export.has_manifest
+    {
+        let path = staging.join(&manifest_name);
+        let result = target.download(&manifest_name, &path, 0).map_err(|e| e.to_string()).and_then(|_| Manifest::read(&path).map_err(|e| e.to_string()));
+        match result
+        {
+            Ok(m) => Some(m),
+            Err(e) => {
+                error!("Couldn't download manifest {} from {} -- Error: {}", manifest_name, target.name(), e);
+                return false;
+            }
+        }
+    }else{
+        warn!("Export {} of source {} has no manifest at {}, so the parts can only be checked against the hashes it reports", export.timestamp, source_name, target.name());
+        None
+    };
+    let expected: Vec<ManifestPart> = match &manifest
+    {
+        Some(m) => m.parts.clone(),
+        None => export.parts.iter().map(|p| ManifestPart{name: p.name.clone(), size: p.size, sha256: String::new()}).collect()
+    };
+
+    progress::start(&format!("download from {} for {source_name}", target.name()), Some(expected.iter().map(|p| p.size).sum()));
+    for part in &expected
+    {
+        if cancel::cancelled()
+        {
+            warn!("Download of export {} of source {} from {} was cancelled, what's downloaded so far is kept for the next restore", export.timestamp, source_name, target.name());
+            progress::finish();
+            return false;
+        }
+        let remote = match export.parts.iter().find(|p| p.name == part.name)
+        {
+            Some(r) => r,
+            None => {
+                error!("{} is missing at {}, can't restore export {} of source {}", part.name, target.name(), export.timestamp, source_name);
+                progress::finish();
+                return false;
+            }
+        };
+        progress::next_file(&part.name);
+        if let Err(e) = download_part(target, part, remote, &staging.join(&part.name))
+        {
+            error!("Couldn't download {} from {} -- Error: {}", part.name, target.name(), e);
+            progress::finish();
+            return false;
+        }
+        progress::file_position(part.size);
+    }
+    progress::finish();
+
+    // the manifest goes in last, once the parts it lists are all there
+    let mut moves: Vec<String> = expected.iter().map(|p| p.name.clone()).collect();
+    if manifest.is_some()
+    {
+        moves.push(manifest_name);
+    }
+    for name in moves
+    {
+        if let Err(e) = fs::rename(staging.join(&name), export_dir.join(&name))
+        {
+            error!("Couldn't move downloaded {} into the export dir -- Error: {}", name, e);
+            return false;
+        }
+    }
+    if let Err(e) = fs::remove_dir_all(&staging)
+    {
+        warn!("Couldn't remove download directory {} -- Error: {}", staging.to_string_lossy(), e);
+    }
+    info!("Downloaded export {} of source {} from {} -- {} parts", export.timestamp, source_name, target.name(), expected.len());
+    true
+}
+
+/**
+Download one part, continuing from what's already in the local file, then check it.
+A part that turns out to be wrong is deleted so the next attempt starts over.
+*/
+fn download_part(target: &dyn UploadTarget, part: &ManifestPart, remote: &RemoteFile, local_path: &Path) -> Result<(), UploadError>
+{
+    let mut last_error = UploadError::Failure(String::from("no attempts made"));
+    for attempt in 1..=DOWNLOAD_ATTEMPTS
+    {
+        let offset = match fs::metadata(local_path)
+        {
+            Ok(m) if m.len() <= part.size => m.len(),
+            _ => 0
+        };
+        if offset < part.size
+        {
+            if offset > 0
+            {
+                info!("Continuing download of {} at byte {} of {}", part.name, offset, part.size);
+            }
+            let result = target.download(&part.name, local_path, offset);
+            metrics::transferred(fs::metadata(local_path).map(|m| m.len()).unwrap_or(offset).saturating_sub(offset));
+            if let Err(e) = result
+            {
+                warn!("Download attempt {} of {} failed -- Error: {}", attempt, part.name, e);
+                last_error = e;
+                continue;
+            }
+        }
+        match check_part(target, part, remote, local_path)
+        {
+            Ok(()) => {
+                info!("Downloaded and checked {}", part.name);
+                return Ok(());
+            },
+            Err(e) => {
+                warn!("Downloaded {} is wrong, starting over -- {}", part.name, e);
+                if let Err(e) = fs::remove_file(local_path)
+                {
+                    return Err(UploadError::Failure(format!("Couldn't remove bad download {}: {e}", local_path.to_string_lossy())));
+                }
+                last_error = UploadError::Failure(e);
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/**
+Check a downloaded part against the manifest, or if there's no hash from a manifest, against the hash the target reports.
+*/
+fn check_part(target: &dyn UploadTarget, part: &ManifestPart, remote: &RemoteFile, local_path: &Path) -> Result<(), String>
+{
+    let size = fs::metadata(local_path).map_err(|e| e.to_string())?.len();
+    if size != part.size
+    {
+        return Err(format!("it's {size} bytes but should be {}", part.size));
+    }
+    if !part.sha256.is_empty()
+    {
+        let hash = file_digest::<Sha256>(local_path).map_err(|e| e.to_string())?;
+        return if hash == part.sha256 {Ok(())} else {Err(format!("its SHA-256 is {hash} but should be {}", part.sha256))};
+    }
+    match target.hash_matches(local_path, remote)
+    {
+        Ok(Some(true)) => Ok(()),
+        Ok(Some(false)) => Err(format!("it doesn't match the hash {} reports", target.name())),
+        Ok(None) => {
+            warn!("{} reports no usable hash for {}, only its size was checked", target.name(), part.name);
+            Ok(())
+        },
+        Err(e) => Err(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn file(name: &str, size: u64) -> RemoteFile
+    {
+        RemoteFile{name: name.to_string(), size, hash: None}
+    }
+
+    #[test]
+    fn groups_files_into_exports()
+    {
+        let exports = remote_exports(vec!(
+            file("client3_main_200.tar.zst.0001", 5),
+            file("client3_main_200.manifest.json", 1),
+            file("client3_main_200.tar.zst.0000", 10),
+            file("web_100.tar.zst.0000", 7),
+            file("web_300.tar.zst.0000", 7),
+            file("web_300.manifest.json", 1),
+            file("web_400.tar.zst.0000", 7),
+            file("notes.txt", 3)
+        ));
+        assert_eq!(exports.len(), 4);
+        assert_eq!(exports[0].source, "client3_main");
+        assert_eq!(exports[0].parts.iter().map(|p| p.name.as_str()).collect::<Vec<&str>>(), vec!("client3_main_200.tar.zst.0000", "client3_main_200.tar.zst.0001"));
+        assert_eq!(exports[0].size(), 15);
+        assert!(exports[0].has_manifest);
+
+        let web: Vec<RemoteExport> = exports.into_iter().filter(|e| e.source == "web").collect();
+        // newest complete one, not the newest
+        assert_eq!(choose_export(&web, 0, 0).map(|e| e.timestamp), Some(300));
+        assert_eq!(choose_export(&web, 0, 1), None);
+        assert_eq!(choose_export(&web, 100, 0).map(|e| e.timestamp), Some(100));
+        assert_eq!(choose_export(&web, 150, 0), None);
+        assert_eq!(choose_export(&web[..1], 0, 0).map(|e| e.timestamp), Some(100));
+    }
+}
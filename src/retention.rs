@@ -0,0 +1,344 @@
+use chrono::{DateTime, Datelike};
+use log::{error, /*warn,*/ info/*, debug, trace, log, Level*/};
+use std::{fmt, fs};
+
+use crate::catalog::{self, Kind};
+use crate::settings::app_settings::{RetentionPolicy, Settings};
+use crate::snapshot::{latest_snapshot, list_partials, list_snapshots, partial_dir, snapshot_dir};
+use crate::{export_files, export_timestamps};
+use crate::manifest::manifest_path;
+
+/**
+Why a generation is being kept.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeepReason
+{
+    Latest,
+    NewestPartial,
+    Last,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly
+}
+
+impl fmt::Display for KeepReason
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let s = match self
+        {
+            KeepReason::Latest  => "latest",
+            KeepReason::NewestPartial => "newest partial",
+            KeepReason::Last    => "last",
+            KeepReason::Daily   => "daily",
+            KeepReason::Weekly  => "weekly",
+            KeepReason::Monthly => "monthly",
+            KeepReason::Yearly  => "yearly"
+        };
+        write!(f, "{s}")
+    }
+}
+
+/**
+What the policy decided for one generation. It is deleted when there are no reasons to keep it.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decision
+{
+    pub timestamp: i64,
+    pub keep: Vec<KeepReason>
+}
+
+/**
+Decide which generations a retention policy keeps.
+
+For each of the daily/weekly/monthly/yearly rules, the newest generation in each of the N most recent periods (UTC) that contain one is kept.
+The newest N generations are also kept, according to `keep_last`. A policy where every rule is 0 is treated as disabled and keeps everything.
+
+# Arguments
+* `policy` - The retention policy to apply
+* `timestamps` - Timestamps of all the generations, in any order
+
+# Returns
+A decision for each generation, newest first.
+
+# Examples
+```
+use redundinator::{retention::apply_policy, settings::app_settings::RetentionPolicy};
+
+let policy = RetentionPolicy{keep_last: 1, keep_daily: 0, keep_weekly: 0, keep_monthly: 0, keep_yearly: 0};
+let decisions = apply_policy(&policy, &[1700000000, 1700086400]);
+assert!(!decisions[0].keep.is_empty());
+assert!(decisions[1].keep.is_empty());
+```
+*/
+pub fn apply_policy(policy: &RetentionPolicy, timestamps: &[i64]) -> Vec<Decision>
+{
+    let mut sorted = timestamps.to_vec();
+    sorted.sort_unstable_by(|a, b| b.cmp(a));
+    sorted.dedup();
+    let mut decisions: Vec<Decision> = sorted.iter().map(|ts| Decision{timestamp: *ts, keep: vec!()}).collect();
+
+    if !policy.is_enabled()
+    {
+        for decision in &mut decisions { decision.keep.push(KeepReason::Last); }
+        return decisions;
+    }
+
+    for decision in decisions.iter_mut().take(policy.keep_last as usize)
+    {
+        decision.keep.push(KeepReason::Last);
+    }
+
+    let rules = [
+        (policy.keep_daily,   KeepReason::Daily),
+        (policy.keep_weekly,  KeepReason::Weekly),
+        (policy.keep_monthly, KeepReason::Monthly),
+        (policy.keep_yearly,  KeepReason::Yearly)
+    ];
+    for (count, reason) in rules
+    {
+        let mut kept = 0;
+        let mut last_key: Option<String> = None;
+        for decision in &mut decisions
+        {
+            if kept >= count {break;}
+            let key = rule_period(reason, decision.timestamp);
+            if last_key.as_ref() != Some(&key)
+            {
+                decision.keep.push(reason);
+                kept += 1;
+                last_key = Some(key);
+            }
+        }
+    }
+
+    decisions
+}
+
+/**
+Identify the period of a rule (day, week, etc.) that a timestamp falls in.
+Generations in the same period produce the same string.
+*/
+fn rule_period(reason: KeepReason, timestamp: i64) -> String
+{
+    match reason
+    {
+        KeepReason::Daily   => period_key(timestamp, "%Y-%m-%d"),
+        KeepReason::Weekly  => match DateTime::from_timestamp(timestamp, 0)
+        {
+            Some(t) => format!("{}-W{}", t.iso_week().year(), t.iso_week().week()),
+            None => String::new()
+        },
+        KeepReason::Monthly => period_key(timestamp, "%Y-%m"),
+        KeepReason::Yearly  => period_key(timestamp, "%Y"),
+        KeepReason::Latest | KeepReason::NewestPartial | KeepReason::Last => timestamp.to_string()
+    }
+}
+
+fn period_key(timestamp: i64, format: &str) -> String
+{
+    match DateTime::from_timestamp(timestamp, 0)
+    {
+        Some(t) => t.format(format).to_string(),
+        None => String::new()
+    }
+}
+
+/**
+Apply the retention policies of a source to its snapshots and exports, deleting the generations that aren't kept.
+
+The snapshot pointed to by `latest`, and any snapshot newer than it, is never deleted.
+Partial snapshots aren't subject to the policy: only the newest one is kept, while it's newer than `latest`, for the next sync to link against.
+Every decision is logged, so with `dry_run` this produces a report of what would happen without changing anything.
+
+# Arguments
+* `source_name` - Name of the source to prune
+* `settings` - The whole settings object for the app. The source's own retention settings are used if it has them, otherwise the global ones.
+* `dry_run` - Only report, don't delete anything
+*/
+pub fn prune(source_name: &str, settings: &Settings, dry_run: bool)
+{
+    let mode = if dry_run {" (dry run)"} else {""};
+    info!("Beginning retention{} for source: {}", mode, source_name);
+    let retention = match settings.sources.get(source_name).and_then(|s| s.retention.clone())
+    {
+        Some(r) => r,
+        None => settings.retention.clone()
+    };
+    let storage_dir = &settings.startup.storage_dir;
+    let export_dir = &settings.startup.export_dir;
+
+    // snapshots
+    let latest = latest_snapshot(storage_dir, source_name);
+    let mut decisions = apply_policy(&retention.snapshots, &list_snapshots(storage_dir, source_name));
+    for decision in &mut decisions
+    {
+        match latest
+        {
+            Some(l) if decision.timestamp >= l => {decision.keep.push(KeepReason::Latest);},
+            None => {decision.keep.push(KeepReason::Latest);}, // without a complete snapshot we can't tell what's safe to delete
+            _ => {}
+        }
+    }
+    let (kept, deleted) = carry_out(source_name, "snapshot", &decisions, dry_run, |ts| {
+        fs::remove_dir_all(snapshot_dir(storage_dir, source_name, ts)).map_err(|e| e.to_string())?;
+        catalog::forget(source_name, Kind::Snapshot, ts, settings);
+        Ok(())
+    });
+    info!("Retention{} for source {} snapshots: kept {}, deleted {}", mode, source_name, kept, deleted);
+
+    // partial snapshots
+    let decisions = partial_decisions(&list_partials(storage_dir, source_name), latest);
+    let (kept, deleted) = carry_out(source_name, "partial snapshot", &decisions, dry_run, |ts| {
+        fs::remove_dir_all(partial_dir(storage_dir, source_name, ts)).map_err(|e| e.to_string())
+    });
+    info!("Retention{} for source {} partial snapshots: kept {}, deleted {}", mode, source_name, kept, deleted);
+
+    // exports
+    let decisions = apply_policy(&retention.exports, &export_timestamps(source_name, export_dir));
+    let (kept, deleted) = carry_out(source_name, "export", &decisions, dry_run, |ts| {
+        // the manifest goes first, so an export that's only partly deleted doesn't look complete
+        if let Some(manifest) = manifest_path(source_name, ts, export_dir)
+        {
+            fs::remove_file(&manifest).map_err(|e| format!("{}: {e}", manifest.to_string_lossy()))?;
+        }
+        for file in export_files(source_name, ts, export_dir)
+        {
+            fs::remove_file(&file).map_err(|e| format!("{file}: {e}"))?;
+        }
+        catalog::forget(source_name, Kind::Export, ts, settings);
+        Ok(())
+    });
+    info!("Retention{} for source {} exports: kept {}, deleted {}", mode, source_name, kept, deleted);
+
+    info!("Completed retention{} for source: {}", mode, source_name);
+}
+
+/**
+Decide which partial snapshots to keep: only the newest, unless there's a complete snapshot that's newer.
+
+# Returns
+A decision for each partial snapshot, newest first.
+*/
+fn partial_decisions(timestamps: &[i64], latest: Option<i64>) -> Vec<Decision>
+{
+    let mut sorted = timestamps.to_vec();
+    sorted.sort_unstable_by(|a, b| b.cmp(a));
+    sorted.iter().enumerate().map(|(i, ts)| {
+        let newest = i == 0 && latest.is_none_or(|l| *ts > l);
+        Decision{timestamp: *ts, keep: if newest {vec!(KeepReason::NewestPartial)} else {vec!()}}
+    }).collect()
+}
+
+/**
+Log each decision and delete the generations that aren't kept.
+
+# Returns
+How many generations were (kept, deleted). With dry_run, deleted is how many would have been.
+*/
+fn carry_out<F>(source_name: &str, kind: &str, decisions: &[Decision], dry_run: bool, delete: F) -> (usize, usize)
+where
+    F: Fn(i64) -> Result<(), String>
+{
+    let mut kept = 0;
+    let mut deleted = 0;
+    for decision in decisions
+    {
+        let when = period_key(decision.timestamp, "%Y-%m-%d %H:%M:%S UTC");
+        if !decision.keep.is_empty()
+        {
+            let reasons = decision.keep.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", ");
+            info!("Keep {} {} ({}) of source {} -- reasons: {}", kind, decision.timestamp, when, source_name, reasons);
+            kept += 1;
+            continue;
+        }
+        if dry_run
+        {
+            info!("Would delete {} {} ({}) of source {}", kind, decision.timestamp, when, source_name);
+            deleted += 1;
+            continue;
+        }
+        match delete(decision.timestamp)
+        {
+            Ok(()) => {
+                info!("Deleted {} {} ({}) of source {}", kind, decision.timestamp, when, source_name);
+                deleted += 1;
+            },
+            Err(e) => {
+                error!("Failed to delete {} {} of source {} -- Error: {}", kind, decision.timestamp, source_name, e);
+                kept += 1;
+            }
+        }
+    }
+    (kept, deleted)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const DAY: i64 = 86400;
+    // 2024-01-01 12:00:00 UTC, a Monday
+    const START: i64 = 1704110400;
+
+    fn policy(keep_last: u32, keep_daily: u32, keep_weekly: u32, keep_monthly: u32, keep_yearly: u32) -> RetentionPolicy
+    {
+        RetentionPolicy{keep_last, keep_daily, keep_weekly, keep_monthly, keep_yearly}
+    }
+
+    fn kept(decisions: &[Decision]) -> Vec<i64>
+    {
+        decisions.iter().filter(|d| !d.keep.is_empty()).map(|d| d.timestamp).collect()
+    }
+
+    #[test]
+    fn disabled_keeps_everything()
+    {
+        let timestamps: Vec<i64> = (0..10).map(|i| START + i * DAY).collect();
+        let decisions = apply_policy(&policy(0, 0, 0, 0, 0), &timestamps);
+        assert_eq!(kept(&decisions).len(), 10);
+    }
+
+    #[test]
+    fn keep_last()
+    {
+        let timestamps: Vec<i64> = (0..10).map(|i| START + i * DAY).collect();
+        let decisions = apply_policy(&policy(3, 0, 0, 0, 0), &timestamps);
+        assert_eq!(kept(&decisions), vec!(START + 9 * DAY, START + 8 * DAY, START + 7 * DAY));
+    }
+
+    #[test]
+    fn daily_keeps_newest_of_each_day()
+    {
+        // two generations per day for 5 days
+        let timestamps: Vec<i64> = (0..5).flat_map(|i| [START + i * DAY, START + i * DAY + 3600]).collect();
+        let decisions = apply_policy(&policy(0, 3, 0, 0, 0), &timestamps);
+        assert_eq!(kept(&decisions), vec!(START + 4 * DAY + 3600, START + 3 * DAY + 3600, START + 2 * DAY + 3600));
+    }
+
+    #[test]
+    fn weekly_and_monthly()
+    {
+        // one generation per day for 70 days, starting on a monday
+        let timestamps: Vec<i64> = (0..70).map(|i| START + i * DAY).collect();
+        let decisions = apply_policy(&policy(0, 0, 2, 2, 0), &timestamps);
+        let newest = START + 69 * DAY; // 2024-03-10, a sunday
+        // weekly: that sunday, and the sunday before. monthly: newest of march, and the last day of february
+        assert_eq!(kept(&decisions), vec!(newest, newest - 7 * DAY, START + 59 * DAY));
+        assert_eq!(decisions[0].keep, vec!(KeepReason::Weekly, KeepReason::Monthly));
+    }
+
+    #[test]
+    fn only_newest_partial_kept()
+    {
+        let partials = [START, START + 2 * DAY, START + DAY];
+        assert_eq!(kept(&partial_decisions(&partials, None)), vec!(START + 2 * DAY));
+        assert_eq!(kept(&partial_decisions(&partials, Some(START + DAY))), vec!(START + 2 * DAY));
+        assert!(kept(&partial_decisions(&partials, Some(START + 3 * DAY))).is_empty());
+        assert_eq!(partial_decisions(&partials, None).len(), 3);
+    }
+}
@@ -1,95 +1,121 @@
-use log::{error,/* warn,*/ info, debug, trace/*, log, Level*/};
+use lazy_static::lazy_static;
+use log::{error, warn, info, debug, trace, log, Level};
 #[cfg(target_family = "unix")]
 use nix;
-use run_script::ScriptOptions;
-use std::{fs, fs::OpenOptions, io::Write, path::PathBuf};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{fs, fs::OpenOptions, io::Write, net::{TcpStream, ToSocketAddrs}, path::{Path, PathBuf}, time::{Duration, Instant}};
 #[cfg(target_family = "unix")]
 use std::os::unix::fs::OpenOptionsExt;
 
-use crate::settings::app_settings::{Settings, SshCreds, Source, SyncMethod};
+use crate::app_logger::error_count;
+use crate::cancel;
+use crate::catalog::catalog_snapshot;
+use crate::hooks::{self, Stage};
+use crate::metrics;
+use crate::notify;
+use crate::progress;
+use crate::shell;
+use crate::settings::app_settings::{ChangeGuard, RsyncSeverity, Settings, Severity, SshCreds, Source, SyncMethod};
+use crate::snapshot::{link_bases, mark_partial, set_latest, snapshot_dir};
+
+/** Port rsync daemons listen on */
+const RSYNCD_PORT: u16 = 873;
+/** How long to wait for each address of a source to answer before trying the next */
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub fn sync(named_source: (&String, &Source), settings: &Settings)
 {
     let (name, source) = named_source;
     info!("Starting rsync for source: {}", name);
 
+    let host = match choose_host(name, source)
+    {
+        Some(h) => h,
+        None => {return;}
+    };
+    metrics::host(&host);
+
+    // Every run goes into a new snapshot, with unchanged files hard-linked to the previous one
+    let timestamp = chrono::Utc::now().timestamp();
+    let mut env = hooks::environment(name, source, &host, &snapshot_dir(&settings.startup.storage_dir, name, timestamp));
+    let errors_before = error_count();
+    let result = if !hooks::run(Stage::Pre, name, source, &host, &env)
+    {
+        error!("Skipping the sync of source {} because a pre-hook failed", name);
+        "skipped"
+    }else if cancel::cancelled()
+    {
+        "cancelled"
+    }else{
+        sync_snapshot(name, source, &host, timestamp, settings);
+        if cancel::cancelled() {"cancelled"} else if error_count() > errors_before {"failed"} else {"ok"}
+    };
+    // post-hooks run however the sync went, e.g. to start again what the pre-hooks stopped
+    env.insert(String::from("REDUNDINATOR_RESULT"), result.to_string());
+    hooks::run(Stage::Post, name, source, &host, &env);
+
+    info!("Completed rsync for source: {}", name);
+}
+
+/**
+Sync each of the source's paths from the host into a new snapshot, and make it the latest if all of them worked, or keep it as partial if some did.
+*/
+fn sync_snapshot(name: &str, source: &Source, host: &str, timestamp: i64, settings: &Settings)
+{
+    let storage_dir = &settings.startup.storage_dir;
+    let severities = source.rsync_severity.as_ref().unwrap_or(&settings.rsync_severity);
+
     let mut exclude_vec = vec!(String::from("$Recycle.Bin"), String::from("MSOCache"), String::from("System Volume Information"));
     exclude_vec.append(&mut source.paths_exclude.clone());
     let excludes = exclude_str(exclude_vec);
 
+    let snapshot = snapshot_dir(storage_dir, name, timestamp);
+    let link_bases = link_bases(storage_dir, name);
+    let mut usable_paths: Vec<String> = Vec::new();
+
+    // check what the sync would change before it replaces the latest snapshot, in case the source was hit by ransomware or mass-deleted
+    let guard = source.change_guard.as_ref().unwrap_or(&settings.change_guard);
+    if settings.action.approve_changes
+    {
+        info!("Changes to source {} are approved, syncing without checking them", name);
+    }else if guard.is_enabled() && !link_bases.is_empty()
+    {
+        if let Some(reason) = check_changes(name, source, host, &link_bases, &excludes, guard)
+        {
+            error!("Stopped the sync of source {} because {}. If that's expected, approve the changes by syncing it with approve_changes, e.g. with --approve_changes -A {} or Approve changes in the web interface", name, reason, name);
+            metrics::held_back(&reason);
+            notify::changes_held_back(settings, name, &reason);
+            return;
+        }
+    }
+
     for source_path in &source.paths
     {
-        let mut dest = PathBuf::from(&settings.startup.storage_dir);
-        dest.push(format!("sources/{name}/paths"));
-        dest.push(source_path.replace(['\\','/',' ',':'],"_"));
+        let path_dir = path_dir_name(source_path);
+        let mut dest = snapshot.clone();
+        dest.push(&path_dir);
 
         if let Err(e) = fs::create_dir_all(&dest)
         {
-            error!("Couldn't create directory to sync a path. Source: {} -- Host: {} -- Path: {} -- Dest: {} -- Error: {}", name, source.hostname, source_path, dest.to_string_lossy(), e);
+            error!("Couldn't create directory to sync a path. Source: {} -- Host: {} -- Path: {} -- Dest: {} -- Error: {}", name, host, source_path, dest.to_string_lossy(), e);
             continue;
         }
 
-        let cmd_sync: String = match &source.method
+        // rsync looks for unchanged files in each of these in turn, so paths that failed in a partial snapshot are still linked against latest
+        let link_dest = link_bases.iter()
+            .map(|base| base.join(&path_dir))
+            .filter(|dir| dir.is_dir())
+            .map(|dir| format!(r#"--link-dest="{}""#, dir.to_string_lossy()))
+            .collect::<Vec<String>>()
+            .join(" ");
+        // progress2 reports on the whole transfer instead of each file, which is what's shown while it runs
+        let options = format!("-a --progress --info=progress2 --stats --delete {link_dest} {excludes}");
+
+        let cmd_sync = match rsync_command(name, source, host, source_path, &options, &dest)
         {
-            SyncMethod::RsyncLocal => {
-                if source.hostname != "localhost" {error!("Tried to use sync method 'RsyncLocal' on non-local host: {}", source.hostname); break;}
-                format!(r#"rsync -a --progress --delete {excludes} {source_path} {}"#, dest.to_string_lossy()).to_string()
-            },
-            SyncMethod::Rsyncd(setup) => {
-                // write credentials file for rsync
-                if let Err(e) = fs::create_dir_all("config/") { error!("Couldn't create directory for rsyncd credentials file. Error: {}", e); break; }
-                let rsync_pw_file = "config/rsync";
-
-                // if we're running as root, the file must be owned by root or rsync will complain
-                if is_root() && fs::remove_file(rsync_pw_file).is_err(){ debug!("Running as root but unable to delete rsync creds file before writing new one. It probably doesn't exist yet which is fine."); }
-                match OpenOptions::new().write(true).create(true).truncate(true).set_mode(600).open(rsync_pw_file)
-                {
-                    Ok(mut file) => {
-                        if let Err(e) = file.write_all(setup.password.as_bytes()) { error!("Failed to write content to successfully opened rsyncd credentials file, skipping sync for source: {} -- Error: {}", name, e); break; }
-                    },
-                    Err(e) => { error!(r#"Failed to open/create rsyncd credentials file "{}", skipping sync for source: {} -- Error: {}"#, rsync_pw_file, name, e); break; }
-                };
-
-                let remote_path = format!(r#"rsync://{}@{}/{}/"#, setup.username, source.hostname, source_path.trim_start_matches('/'));
-                format!(r#"rsync -a --progress --delete --password-file={rsync_pw_file} {excludes} {remote_path} {}"#, dest.to_string_lossy())
-            },
-            SyncMethod::RsyncSsh(setup) => {
-                /* When the path isn't specified we use some magic that attempts to put the remote env in interactive mode, which makes it load the correct PATH to be able to find rsync
-                It is a zero-configuration alternative to specifying the remote rsync binary location, e.g. /system/xbin/rsync (on android)
-                See: https://superuser.com/questions/1623574/how-do-i-solve-the-error-execv-no-such-file-or-directory-from-rsync
-                However, if the remote rsync isn't in the PATH you'll still have to specify it instead.
-                */
-                let rsync_path = match &setup.remote_path_to_rsync_binary
-                {
-                    Some(p) => p,
-                    None => r#"sh -lc \"rsync \\\"\\\${@}\\\"\" rsync"#
-                };
-                match &setup.creds
-                {
-                    SshCreds::Key(creds) => {
-                        let remote_path = format!(r#"{}@{}:{source_path}/"#, creds.username, source.hostname);
-                        format!(r#"rsync -a --progress --delete --rsync-path="{}" -e "ssh -i {} -p {}" {} {} {}"#,
-                            rsync_path,
-                            creds.keyfile_path,
-                            setup.port,
-                            excludes,
-                            remote_path,
-                            dest.to_string_lossy()
-                        )
-                    },
-                    SshCreds::Password(creds) => {
-                        let remote_path = format!(r#"{}@{}:{source_path}/"#, creds.username, source.hostname);
-                        format!(r#"sshpass -p "{}" rsync -a --progress --delete --rsync-path="{}" -e "ssh -p {}" {} {} {}"#,
-                            rsync_path,
-                            creds.password,
-                            setup.port,
-                            excludes,
-                            remote_path,
-                            dest.to_string_lossy()
-                        )
-                    }
-                }
-            }
+            Some(c) => c,
+            None => {break;}
         };
         // These commands are for a sync method which would SSH into the remote and use a remote command to initiate the sync back to the server, just in case we want to implement such a feature.
         // Such a roundabout method will probably never be needed. In theory it's a workaround for weird problems.
@@ -97,18 +123,35 @@ pub fn sync(named_source: (&String, &Source), settings: &Settings)
         //reverse rsync-ssh: ssh -i {key to remote} -p {remote ssh port} -l {remote ssh user} {remote host} -- "rsync -a --progress --delete -e 'ssh -i {remote file with key to local ssh} -p {local ssh port}' {excludes} {remote path to backup} {local ssh user to be used by remote}@{ip of local}:{sync dest path on local}"
 
         info!(target: "cmdlog", "{}", cmd_sync);
-        match run_script::run(&cmd_sync, &Vec::new(), &ScriptOptions::new())
+        progress::start(&format!("sync for {name}: {source_path}"), None);
+        let started_at = chrono::Utc::now().timestamp();
+        let started = Instant::now();
+        let result = shell::run_watching(&cmd_sync, watch_progress);
+        let transferred = progress::finish().map(|p| p.bytes_done).unwrap_or(0);
+        match result
         {
             Ok(v) => {
                 let (code, stdout, stderr) = v;
+                metrics::transferred(transferred);
+                metrics::exit_code(code);
+                if snapshot_usable(code) {usable_paths.push(path_dir.clone());}
+                let file_errors = file_errors(&stderr);
+                let outcome = classify(code, &stderr, &file_errors);
+                metrics::rsync_outcome(outcome, file_errors.clone());
+                if let Some(stats) = parse_stats(&stdout)
+                {
+                    metrics::sync_stats(SyncStats{path: source_path.clone(), started_at, elapsed_secs: started.elapsed().as_secs_f64(), ..stats});
+                }
                 if code != 0
                 {
-                    error!("Rsync returned nonzero exit code! Source: {} -- Host: {} -- Path: {} -- Full Command: {} -- Exit Code: {} -- see log folder for stdout and stderr output",
+                    log!(outcome.severity(severities).level(), "Rsync {} -- Source: {} -- Host: {} -- Path: {} -- Full Command: {} -- Exit Code: {} -- Files with errors: {} -- see log folder for stdout and stderr output",
+                        outcome.describe(),
                         name,
-                        source.hostname,
+                        host,
                         source_path,
                         cmd_sync,
                         code,
+                        file_errors.len()
                     );
                     info!(target: "stdoutlog", "Full Command: {} -- Exit Code: {} -- stdout: {}",
                         cmd_sync,
@@ -122,14 +165,527 @@ pub fn sync(named_source: (&String, &Source), settings: &Settings)
                     );
                 }
             },
+            Err(_) if cancel::cancelled() => {break;},
             Err(e) => {
-                error!("Failed to run rsync! Source: {} -- Host: {} -- Path: {} -- Error: {}", name, source.hostname, source_path, e);
+                error!("Failed to run rsync! Source: {} -- Host: {} -- Path: {} -- Error: {}", name, host, source_path, e);
             }
         }
         
     }
 
-    info!("Completed rsync for source: {}", name);
+    if cancel::cancelled()
+    {
+        warn!("Sync of source {} was cancelled -- discarding snapshot {}", name, timestamp);
+        if let Err(e) = fs::remove_dir_all(&snapshot)
+        {
+            error!("Couldn't remove discarded snapshot: {} -- Error: {}", snapshot.to_string_lossy(), e);
+        }
+    }else if usable_paths.len() == source.paths.len()
+    {
+        if set_latest(storage_dir, name, timestamp)
+        {
+            catalog_snapshot(name, timestamp, settings);
+        }
+    }else if !usable_paths.is_empty()
+    {
+        // latest is what gets exported, so it mustn't lose the paths that failed. The next sync still links against this one
+        error!("Only {} of {} paths were synced for source: {} -- keeping snapshot {} as partial, but latest still points at the previous one", usable_paths.len(), source.paths.len(), name, timestamp);
+        // without what's left of the failed paths, the next sync links and compares them against latest instead
+        for path_dir in source.paths.iter().map(|p| path_dir_name(p)).filter(|d| !usable_paths.contains(d))
+        {
+            let failed = snapshot.join(path_dir);
+            if failed.exists()
+            {
+                if let Err(e) = fs::remove_dir_all(&failed)
+                {
+                    error!("Couldn't remove failed path from partial snapshot: {} -- Error: {}", failed.to_string_lossy(), e);
+                }
+            }
+        }
+        mark_partial(storage_dir, name, timestamp);
+    }else{
+        error!("No paths were synced for source: {} -- discarding snapshot {}", name, timestamp);
+        if let Err(e) = fs::remove_dir_all(&snapshot)
+        {
+            error!("Couldn't remove discarded snapshot: {} -- Error: {}", snapshot.to_string_lossy(), e);
+        }
+    }
+}
+
+/**
+Make the rsync command to sync one of a source's paths, for its sync method. For rsyncd, this writes the password file it reads.
+
+# Returns
+The command, or None if it can't be synced, which has been logged.
+*/
+fn rsync_command(name: &str, source: &Source, host: &str, source_path: &str, options: &str, dest: &Path) -> Option<String>
+{
+    let command = match &source.method
+    {
+        SyncMethod::RsyncLocal => {
+            if host != "localhost" {error!("Tried to use sync method 'RsyncLocal' on non-local host: {}", host); return None;}
+            format!(r#"rsync {options} {source_path} {}"#, dest.to_string_lossy())
+        },
+        SyncMethod::Rsyncd(setup) => {
+            // write credentials file for rsync
+            if let Err(e) = fs::create_dir_all("config/") { error!("Couldn't create directory for rsyncd credentials file. Error: {}", e); return None; }
+            let rsync_pw_file = "config/rsync";
+
+            // if we're running as root, the file must be owned by root or rsync will complain
+            if is_root() && fs::remove_file(rsync_pw_file).is_err(){ debug!("Running as root but unable to delete rsync creds file before writing new one. It probably doesn't exist yet which is fine."); }
+            match OpenOptions::new().write(true).create(true).truncate(true).set_mode(600).open(rsync_pw_file)
+            {
+                Ok(mut file) => {
+                    if let Err(e) = file.write_all(setup.password.as_bytes()) { error!("Failed to write content to successfully opened rsyncd credentials file, skipping sync for source: {} -- Error: {}", name, e); return None; }
+                },
+                Err(e) => { error!(r#"Failed to open/create rsyncd credentials file "{}", skipping sync for source: {} -- Error: {}"#, rsync_pw_file, name, e); return None; }
+            };
+
+            let remote_path = format!(r#"rsync://{}@{}/{}/"#, setup.username, host, source_path.trim_start_matches('/'));
+            format!(r#"rsync {options} --password-file={rsync_pw_file} {remote_path} {}"#, dest.to_string_lossy())
+        },
+        SyncMethod::RsyncSsh(setup) => {
+            /* When the path isn't specified we use some magic that attempts to put the remote env in interactive mode, which makes it load the correct PATH to be able to find rsync
+            It is a zero-configuration alternative to specifying the remote rsync binary location, e.g. /system/xbin/rsync (on android)
+            See: https://superuser.com/questions/1623574/how-do-i-solve-the-error-execv-no-such-file-or-directory-from-rsync
+            However, if the remote rsync isn't in the PATH you'll still have to specify it instead.
+            */
+            let rsync_path = match &setup.remote_path_to_rsync_binary
+            {
+                Some(p) => p,
+                None => r#"sh -lc \"rsync \\\"\\\${@}\\\"\" rsync"#
+            };
+            match &setup.creds
+            {
+                SshCreds::Key(creds) => {
+                    let remote_path = format!(r#"{}@{}:{source_path}/"#, creds.username, host);
+                    format!(r#"rsync {} --rsync-path="{}" -e "ssh -i {} -p {}" {} {}"#,
+                        options,
+                        rsync_path,
+                        creds.keyfile_path,
+                        setup.port,
+                        remote_path,
+                        dest.to_string_lossy()
+                    )
+                },
+                SshCreds::Password(creds) => {
+                    let remote_path = format!(r#"{}@{}:{source_path}/"#, creds.username, host);
+                    format!(r#"sshpass -p "{}" rsync {} --rsync-path="{}" -e "ssh -p {}" {} {}"#,
+                        creds.password,
+                        options,
+                        rsync_path,
+                        setup.port,
+                        remote_path,
+                        dest.to_string_lossy()
+                    )
+                }
+            }
+        }
+    };
+    Some(command)
+}
+
+/**
+How much a sync would change compared to the latest snapshot.
+*/
+#[derive(Default, Debug, PartialEq)]
+struct Changes
+{
+    /** Files and directories that would be deleted */
+    deleted: u64,
+    /** Files that would be transferred again because they changed */
+    modified: u64,
+    /** Files and directories in the latest snapshot */
+    previous: u64
+}
+
+impl Changes
+{
+    /**
+    Check the changes against the guard's limits.
+
+    # Returns
+    What's over the limits, or None if nothing is.
+    */
+    fn over_limits(&self, guard: &ChangeGuard) -> Option<String>
+    {
+        let percent = |n: u64| if self.previous == 0 {0.0} else {n as f64 * 100.0 / self.previous as f64};
+        let mut reasons = Vec::new();
+        for (what, count, max, max_percent) in [("delete", self.deleted, guard.max_deleted, guard.max_deleted_percent), ("modify", self.modified, guard.max_modified, guard.max_modified_percent)]
+        {
+            let over_count = max > 0 && count > max;
+            let over_percent = max_percent > 0.0 && percent(count) > max_percent;
+            if over_count || over_percent
+            {
+                let limits = [(max > 0).then(|| max.to_string()), (max_percent > 0.0).then(|| format!("{max_percent}%"))];
+                reasons.push(format!("it would {what} {count} of {} files and directories ({:.1}%), over the limit of {}",
+                    self.previous, percent(count), limits.into_iter().flatten().collect::<Vec<String>>().join(" or ")));
+            }
+        }
+        (!reasons.is_empty()).then(|| reasons.join(", and "))
+    }
+}
+
+/**
+Do a dry run of syncing each of the source's paths over its copy in the newest snapshot that has one, and check how much it would change against the guard's limits.
+Paths with nothing to compare to, and dry runs that don't work, aren't checked: the real sync will report what's wrong with them.
+
+# Returns
+What's over the limits, or None if nothing is.
+*/
+fn check_changes(name: &str, source: &Source, host: &str, bases: &[PathBuf], excludes: &str, guard: &ChangeGuard) -> Option<String>
+{
+    let mut changes = Changes::default();
+    for source_path in &source.paths
+    {
+        let previous = match bases.iter().map(|base| base.join(path_dir_name(source_path))).find(|dir| dir.is_dir())
+        {
+            Some(p) => p,
+            None => {continue;}
+        };
+        let options = format!("-a --dry-run --itemize-changes --stats --delete {excludes}");
+        let cmd = rsync_command(name, source, host, source_path, &options, &previous)?;
+        info!(target: "cmdlog", "{}", cmd);
+        progress::start(&format!("checking changes for {name}: {source_path}"), None);
+        let result = shell::run(&cmd);
+        progress::finish();
+        match result.map(|(_, stdout, _)| count_changes(&stdout))
+        {
+            Ok(Some(c)) => {
+                info!("A sync of source {} path {} would delete {} and modify {} of {} files and directories", name, source_path, c.deleted, c.modified, c.previous);
+                changes.deleted += c.deleted;
+                changes.modified += c.modified;
+                changes.previous += c.previous;
+            },
+            Ok(None) => {warn!("Couldn't check the changes to source {} path {}, rsync didn't report any stats", name, source_path);},
+            Err(e) => {warn!("Couldn't check the changes to source {} path {} -- Error: {}", name, source_path, e);}
+        }
+    }
+    changes.over_limits(guard)
+}
+
+/**
+Count the changes in the output of a dry run with --itemize-changes and --stats.
+Deletions are listed as "*deleting   path", and changed files as e.g. ">f.st...... path", where new ones have "+++++++++" instead.
+
+# Returns
+The changes, or None without stats to tell how many files there were.
+*/
+fn count_changes(stdout: &str) -> Option<Changes>
+{
+    let stats = parse_stats(stdout)?;
+    let mut changes = Changes::default();
+    for line in stdout.lines()
+    {
+        if line.starts_with("*deleting ")
+        {
+            changes.deleted += 1;
+        }else if (line.starts_with(">f") || line.starts_with("<f")) && !line.get(2..11).unwrap_or("").starts_with("++")
+        {
+            changes.modified += 1;
+        }
+    }
+    // what's there now, less what's new, plus what's gone
+    changes.previous = (stats.files + changes.deleted).saturating_sub(stats.created);
+    Some(changes)
+}
+
+/**
+Pick the address to sync a source from: the first of its hostname and fallback hostnames that answers on the port the sync method uses.
+With only one there's nothing to choose, so it's used without checking, and rsync reports any problem reaching it.
+
+# Returns
+The hostname or IP to use, or None if none of them answered.
+*/
+fn choose_host(name: &str, source: &Source) -> Option<String>
+{
+    let port = match &source.method
+    {
+        SyncMethod::Rsyncd(_) => RSYNCD_PORT,
+        SyncMethod::RsyncSsh(setup) => setup.port,
+        SyncMethod::RsyncLocal => {return Some(source.hostname.clone());}
+    };
+    let candidates = std::iter::once(&source.hostname).chain(&source.fallback_hostnames).collect::<Vec<&String>>();
+    if candidates.len() == 1
+    {
+        return Some(source.hostname.clone());
+    }
+    for candidate in &candidates
+    {
+        match probe(candidate, port)
+        {
+            Ok(()) => {
+                info!("Syncing source {} from {}", name, candidate);
+                return Some(candidate.to_string());
+            },
+            Err(e) => {info!("Source {} isn't answering at {}:{} -- Error: {}", name, candidate, port, e);}
+        }
+    }
+    error!("Source {} didn't answer at any of its addresses on port {}, skipping sync: {}", name, port, candidates.iter().map(|c| c.as_str()).collect::<Vec<&str>>().join(", "));
+    None
+}
+
+/**
+Check that something is listening on a host's port, trying each address the hostname resolves to.
+*/
+fn probe(host: &str, port: u16) -> std::io::Result<()>
+{
+    let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "hostname didn't resolve to any address");
+    for addr in (host, port).to_socket_addrs()?
+    {
+        match TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)
+        {
+            Ok(_) => {return Ok(());},
+            Err(e) => {last_error = e;}
+        }
+    }
+    Err(last_error)
+}
+
+/**
+How an rsync went, from best to worst.
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum RsyncOutcome
+{
+    Success,
+    /** Some files weren't transferred, but only because they vanished or were locked, which happens routinely */
+    PartialBenign,
+    /** Some files weren't transferred for other reasons */
+    PartialReal,
+    ProtocolMismatch,
+    AuthFailure,
+    ConnectionFailure,
+    OtherFailure
+}
+
+impl RsyncOutcome
+{
+    pub fn severity(&self, severities: &RsyncSeverity) -> Severity
+    {
+        match self
+        {
+            RsyncOutcome::Success           => Severity::Info,
+            RsyncOutcome::PartialBenign     => severities.partial_benign,
+            RsyncOutcome::PartialReal       => severities.partial_real,
+            RsyncOutcome::ProtocolMismatch  => severities.protocol_mismatch,
+            RsyncOutcome::AuthFailure       => severities.auth_failure,
+            RsyncOutcome::ConnectionFailure => severities.connection_failure,
+            RsyncOutcome::OtherFailure      => severities.other_failure
+        }
+    }
+
+    pub fn describe(&self) -> &'static str
+    {
+        match self
+        {
+            RsyncOutcome::Success           => "succeeded",
+            RsyncOutcome::PartialBenign     => "skipped files that vanished or were locked",
+            RsyncOutcome::PartialReal       => "couldn't transfer some files",
+            RsyncOutcome::ProtocolMismatch  => "couldn't talk to the rsync on the other end",
+            RsyncOutcome::AuthFailure       => "couldn't log in",
+            RsyncOutcome::ConnectionFailure => "couldn't connect or lost the connection",
+            RsyncOutcome::OtherFailure      => "failed"
+        }
+    }
+}
+
+impl Severity
+{
+    fn level(&self) -> Level
+    {
+        match self
+        {
+            Severity::Info    => Level::Info,
+            Severity::Warning => Level::Warn,
+            Severity::Error   => Level::Error
+        }
+    }
+}
+
+lazy_static!{
+    static ref AUTH_FAILURE_REGEX: Regex = Regex::new(r"@ERROR: auth failed|Permission denied \(publickey|Permission denied, please try again|Host key verification failed").expect("Error in regex for rsync auth failures");
+    static ref CONNECTION_FAILURE_REGEX: Regex = Regex::new(r"(?i)connection refused|connection timed out|connection reset|connection unexpectedly closed|no route to host|could not resolve hostname|name or service not known|network is unreachable").expect("Error in regex for rsync connection failures");
+    static ref PROTOCOL_MISMATCH_REGEX: Regex = Regex::new(r"(?i)protocol version mismatch|protocol incompatibility|is your shell clean|rsync: (command )?not found").expect("Error in regex for rsync protocol mismatches");
+    /** Per-file errors that happen routinely: files deleted mid-sync, and files Windows has locked */
+    static ref BENIGN_FILE_ERROR_REGEX: Regex = Regex::new(r"^file has vanished: |Device or resource busy|being used by another process").expect("Error in regex for benign rsync file errors");
+    static ref FILE_ERROR_REGEX: Regex = Regex::new(r#"^(rsync: |rsync warning: |file has vanished: ).*"[^"]+""#).expect("Error in regex for rsync file errors");
+}
+
+/**
+What rsync's --stats reported about syncing one path of a source.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct SyncStats
+{
+    /** The source's path that was synced */
+    pub path: String,
+    pub started_at: i64,
+    /** Files and directories rsync looked at */
+    pub files: u64,
+    pub created: u64,
+    pub deleted: u64,
+    /** Regular files that were new or changed, so had to be transferred */
+    pub transferred: u64,
+    /** Size of all the files */
+    pub total_size: u64,
+    /** Size of the files that were transferred */
+    pub transferred_size: u64,
+    /** Bytes of the transferred files that actually had to be sent, as opposed to matched to what was already there */
+    pub literal_bytes: u64,
+    pub matched_bytes: u64,
+    /** Total size divided by the bytes sent and received: how much rsync saved by only sending differences */
+    pub speedup: f64,
+    pub elapsed_secs: f64
+}
+
+lazy_static!{
+    static ref STATS_LINE_REGEX: Regex = Regex::new(r"^(?P<name>Number of [a-z ]+|Total [a-z ]+|Literal data|Matched data|File list [a-z ]+): (?P<value>[\d,.]+)").expect("Error in regex for rsync stats lines");
+    static ref SPEEDUP_REGEX: Regex = Regex::new(r"speedup is (?P<speedup>[\d,.]+)").expect("Error in regex for rsync speedup");
+}
+
+/**
+Read rsync's --stats output, e.g.
+```text
+Number of files: 1,234 (reg: 1,000, dir: 234)
+Number of created files: 10 (reg: 10)
+Number of deleted files: 2
+Number of regular files transferred: 15
+Total file size: 123,456,789 bytes
+Total transferred file size: 12,345 bytes
+Literal data: 12,000 bytes
+Matched data: 345 bytes
+...
+total size is 123,456,789  speedup is 2,127.71
+```
+
+# Returns
+The stats, with the path, start and elapsed time left for the caller to fill in. None if there aren't any, e.g. when rsync couldn't connect.
+*/
+fn parse_stats(stdout: &str) -> Option<SyncStats>
+{
+    let mut stats = SyncStats::default();
+    let mut found = false;
+    for line in stdout.lines().map(str::trim)
+    {
+        if let Some(caps) = STATS_LINE_REGEX.captures(line)
+        {
+            let value = caps["value"].replace(',', "").parse::<u64>().unwrap_or(0);
+            let field = match &caps["name"]
+            {
+                "Number of files" => &mut stats.files,
+                "Number of created files" => &mut stats.created,
+                "Number of deleted files" => &mut stats.deleted,
+                // before rsync 3.1 it was "Number of files transferred"
+                "Number of regular files transferred" | "Number of files transferred" => &mut stats.transferred,
+                "Total file size" => &mut stats.total_size,
+                "Total transferred file size" => &mut stats.transferred_size,
+                "Literal data" => &mut stats.literal_bytes,
+                "Matched data" => &mut stats.matched_bytes,
+                _ => {continue;}
+            };
+            *field = value;
+            found = true;
+        }else if let Some(caps) = SPEEDUP_REGEX.captures(line)
+        {
+            stats.speedup = caps["speedup"].replace(',', "").parse().unwrap_or(0.0);
+        }
+    }
+    found.then_some(stats)
+}
+
+/**
+Work out how an rsync went from its exit code and what it said on stderr.
+Exit codes are described in the rsync man page. The stderr messages tell apart the failures that share a code,
+e.g. 5 for both a refused login and an unknown module, or 255 from ssh for both a refused login and an unreachable host.
+
+# Arguments
+* `file_errors` - The per-file error lines from stderr, as found by `file_errors`
+*/
+fn classify(code: i32, stderr: &str, file_errors: &[String]) -> RsyncOutcome
+{
+    match code
+    {
+        0 => RsyncOutcome::Success,
+        _ if AUTH_FAILURE_REGEX.is_match(stderr) => RsyncOutcome::AuthFailure,
+        24 => RsyncOutcome::PartialBenign,
+        23 if !file_errors.is_empty() && file_errors.iter().all(|e| BENIGN_FILE_ERROR_REGEX.is_match(e)) => RsyncOutcome::PartialBenign,
+        23 => RsyncOutcome::PartialReal,
+        2 => RsyncOutcome::ProtocolMismatch,
+        _ if PROTOCOL_MISMATCH_REGEX.is_match(stderr) => RsyncOutcome::ProtocolMismatch,
+        10 | 30 | 35 | 255 => RsyncOutcome::ConnectionFailure,
+        _ if CONNECTION_FAILURE_REGEX.is_match(stderr) => RsyncOutcome::ConnectionFailure,
+        12 => RsyncOutcome::ProtocolMismatch,
+        _ => RsyncOutcome::OtherFailure
+    }
+}
+
+/**
+Pick out the lines of rsync's stderr about a particular file, e.g. `rsync: [sender] send_files failed to open "/home/x": Permission denied (13)`.
+*/
+fn file_errors(stderr: &str) -> Vec<String>
+{
+    stderr.lines().map(str::trim).filter(|l| FILE_ERROR_REGEX.is_match(l)).map(String::from).collect()
+}
+
+/**
+Keep track of a sync's progress from a line of rsync's output: either a file name, or with --info=progress2, how much of the whole transfer is done.
+*/
+fn watch_progress(line: &str)
+{
+    match parse_progress(line)
+    {
+        Some((done, total)) => progress::overall(done, total),
+        None => {
+            let line = line.trim_end();
+            let summary = line.is_empty() || line.starts_with(' ') || line.ends_with("incremental file list") || line.starts_with("sent ") || line.starts_with("total size is ") || STATS_LINE_REGEX.is_match(line);
+            if !summary
+            {
+                progress::next_file(line);
+            }
+        }
+    }
+}
+
+/**
+Read a line of rsync's --info=progress2 output, e.g. "  1,238,099  10%  117.60MB/s    0:00:09 (xfr#4, ir-chk=1002/1011)"
+
+# Returns
+The bytes transferred so far and, if it can be worked out from the percentage, the total. None for other lines.
+*/
+fn parse_progress(line: &str) -> Option<(u64, Option<u64>)>
+{
+    let caps = PROGRESS_LINE_REGEX.captures(line)?;
+    let done: u64 = caps["done"].replace([',', '.'], "").parse().ok()?;
+    let percent: u64 = caps["percent"].parse().ok()?;
+    let total = match percent
+    {
+        0 => None,
+        p => Some((done * 100 / p).max(done))
+    };
+    Some((done, total))
+}
+
+lazy_static!{
+    static ref PROGRESS_LINE_REGEX: Regex = Regex::new(r"^\s*(?P<done>[\d,.]+)\s+(?P<percent>\d+)%\s+\S+/s\s+\d+:\d{2}:\d{2}").expect("Error in regex for rsync progress lines");
+}
+
+/**
+Name of the directory inside a snapshot that holds the files from one of the source's paths.
+*/
+pub fn path_dir_name(source_path: &str) -> String
+{
+    source_path.replace(['\\','/',' ',':'],"_")
+}
+
+/**
+Whether a path synced with the given rsync exit code leaves the snapshot in a state worth keeping.
+Besides full success that includes partial transfers (23, 24), which happen routinely with files that are locked or vanish mid-sync.
+*/
+fn snapshot_usable(code: i32) -> bool
+{
+    matches!(code, 0 | 23 | 24)
 }
 
 fn exclude_str(paths: Vec<String>) -> String
@@ -170,4 +726,120 @@ fn is_root() -> bool
 fn is_root() -> bool
 {
     false
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn first_answering_host()
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let source = |hostname: &str, fallbacks: &[&str], port: u16| Source{
+            hostname: hostname.to_string(),
+            fallback_hostnames: fallbacks.iter().map(|h| h.to_string()).collect(),
+            method: SyncMethod::RsyncSsh(crate::settings::app_settings::RsyncSshSetup{
+                port,
+                remote_path_to_rsync_binary: None,
+                creds: SshCreds::Password(crate::settings::app_settings::SshCredsPassword{username: String::new(), password: String::new()})
+            }),
+            ..Default::default()
+        };
+        assert_eq!(choose_host("laptop", &source("nowhere.invalid", &["127.0.0.1"], port)), Some(String::from("127.0.0.1")));
+        assert_eq!(choose_host("laptop", &source("localhost", &["127.0.0.1"], port)), Some(String::from("localhost")));
+        assert_eq!(choose_host("laptop", &source("127.0.0.1", &["nowhere.invalid"], closed)), None);
+        // one address is used as is
+        assert_eq!(choose_host("laptop", &source("nowhere.invalid", &[], closed)), Some(String::from("nowhere.invalid")));
+    }
+
+    #[test]
+    fn outcomes()
+    {
+        let stderr = r#"file has vanished: "/home/alice/.cache/tmp1"
+rsync: read errors mapping "/cygdrive/c/Users/bob/NTUSER.DAT": Device or resource busy (16)
+rsync error: some files/attrs were not transferred (see previous errors) (code 23) at main.c(1338) [generator=3.2.7]"#;
+        let errors = file_errors(stderr);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(classify(23, stderr, &errors), RsyncOutcome::PartialBenign);
+        let stderr = format!(r#"{stderr}
+rsync: [sender] send_files failed to open "/home/alice/secret": Permission denied (13)"#);
+        let errors = file_errors(&stderr);
+        assert_eq!(errors[2], r#"rsync: [sender] send_files failed to open "/home/alice/secret": Permission denied (13)"#);
+        assert_eq!(classify(23, &stderr, &errors), RsyncOutcome::PartialReal);
+        assert_eq!(classify(24, "", &[]), RsyncOutcome::PartialBenign);
+        assert_eq!(classify(5, "@ERROR: auth failed on module home", &[]), RsyncOutcome::AuthFailure);
+        assert_eq!(classify(255, "user@laptop: Permission denied (publickey,password).", &[]), RsyncOutcome::AuthFailure);
+        assert_eq!(classify(255, "ssh: connect to host laptop port 22: No route to host", &[]), RsyncOutcome::ConnectionFailure);
+        assert_eq!(classify(10, "rsync: failed to connect to laptop (10.0.0.5): Connection refused (111)", &[]), RsyncOutcome::ConnectionFailure);
+        assert_eq!(classify(12, "bash: line 1: rsync: command not found\nrsync: connection unexpectedly closed (0 bytes received so far)", &[]), RsyncOutcome::ProtocolMismatch);
+        assert_eq!(classify(12, "rsync: connection unexpectedly closed (1234 bytes received so far) [receiver]", &[]), RsyncOutcome::ConnectionFailure);
+        assert_eq!(classify(11, "rsync: write failed on \"/backups/x\": No space left on device (28)", &[]), RsyncOutcome::OtherFailure);
+    }
+
+    #[test]
+    fn stats()
+    {
+        let stdout = "sending incremental file list
+home/alice/notes.txt
+
+Number of files: 1,234 (reg: 1,000, dir: 234)
+Number of created files: 10 (reg: 10)
+Number of deleted files: 2 (reg: 2)
+Number of regular files transferred: 15
+Total file size: 123,456,789 bytes
+Total transferred file size: 12,345 bytes
+Literal data: 12,000 bytes
+Matched data: 345 bytes
+File list size: 0
+File list generation time: 0.001 seconds
+File list transfer time: 0.000 seconds
+Total bytes sent: 12,500
+Total bytes received: 300
+
+sent 12,500 bytes  received 300 bytes  25,600.00 bytes/sec
+total size is 123,456,789  speedup is 9,645.06
+";
+        assert_eq!(parse_stats(stdout), Some(SyncStats{
+            files: 1234, created: 10, deleted: 2, transferred: 15, total_size: 123456789, transferred_size: 12345, literal_bytes: 12000, matched_bytes: 345, speedup: 9645.06,
+            ..Default::default()
+        }));
+        assert_eq!(parse_stats("rsync: failed to connect to laptop: Connection refused (111)"), None);
+    }
+
+    #[test]
+    fn change_guard()
+    {
+        let stdout = "*deleting   docs/old.txt
+*deleting   docs/older.txt
+>f.st...... docs/report.docx.encrypted
+>f+++++++++ docs/README_RANSOM.txt
+.d..t...... docs/
+
+Number of files: 100 (reg: 90, dir: 10)
+Number of created files: 1 (reg: 1)
+Number of deleted files: 2 (reg: 2)
+Number of regular files transferred: 2
+";
+        let changes = count_changes(stdout).unwrap();
+        assert_eq!(changes, Changes{deleted: 2, modified: 1, previous: 101});
+        let guard = |max_deleted: u64, max_deleted_percent: f64, max_modified: u64, max_modified_percent: f64| ChangeGuard{max_deleted, max_deleted_percent, max_modified, max_modified_percent};
+        assert_eq!(changes.over_limits(&guard(0, 25.0, 0, 50.0)), None);
+        assert_eq!(changes.over_limits(&guard(1, 0.0, 0, 0.5)).unwrap(),
+            "it would delete 2 of 101 files and directories (2.0%), over the limit of 1, and it would modify 1 of 101 files and directories (1.0%), over the limit of 0.5%");
+        assert_eq!(count_changes("*deleting   x"), None);
+    }
+
+    #[test]
+    fn progress_lines()
+    {
+        assert_eq!(parse_progress("      1,238,099  10%  117.60MB/s    0:00:09 (xfr#4, ir-chk=1002/1011)"), Some((1238099, Some(12380990))));
+        assert_eq!(parse_progress("         32,768   0%    0.00kB/s    0:00:00  "), Some((32768, None)));
+        assert_eq!(parse_progress("    30,000,003 100%   19.07MB/s    0:00:01 (xfr#2, to-chk=0/4)"), Some((30000003, Some(30000003))));
+        assert_eq!(parse_progress("home/alice/notes 2024.txt"), None);
+        assert_eq!(parse_progress("sent 30,007,442 bytes  received 61 bytes  12,003,001.20 bytes/sec"), None);
+    }
+}
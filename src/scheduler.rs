@@ -0,0 +1,270 @@
+use chrono::{DateTime, Local, TimeZone};
+use cron::Schedule as CronSchedule;
+use log::{error, warn, info/*, debug, trace, log, Level*/};
+use std::{str::FromStr, thread, time::Duration};
+
+use crate::jobs::{self, jobs_file};
+use crate::settings::app_settings::{Action, Settings};
+
+/*
+Schedules come from the config: the global `schedules` run for every source, and each source's own `schedules` only for that source.
+While the web interface is running, a background thread queues a job whenever one is due, unless the previous job
+from the same schedule is still queued or running, in which case that run is skipped.
+Runs that fall while the app isn't running are skipped too, as with cron.
+*/
+
+/** The longest the scheduler sleeps between checks, so it notices when the system clock jumps */
+const MAX_SLEEP_SECS: i64 = 30;
+
+/** Days of the week as the cron crate names them, numbered as in a crontab */
+const DAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/**
+One schedule from the config, ready to run.
+*/
+pub struct Scheduled
+{
+    /** The schedule's name for global schedules, "{source}/{name}" for a source's own */
+    pub id: String,
+    /** The source it runs for, blank for all */
+    pub source: String,
+    /** The cron expression as given in the config */
+    pub cron: String,
+    pub actions: Vec<String>,
+    schedule: CronSchedule
+}
+
+impl Scheduled
+{
+    /**
+    # Returns
+    The schedule, or a description of what's wrong with it.
+    */
+    pub fn new(id: &str, source_name: &str, cron: &str, actions: &[String]) -> Result<Scheduled, String>
+    {
+        if actions.is_empty()
+        {
+            return Err(String::from("no actions"));
+        }
+        if let Some(unknown) = actions.iter().find(|a| !Action::NAMES.contains(&a.as_str()))
+        {
+            return Err(format!("unknown action {unknown}, the actions are: {}", Action::NAMES.join(", ")));
+        }
+        Ok(Scheduled{
+            id: id.to_string(),
+            source: source_name.to_string(),
+            cron: cron.to_string(),
+            actions: actions.to_vec(),
+            schedule: parse_cron(cron)?
+        })
+    }
+
+    /**
+    When the schedule next runs after the given time.
+    */
+    pub fn next_run<Z: TimeZone>(&self, after: &DateTime<Z>) -> Option<DateTime<Z>>
+    {
+        self.schedule.after(after).next()
+    }
+
+    /**
+    The action a job for this schedule does.
+    */
+    pub fn action(&self) -> Action
+    {
+        let mut action = Action{source: self.source.clone(), ..Default::default()};
+        for name in &self.actions
+        {
+            action.enable(name);
+        }
+        action
+    }
+}
+
+/**
+Get all the schedules in the config, ordered by id.
+
+# Returns
+The valid schedules, and a description of each one that isn't.
+*/
+pub fn schedules(settings: &Settings) -> (Vec<Scheduled>, Vec<String>)
+{
+    let mut entries = settings.schedules.iter().map(|(name, s)| (name.clone(), String::new(), s)).collect::<Vec<_>>();
+    for (source_name, source) in &settings.sources
+    {
+        for (name, s) in source.schedules.iter().flatten()
+        {
+            entries.push((format!("{source_name}/{name}"), source_name.clone(), s));
+        }
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut valid = Vec::new();
+    let mut problems = Vec::new();
+    for (id, source_name, s) in entries
+    {
+        match Scheduled::new(&id, &source_name, &s.cron, &s.actions)
+        {
+            Ok(scheduled) => valid.push(scheduled),
+            Err(e) => problems.push(format!("schedule {id}: {e}"))
+        }
+    }
+    (valid, problems)
+}
+
+/**
+Parse a cron expression as written in a crontab: "minute hour day-of-month month day-of-week", where day-of-week 0 or 7 is Sunday.
+Expressions with seconds (and optionally years) in the style of the cron crate, and @hourly, @daily, @weekly, @monthly and @yearly, work too.
+*/
+pub fn parse_cron(cron: &str) -> Result<CronSchedule, String>
+{
+    let fields = cron.split_whitespace().collect::<Vec<&str>>();
+    let expression = match fields.len()
+    {
+        5 => {
+            // the cron crate numbers days of the week from 1 for Sunday, so use names instead
+            let day_of_week = fields[4].split(',').map(day_of_week_names).collect::<Vec<String>>().join(",");
+            format!("0 {} {} {} {} {day_of_week}", fields[0], fields[1], fields[2], fields[3])
+        },
+        _ => cron.trim().to_string()
+    };
+    CronSchedule::from_str(&expression).map_err(|e| format!("invalid cron expression {cron}: {e}"))
+}
+
+/**
+Turn an item of a crontab's day-of-week field that uses numbers, like "5", "1-5", "5-7" or "1-7/2", into the days it means by name.
+Ranges are written out day by day, since one ending in 7 for Sunday would wrap around the cron crate's week. Anything else is left as it is.
+*/
+fn day_of_week_names(item: &str) -> String
+{
+    if item == "*"
+    {
+        return item.to_string();
+    }
+    let (range, step) = match item.split_once('/')
+    {
+        Some((range, step)) => match step.parse::<usize>()
+        {
+            Ok(step) if step > 0 => (range, Some(step)),
+            _ => {return item.to_string();}
+        },
+        None => (item, None)
+    };
+    let bounds = match range.split_once('-')
+    {
+        _ if range == "*" => Some((0, 6)),
+        Some((first, last)) => first.parse::<usize>().ok().zip(last.parse::<usize>().ok()),
+        // a single day with a step means from that day to the end of the week
+        None => range.parse::<usize>().ok().map(|first| (first, if step.is_some() {6} else {first}))
+    };
+    match bounds
+    {
+        Some((first, last)) if first <= last && last <= 7 => {
+            let mut names = Vec::new();
+            for day in (first..=last).step_by(step.unwrap_or(1))
+            {
+                let name = DAY_NAMES[day % 7];
+                if !names.contains(&name)
+                {
+                    names.push(name);
+                }
+            }
+            names.join(",")
+        },
+        _ => item.to_string()
+    }
+}
+
+/**
+Start queueing jobs for the schedules in the background. Problems with the schedules are logged, and those schedules are left out.
+*/
+pub fn start(settings: Settings)
+{
+    let (scheduled, problems) = schedules(&settings);
+    for problem in problems
+    {
+        error!("Ignoring invalid {}", problem);
+    }
+    if scheduled.is_empty()
+    {
+        return;
+    }
+    info!("Starting scheduler with {} schedules", scheduled.len());
+    thread::spawn(move || { run(&settings, &scheduled); });
+}
+
+/**
+Queue a job for each schedule whenever it's due. Never returns.
+*/
+fn run(settings: &Settings, scheduled: &[Scheduled])
+{
+    let jobs_file = jobs_file(settings);
+    let now = Local::now();
+    let mut next_runs = scheduled.iter().map(|s| s.next_run(&now)).collect::<Vec<Option<DateTime<Local>>>>();
+    loop
+    {
+        let now = Local::now();
+        for (s, next_run) in scheduled.iter().zip(next_runs.iter_mut())
+        {
+            match next_run
+            {
+                Some(t) if *t <= now => {},
+                _ => {continue;}
+            }
+            match jobs::enqueue_scheduled(&jobs_file, &s.action(), &s.id)
+            {
+                Ok(Some(id)) => {info!("Queued job {} for schedule {}", id, s.id);},
+                Ok(None) => {warn!("Skipping this run of schedule {} because its previous job is still queued or running", s.id);},
+                Err(e) => {error!("Couldn't queue a job for schedule {} -- Error: {}", s.id, e);}
+            }
+            *next_run = s.next_run(&now);
+        }
+
+        let sleep_secs = next_runs.iter().flatten().map(|t| (*t - now).num_seconds() + 1).min().unwrap_or(MAX_SLEEP_SECS).clamp(1, MAX_SLEEP_SECS);
+        thread::sleep(Duration::from_secs(sleep_secs.unsigned_abs()));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use chrono::Utc;
+
+    fn next(cron: &str, after: &str) -> String
+    {
+        let after = DateTime::parse_from_rfc3339(after).unwrap().with_timezone(&Utc);
+        let scheduled = Scheduled::new("test", "", cron, &[String::from("sync")]).unwrap();
+        scheduled.next_run(&after).unwrap().to_rfc3339()
+    }
+
+    #[test]
+    fn crontab_expressions()
+    {
+        // 2024-01-01 is a Monday
+        assert_eq!(next("0 3 * * *", "2024-01-01T12:00:00Z"), "2024-01-02T03:00:00+00:00");
+        assert_eq!(next("30 4 * * 0", "2024-01-01T12:00:00Z"), "2024-01-07T04:30:00+00:00");
+        assert_eq!(next("0 22 * * 1-5", "2024-01-05T23:00:00Z"), "2024-01-08T22:00:00+00:00");
+        assert_eq!(next("@weekly", "2024-01-01T12:00:00Z"), "2024-01-07T00:00:00+00:00");
+        assert_eq!(next("0 9 * * 5-7", "2024-01-01T12:00:00Z"), "2024-01-05T09:00:00+00:00");
+        assert_eq!(next("0 9 * * 5-7", "2024-01-06T12:00:00Z"), "2024-01-07T09:00:00+00:00");
+        assert_eq!(next("0 9 * * 1-7", "2024-01-06T12:00:00Z"), "2024-01-07T09:00:00+00:00");
+        assert_eq!(next("0 9 * * 1-7/2", "2024-01-06T12:00:00Z"), "2024-01-07T09:00:00+00:00");
+        assert_eq!(next("0 9 * * Mon-Fri", "2024-01-06T12:00:00Z"), "2024-01-08T09:00:00+00:00");
+        assert_eq!(day_of_week_names("5-7"), "Fri,Sat,Sun");
+        assert_eq!(day_of_week_names("*/2"), "Sun,Tue,Thu,Sat");
+        assert_eq!(day_of_week_names("0-7"), "Sun,Mon,Tue,Wed,Thu,Fri,Sat");
+        assert!(parse_cron("0 3 * *").is_err());
+    }
+
+    #[test]
+    fn schedule_actions()
+    {
+        let scheduled = Scheduled::new("laptop/weekly", "laptop", "@weekly", &[String::from("export"), String::from("upload")]).unwrap();
+        let action = scheduled.action();
+        assert!(action.export && action.upload && !action.sync);
+        assert_eq!(action.source, "laptop");
+        assert!(Scheduled::new("bad", "", "@daily", &[String::from("explode")]).is_err());
+        assert!(Scheduled::new("empty", "", "@daily", &[]).is_err());
+    }
+}
@@ -17,20 +17,53 @@ pub struct Startup
     pub listen_addr: String
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Source
 {
     pub hostname: String,
+    /** Other hostnames or IPs of the source, e.g. on other networks, tried in order when hostname doesn't answer. */
+    #[serde(default)]
+    pub fallback_hostnames: Vec<String>,
     pub paths: Vec<String>,
     pub paths_exclude: Vec<String>,
-    pub method: SyncMethod
+    pub method: SyncMethod,
+    /** Overrides the global retention settings for this source. */
+    pub retention: Option<Retention>,
+    /** Overrides the global rsync_severity settings for this source. */
+    pub rsync_severity: Option<RsyncSeverity>,
+    /** Overrides the global change_guard settings for this source. */
+    pub change_guard: Option<ChangeGuard>,
+    /** Commands to run before syncing, e.g. to stop a service or flush a database. If one fails, the source isn't synced. */
+    #[serde(default)]
+    pub pre_hooks: Vec<Hook>,
+    /** Commands to run after syncing, even if it failed or a pre-hook did, e.g. to start the service again. */
+    #[serde(default)]
+    pub post_hooks: Vec<Hook>,
+    /** Schedules that only run for this source, in addition to the global ones. */
+    pub schedules: Option<HashMap<String, Schedule>>
 }
 
+/**
+A command run before or after a source is synced, with environment variables describing the sync (see the README).
+*/
 #[derive(Serialize, Deserialize, Clone)]
+pub struct Hook
+{
+    /** Shell command to run. */
+    pub command: String,
+    /** Run it on the source over ssh, with the source's credentials, instead of on this machine. Only for sources synced with RsyncSsh. */
+    #[serde(default)]
+    pub remote: bool,
+    /** How long it may run before it's stopped and counts as failed. Defaults to 10 minutes, 0 for no limit. */
+    pub timeout_secs: Option<u64>
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub enum SyncMethod
 {
     Rsyncd(RsyncdSetup),
     RsyncSsh(RsyncSshSetup),
+    #[default]
     RsyncLocal
 }
 
@@ -70,6 +103,142 @@ pub struct SshCredsKey
     pub keyfile_path: String
 }
 
+impl SshCreds
+{
+    fn secret_fields(&mut self) -> Vec<&mut String>
+    {
+        match self
+        {
+            SshCreds::Password(p) => vec![&mut p.password],
+            SshCreds::Key(_) => Vec::new()
+        }
+    }
+}
+
+/**
+How many generations of something to keep. Follows the usual scheme of backup rotation tools:
+the newest `keep_last` generations are kept, plus the newest generation in each of the most recent `keep_daily` days, `keep_weekly` weeks, etc.
+When every value is 0 the policy is disabled and nothing is deleted.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RetentionPolicy
+{
+    pub keep_last: u32,
+    pub keep_daily: u32,
+    pub keep_weekly: u32,
+    pub keep_monthly: u32,
+    pub keep_yearly: u32
+}
+
+impl RetentionPolicy
+{
+    pub fn is_enabled(&self) -> bool
+    {
+        self.keep_last > 0 || self.keep_daily > 0 || self.keep_weekly > 0 || self.keep_monthly > 0 || self.keep_yearly > 0
+    }
+}
+
+/**
+Actions to run automatically, like a crontab entry. The web interface queues them as jobs when they're due.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Schedule
+{
+    /** When to run, in the server's local time: "minute hour day-of-month month day-of-week" as in crontab, e.g. "0 3 * * *" for 3:00 every night, or @daily, @weekly, etc. */
+    pub cron: String,
+    /** Actions to run, by the names used in the web interface, e.g. ["export", "upload"]. */
+    pub actions: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Retention
+{
+    pub snapshots: RetentionPolicy,
+    pub exports: RetentionPolicy
+}
+
+/**
+How a problem is logged. Only errors make the step fail.
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity
+{
+    Info,
+    Warning,
+    Error
+}
+
+/**
+How each kind of rsync failure is logged, see the README for what falls into each.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RsyncSeverity
+{
+    /** Some files weren't transferred, but only for routine reasons: they vanished mid-sync or were locked. */
+    pub partial_benign: Severity,
+    /** Some files weren't transferred for other reasons, e.g. permissions. */
+    pub partial_real: Severity,
+    pub connection_failure: Severity,
+    pub auth_failure: Severity,
+    /** The rsync on the other end is incompatible, missing, or its shell prints things it shouldn't. */
+    pub protocol_mismatch: Severity,
+    /** Anything else, e.g. a full disk. */
+    pub other_failure: Severity
+}
+
+/**
+Limits on how much a sync may change compared to the latest snapshot before it's stopped for an operator to approve,
+so a source whose files were encrypted or mass-deleted doesn't replace a good backup. 0 for no limit.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChangeGuard
+{
+    /** Files and directories deleted since the latest snapshot. */
+    pub max_deleted: u64,
+    pub max_deleted_percent: f64,
+    /** Files whose contents changed since the latest snapshot. */
+    pub max_modified: u64,
+    pub max_modified_percent: f64
+}
+
+impl ChangeGuard
+{
+    pub fn is_enabled(&self) -> bool
+    {
+        self.max_deleted > 0 || self.max_deleted_percent > 0.0 || self.max_modified > 0 || self.max_modified_percent > 0.0
+    }
+}
+
+/**
+How exports are written: a tar archive compressed with zstd, split into parts.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Export
+{
+    /** Maximum size of each part file. */
+    pub part_size_mib: u64,
+    /** zstd compression level, 1 (fastest) to 22 (smallest). */
+    pub compression_level: i32,
+    /** Number of compression worker threads. 0 for one per CPU core. */
+    pub threads: u32,
+    pub encryption: Encryption
+}
+
+/**
+Optional age encryption of exports. Set either recipients or a passphrase to enable it.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Encryption
+{
+    /** Public keys to encrypt to: age keys (age1...) or SSH public keys (ssh-ed25519 ... / ssh-rsa ...). Any one of the matching private keys can decrypt. */
+    pub recipients: Vec<String>,
+    /** Encrypt with a passphrase instead of recipients. Anyone with the config can then decrypt exports. */
+    pub passphrase: String,
+    /** File with the private key to decrypt exports for unexport: an age identity file, or an SSH private key without a passphrase. Not needed to make exports. */
+    pub identity_file: String
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Mysql
 {
@@ -77,12 +246,23 @@ pub struct Mysql
     pub mysqldump_password: String
 }
 
+/**
+A place to upload exports to, and the provider-specific settings for it.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub enum UploadTargetSetup
+{
+    Dropbox(Dropbox),
+    GDrive(GDrive),
+    S3(S3),
+    Sftp(Sftp)
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Dropbox
 {
     pub dest_path: String,
-    pub app_key: String,
-    pub oauth_token: String
+    pub app_key: String
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -93,17 +273,256 @@ pub struct GDrive
     pub service_account_key_file: String
 }
 
+/**
+The dropbox section of configs from before upload targets, moved into the "dropbox" upload target when the settings are loaded.
+The deprecated --dropbox_* options and -D/--upload_dropbox go here too.
+*/
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct LegacyDropbox
+{
+    pub dest_path: String,
+    pub app_key: String,
+    pub oauth_token: String,
+    pub upload: bool
+}
+
+/**
+The gdrive section of configs from before upload targets, moved into the "gdrive" upload target when the settings are loaded.
+The deprecated --gdrive_* options and -G/--upload_gdrive go here too.
+*/
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct LegacyGDrive
+{
+    pub dir_id: String,
+    pub email: String,
+    pub service_account_key_file: String,
+    pub upload: bool
+}
+
+/**
+S3-compatible object storage: AWS S3, Backblaze B2, Wasabi, MinIO, etc.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct S3
+{
+    /** Base URL of the service, e.g. https://s3.us-east-1.amazonaws.com or http://minio:9000 */
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /** Prepended to the names of uploaded files, e.g. "redundinator/" to store them in a folder. */
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /** Storage class for uploaded files, e.g. STANDARD_IA or GLACIER. Blank for the bucket's default. */
+    pub storage_class: String,
+    /** Size of each part of a multipart upload. Larger files are uploaded in parts so interrupted uploads can be resumed. */
+    pub part_size_mib: u64,
+    /** Address the bucket in the URL path instead of the hostname. Needed for MinIO and most other self-hosted services. */
+    pub path_style: bool
+}
+
+/**
+A directory on any server reachable by SFTP. The server's host key must be in the known_hosts file of the user running the app.
+*/
 #[derive(Serialize, Deserialize, Clone)]
+pub struct Sftp
+{
+    pub hostname: String,
+    pub port: u16,
+    pub creds: SshCreds,
+    pub dest_path: String
+}
+
+/**
+Who can use the web interface, and how it's served.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Web
+{
+    /** Users who can log in with HTTP basic auth, and the argon2 hashes of their passwords in PHC format ($argon2id$v=19$...). */
+    pub users: HashMap<String, String>,
+    /** Tokens for scripts using the API, by name: the SHA-256 hash of each token, in hex. Clients send the token in an "Authorization: Bearer" header. */
+    pub api_tokens: HashMap<String, String>,
+    /** Let anyone who can reach the web interface use it without logging in. Only for when something in front of it, like a reverse proxy, checks logins. */
+    pub allow_anonymous: bool,
+    /** PEM file with the certificate chain to serve HTTPS with. Blank to serve plain HTTP. */
+    pub tls_cert_file: String,
+    /** PEM file with the private key of the certificate. */
+    pub tls_key_file: String
+}
+
+/**
+Where to send notifications of how jobs went, and of sources that haven't been backed up in a while.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Notifications
+{
+    /** Notify "stale" when a source hasn't had a successful sync in this many days. 0 to never. */
+    pub stale_after_days: u32,
+    pub notifiers: HashMap<String, Notifier>
+}
+
+/**
+One place to send notifications to, and which ones.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Notifier
+{
+    /** What to notify of: any of "success" and "failure" of jobs, "stale" sources, and "held_back" syncs. */
+    pub events: Vec<String>,
+    /** Template for the title, or subject of an email. Blank for the default. {placeholders} are filled in, see the README. */
+    #[serde(default)]
+    pub title: String,
+    /** Template for the message. Blank for the default. */
+    #[serde(default)]
+    pub message: String,
+    pub method: NotifyMethod
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum NotifyMethod
+{
+    Email(Email),
+    Webhook(Webhook),
+    Ntfy(Push),
+    Gotify(Push)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Email
+{
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    /** "starttls", "tls" for TLS from the start (usually port 465), or "none" for a local mail server. */
+    pub security: String,
+    /** Blank to send without logging in. */
+    pub username: String,
+    pub password: String,
+    pub from: String,
+    pub to: Vec<String>
+}
+
+/**
+POSTs the notification as JSON to a URL.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Webhook
+{
+    pub url: String,
+    /** Extra headers to send, e.g. for logging in. */
+    pub headers: HashMap<String, String>
+}
+
+/**
+A push notification server. For ntfy the URL includes the topic, e.g. https://ntfy.sh/mybackups. For Gotify it's the server, e.g. https://gotify.lan.
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Push
+{
+    pub url: String,
+    /** Access token for ntfy, or app token for Gotify. Blank for none. */
+    pub token: String,
+    /** From 1 to 5 for ntfy, 0 to 10 for Gotify. 0 for the server's default. */
+    pub priority: u8
+}
+
+/** What secrets are replaced with wherever they're shown */
+pub const REDACTED: &str = "********";
+
+/**
+Replace the values of secrets that are set, leaving blank ones blank so it's still clear which are set.
+*/
+fn redact_fields(fields: Vec<&mut String>)
+{
+    for field in fields.into_iter().filter(|f| !f.is_empty())
+    {
+        *field = String::from(REDACTED);
+    }
+}
+
+/**
+What to do. Missing fields default to off, so jobs queued by older versions can still be read.
+*/
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct Action
 {
     pub sync: bool,
     pub export: bool,
-    pub upload_dropbox: bool,
-    pub auth_dropbox: bool,
-    pub upload_gdrive: bool,
+    pub upload: bool,
+    pub auth_upload: bool,
+    pub upload_target: String,
+    pub oauth_token: String,
     pub mysql_dump: bool,
     pub source: String,
-    pub unexport: bool
+    pub unexport: bool,
+    pub list_exports: bool,
+    pub list_remote: bool,
+    pub restore: bool,
+    pub timestamp: i64,
+    pub generations_ago: u32,
+    pub include: String,
+    pub restore_dest: String,
+    pub catalog: bool,
+    pub search: String,
+    pub search_regex: bool,
+    pub verify: bool,
+    pub prune: bool,
+    pub dry_run: bool,
+    /** With sync: skip the change guard, once an operator has checked that the changes it stopped are legitimate */
+    pub approve_changes: bool,
+    pub hash_password: bool,
+    pub test_notifiers: bool
+}
+
+impl Action
+{
+    /** The actions that can be requested by name, from the web interface or schedules */
+    pub const NAMES: [&'static str; 11] = ["sync", "mysql_dump", "upload", "export", "unexport", "list_exports", "list_remote", "restore", "verify", "prune", "catalog"];
+
+    /**
+    Turn on one of the actions in NAMES.
+
+    # Returns
+    bool for whether the name is one of them.
+    */
+    pub fn enable(&mut self, name: &str) -> bool
+    {
+        let flag = match name
+        {
+            "sync"         => &mut self.sync,
+            "mysql_dump"   => &mut self.mysql_dump,
+            "upload"       => &mut self.upload,
+            "export"       => &mut self.export,
+            "unexport"     => &mut self.unexport,
+            "list_exports" => &mut self.list_exports,
+            "list_remote"  => &mut self.list_remote,
+            "restore"      => &mut self.restore,
+            "verify"       => &mut self.verify,
+            "prune"        => &mut self.prune,
+            "catalog"      => &mut self.catalog,
+            _ => {return false;}
+        };
+        *flag = true;
+        true
+    }
+
+    /**
+    A copy with any secrets replaced, safe to show.
+    */
+    pub fn redacted(&self) -> Action
+    {
+        let mut action = self.clone();
+        redact_fields(action.secret_fields());
+        action
+    }
+
+    fn secret_fields(&mut self) -> Vec<&mut String>
+    {
+        vec![&mut self.oauth_token]
+    }
 }
 
 /**
@@ -116,8 +535,21 @@ pub struct Settings
     pub sources: HashMap<String, Source>,
     pub mysql: Mysql,
     pub action: Action,
-    pub dropbox: Dropbox,
-    pub gdrive: GDrive
+    pub upload_targets: HashMap<String, UploadTargetSetup>,
+    pub export: Export,
+    pub retention: Retention,
+    pub rsync_severity: RsyncSeverity,
+    pub change_guard: ChangeGuard,
+    /** Schedules that run for every source. */
+    pub schedules: HashMap<String, Schedule>,
+    pub web: Web,
+    pub notifications: Notifications,
+    /** Deprecated, only read from old configs and options: see LegacyDropbox. */
+    #[serde(default, skip_serializing)]
+    pub dropbox: Option<LegacyDropbox>,
+    /** Deprecated, only read from old configs and options: see LegacyGDrive. */
+    #[serde(default, skip_serializing)]
+    pub gdrive: Option<LegacyGDrive>
 }
 
 impl Settings
@@ -141,41 +573,235 @@ impl Settings
                 mysqldump_username: String::from(""),
                 mysqldump_password: String::from("")
             },
-            dropbox: Dropbox
+            upload_targets: vec![
+                (String::from("dropbox"), UploadTargetSetup::Dropbox(Dropbox{dest_path: String::from("/Backup/redundinator"), app_key: String::from("")})),
+                (String::from("gdrive"),  UploadTargetSetup::GDrive(GDrive{dir_id: String::from(""), email: String::from(""), service_account_key_file: String::from("")})),
+                (String::from("s3"),      UploadTargetSetup::S3(S3{endpoint: String::from("http://minio:9000"), region: String::from("us-east-1"), bucket: String::from("redundinator"), prefix: String::from(""), access_key_id: String::from(""), secret_access_key: String::from(""), storage_class: String::from(""), part_size_mib: 64, path_style: true})),
+                (String::from("sftp"),    UploadTargetSetup::Sftp(Sftp{hostname: String::from("offsite"), port: 22, creds: SshCreds::Key(SshCredsKey{username: String::from("user"), keyfile_path: String::from("/home/user/offsite.key")}), dest_path: String::from("/backup/redundinator")})),
+            ].into_iter().collect(),
+            export: Export
+            {
+                part_size_mib:     102400,
+                compression_level: 3,
+                threads:           0,
+                encryption:        Encryption{recipients: Vec::new(), passphrase: String::from(""), identity_file: String::from("")}
+            },
+            retention: Retention
+            {
+                snapshots: RetentionPolicy{keep_last: 3, keep_daily: 14, keep_weekly: 8, keep_monthly: 12, keep_yearly: 5},
+                exports:   RetentionPolicy{keep_last: 2, keep_daily: 0,  keep_weekly: 0, keep_monthly: 0,  keep_yearly: 0}
+            },
+            rsync_severity: RsyncSeverity
+            {
+                partial_benign:     Severity::Info,
+                partial_real:       Severity::Error,
+                connection_failure: Severity::Error,
+                auth_failure:       Severity::Error,
+                protocol_mismatch:  Severity::Error,
+                other_failure:      Severity::Error
+            },
+            change_guard: ChangeGuard
             {
-                dest_path:    String::from("/Backup/redundinator"),
-                app_key:      String::from(""),
-                oauth_token:  String::from("")
+                max_deleted:          0,
+                max_deleted_percent:  25.0,
+                max_modified:         0,
+                max_modified_percent: 50.0
             },
-            gdrive: GDrive
+            schedules: HashMap::new(),
+            web: Web
             {
-                dir_id:                   String::from(""),
-                email:                    String::from(""),
-                service_account_key_file: String::from("")
+                users:           HashMap::new(),
+                api_tokens:      HashMap::new(),
+                allow_anonymous: false,
+                tls_cert_file:   String::from(""),
+                tls_key_file:    String::from("")
             },
+            notifications: Notifications
+            {
+                stale_after_days: 0,
+                notifiers: vec![
+                    (String::from("email"),   Notifier{events: vec!(String::from("failure"), String::from("stale")), title: String::from(""), message: String::from(""), method: NotifyMethod::Email(Email{smtp_host: String::from("smtp.example.com"), smtp_port: 587, security: String::from("starttls"), username: String::from(""), password: String::from(""), from: String::from("redundinator@example.com"), to: vec!(String::from("admin@example.com"))})}),
+                    (String::from("webhook"), Notifier{events: vec!(String::from("success"), String::from("failure"), String::from("stale")), title: String::from(""), message: String::from(""), method: NotifyMethod::Webhook(Webhook{url: String::from("http://monitoring.lan/hooks/backups"), headers: HashMap::new()})}),
+                    (String::from("ntfy"),    Notifier{events: vec!(String::from("failure"), String::from("stale")), title: String::from(""), message: String::from(""), method: NotifyMethod::Ntfy(Push{url: String::from("https://ntfy.sh/my-backups"), token: String::from(""), priority: 0})}),
+                ].into_iter().collect()
+            },
+            dropbox: None,
+            gdrive: None,
             sources: vec![
-                (String::from("localhost"),         Source{hostname: String::from("localhost"), paths: vec!(String::from("/home/")),        paths_exclude: Vec::new(), method: SyncMethod::RsyncLocal }),
-                (String::from("client1"),           Source{hostname: String::from("client1"),   paths: vec!(String::from("/home/")),        paths_exclude: Vec::new(), method: SyncMethod::Rsyncd(RsyncdSetup{username: String::from("user"), password: String::from("pass")}) }),
-                (String::from("client2"),           Source{hostname: String::from("client2"),   paths: vec!(String::from("/home/")),        paths_exclude: Vec::new(), method: SyncMethod::RsyncSsh(RsyncSshSetup{port: 22, remote_path_to_rsync_binary: Some(String::from("/bin/rsync")), creds: SshCreds::Key(SshCredsKey{username: String::from("user"), keyfile_path: String::from("/home/user/client2.key")})}) }),
-                (String::from("client3_main"),      Source{hostname: String::from("client3"),   paths: vec!(String::from("/home/")),        paths_exclude: Vec::new(), method: SyncMethod::RsyncSsh(RsyncSshSetup{port: 22, remote_path_to_rsync_binary: None,                             creds: SshCreds::Password(SshCredsPassword{username: String::from("user"), password: String::from("pass")})}) }),
-                (String::from("client3_hugefiles"), Source{hostname: String::from("client3"),   paths: vec!(String::from("/mnt/archive/")), paths_exclude: Vec::new(), method: SyncMethod::RsyncSsh(RsyncSshSetup{port: 22, remote_path_to_rsync_binary: None,                             creds: SshCreds::Password(SshCredsPassword{username: String::from("user"), password: String::from("pass")})}) }),
+                (String::from("localhost"),         Source{hostname: String::from("localhost"), fallback_hostnames: Vec::new(), paths: vec!(String::from("/home/")),        paths_exclude: Vec::new(), method: SyncMethod::RsyncLocal, ..Default::default() }),
+                (String::from("client1"),           Source{hostname: String::from("client1"),   fallback_hostnames: vec!(String::from("client1-wifi"), String::from("10.8.0.11")), paths: vec!(String::from("/home/")),        paths_exclude: Vec::new(), method: SyncMethod::Rsyncd(RsyncdSetup{username: String::from("user"), password: String::from("pass")}), ..Default::default() }),
+                (String::from("client2"),           Source{hostname: String::from("client2"),   fallback_hostnames: Vec::new(), paths: vec!(String::from("/home/")),        paths_exclude: Vec::new(), method: SyncMethod::RsyncSsh(RsyncSshSetup{port: 22, remote_path_to_rsync_binary: Some(String::from("/bin/rsync")), creds: SshCreds::Key(SshCredsKey{username: String::from("user"), keyfile_path: String::from("/home/user/client2.key")})}), ..Default::default() }),
+                (String::from("client3_main"),      Source{hostname: String::from("client3"),   fallback_hostnames: Vec::new(), paths: vec!(String::from("/home/")),        paths_exclude: Vec::new(), method: SyncMethod::RsyncSsh(RsyncSshSetup{port: 22, remote_path_to_rsync_binary: None,                             creds: SshCreds::Password(SshCredsPassword{username: String::from("user"), password: String::from("pass")})}), ..Default::default() }),
+                (String::from("client3_hugefiles"), Source{hostname: String::from("client3"),   fallback_hostnames: Vec::new(), paths: vec!(String::from("/mnt/archive/")), paths_exclude: Vec::new(), method: SyncMethod::RsyncSsh(RsyncSshSetup{port: 22, remote_path_to_rsync_binary: None,                             creds: SshCreds::Password(SshCredsPassword{username: String::from("user"), password: String::from("pass")})}), ..Default::default() }),
             ].into_iter().collect(),
             action: Action
             {
                 sync:           false,
                 export:         false,
                 unexport:       false,
-                upload_dropbox: false,
-                auth_dropbox:   false,
-                upload_gdrive:  false,
+                list_exports:   false,
+                list_remote:    false,
+                restore:        false,
+                timestamp:      0,
+                generations_ago: 0,
+                include:        String::from(""),
+                restore_dest:   String::from(""),
+                catalog:        false,
+                search:         String::from(""),
+                search_regex:   false,
+                upload:         false,
+                auth_upload:    false,
+                upload_target:  String::from(""),
+                oauth_token:    String::from(""),
                 mysql_dump:     false,
+                verify:         false,
+                prune:          false,
+                dry_run:        false,
+                approve_changes: false,
+                hash_password:  false,
+                test_notifiers: false,
                 source:         String::from("")
             }
         };
 
         let mut default_without_sources = default_settings.clone();
         default_without_sources.sources = HashMap::new();
-        crate::settings::settings_resolver::load::<Settings, ClapArgs>(&default_settings, &default_without_sources)
+        default_without_sources.upload_targets = HashMap::new();
+        default_without_sources.notifications.notifiers = HashMap::new();
+        let mut settings = crate::settings::settings_resolver::load::<Settings, ClapArgs>(&default_settings, &default_without_sources);
+        settings.migrate_legacy_uploads();
+        settings
+    }
+
+    /**
+    Move the dropbox and gdrive sections of configs from before upload targets into the upload targets of the same names,
+    with any of their fields that are set overriding the target's. -D/--upload_dropbox and -G/--upload_gdrive become --upload to those targets.
+
+    # Panics
+    If a target with one of those names isn't of that kind, or -D and -G are used together while there are other upload targets,
+    since upload_target can only name one.
+    */
+    fn migrate_legacy_uploads(&mut self)
+    {
+        let mut legacy_uploads = Vec::new();
+        if let Some(legacy) = self.dropbox.take()
+        {
+            if !legacy.dest_path.is_empty() || !legacy.app_key.is_empty()
+            {
+                let target = self.upload_targets.entry(String::from("dropbox"))
+                    .or_insert_with(|| UploadTargetSetup::Dropbox(Dropbox{dest_path: String::from("/Backup/redundinator"), app_key: String::new()}));
+                match target
+                {
+                    UploadTargetSetup::Dropbox(dropbox) => {
+                        if !legacy.dest_path.is_empty() {dropbox.dest_path = legacy.dest_path;}
+                        if !legacy.app_key.is_empty() {dropbox.app_key = legacy.app_key;}
+                    },
+                    _ => panic!("The config has an old dropbox section, but the upload target named dropbox isn't a Dropbox target. Move the section into upload_targets under another name")
+                }
+            }
+            if self.action.oauth_token.is_empty()
+            {
+                self.action.oauth_token = legacy.oauth_token;
+            }
+            if legacy.upload {legacy_uploads.push("dropbox");}
+        }
+        if let Some(legacy) = self.gdrive.take()
+        {
+            if !legacy.dir_id.is_empty() || !legacy.email.is_empty() || !legacy.service_account_key_file.is_empty()
+            {
+                let target = self.upload_targets.entry(String::from("gdrive"))
+                    .or_insert_with(|| UploadTargetSetup::GDrive(GDrive{dir_id: String::new(), email: String::new(), service_account_key_file: String::new()}));
+                match target
+                {
+                    UploadTargetSetup::GDrive(gdrive) => {
+                        if !legacy.dir_id.is_empty() {gdrive.dir_id = legacy.dir_id;}
+                        if !legacy.email.is_empty() {gdrive.email = legacy.email;}
+                        if !legacy.service_account_key_file.is_empty() {gdrive.service_account_key_file = legacy.service_account_key_file;}
+                    },
+                    _ => panic!("The config has an old gdrive section, but the upload target named gdrive isn't a GDrive target. Move the section into upload_targets under another name")
+                }
+            }
+            if legacy.upload {legacy_uploads.push("gdrive");}
+        }
+        match legacy_uploads[..]
+        {
+            [] => {},
+            [name] => {
+                self.action.upload = true;
+                self.action.upload_target = name.to_string();
+            },
+            _ if self.upload_targets.keys().all(|name| name == "dropbox" || name == "gdrive") => {
+                self.action.upload = true;
+                self.action.upload_target = String::new();
+            },
+            _ => panic!("-D/--upload_dropbox and -G/--upload_gdrive are deprecated and can't be used together when there are other upload targets. Use --upload, with --upload_target to pick one")
+        }
+    }
+
+    /**
+    A copy of the settings with every password, key and other secret replaced, safe to show.
+    */
+    pub fn redacted(&self) -> Settings
+    {
+        let mut settings = self.clone();
+        redact_fields(settings.secret_fields());
+        settings
+    }
+
+    /**
+    Replace any of the secrets in the settings that turn up in some text, like a command line or a log.
+
+    # Returns
+    The text with the secrets replaced.
+    */
+    pub fn redact(&self, text: &str) -> String
+    {
+        let mut settings = self.clone();
+        let mut secrets = settings.secret_fields().into_iter().map(|f| f.clone()).filter(|f| !f.is_empty()).collect::<Vec<String>>();
+        // longest first, in case one secret contains another
+        secrets.sort_by_key(|f| std::cmp::Reverse(f.len()));
+        secrets.iter().fold(text.to_string(), |text, secret| text.replace(secret, REDACTED))
+    }
+
+    /**
+    Every setting that's a secret: passwords, keys, tokens and the hashes of the web interface's logins.
+    */
+    fn secret_fields(&mut self) -> Vec<&mut String>
+    {
+        let mut fields = Vec::new();
+        for source in self.sources.values_mut()
+        {
+            match &mut source.method
+            {
+                SyncMethod::Rsyncd(setup) => fields.push(&mut setup.password),
+                SyncMethod::RsyncSsh(setup) => fields.extend(setup.creds.secret_fields()),
+                SyncMethod::RsyncLocal => {}
+            }
+        }
+        for target in self.upload_targets.values_mut()
+        {
+            match target
+            {
+                UploadTargetSetup::S3(s3) => fields.push(&mut s3.secret_access_key),
+                UploadTargetSetup::Sftp(sftp) => fields.extend(sftp.creds.secret_fields()),
+                UploadTargetSetup::Dropbox(_) | UploadTargetSetup::GDrive(_) => {}
+            }
+        }
+        fields.push(&mut self.mysql.mysqldump_password);
+        fields.push(&mut self.export.encryption.passphrase);
+        fields.extend(self.web.users.values_mut());
+        fields.extend(self.web.api_tokens.values_mut());
+        for notifier in self.notifications.notifiers.values_mut()
+        {
+            match &mut notifier.method
+            {
+                NotifyMethod::Email(email) => fields.push(&mut email.password),
+                NotifyMethod::Webhook(webhook) => fields.extend(webhook.headers.values_mut()),
+                NotifyMethod::Ntfy(push) | NotifyMethod::Gotify(push) => fields.push(&mut push.token)
+            }
+        }
+        fields.extend(self.action.secret_fields());
+        fields
     }
 }
 
@@ -197,21 +823,42 @@ struct ClapArgs {
     /** ip:port for the web interface to listen on. Use 0.0.0.0 for the ip to listen on all interfaces.      Default: 0.0.0.0:80                    */ #[arg(short='w', long="listen_addr",           env="REDUNDINATOR_LISTEN_ADDR"           )]  startup_listen_addr: Option<String>,
     /** Username for mysqldump on localhost.                                                                                                        */ #[arg(short='u', long="mysqldump_username",    env="REDUNDINATOR_MYSQLDUMP_USERNAME"    )]  mysql_mysqldump_username: Option<String>,
     /** Password for mysqldump on localhost.                                                                                                        */ #[arg(short='p', long="mysqldump_password",    env="REDUNDINATOR_MYSQLDUMP_PASSWORD"    )]  mysql_mysqldump_password: Option<String>,
+    /** PEM file with the certificate chain for the web interface to serve HTTPS with. When blank, serve plain HTTP.                                 */ #[arg(           long="tls_cert_file",         env="REDUNDINATOR_TLS_CERT_FILE"         )]  web_tls_cert_file: Option<String>,
+    /** PEM file with the private key of the web interface's certificate.                                                                           */ #[arg(           long="tls_key_file",          env="REDUNDINATOR_TLS_KEY_FILE"          )]  web_tls_key_file: Option<String>,
 
-    /** Dropbox API App Key                                                                                                                         */ #[arg(short='k', long="dropbox_app_key",       env="REDUNDINATOR_DROPBOX_APP_KEY"       )]  dropbox_app_key: Option<String>,
-    /** Token retrieved from Dropbox during interactive auth. If provided while using auth_dropbox, resumes auth instead of generating new URL.     */ #[arg(short='d', long="dropbox_oauth_token",   env="REDUNDINATOR_DROPBOX_OAUTH_TOKEN"   )]  dropbox_oauth_token: Option<String>,
-    /** Directory in your dropbox account where exports should be stored.                                    Default: /Backup/redundinator          */ #[arg(short='b', long="dropbox_dest_path",     env="REDUNDINATOR_DROPBOX_DEST_PATH"     )]  dropbox_dest_path: Option<String>,
-    /** ID of the directory in google drive to store exports.                                                                                       */ #[arg(short='i', long="gdrive_dir_id",         env="REDUNDINATOR_GDRIVE_DIR_ID"         )]  gdrive_dir_id: Option<String>,
-    /** Email address of the Google Workspaces user to impersonate using Domain-Wide Delegation in order to access the directory.                   */ #[arg(short='o', long="gdrive_email",          env="REDUNDINATOR_GDRIVE_EMAIL"          )]  gdrive_email: Option<String>,
-    /** Path to the Google Drive API Service Account Key File -- the JSON file you get when you create a new private key for a service account.     */ #[arg(short='e', long="gdrive_keyfile",        env="REDUNDINATOR_GDRIVE_KEYFILE"        )]  gdrive_service_account_key_file: Option<String>,
+    /** Deprecated, use the upload_targets section of the config: Dropbox API App Key.                                                              */ #[arg(           long="dropbox_app_key",       env="REDUNDINATOR_DROPBOX_APP_KEY"       )]  dropbox_app_key: Option<String>,
+    /** Deprecated, use --oauth_token.                                                                                                              */ #[arg(           long="dropbox_oauth_token",   env="REDUNDINATOR_DROPBOX_OAUTH_TOKEN"   )]  dropbox_oauth_token: Option<String>,
+    /** Deprecated, use the upload_targets section of the config: directory in your dropbox account where exports should be stored.                 */ #[arg(           long="dropbox_dest_path",     env="REDUNDINATOR_DROPBOX_DEST_PATH"     )]  dropbox_dest_path: Option<String>,
+    /** Deprecated, use the upload_targets section of the config: ID of the directory in google drive to store exports.                             */ #[arg(           long="gdrive_dir_id",         env="REDUNDINATOR_GDRIVE_DIR_ID"         )]  gdrive_dir_id: Option<String>,
+    /** Deprecated, use the upload_targets section of the config: email address of the Google Workspaces user to impersonate.                       */ #[arg(           long="gdrive_email",          env="REDUNDINATOR_GDRIVE_EMAIL"          )]  gdrive_email: Option<String>,
+    /** Deprecated, use the upload_targets section of the config: path to the Google Drive API Service Account Key File.                            */ #[arg(           long="gdrive_keyfile",        env="REDUNDINATOR_GDRIVE_KEYFILE"        )]  gdrive_service_account_key_file: Option<String>,
+    /** Deprecated, use --upload --upload_target dropbox.                                                                                           */ #[arg(short='D', long="upload_dropbox",        env="REDUNDINATOR_UPLOAD_DROPBOX"        )]  dropbox_upload: bool,
+    /** Deprecated, use --upload --upload_target gdrive.                                                                                            */ #[arg(short='G', long="upload_gdrive",         env="REDUNDINATOR_UPLOAD_GDRIVE"         )]  gdrive_upload: bool,
 
     /** Sync files from source host to backup storage directory.                                                                                    */ #[arg(short='S', long="sync",                  env="REDUNDINATOR_SYNC"                  )]  action_sync: bool,
     /** Export contents of backup storage directory to export directory, processed with tar+zstd|split                                              */ #[arg(short='E', long="export",                env="REDUNDINATOR_EXPORT"                )]  action_export: bool,
     /** Extract original files from an export.                                                                                                      */ #[arg(short='U', long="unexport",              env="REDUNDINATOR_UNEXPORT"              )]  action_unexport: bool,
-    /** Upload exports to Dropbox.                                                                                                                  */ #[arg(short='D', long="upload_dropbox",        env="REDUNDINATOR_UPLOAD_DROPBOX"        )]  action_upload_dropbox: bool,
-    /** Perform interactive authorization to Dropbox -- must do this before uploading to dropbox will work.                                         */ #[arg(short='R', long="auth_dropbox",          env="REDUNDINATOR_AUTH_DROPBOX"          )]  action_auth_dropbox: bool,
-    /** Upload exports to Google Drive.                                                                                                             */ #[arg(short='G', long="upload_gdrive",         env="REDUNDINATOR_UPLOAD_GDRIVE"         )]  action_upload_gdrive: bool,
+    /** List the exports in the export directory.                                                                                                   */ #[arg(short='X', long="list_exports",          env="REDUNDINATOR_LIST_EXPORTS"          )]  action_list_exports: bool,
+    /** List the exports stored at the upload targets.                                                                                              */ #[arg(short='L', long="list_remote",           env="REDUNDINATOR_LIST_REMOTE"           )]  action_list_remote: bool,
+    /** Download an export from the first upload target that has it, then extract it like unexport.                                                 */ #[arg(short='F', long="restore",               env="REDUNDINATOR_RESTORE"               )]  action_restore: bool,
+    /** With unexport or restore: timestamp of the export to use. When 0, go by generations_ago.                                                    */ #[arg(short='t', long="timestamp",             env="REDUNDINATOR_TIMESTAMP"             )]  action_timestamp: Option<i64>,
+    /** With unexport or restore: use the export this many before the newest (0 for the newest). Restore only counts complete exports.              */ #[arg(short='g', long="generations_ago",       env="REDUNDINATOR_GENERATIONS_AGO"       )]  action_generations_ago: Option<u32>,
+    /** With unexport or restore: only extract the paths matching these comma separated globs, e.g. "_home_/alice,_etc_/hosts"                      */ #[arg(short='i', long="include",               env="REDUNDINATOR_INCLUDE"               )]  action_include: Option<String>,
+    /** With restore: extract into {restore_dest}/{source}/ instead of the unexport directory.                                                      */ #[arg(short='o', long="restore_dest",          env="REDUNDINATOR_RESTORE_DEST"          )]  action_restore_dest: Option<String>,
+    /** Catalog the snapshots that aren't in the file catalog yet, e.g. ones taken before it existed.                                               */ #[arg(short='C', long="catalog",               env="REDUNDINATOR_CATALOG"               )]  action_catalog: bool,
+    /** Search the file catalog for paths matching this glob, e.g. "*.pdf", and list every version found and where it is.                           */ #[arg(short='f', long="search",                env="REDUNDINATOR_SEARCH"                )]  action_search: Option<String>,
+    /** With search: treat the pattern as a regex instead of a glob.                                                                                */ #[arg(short='e', long="search_regex",          env="REDUNDINATOR_SEARCH_REGEX"          )]  action_search_regex: bool,
+    /** Upload exports to the upload targets.                                                                                                       */ #[arg(short='O', long="upload",                env="REDUNDINATOR_UPLOAD"                )]  action_upload: bool,
+    /** Perform interactive authorization to the upload targets that need it (Dropbox) -- must do this before uploading to them will work.          */ #[arg(short='R', long="auth_upload",           env="REDUNDINATOR_AUTH_UPLOAD"           )]  action_auth_upload: bool,
+    /** Only upload to / authorize the named upload target. When blank, use all.                                                                    */ #[arg(short='T', long="upload_target",         env="REDUNDINATOR_UPLOAD_TARGET"         )]  action_upload_target: Option<String>,
+    /** Token retrieved from Dropbox during interactive auth. If provided while using auth_upload, resumes auth instead of generating new URL.      */ #[arg(short='d', long="oauth_token",           env="REDUNDINATOR_OAUTH_TOKEN"           )]  action_oauth_token: Option<String>,
     /** Dump localhost mysql contents to flat file and include in the backup storage directory                                                      */ #[arg(short='M', long="mysql_dump",            env="REDUNDINATOR_MYSQL_DUMP"            )]  action_mysql_dump: bool,
+    /** Check the latest exports against their manifests, test-decompress them, and compare the copies at the upload targets.                       */ #[arg(short='v', long="verify",                env="REDUNDINATOR_VERIFY"                )]  action_verify: bool,
+    /** Delete old snapshots and exports according to the retention settings.                                                                       */ #[arg(short='P', long="prune",                 env="REDUNDINATOR_PRUNE"                 )]  action_prune: bool,
+    /** With prune: only report what would be kept and deleted, don't delete anything.                                                              */ #[arg(short='y', long="dry_run",               env="REDUNDINATOR_DRY_RUN"               )]  action_dry_run: bool,
+    /** With sync: skip the change guard, after checking that the changes it stopped a sync for are legitimate.                                     */ #[arg(           long="approve_changes",       env="REDUNDINATOR_APPROVE_CHANGES"       )]  action_approve_changes: bool,
+    /** Read a password for a web interface user from stdin and print the hash of it to put in the web section of the config.                      */ #[arg(short='H', long="hash_password",         env="REDUNDINATOR_HASH_PASSWORD"         )]  action_hash_password: bool,
+    /** Send a test notification through every notifier in the config.                                                                             */ #[arg(           long="test_notifiers",        env="REDUNDINATOR_TEST_NOTIFIERS"        )]  action_test_notifiers: bool,
     /** Only do actions for the named data source. When blank, use all.                                                                             */ #[arg(short='A', long="active_source",         env="REDUNDINATOR_ACTIVE_SOURCE"         )]  action_source: Option<String>,
 }
 
@@ -233,6 +880,43 @@ mod tests
         let _config = Settings::load();
     }
 
+    #[test]
+    fn secrets_are_redacted()
+    {
+        let mut settings = Settings::load();
+        settings.mysql.mysqldump_password = String::from("hunter2");
+        settings.action.oauth_token = String::from("tok");
+        let redacted = settings.redacted();
+        assert_eq!(redacted.mysql.mysqldump_password, REDACTED);
+        assert_eq!(redacted.action.oauth_token, REDACTED);
+        assert_eq!(redacted.export.encryption.passphrase, "");
+        assert_eq!(settings.action.redacted().oauth_token, REDACTED);
+        assert_eq!(settings.redact("mysqldump -u root -phunter2 --all"), format!("mysqldump -u root -p{REDACTED} --all"));
+    }
+
+    #[test]
+    fn legacy_uploads_are_migrated()
+    {
+        let mut settings = Settings::load();
+        settings.upload_targets = HashMap::new();
+        settings.dropbox = Some(LegacyDropbox{dest_path: String::from("/Backups"), app_key: String::from("key"), oauth_token: String::new(), upload: false});
+        settings.gdrive = Some(LegacyGDrive{dir_id: String::from("dir"), upload: true, ..Default::default()});
+        settings.migrate_legacy_uploads();
+        assert!(matches!(&settings.upload_targets["dropbox"], UploadTargetSetup::Dropbox(d) if d.dest_path == "/Backups" && d.app_key == "key"));
+        assert!(matches!(&settings.upload_targets["gdrive"], UploadTargetSetup::GDrive(g) if g.dir_id == "dir" && g.email.is_empty()));
+        assert!(settings.action.upload);
+        assert_eq!(settings.action.upload_target, "gdrive");
+        assert!(settings.dropbox.is_none() && settings.gdrive.is_none());
+
+        // -D on its own doesn't make a target, and uploads to the one in the config
+        settings.action.upload = false;
+        settings.dropbox = Some(LegacyDropbox{upload: true, ..Default::default()});
+        settings.migrate_legacy_uploads();
+        assert!(settings.action.upload);
+        assert_eq!(settings.action.upload_target, "dropbox");
+        assert!(matches!(&settings.upload_targets["dropbox"], UploadTargetSetup::Dropbox(d) if d.dest_path == "/Backups"));
+    }
+
     //This test from the Clap docs ensures consistency of the cli config structure
     #[test]
     fn verify_cli() {
@@ -1,5 +1,11 @@
-use log::{error, /*warn,*/ info/*, debug, trace, log, Level*/};
+use log::{error, warn, info/*, debug, trace, log, Level*/};
 use run_script::ScriptOptions;
+use std::{io::{self, Read}, process::{Child, Command, Stdio}, sync::mpsc, thread, time::{Duration, Instant}};
+
+use crate::cancel;
+
+/** How long a cancelled command gets to exit after being asked to, before it's killed outright */
+const TERMINATE_GRACE: Duration = Duration::from_secs(10);
 
 /**
 Run a shell command with extensive logging.
@@ -71,4 +77,197 @@ pub fn shell_and_log(cmd: String, options: &ScriptOptions, purpose: &str, source
             None
         }
     }
-}
\ No newline at end of file
+}
+
+/**
+Run a shell command and collect its output, like run_script::run with the default options.
+If the running job is cancelled, the command is stopped along with everything it started.
+
+# Returns
+- The command's exit code (-1 if it was ended by a signal), stdout and stderr
+- An error if the command couldn't be run, or one of kind Interrupted if it was stopped because the job was cancelled
+*/
+pub fn run(cmd: &str) -> io::Result<(i32, String, String)>
+{
+    run_watching(cmd, |_| {})
+}
+
+/**
+Run a shell command like `run`, while watching what it writes to stdout.
+
+# Arguments
+* `on_output` - Called with each line of stdout as it's written. Carriage returns end lines too, since that's how progress meters redraw themselves.
+*/
+pub fn run_watching<F: FnMut(&str)>(cmd: &str, mut on_output: F) -> io::Result<(i32, String, String)>
+{
+    let mut command = shell_command(cmd);
+    command.stdin(Stdio::inherit()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    // in the queue, give the command its own process group so cancelling can stop everything it started.
+    // on the command line it stays in ours, so ctrl+c reaches it
+    if cancel::in_job()
+    {
+        new_process_group(&mut command);
+    }
+    let mut child = command.spawn()?;
+    let (lines_sender, lines) = mpsc::channel();
+    let stdout = read_in_background(child.stdout.take(), Some(lines_sender));
+    let stderr = read_in_background(child.stderr.take(), None);
+    let status = loop
+    {
+        lines.try_iter().for_each(|line| on_output(&line));
+        if let Some(status) = child.try_wait()?
+        {
+            break status;
+        }
+        if cancel::cancelled()
+        {
+            // the command itself can contain passwords, so it only goes to cmdlog
+            warn!("Stopping a running command because the job was cancelled");
+            info!(target: "cmdlog", "Stopping because the job was cancelled: {}", cmd);
+            terminate(&mut child);
+            let _ = stdout.join();
+            let _ = stderr.join();
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+        }
+        thread::sleep(cancel::POLL_INTERVAL);
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    lines.try_iter().for_each(|line| on_output(&line));
+    Ok((status.code().unwrap_or(-1), stdout, stderr.join().unwrap_or_default()))
+}
+
+/**
+Read everything from a pipe on another thread.
+
+# Arguments
+* `lines` - Where to send each line as it's read, if anywhere
+
+# Returns
+The thread, which returns everything that was read.
+*/
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>, lines: Option<mpsc::Sender<String>>) -> thread::JoinHandle<String>
+{
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let mut p = match pipe
+        {
+            Some(p) => p,
+            None => {return String::new();}
+        };
+        let mut buf = [0u8; 8192];
+        let mut line_start = 0;
+        loop
+        {
+            let n = match p.read(&mut buf)
+            {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break
+            };
+            bytes.extend_from_slice(&buf[..n]);
+            if let Some(sender) = &lines
+            {
+                while let Some(end) = bytes[line_start..].iter().position(|b| *b == b'\n' || *b == b'\r')
+                {
+                    let _ = sender.send(String::from_utf8_lossy(&bytes[line_start..line_start + end]).into_owned());
+                    line_start += end + 1;
+                }
+            }
+        }
+        if let Some(sender) = &lines
+        {
+            if line_start < bytes.len()
+            {
+                let _ = sender.send(String::from_utf8_lossy(&bytes[line_start..]).into_owned());
+            }
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+/**
+Ask a command to exit, then kill it if it hasn't after a while, and wait for it.
+*/
+fn terminate(child: &mut Child)
+{
+    signal_group(child, false);
+    let deadline = Instant::now() + TERMINATE_GRACE;
+    while Instant::now() < deadline
+    {
+        if let Ok(Some(_)) = child.try_wait()
+        {
+            return;
+        }
+        thread::sleep(cancel::POLL_INTERVAL);
+    }
+    signal_group(child, true);
+    let _ = child.wait();
+}
+
+#[cfg(target_family = "unix")]
+fn shell_command(cmd: &str) -> Command
+{
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+#[cfg(not(target_family = "unix"))]
+fn shell_command(cmd: &str) -> Command
+{
+    let mut command = Command::new("cmd.exe");
+    command.arg("/C").arg(cmd);
+    command
+}
+
+#[cfg(target_family = "unix")]
+fn new_process_group(command: &mut Command)
+{
+    std::os::unix::process::CommandExt::process_group(command, 0);
+}
+
+#[cfg(not(target_family = "unix"))]
+fn new_process_group(_command: &mut Command)
+{
+}
+
+/**
+Send SIGTERM, or SIGKILL if `kill`, to the command and everything in its process group.
+*/
+#[cfg(target_family = "unix")]
+fn signal_group(child: &mut Child, kill: bool)
+{
+    use nix::{sys::signal::{kill as send, killpg, Signal}, unistd::Pid};
+    let signal = if kill {Signal::SIGKILL} else {Signal::SIGTERM};
+    let pid = Pid::from_raw(child.id() as i32);
+    // when not in a group of its own, only the command itself can be signalled
+    if killpg(pid, signal).is_err()
+    {
+        let _ = send(pid, signal);
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+fn signal_group(child: &mut Child, _kill: bool)
+{
+    let _ = child.kill();
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn runs_commands()
+    {
+        let (code, stdout, stderr) = run("echo out; echo err >&2; exit 3").unwrap();
+        assert_eq!((code, stdout.as_str(), stderr.as_str()), (3, "out\n", "err\n"));
+
+        let mut lines = Vec::new();
+        let (code, stdout, _) = run_watching("printf 'one\\rtwo\\nthree'", |line| lines.push(line.to_string())).unwrap();
+        assert_eq!((code, stdout.as_str()), (0, "one\rtwo\nthree"));
+        assert_eq!(lines, ["one", "two", "three"]);
+    }
+}
@@ -0,0 +1,227 @@
+use log::{error, /*warn,*/ info/*, debug, trace, log, Level*/};
+use std::{fs, path::PathBuf};
+
+use crate::upload::dir_symlink;
+
+/*
+Layout of the backup storage for one source:
+
+    {storage_dir}/sources/{name}/snapshots/{timestamp}/{path}/...   one directory per sync run
+    {storage_dir}/sources/{name}/latest -> snapshots/{timestamp}    pointer to the newest complete run
+    {storage_dir}/sources/{name}/snapshots/{timestamp}.partial/...  a run where some of the paths failed to sync
+
+Unchanged files are hard-linked between generations by rsync --link-dest, so each snapshot
+only costs the space of what changed since the one before it. A partial run is kept to link the next one against,
+until retention deletes it once a newer run exists.
+Storage created by older versions used {storage_dir}/sources/{name}/paths/{path}/... instead;
+that directory is still used as the link base for the first snapshot so nothing has to be copied again.
+*/
+
+/**
+Directory containing everything stored for a source.
+*/
+pub fn source_dir(storage_dir: &str, source_name: &str) -> PathBuf
+{
+    let mut dir = PathBuf::from(storage_dir);
+    dir.push("sources");
+    dir.push(source_name);
+    dir
+}
+
+/**
+Directory of one snapshot generation of a source.
+*/
+pub fn snapshot_dir(storage_dir: &str, source_name: &str, timestamp: i64) -> PathBuf
+{
+    let mut dir = source_dir(storage_dir, source_name);
+    dir.push("snapshots");
+    dir.push(timestamp.to_string());
+    dir
+}
+
+/**
+The `latest` pointer of a source. Reading through it always gets the newest complete snapshot.
+*/
+pub fn latest_link(storage_dir: &str, source_name: &str) -> PathBuf
+{
+    let mut link = source_dir(storage_dir, source_name);
+    link.push("latest");
+    link
+}
+
+/**
+The directory used by versions of Redundinator from before snapshots existed.
+*/
+pub fn legacy_dir(storage_dir: &str, source_name: &str) -> PathBuf
+{
+    let mut dir = source_dir(storage_dir, source_name);
+    dir.push("paths");
+    dir
+}
+
+/**
+Get the timestamps of all the snapshots of a source, oldest first.
+
+This includes a snapshot currently being written, if any. Use `latest_snapshot` to find the newest complete one.
+*/
+pub fn list_snapshots(storage_dir: &str, source_name: &str) -> Vec<i64>
+{
+    let mut dir = source_dir(storage_dir, source_name);
+    dir.push("snapshots");
+    let entries = match fs::read_dir(&dir)
+    {
+        Ok(e) => e,
+        Err(_) => {return vec!();} // no snapshots taken yet
+    };
+    let mut timestamps: Vec<i64> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_string_lossy().parse::<i64>().ok())
+        .collect();
+    timestamps.sort_unstable();
+    timestamps
+}
+
+/**
+Get the timestamp of the snapshot the `latest` pointer of a source refers to.
+
+# Returns
+The timestamp, or None if the source has never completed a snapshot.
+*/
+pub fn latest_snapshot(storage_dir: &str, source_name: &str) -> Option<i64>
+{
+    let target = fs::read_link(latest_link(storage_dir, source_name)).ok()?;
+    target.file_name()?.to_string_lossy().parse::<i64>().ok()
+}
+
+/**
+Point the `latest` pointer of a source at the given snapshot.
+
+The new link is created beside the old one and renamed over it, so anything reading through `latest` never sees it missing.
+
+# Returns
+bool for whether it succeeded. Failures are logged.
+*/
+pub fn set_latest(storage_dir: &str, source_name: &str, timestamp: i64) -> bool
+{
+    let link = latest_link(storage_dir, source_name);
+    let mut tmp_link = source_dir(storage_dir, source_name);
+    tmp_link.push("latest.tmp");
+    let target = format!("snapshots/{timestamp}");
+
+    if fs::symlink_metadata(&tmp_link).is_ok()
+    {
+        if let Err(e) = fs::remove_file(&tmp_link)
+        {
+            error!("Couldn't remove leftover temporary snapshot link: {} -- Error: {}", tmp_link.to_string_lossy(), e);
+            return false;
+        }
+    }
+    if !dir_symlink(&target, &tmp_link.to_string_lossy())
+    {
+        error!("Couldn't create link to snapshot {} for source: {}", target, source_name);
+        return false;
+    }
+    if let Err(e) = fs::rename(&tmp_link, &link)
+    {
+        error!("Couldn't move latest snapshot link into place for source: {} -- Error: {}", source_name, e);
+        return false;
+    }
+    info!("Latest snapshot for source {} is now {}", source_name, timestamp);
+    true
+}
+
+/**
+Directory of a snapshot generation where some of the paths failed to sync.
+*/
+pub fn partial_dir(storage_dir: &str, source_name: &str, timestamp: i64) -> PathBuf
+{
+    let mut dir = source_dir(storage_dir, source_name);
+    dir.push("snapshots");
+    dir.push(format!("{timestamp}.partial"));
+    dir
+}
+
+/**
+Get the timestamps of all the partial snapshots of a source, oldest first.
+*/
+pub fn list_partials(storage_dir: &str, source_name: &str) -> Vec<i64>
+{
+    let mut dir = source_dir(storage_dir, source_name);
+    dir.push("snapshots");
+    let entries = match fs::read_dir(&dir)
+    {
+        Ok(e) => e,
+        Err(_) => {return vec!();}
+    };
+    let mut timestamps: Vec<i64> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_string_lossy().strip_suffix(".partial")?.parse::<i64>().ok())
+        .collect();
+    timestamps.sort_unstable();
+    timestamps
+}
+
+/**
+Mark a snapshot as partial, so it isn't taken for a complete one. It's still used as a link base until there's a newer snapshot.
+
+# Returns
+bool for whether it succeeded. Failures are logged.
+*/
+pub fn mark_partial(storage_dir: &str, source_name: &str, timestamp: i64) -> bool
+{
+    let partial = partial_dir(storage_dir, source_name, timestamp);
+    match fs::rename(snapshot_dir(storage_dir, source_name, timestamp), &partial)
+    {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Couldn't mark snapshot {} of source {} as partial: {} -- Error: {}", timestamp, source_name, partial.to_string_lossy(), e);
+            false
+        }
+    }
+}
+
+/**
+Find the directories a new snapshot should hard-link unchanged files against, for rsync to look in, in order.
+
+# Returns
+The absolute paths of the newest partial snapshot if it's newer than the latest one, then of the latest snapshot,
+or of the legacy directory if the source was last synced before snapshots existed. Empty if there's nothing to link against.
+*/
+pub fn link_bases(storage_dir: &str, source_name: &str) -> Vec<PathBuf>
+{
+    let mut bases = Vec::new();
+    if let Some(partial) = list_partials(storage_dir, source_name).last()
+    {
+        if latest_snapshot(storage_dir, source_name).is_none_or(|latest| *partial > latest)
+        {
+            bases.push(partial_dir(storage_dir, source_name, *partial));
+        }
+    }
+    let latest = latest_link(storage_dir, source_name);
+    bases.push(if latest.is_dir() {latest} else {legacy_dir(storage_dir, source_name)});
+    bases.into_iter().filter_map(|base| fs::canonicalize(base).ok()).collect()
+}
+
+/**
+Find the directory holding the data that should go into an export of a source.
+
+# Returns
+The latest snapshot, or for storage that hasn't been synced since snapshots were introduced, the legacy directory.
+None if there's neither: partial snapshots are never exported.
+*/
+pub fn export_base(storage_dir: &str, source_name: &str) -> Option<PathBuf>
+{
+    let latest = latest_link(storage_dir, source_name);
+    let legacy = legacy_dir(storage_dir, source_name);
+    if latest.is_dir()
+    {
+        Some(latest)
+    }else if legacy.is_dir()
+    {
+        Some(legacy)
+    }else{
+        None
+    }
+}
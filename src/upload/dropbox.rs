@@ -2,8 +2,10 @@ use log::{error, warn, info/*, debug, trace, log, Level*/};
 use std::path::Path;
 use dropbox_sdk::{oauth2, oauth2::{Authorization, Oauth2Type, PkceCode}, default_client::NoauthDefaultClient };
 use crate::backoff::calculate_backoff_series;
-use crate::settings::app_settings::Settings;
-use crate::upload::list_files;
+use crate::cancel;
+use crate::progress;
+use crate::settings::app_settings::{Dropbox, Settings};
+use crate::upload::{check_cancelled, file_name, open_download, RemoteFile, RemoteHash, UploadError, UploadTarget};
 use crate::tokens::{get_token, save_token};
 
 /**
@@ -38,11 +40,33 @@ impl From<PkceCodePub> for PkceCode
     }
 }
 
-pub fn dropbox_auth(settings: &Settings)
+/**
+Name under which a token belonging to a dropbox upload target is saved in the tokens DB.
+*/
+fn token_name(base: &str, target_name: &str) -> String
+{
+    format!("{base}:{target_name}")
+}
+
+/**
+Get a token belonging to a dropbox upload target from the tokens DB.
+Falls back to the name used before there could be more than one dropbox target, so existing authorizations keep working.
+*/
+fn get_target_token(tokens_file: &str, base: &str, target_name: &str) -> Result<String, sqlite::Error>
+{
+    let value = get_token(tokens_file, &token_name(base, target_name))?;
+    if value.is_empty()
+    {
+        return get_token(tokens_file, base);
+    }
+    Ok(value)
+}
+
+pub fn dropbox_auth(target_name: &str, config: &Dropbox, settings: &Settings)
 {
     let tokens_file = &settings.startup.tokens_file;
-    let app_key = &settings.dropbox.app_key;
-    let config_oauth_token = settings.dropbox.oauth_token.trim().to_string();
+    let app_key = &config.app_key;
+    let config_oauth_token = settings.action.oauth_token.trim().to_string();
 
     /* dropbox documentation says these are the same thing,
        so we explicity set it here to avoid mixing up the terminology throughout the code
@@ -53,7 +77,7 @@ pub fn dropbox_auth(settings: &Settings)
     let mut interactive_oauth_token = String::new();
     
     let pkce_code_token_name = "dropbox_PKCE_code";
-    let retrieved_pkce_code = match get_token(tokens_file, pkce_code_token_name)
+    let retrieved_pkce_code = match get_target_token(tokens_file, pkce_code_token_name, target_name)
     {
         Ok(file_code) => {
             if file_code.is_empty() {
@@ -75,7 +99,7 @@ pub fn dropbox_auth(settings: &Settings)
             let new_code = PkceCode::new();
             let pk_pub = PkceCodePub::from(new_code.clone());
             
-            save_token(tokens_file, pkce_code_token_name, &pk_pub.code).unwrap();
+            save_token(tokens_file, &token_name(pkce_code_token_name, target_name), &pk_pub.code).unwrap();
             new_code
         }
     };
@@ -83,9 +107,9 @@ pub fn dropbox_auth(settings: &Settings)
 
     let auth_code = if config_oauth_token.is_empty()
     {
-        info!("Performing dropbox interactive auth");
+        info!("Performing dropbox interactive auth for upload target: {}", target_name);
         let auth_url = oauth2::AuthorizeUrlBuilder::new(client_id, &flow_type).build();
-        println!("To authorize dropbox, go to the following URL to get a token.\n{auth_url}\nEnter the token in one of two ways:\n1. Type in the token now\n2. Press enter to cancel, then run this action later while passing the token using the option --dropbox_oauth_token");
+        println!("To authorize dropbox, go to the following URL to get a token.\n{auth_url}\nEnter the token in one of two ways:\n1. Type in the token now\n2. Press enter to cancel, then run this action later while passing the token using the option --oauth_token");
         match std::io::stdin().read_line(&mut interactive_oauth_token)
         {
             Ok(_) => {
@@ -112,7 +136,7 @@ pub fn dropbox_auth(settings: &Settings)
             info!("Dropbox auth succeeded.");
             if let Some(state) = auth.save()
             {
-                if let Err(e) = save_token(tokens_file, "dropbox_auth_state", &state)
+                if let Err(e) = save_token(tokens_file, &token_name("dropbox_auth_state", target_name), &state)
                 {
                     error!("Couldn't save dropbox auth state in tokens DB: {}", e)
                 }else{
@@ -125,130 +149,179 @@ pub fn dropbox_auth(settings: &Settings)
     }
 }
 
-pub fn dropbox_up(source_name: &str, settings: &Settings)
+/**
+Upload target storing files in a folder of a Dropbox account.
+*/
+pub struct DropboxTarget
 {
-    info!("Starting dropbox upload of exports for source: {}", source_name);
+    name: String,
+    client: Arc<UserAuthDefaultClient>,
+    dest: String
+}
 
-    let dest: String = if settings.dropbox.dest_path.as_bytes()[0] == b'/'
+impl DropboxTarget
+{
+    /**
+    Start a client using the authorization saved by dropbox_auth.
+    */
+    pub fn connect(name: &str, config: &Dropbox, settings: &Settings) -> Result<DropboxTarget, UploadError>
     {
-        settings.dropbox.dest_path.clone()
-    }else{
-        ["/", &settings.dropbox.dest_path].concat()
-    };
-    let tokens_file = &settings.startup.tokens_file;
-    let app_key = &settings.dropbox.app_key;
-
-    let client_id = app_key;
+        let mut dest: String = if config.dest_path.starts_with('/')
+        {
+            config.dest_path.clone()
+        }else{
+            ["/", &config.dest_path].concat()
+        };
+        while dest.ends_with('/') { dest.pop(); }
+        let tokens_file = &settings.startup.tokens_file;
+        let client_id = &config.app_key;
 
-    // retrieve our saved dropbox authentication state and use it to startup a client
-    let auth_state = match get_token(tokens_file, "dropbox_auth_state")
-    {
-        Err(e) => {
-            error!("Couldn't get dropbox auth state from tokens DB: {}", e);
-            return;
-        },
-        Ok(state) => {
-            if state.is_empty()
-            {
-                error!("There is no saved dropbox authorization. Use the auth_dropbox action to start interactive authorization.");
-                return;
+        // retrieve our saved dropbox authentication state and use it to startup a client
+        let auth_state = match get_target_token(tokens_file, "dropbox_auth_state", name)
+        {
+            Err(e) => {
+                return Err(UploadError::Systemic(format!("Couldn't get dropbox auth state from tokens DB: {e}")));
+            },
+            Ok(state) => {
+                if state.is_empty()
+                {
+                    return Err(UploadError::Systemic(String::from("There is no saved dropbox authorization. Use the auth_upload action to start interactive authorization.")));
+                }
+                state
             }
-            state
-        }
-    };
-    let auth = match Authorization::load(client_id.to_string(), &auth_state)
+        };
+        let auth = match Authorization::load(client_id.to_string(), &auth_state)
+        {
+            Some(a) => a,
+            None => {
+                return Err(UploadError::Systemic(String::from("Retrieved dropbox auth state was not loadable. Use the auth_upload action to do a new interactive authorization.")));
+            }
+        };
+
+        Ok(DropboxTarget{
+            name: name.to_string(),
+            client: Arc::new(UserAuthDefaultClient::new(auth)),
+            dest
+        })
+    }
+
+    fn remote_path(&self, remote_name: &str) -> String
     {
-        Some(a) => a,
-        None => {
-            error!("Retrieved dropbox auth state was not loadable. Use the auth_dropbox action to do a new interactive authorization.");
-            return;
-        }
-    };
-    let client = Arc::new(UserAuthDefaultClient::new(auth));
+        format!("{}/{remote_name}", self.dest)
+    }
+}
 
-    // iterate the files to be uploaded
-    let files = list_files(source_name, settings);
-    for file_str in files
+impl UploadTarget for DropboxTarget
+{
+    fn name(&self) -> &str
     {
-        // calculate the destination path from configuration
-        let source_path = Path::new(&file_str);
-        let basename = match source_path.file_name()
-        {
-            Some(n)=> match n.to_str(){
-                Some(f) => f,
-                None => { error!("Failed to process filename: {}",file_str); continue; }
-            },
-            None => { error!("Failed to get filename from path: {}",file_str); continue; }
-        };
-        let dest_file = format!("{dest}/{basename}");
+        &self.name
+    }
 
-        // open the source file for reading
-        let source_file = match File::open(source_path)
+    fn list_remote(&self) -> Result<Vec<RemoteFile>, UploadError>
+    {
+        let mut listed = match files::list_folder(self.client.as_ref(), &files::ListFolderArg::new(self.dest.clone()))
         {
-            Ok(f) => f,
-            Err(e) => {error!("Failed to open file: {}", e); continue;}
+            Ok(Ok(r)) => r,
+            Ok(Err(files::ListFolderError::Path(files::LookupError::NotFound))) => {return Ok(vec!());},
+            Ok(Err(e)) => {return Err(UploadError::Failure(format!("Couldn't list dropbox folder {}: {e}", self.dest)));},
+            Err(e) => {return Err(UploadError::Systemic(format!("Couldn't list dropbox folder {}: {e}", self.dest)));}
         };
-        let source_file_size = match source_file.metadata()
+        let mut remote_files = Vec::new();
+        loop
         {
-            Ok(metadata) => metadata.len(),
-            Err(e) => {error!("Failed to get metadata of file: {}", e); continue;}
-        };
-        drop(source_file);
+            for entry in listed.entries
+            {
+                if let files::Metadata::File(metadata) = entry
+                {
+                    remote_files.push(remote_file(metadata));
+                }
+            }
+            if !listed.has_more {break;}
+            listed = match files::list_folder_continue(self.client.as_ref(), &files::ListFolderContinueArg::new(listed.cursor))
+            {
+                Ok(Ok(r)) => r,
+                Ok(Err(e)) => {return Err(UploadError::Failure(format!("Couldn't continue listing dropbox folder {}: {e}", self.dest)));},
+                Err(e) => {return Err(UploadError::Systemic(format!("Couldn't continue listing dropbox folder {}: {e}", self.dest)));}
+            };
+        }
+        Ok(remote_files)
+    }
 
-        
-        // check for conflicts with the destination path and normalize if necessary
-        let dest_path = match get_destination_path(client.as_ref(), &dest_file, source_path, &source_file_size) 
-        {
-            PathNormalizationResult::NewFile(p) => p,
-            PathNormalizationResult::Replace(p) => p,
-            PathNormalizationResult::SkipMatching => { info!("File already uploaded, skipping: {}", basename); continue; }
-            PathNormalizationResult::Err(e) => { error!("Failed to normalize destination path: {}", e); continue; }
+    fn stat(&self, remote_name: &str) -> Result<Option<RemoteFile>, UploadError>
+    {
+        let dest_path = self.remote_path(remote_name);
+
+        // Ask the API about our proposed destination path.
+        let mut backoff = vec!(0.0);
+        backoff.append(&mut calculate_backoff_series(0.5, 1.5, 6, 30.0, 60.0, 0.5));
+        let mut backoff_index = 0;
+        let meta_result = loop{
+            match files::get_metadata(self.client.as_ref(), &files::GetMetadataArg::new(dest_path.clone()))
+            {
+                Ok(r) => {break r;},
+                Err(e)=> {
+                    backoff_index += 1;
+                    if backoff_index >= backoff.len() {return Err(UploadError::Failure(format!("Retries exceeded while looking up destination. Last error: {e}")));}
+                    let time = backoff[backoff_index];
+                    info!("Waiting for {}", time);
+                    sleep(Duration::from_secs_f32(time));
+                }
+            };
         };
 
+        match meta_result {
+            Ok(files::Metadata::File(metadata)) => Ok(Some(remote_file(metadata))),
+            Ok(files::Metadata::Folder(_)) => Err(UploadError::Failure(format!("Destination is a folder: {dest_path}"))),
+            Ok(files::Metadata::Deleted(_)) => Err(UploadError::Failure("unexpected deleted metadata received".to_string())),
+            Err(files::GetMetadataError::Path(files::LookupError::NotFound)) => Ok(None),
+            Err(e) => Err(UploadError::Failure(format!("Error looking up destination: {e}")))
+        }
+    }
+
+    fn upload(&self, local_path: &Path) -> Result<(), UploadError>
+    {
+        /* You might expect that we can check the existing file at the destination to determine whether to resume. However:
+           It's not possible to determine from the metadata if a resume is necessary, and if so, what byte to resume from (there may be gaps before the last byte present so length doesn't help)
+           The information we need to resume is returned from the failed upload. Therefore we use this for automated retry/backoff and if it fails enough to give up then next time we'll just start over.
+           This also means the resume attempt cycle is done in a contained way so that we don't get problems related to file state affecting later invocations of the program.
+        */
+        let dest_path = self.remote_path(&file_name(local_path)?);
+
         let mut backoff = vec!(0.0);
         backoff.append(&mut calculate_backoff_series(0.5, 1.5, 10, 60.0, 600.0, 0.5));
         let mut resume: Option<Resume> = None;
-        let mut success = false;
         let mut retry_count = 0;
         while retry_count < backoff.len()
         {
             let time = backoff[retry_count];
             info!("Waiting for {}", time);
-            sleep(Duration::from_secs_f32(time));
+            cancel::sleep(Duration::from_secs_f32(time));
+            check_cancelled()?;
 
-            let source_file = match File::open(source_path)
+            let source_file = match File::open(local_path)
             {
                 Ok(f) => f,
-                Err(e) => {error!("Failed to open file: {}", e); break;}
+                Err(e) => {return Err(UploadError::Failure(format!("Failed to open file: {e}")));}
             };
-            match upload_file(client.clone(), source_file, dest_path.clone(), resume.clone())
+            match upload_file(self.client.clone(), source_file, dest_path.clone(), resume.clone())
             {
                 Ok(()) => {
-                    info!("Uploaded file: {}", file_str);
-                    success = true;
-                    break;
+                    return Ok(());
                 },
                 Err(failure) => {
                     match failure
                     {
                         UploadFailure::Nonresumable(s) => {
-                            error!("File upload error: {}", s);
-                            break;
+                            return Err(UploadError::Failure(format!("File upload error: {s}")));
                         },
                         UploadFailure::Resumable(r) => {
                             // Only increment retry count and use exponential backoff when there are repeated failures at the same progress level
                             // As long as progress is happening, reset the retry count and only wait the minimum time before resuming
                             match resume
                             {
-                                None => {retry_count = 0;},
-                                Some(resume_data) => {
-                                    if resume_data.start_offset == r.start_offset
-                                    {
-                                        retry_count += 1;
-                                    }else{
-                                        retry_count = 0;
-                                    }
-                                }
+                                Some(resume_data) if resume_data.start_offset == r.start_offset => {retry_count += 1;},
+                                _ => {retry_count = 0;}
                             }
                             warn!("Upload interrupted! Resume data: {}", r.start_offset);
                             resume = Some(r);
@@ -257,14 +330,49 @@ pub fn dropbox_up(source_name: &str, settings: &Settings)
                 }
             }
         }
-        if !success
+        Err(UploadError::Failure(String::from("Retries exceeded")))
+    }
+
+    fn delete(&self, remote_name: &str) -> Result<(), UploadError>
+    {
+        match files::delete_v2(self.client.as_ref(), &files::DeleteArg::new(self.remote_path(remote_name)))
         {
-            error!("Failed to upload file {}", file_str);
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(UploadError::Failure(format!("Couldn't delete {remote_name} from dropbox: {e}"))),
+            Err(e) => Err(UploadError::Failure(format!("Couldn't delete {remote_name} from dropbox: {e}")))
         }
-        
     }
 
-    info!("Finished dropbox upload of exports for source: {}", source_name);
+    fn download(&self, remote_name: &str, local_path: &Path, offset: u64) -> Result<(), UploadError>
+    {
+        let range_start = if offset > 0 {Some(offset)} else {None};
+        let result = match files::download(self.client.as_ref(), &files::DownloadArg::new(self.remote_path(remote_name)), range_start, None)
+        {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => {return Err(UploadError::Failure(format!("Couldn't download {remote_name} from dropbox: {e}")));},
+            Err(e) => {return Err(UploadError::Failure(format!("Couldn't download {remote_name} from dropbox: {e}")));}
+        };
+        let mut body = match result.body
+        {
+            Some(b) => b,
+            None => {return Err(UploadError::Failure(format!("Dropbox sent no content for {remote_name}")));}
+        };
+        let mut local_file = open_download(local_path, offset)?;
+        if let Err(e) = std::io::copy(&mut body, &mut local_file)
+        {
+            return Err(UploadError::Failure(format!("Download of {remote_name} from dropbox interrupted: {e}")));
+        }
+        Ok(())
+    }
+}
+
+fn remote_file(metadata: files::FileMetadata) -> RemoteFile
+{
+    RemoteFile{
+        name: metadata.name,
+        size: metadata.size,
+        hash: metadata.content_hash.map(RemoteHash::DropboxContentHash)
+    }
 }
 
 
@@ -280,7 +388,7 @@ use std::io::{Seek, SeekFrom};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
 use std::thread::sleep;
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Duration, SystemTime};
 
 /// How many blocks to upload in parallel.
 const PARALLELISM: usize = 20;
@@ -309,94 +417,6 @@ impl std::str::FromStr for Resume {
     }
 }
 
-enum PathNormalizationResult
-{
-    NewFile(String), // Path did not already exist, upload new file
-    //Resume(String),  // Partial file exists from an interrupted upload, resume it
-    Replace(String), // Wrong file exists at this path, overwrite it
-    SkipMatching,    // Matching file already exists, do nothing
-    Err(String)      // error that's bad enough we have to skip the file for now
-}
-
-impl PathNormalizationResult
-{
-    pub fn from_path(client: &UserAuthDefaultClient, dest_path: &str, source_filename: &str, source_file_size: &u64) -> PathNormalizationResult
-    {
-        // Ask the API about our proposed destination path.
-        let mut backoff = vec!(0.0);
-        backoff.append(&mut calculate_backoff_series(0.5, 1.5, 6, 30.0, 60.0, 0.5));
-        let mut backoff_index = 0;
-        let meta_result = loop{
-            match files::get_metadata(client, &files::GetMetadataArg::new(dest_path.to_owned()))
-            {
-                Ok(r) => {break r;},
-                Err(e)=> {
-                    backoff_index += 1;
-                    if backoff_index >= backoff.len() {return PathNormalizationResult::Err(format!("Retries exceeded while looking up destination. Last error: {e}"));}
-                    let time = backoff[backoff_index];
-                    info!("Waiting for {}", time);
-                    sleep(Duration::from_secs_f32(time));
-                }
-            };
-        };
-
-        match meta_result {
-            Ok(files::Metadata::File(metadata)) => {
-                /* You might expect that we can determine whether to resume here. However:
-                   It's not possible to determine from the metadata if a resume is necessary, and if so, what byte to resume from (there may be gaps before the last byte present so length doesn't help)
-                   The information we need to resume is returned from the failed upload. Therefore the upload function uses this for automated retry/backoff and if it fails enough to give up then next time we'll just start over.
-                   This also means the resume attempt cycle is done in a contained way so that we don't get problems related to file state affecting later invocations of the program.
-                */
-
-                /* Determine if an existing file matches our file, so we can know to skip or replace it.
-                   It would be better to use a hash, and metadata does give us one for the server side file, but computing a hash of the local file is costly enough that we'd have to do it ahead of time to use it here
-                */
-                if metadata.size == *source_file_size
-                {
-                    PathNormalizationResult::SkipMatching
-                }else{
-                    PathNormalizationResult::Replace(dest_path.to_string())
-                }
-            }
-            Ok(files::Metadata::Folder(_)) => {
-                // Given destination path points to a folder, so append the source path's filename and
-                // use that as the actual destination.
-
-                let mut path = dest_path.to_owned();
-                path.push('/');
-                path.push_str(source_filename);
-
-                // The new proposed path must also be checked in this way so we recurse
-                PathNormalizationResult::from_path(client, &path, source_filename, source_file_size)
-            }
-            Ok(files::Metadata::Deleted(_)) => PathNormalizationResult::Err("unexpected deleted metadata received".to_string()),
-            Err(files::GetMetadataError::Path(files::LookupError::NotFound)) => PathNormalizationResult::NewFile(dest_path.to_string()),
-            Err(e) => PathNormalizationResult::Err(format!("Error looking up destination: {e}"))
-        }
-    }
-}
-
-/// Figure out if destination is a folder or not and change the destination path accordingly.
-fn get_destination_path(client: &UserAuthDefaultClient, given_path: &str, source_path: &Path, source_file_size: &u64) -> PathNormalizationResult
-{
-    // The dropbox destination path -- mut because we may need to do some pre-normalization before checking its validity with the API
-    let mut dest_path = given_path.to_string();
-
-    //Check that the source file has a valid filename
-    let filename = match source_path.file_name()
-    {
-        Some(p) => p,
-        None => {return PathNormalizationResult::Err(format!("invalid source path {source_path:?} has no filename"));}
-    }.to_string_lossy();
-
-    // When we check the dest path, we can't get metadata for the root, so use the source path filename.
-    if dest_path == "/" {
-        dest_path.push_str(&filename);
-    }
-
-    PathNormalizationResult::from_path(client, &dest_path, &filename, source_file_size)
-}
-
 /// Keep track of some shared state accessed / updated by various parts of the uploading process.
 struct UploadSession {
     session_id: String,
@@ -563,7 +583,6 @@ fn upload_file(
     // BLOCK_SIZE, we'll need to upload an empty buffer when closing the session.
     let last_block = Arc::new(Mutex::new((source_len, vec![])));
 
-    let start_time = Instant::now();
     let upload_result = {
         let client = client.clone();
         let session = session.clone();
@@ -574,6 +593,9 @@ fn upload_file(
             BLOCK_SIZE * BLOCKS_PER_REQUEST,
             PARALLELISM,
             Arc::new(move |block_offset, data: &[u8]| -> Result<(), String> {
+                if cancel::cancelled() {
+                    return Err(String::from("cancelled"));
+                }
                 let append_arg = session.append_arg(block_offset);
                 if data.len() != BLOCK_SIZE * BLOCKS_PER_REQUEST {
                     // This must be the last block. Only the last one is allowed to be not 4 MiB
@@ -589,7 +611,6 @@ fn upload_file(
                     client.as_ref(),
                     &append_arg,
                     data,
-                    start_time,
                     session.as_ref(),
                     resume.as_ref(),
                 );
@@ -610,7 +631,7 @@ fn upload_file(
     let mut arg = session.append_arg(last_block_offset);
     arg.close = true;
     if let Err(e) = upload_block_with_retry(
-        client.as_ref(), &arg, &last_block_data, start_time, session.as_ref(), resume.as_ref())
+        client.as_ref(), &arg, &last_block_data, session.as_ref(), resume.as_ref())
     {
         warn!("failed to close session: {}", e);
         // But don't error out; try committing anyway. It could be we're resuming a file where we
@@ -642,17 +663,14 @@ fn upload_file(
 
 /// Upload a single block, retrying a few times if an error occurs.
 ///
-/// Prints progress and upload speed, and updates the UploadSession if successful.
+/// Reports progress, and updates the UploadSession if successful.
 fn upload_block_with_retry(
     client: &UserAuthDefaultClient,
     arg: &files::UploadSessionAppendArg,
     buf: &[u8],
-    start_time: Instant,
     session: &UploadSession,
     resume: Option<&Resume>,
 ) -> Result<(), String> {
-    let block_start_time = Instant::now();
-
     /* If we get a rate limit message, wait the amount of time they tell us before retrying
        On any other errors, implement our own retry and backoff logic
     */
@@ -665,12 +683,15 @@ fn upload_block_with_retry(
 
     while rate_limit_retries < max_rate_limits && other_error_retries < max_other_errors
     {
+        if cancel::cancelled() {
+            return Err(String::from("cancelled"));
+        }
         match files::upload_session_append_v2(client, arg, buf) {
             Ok(Ok(())) => { break; }
             Err(dropbox_sdk::Error::RateLimited { reason, retry_after_seconds }) => {
-                eprintln!("rate-limited ({reason}), waiting {retry_after_seconds} seconds");
+                warn!("Rate limited by Dropbox ({reason}), waiting {retry_after_seconds} seconds");
                 if retry_after_seconds > 0 {
-                    sleep(Duration::from_secs(u64::from(retry_after_seconds)));
+                    cancel::sleep(Duration::from_secs(u64::from(retry_after_seconds)));
                 }
                 rate_limit_retries += 1;
             }
@@ -678,7 +699,7 @@ fn upload_block_with_retry(
                 warn!("Error calling upload_session_append: {error:?}");
                 let wait_time = backoff[other_error_retries];
                 warn!("waiting for {wait_time}s");
-                sleep(Duration::from_secs_f32(wait_time));
+                cancel::sleep(Duration::from_secs_f32(wait_time));
                 other_error_retries += 1;
             }
         }
@@ -692,50 +713,13 @@ fn upload_block_with_retry(
         return Err("Max retries exceeded".to_string());
     }
 
-    let now = Instant::now();
-    let block_dur = now.duration_since(block_start_time);
-    let overall_dur = now.duration_since(start_time);
-
     let block_bytes = buf.len() as u64;
     let bytes_sofar = session.bytes_transferred.fetch_add(block_bytes, SeqCst) + block_bytes;
-
-    let percent = (resume.map(|r| r.start_offset).unwrap_or(0) + bytes_sofar) as f64
-        / session.file_size as f64 * 100.;
-
-    // This assumes that we have `PARALLELISM` uploads going at the same time and at roughly the
-    // same upload speed:
-    let block_rate = block_bytes as f64 / block_dur.as_secs_f64() * PARALLELISM as f64;
-
-    let overall_rate = bytes_sofar as f64 / overall_dur.as_secs_f64();
-
-    eprintln!("{:.01}%: {}Bytes uploaded, {}Bytes per second, {}Bytes per second average",
-        percent,
-        human_number(bytes_sofar),
-        human_number(block_rate as u64),
-        human_number(overall_rate as u64),
-        );
+    progress::file_position(resume.map(|r| r.start_offset).unwrap_or(0) + bytes_sofar);
 
     Ok(())
 }
 
-fn human_number(n: u64) -> String {
-    let mut f = n as f64;
-    let prefixes = ['k','M','G','T','E'];
-    let mut mag = 0;
-    while mag < prefixes.len() {
-        if f < 1000. {
-            break;
-        }
-        f /= 1000.;
-        mag += 1;
-    }
-    if mag == 0 {
-        format!("{n} ")
-    } else {
-        format!("{:.02} {}", f, prefixes[mag - 1])
-    }
-}
-
 fn iso8601(t: SystemTime) -> String {
     let timestamp: i64 = match t.duration_since(SystemTime::UNIX_EPOCH) {
         Ok(duration) => duration.as_secs() as i64,
@@ -4,44 +4,261 @@ extern crate rustls;
 
 use chrono::{DateTime, Duration, Utc};
 use google_drive3::{api::{File, Scope}, Delegate, DriveHub, hyper_util, yup_oauth2 as oauth2};
-use google_apis_common::{MethodInfo, Retry};
+use google_apis_common::{ContentRange, MethodInfo, Retry};
 use hyper_util::client::legacy::{Client, connect::HttpConnector};
 use hyper_rustls::HttpsConnector;
 use log::{error, /*warn,*/ info, /*debug,*/ trace, /*log, Level*/};
-use std::{fs, path::PathBuf};
+use std::{fs, io::Write, path::{Path, PathBuf}};
 
 type Hub = DriveHub<HttpsConnector<HttpConnector>>;
 
 use crate::backoff::calculate_backoff_series;
-use crate::settings::app_settings::Settings;
-use crate::{new_tokio_runtime, upload::list_files};
+use crate::cancel;
+use crate::new_tokio_runtime;
+use crate::progress;
+use crate::settings::app_settings::{GDrive, Settings};
+use crate::upload::{file_name, open_download, RemoteFile, RemoteHash, UploadError, UploadTarget};
 
 /**
-Upload exports to Google Drive for a given source.
+Upload target storing files in a Google Drive folder.
 
-If an auth token doesn't exist, this will give instructions on stdout and wait indefinitely to
-recieve the signal from Google that you've followed them. In other words, it will go
-interactive. However, this should only happen once as token refreshes are handled automatically.
+Connecting authenticates with a service account impersonating a Workspaces user through Domain-Wide Delegation, so no interactive steps are needed.
+*/
+pub struct GDriveTarget
+{
+    name: String,
+    runtime: tokio::runtime::Runtime,
+    hub: Hub,
+    dir_id: String
+}
 
-# Arguments
-* `source_name` - Name of the source for which to upload files.
-* `settings` - The whole settings object for the app.
-* `parent` - Destination folder on Google Drive. Note that this is the folder ID, not the name. Get this by calling get_parent.
+impl GDriveTarget
+{
+    pub fn connect(name: &str, config: &GDrive, settings: &Settings) -> Result<GDriveTarget, UploadError>
+    {
+        let runtime = match new_tokio_runtime()
+        {
+            Ok(r) => r,
+            Err(e) => {return Err(UploadError::Systemic(format!("Couldn't create tokio runtime! Error: {e}")));}
+        };
+        let hub = match runtime.block_on(connect(name, config, settings))
+        {
+            Some(h) => h,
+            None => {return Err(UploadError::Systemic(String::from("Couldn't connect to Google Drive")));}
+        };
+        Ok(GDriveTarget{name: name.to_string(), runtime, hub, dir_id: config.dir_id.clone()})
+    }
 
-# Returns
-bool for whether uploading was found to be possible. The actual uploads may or may not have succeeded, but if this is false no more uploads should be attempted.
+    /**
+    Search the target folder for a file by name.
+    */
+    async fn find(&self, remote_name: &str) -> Result<Option<File>, UploadError>
+    {
+        //search for the file: https://developers.google.com/drive/api/guides/search-files
+        let parent = &self.dir_id;
+        let query = format!("trashed = false and name = '{remote_name}' and '{parent}' in parents");
+        let (_, search_result) = match self.hub.files().list()
+            .supports_all_drives(true)
+            .spaces("drive")
+            .q(&query)
+            .include_items_from_all_drives(true)
+            .corpora("allDrives")
+            .param("fields", FILE_LIST_FIELDS)
+            .add_scope(Scope::Full)
+            .doit().await
+        {
+            Ok(r) => r,
+            Err(e) => {return Err(UploadError::Failure(format!("Couldn't search for file! Error: {e}")));}
+        };
+        if search_result.incomplete_search == Some(true) && search_result.files.is_none()
+        {
+            return Err(UploadError::Failure(format!("Unable to determine if gdrive file already exists: {remote_name}")));
+        }
+        Ok(search_result.files.and_then(|files| files.into_iter().next()))
+    }
+
+    async fn list_all(&self) -> Result<Vec<RemoteFile>, UploadError>
+    {
+        let query = format!("trashed = false and '{}' in parents", self.dir_id);
+        let mut remote_files = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop
+        {
+            let mut call = self.hub.files().list()
+                .supports_all_drives(true)
+                .spaces("drive")
+                .q(&query)
+                .include_items_from_all_drives(true)
+                .corpora("allDrives")
+                .page_size(1000)
+                .param("fields", FILE_LIST_FIELDS)
+                .add_scope(Scope::Full);
+            if let Some(t) = &page_token
+            {
+                call = call.page_token(t);
+            }
+            let (_, list_result) = match call.doit().await
+            {
+                Ok(r) => r,
+                Err(e) => {return Err(UploadError::Failure(format!("Couldn't list files in gdrive folder! Error: {e}")));}
+            };
+            remote_files.extend(list_result.files.unwrap_or_default().into_iter().filter_map(remote_file));
+            page_token = list_result.next_page_token;
+            if page_token.is_none() {break;}
+        }
+        Ok(remote_files)
+    }
+
+    async fn upload_async(&self, local_path: &Path) -> Result<(), UploadError>
+    {
+        let filename = local_path.to_string_lossy().into_owned();
+        let mime_str = "application/octet-stream"; //"application/octet-stream";
+        let mime_type: mime::Mime = match mime_str.parse() {Ok(f)=>f,Err(e)=>{return Err(UploadError::Systemic(format!("Couldn't parse mime type! Error: {e}")));}};
+        let file = match fs::File::open(local_path) {Ok(f)=>f,Err(e)=>{return Err(UploadError::Failure(format!("Couldn't open file for uploading! File: {filename} -- Error: {e}")));}};
+        let dest_filename = file_name(local_path)?;
+
+        // Drive allows multiple files with the same name in a folder, so get rid of the old one first
+        if let Some(existing) = self.find(&dest_filename).await?
+        {
+            info!("Replacing file in gdrive: {filename}");
+            self.delete_file(existing).await?;
+        }
+
+        let file_props = get_create_file(String::from("backup archive"), dest_filename, self.dir_id.clone());
+        match upload_file(&self.hub, filename, mime_type, file_props, file).await
+        {
+            UploadResult::Success => Ok(()),
+            UploadResult::Failure(e) => Err(UploadError::Failure(e)),
+            UploadResult::SystemicFailure(e) => Err(UploadError::Systemic(e))
+        }
+    }
+
+    async fn delete_file(&self, file: File) -> Result<(), UploadError>
+    {
+        let id = match file.id
+        {
+            Some(i) => i,
+            None => {return Err(UploadError::Failure(String::from("Google Drive returned a file without an ID")));}
+        };
+        match self.hub.files().delete(&id).supports_all_drives(true).add_scope(Scope::Full).doit().await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(UploadError::Failure(format!("Couldn't delete file from gdrive! Error: {e}")))
+        }
+    }
+
+    /**
+    The generated API can't ask for part of a file, so this makes the request itself, with the hub's credentials.
+    */
+    async fn download_async(&self, remote_name: &str, local_path: &Path, offset: u64) -> Result<(), UploadError>
+    {
+        let id = match self.find(remote_name).await?.and_then(|f| f.id)
+        {
+            Some(i) => i,
+            None => {return Err(UploadError::Failure(format!("File not found in gdrive: {remote_name}")));}
+        };
+        let token = match self.hub.auth.get_token(&[Scope::Full.as_ref()]).await
+        {
+            Ok(Some(t)) => t,
+            Ok(None) => {return Err(UploadError::Systemic(String::from("No gdrive access token available")));},
+            Err(e) => {return Err(UploadError::Systemic(format!("Couldn't get gdrive access token: {e}")));}
+        };
+        let mut request = reqwest::Client::new()
+            .get(format!("https://www.googleapis.com/drive/v3/files/{id}"))
+            .query(&[("alt", "media"), ("supportsAllDrives", "true")])
+            .bearer_auth(token);
+        if offset > 0
+        {
+            request = request.header(reqwest::header::RANGE, format!("bytes={offset}-"));
+        }
+        let mut response = match request.send().await
+        {
+            Ok(r) if r.status().is_success() => r,
+            Ok(r) => {return Err(UploadError::Failure(format!("Couldn't download {remote_name} from gdrive, HTTP status {}", r.status())));},
+            Err(e) => {return Err(UploadError::Failure(format!("Couldn't download {remote_name} from gdrive! Error: {e}")));}
+        };
+        if offset > 0 && response.status() != reqwest::StatusCode::PARTIAL_CONTENT
+        {
+            return Err(UploadError::Failure(format!("gdrive didn't send the requested range of {remote_name}")));
+        }
+        let mut local_file = open_download(local_path, offset)?;
+        let mut position = offset;
+        loop
+        {
+            let chunk = match response.chunk().await
+            {
+                Ok(Some(c)) => c,
+                Ok(None) => {break;},
+                Err(e) => {return Err(UploadError::Failure(format!("Download of {remote_name} from gdrive interrupted: {e}")));}
+            };
+            if let Err(e) = local_file.write_all(&chunk)
+            {
+                return Err(UploadError::Failure(format!("Couldn't write to local file {}: {e}", local_path.to_string_lossy())));
+            }
+            position += chunk.len() as u64;
+            progress::file_position(position);
+        }
+        Ok(())
+    }
+}
+
+impl UploadTarget for GDriveTarget
+{
+    fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    fn list_remote(&self) -> Result<Vec<RemoteFile>, UploadError>
+    {
+        self.runtime.block_on(self.list_all())
+    }
+
+    fn stat(&self, remote_name: &str) -> Result<Option<RemoteFile>, UploadError>
+    {
+        Ok(self.runtime.block_on(self.find(remote_name))?.and_then(remote_file))
+    }
+
+    fn upload(&self, local_path: &Path) -> Result<(), UploadError>
+    {
+        self.runtime.block_on(self.upload_async(local_path))
+    }
+
+    fn delete(&self, remote_name: &str) -> Result<(), UploadError>
+    {
+        self.runtime.block_on(async {
+            match self.find(remote_name).await?
+            {
+                Some(f) => self.delete_file(f).await,
+                None => Err(UploadError::Failure(format!("File not found in gdrive: {remote_name}")))
+            }
+        })
+    }
+
+    fn download(&self, remote_name: &str, local_path: &Path, offset: u64) -> Result<(), UploadError>
+    {
+        self.runtime.block_on(self.download_async(remote_name, local_path, offset))
+    }
+}
+
+/**
+Fields to request when listing files. Without this, the API leaves out sizes and checksums.
 */
-pub fn gdrive_up(source_name: &str, settings: &Settings) -> bool
+const FILE_LIST_FIELDS: &str = "nextPageToken, incompleteSearch, files(id, name, size, md5Checksum, sha256Checksum)";
+
+fn remote_file(file: File) -> Option<RemoteFile>
 {
-    info!("Starting Google Drive upload of exports for source: {source_name}");
-    let runtime = match new_tokio_runtime()
+    let hash = match (file.sha256_checksum, file.md5_checksum)
     {
-        Ok(r) => r,
-        Err(e) => {error!("Couldn't create tokio runtime! Error: {e}"); return false;}
+        (Some(sha256), _) => Some(RemoteHash::Sha256(sha256)),
+        (None, Some(md5)) => Some(RemoteHash::Md5(md5)),
+        (None, None) => None
     };
-    runtime.block_on(
-        upload_files(source_name, settings)
-    )
+    Some(RemoteFile{
+        name: file.name?,
+        size: file.size.unwrap_or(0).try_into().unwrap_or(0),
+        hash
+    })
 }
 
 /**
@@ -49,12 +266,14 @@ Get the Hub object on which you can call all the google drive interaction functi
 As needed, this will handle the token cache file, authenticate to Google, and build the Hub.
 
 # Arguments
+* `name` - Name of the upload target, to keep its token cache separate from other targets
+* `config` - Settings of the upload target
 * `settings` - The whole settings object for the app.
 
 # Returns
 The hub object, or None if something failed
 */
-async fn connect(settings: &Settings) -> Option<Hub>
+async fn connect(name: &str, config: &GDrive, settings: &Settings) -> Option<Hub>
 {
     trace!("Opening connection to Google Drive");
 
@@ -65,10 +284,10 @@ async fn connect(settings: &Settings) -> Option<Hub>
         error!("Couldn't create directory to cache google drive tokens. Dir: {} -- Error: {e}", cache_path.to_string_lossy());
         return None;
     }
-    cache_path.push("gdrive_tokens.json");
+    cache_path.push(format!("gdrive_tokens_{name}.json"));
 
     // Authenticate to Google
-    let service_account_key = match oauth2::read_service_account_key(&settings.gdrive.service_account_key_file).await {
+    let service_account_key = match oauth2::read_service_account_key(&config.service_account_key_file).await {
         Ok(k) => k,
         Err(e) => {error!("Couldn't read gdrive key file: {e}"); return None;}
     };
@@ -78,7 +297,7 @@ async fn connect(settings: &Settings) -> Option<Hub>
         .enable_http2()
         .build();
     let client = Client::builder(hyper_util::rt::TokioExecutor::new()).build(connector);
-    let auth = match oauth2::ServiceAccountAuthenticator::builder(service_account_key).persist_tokens_to_disk(cache_path).subject(&settings.gdrive.email).build().await
+    let auth = match oauth2::ServiceAccountAuthenticator::builder(service_account_key).persist_tokens_to_disk(cache_path).subject(&config.email).build().await
     {
         Ok(a) => a,
         Err(e) => {error!("Couldn't authenticate to Google: {e}"); return None;}
@@ -140,80 +359,10 @@ async fn check_free_space(hub: &Hub, upload_size: u64) -> bool
     true
 }
 
-/**
-Upload all the files for a given source.
-
-Don't upload files that are already there on the google drive. That way it can be ran repeatedly
-in case of error until all the files are up.
-
-# Returns
-bool for whether uploading was found to be possible. The actual uploads may or may not have succeeded, but if this is false no more uploads should be attempted.
-*/
-async fn upload_files(source_name: &str, settings: &Settings) -> bool
-{
-    trace!("Enumerating exports for source: {source_name}");
-    let hub = match connect(settings).await {Some(h)=>h,None=>{return false;}};
-    let mime_str = "application/octet-stream"; //"application/octet-stream";
-    let mime_type: mime::Mime = match mime_str.parse() {Ok(f)=>f,Err(e)=>{error!("Couldn't parse mime type! Error: {e}");return false;}};
-    let parent = settings.gdrive.dir_id.clone();
-    
-    // Prepare upload
-    for filename in list_files(source_name, settings)
-    {
-        let file = match fs::File::open(&filename) {Ok(f)=>f,Err(e)=>{error!("Couldn't open file for hashing! File: {filename} -- Error: {e}");continue;}};
-        let dest_filename = (match PathBuf::from(&filename).file_name() {Some(f)=>f,None=>{error!("Couldn't determine filename! File: {filename} ");continue;}}).to_string_lossy().into_owned();
-        let file_props = get_create_file(String::from("backup archive"), dest_filename.clone(), parent.clone());
-
-        //search for the file: https://developers.google.com/drive/api/guides/search-files
-        let query = format!("trashed = false and name = '{dest_filename}' and '{parent}' in parents");
-        let (_, search_result) = match hub.files().list()
-            .supports_all_drives(true)
-            .spaces("drive")
-            .q(&query)
-            .include_items_from_all_drives(true)
-            .corpora("allDrives")
-            .add_scope(Scope::Full)
-            .doit().await
-        {
-            Ok(r) => r,
-            Err(e) => {error!("Couldn't search for file! Error: {e}");continue;}
-        };
-        if search_result.incomplete_search == Some(true) && search_result.files.is_none()
-        {
-            error!("Unable to determine if gdrive file already exists: {dest_filename}");
-            continue;
-        }
-        let found = match search_result.files
-        {
-            None => false,
-            Some(files) => {
-                match files.first()
-                {
-                    None => false,
-                    Some(_) => {
-                        info!("File already in gdrive: {filename}");
-                        true
-                    }
-                }
-            }
-        };
-        if !found
-        {
-            match upload_file(&hub, filename, mime_type.clone(), file_props, file).await
-            {
-                UploadResult::Success => {},
-                UploadResult::Failure => {continue;},
-                UploadResult::SystemicFailure => {return false;},
-            }
-        }
-    }
-    true
-}
-
 /**
 Upload a single file to google drive.
 
-Intended to be called by upload_files which iterates all the files of the source.
+Intended to be called by GDriveTarget::upload which takes care of replacing any existing file.
 */
 async fn upload_file(hub: &Hub, filename: String, mime_type: mime::Mime, file_props: File, file: fs::File) -> UploadResult
 {
@@ -222,11 +371,10 @@ async fn upload_file(hub: &Hub, filename: String, mime_type: mime::Mime, file_pr
     {
         Ok(m) => m,
         Err(e) => {
-            error!("Couldn't get file size, stopping uploads: {e}");
-            return UploadResult::SystemicFailure;
+            return UploadResult::SystemicFailure(format!("Couldn't get file size, stopping uploads: {e}"));
         }
     }.len();
-    if !check_free_space(hub, size).await {return UploadResult::SystemicFailure;}
+    if !check_free_space(hub, size).await {return UploadResult::SystemicFailure(String::from("Not enough space in Google Drive"));}
     match hub.files().create(file_props)
         .use_content_as_indexable_text(false)
         .supports_all_drives(true)
@@ -257,8 +405,8 @@ async fn upload_file(hub: &Hub, filename: String, mime_type: mime::Mime, file_pr
                     (format!("HTTP response contained failure code: {:?}", response), !fatal)
                 }
             };
-            error!("Couldn't upload file! File: {filename} -- Reason: {msg}");
-            return if continuable {UploadResult::Failure} else {UploadResult::SystemicFailure};
+            let msg = format!("Couldn't upload file! File: {filename} -- Reason: {msg}");
+            return if continuable {UploadResult::Failure(msg)} else {UploadResult::SystemicFailure(msg)};
         },
         Ok(r) => r
     };
@@ -269,8 +417,8 @@ async fn upload_file(hub: &Hub, filename: String, mime_type: mime::Mime, file_pr
 enum UploadResult
 {
     Success,
-    Failure,
-    SystemicFailure
+    Failure(String),
+    SystemicFailure(String)
 }
 
 struct UploadDelegate
@@ -358,6 +506,16 @@ impl Delegate for UploadDelegate
         1 << 27
     }
 
+    // Called before each chunk, so progress is where the chunk starts.
+    // Stop between chunks when the job is cancelled. The upload fails with Error::Cancelled.
+    fn cancel_chunk_upload(&mut self, chunk: &ContentRange) -> bool {
+        if let Some(range) = &chunk.range
+        {
+            progress::file_position(range.first);
+        }
+        cancel::cancelled()
+    }
+
     fn finished(&mut self, _is_success: bool) {
         self.last_backoff_index_and_when = None;
     }
@@ -1,34 +1,511 @@
 pub mod dropbox;
 pub mod gdrive;
+pub mod s3;
+pub mod sftp;
 
-use log::{error,/* warn,*/ info/*, debug, trace, log, Level*/};
-use glob::glob;
+use log::{error, warn, info/*, debug, trace, log, Level*/};
+use serde::Serialize;
+use md5::Md5;
+use sha2::Sha256;
+use std::{collections::HashMap, fmt, fs, io::{Seek, SeekFrom}, path::Path};
 
-use crate::settings::app_settings::Settings;
-use crate::latest_export_ts;
+use crate::cancel;
+use crate::checksum::{dropbox_content_hash, file_digest, s3_multipart_etag};
+use crate::manifest::{self, Manifest, ManifestPart};
+use crate::metrics;
+use crate::progress;
+use crate::settings::app_settings::{Settings, UploadTargetSetup};
+use crate::{export_files, latest_export_ts};
 
-pub fn list_files(source_name: &str, settings: &Settings) -> Vec<String>
+/**
+A file stored at an upload target.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RemoteFile
 {
-    let target_timestamp = match latest_export_ts(source_name, &settings.startup.export_dir)
+    pub name: String,
+    pub size: u64,
+    pub hash: Option<RemoteHash>
+}
+
+/**
+A checksum reported by an upload target. Each provider has its own idea of which hash to offer.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum RemoteHash
+{
+    /** Dropbox's content_hash: SHA-256 of the concatenated SHA-256 hashes of each 4MiB block, hex encoded */
+    DropboxContentHash(String),
+    /** Hex encoded MD5 of the whole file */
+    Md5(String),
+    /** Hex encoded SHA-256 of the whole file */
+    Sha256(String),
+    /** S3's ETag: hex encoded MD5 of the whole file for single part uploads, or for multipart uploads the MD5 of the concatenated part MD5s followed by -{number of parts} */
+    S3ETag(String)
+}
+
+impl RemoteHash
+{
+    /**
+    Check whether two hashes are of the same kind and have the same value.
+    */
+    pub fn matches(&self, other: &RemoteHash) -> bool
+    {
+        match (self, other)
+        {
+            (RemoteHash::DropboxContentHash(a), RemoteHash::DropboxContentHash(b)) |
+            (RemoteHash::Md5(a), RemoteHash::Md5(b)) |
+            (RemoteHash::Sha256(a), RemoteHash::Sha256(b)) |
+            (RemoteHash::S3ETag(a), RemoteHash::S3ETag(b)) => a.eq_ignore_ascii_case(b),
+            _ => false
+        }
+    }
+}
+
+/**
+How a local file compares to what's at the upload target under the same name.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub enum RemoteState
+{
+    Missing,
+    Matches,
+    Differs
+}
+
+/**
+Failures from an upload target.
+*/
+#[derive(Clone, Debug)]
+pub enum UploadError
+{
+    /** Something went wrong with one file. Other files may still work. */
+    Failure(String),
+    /** Something went wrong that will affect every file, e.g. auth or quota problems, so the target shouldn't be used any further for now. */
+    Systemic(String)
+}
+
+impl fmt::Display for UploadError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            UploadError::Failure(s) => write!(f, "{s}"),
+            UploadError::Systemic(s) => write!(f, "(systemic) {s}")
+        }
+    }
+}
+
+/**
+A place exports can be uploaded to. Each file is stored under its local filename, in whatever location the target is configured to use.
+*/
+pub trait UploadTarget
+{
+    /** Name of the target, as configured. */
+    fn name(&self) -> &str;
+
+    /** Get all the files stored at the target. */
+    fn list_remote(&self) -> Result<Vec<RemoteFile>, UploadError>;
+
+    /** Look up one file stored at the target. None if it isn't there. */
+    fn stat(&self, remote_name: &str) -> Result<Option<RemoteFile>, UploadError>;
+
+    /**
+    Upload a file, replacing any file with the same name. Interrupted transfers are resumed when the provider supports it.
+    */
+    fn upload(&self, local_path: &Path) -> Result<(), UploadError>;
+
+    /** Delete a file from the target. */
+    fn delete(&self, remote_name: &str) -> Result<(), UploadError>;
+
+    /**
+    Download a file from the target.
+
+    # Arguments
+    * `offset` - Where to start in the file. Anything in the local file beyond this is replaced, so a download that was interrupted can be continued.
+    */
+    fn download(&self, remote_name: &str, local_path: &Path, offset: u64) -> Result<(), UploadError>;
+
+    /**
+    Compare a local file to the one with the same name at the target.
+    */
+    fn check(&self, local_path: &Path) -> Result<RemoteState, UploadError>
+    {
+        let remote_name = file_name(local_path)?;
+        let local_size = match fs::metadata(local_path)
+        {
+            Ok(m) => m.len(),
+            Err(e) => {return Err(UploadError::Failure(format!("Couldn't get size of local file {}: {e}", local_path.to_string_lossy())));}
+        };
+        Ok(match self.stat(&remote_name)?
+        {
+            None => RemoteState::Missing,
+            Some(remote) if remote.size == local_size => RemoteState::Matches,
+            Some(_) => RemoteState::Differs
+        })
+    }
+
+    /**
+    Compare a local file's contents to a file at the target, using the hash the target reported for it.
+    This reads the whole local file.
+
+    # Returns
+    Whether they match, or None if the target didn't report a hash that can be compared.
+    */
+    fn hash_matches(&self, local_path: &Path, remote: &RemoteFile) -> Result<Option<bool>, UploadError>
+    {
+        match &remote.hash
+        {
+            Some(hash) => compare_hash(local_path, hash, None),
+            None => Ok(None)
+        }
+    }
+}
+
+/**
+Hash a local file the same way as a hash reported by an upload target, and compare them.
+
+# Arguments
+* `local_path` - File to hash
+* `remote_hash` - The hash from the target, which determines how the local file is hashed
+* `multipart_part_size` - For S3 multipart ETags, the size of the parts the file was uploaded in
+
+# Returns
+Whether they match, or None if the local file can't be hashed the same way (a multipart ETag without a known part size).
+*/
+pub fn compare_hash(local_path: &Path, remote_hash: &RemoteHash, multipart_part_size: Option<u64>) -> Result<Option<bool>, UploadError>
+{
+    let local_hash = match remote_hash
+    {
+        RemoteHash::DropboxContentHash(_) => dropbox_content_hash(local_path).map(RemoteHash::DropboxContentHash),
+        RemoteHash::Md5(_) => file_digest::<Md5>(local_path).map(RemoteHash::Md5),
+        RemoteHash::Sha256(_) => file_digest::<Sha256>(local_path).map(RemoteHash::Sha256),
+        RemoteHash::S3ETag(etag) => match (etag.contains('-'), multipart_part_size)
+        {
+            (false, _) => file_digest::<Md5>(local_path).map(RemoteHash::S3ETag),
+            (true, Some(part_size)) => s3_multipart_etag(local_path, part_size).map(RemoteHash::S3ETag),
+            (true, None) => {return Ok(None);}
+        }
+    };
+    match local_hash
+    {
+        Ok(h) => Ok(Some(h.matches(remote_hash))),
+        Err(e) => Err(UploadError::Failure(format!("Couldn't hash local file {}: {e}", local_path.to_string_lossy())))
+    }
+}
+
+/**
+Stop transferring if the running job was cancelled. This counts as a systemic failure, since nothing more should be done with the target.
+*/
+pub fn check_cancelled() -> Result<(), UploadError>
+{
+    match cancel::cancelled()
+    {
+        true => Err(UploadError::Systemic(String::from("cancelled"))),
+        false => Ok(())
+    }
+}
+
+/**
+Get the filename part of a path, as used for the file's name at upload targets.
+*/
+pub fn file_name(path: &Path) -> Result<String, UploadError>
+{
+    match path.file_name()
+    {
+        Some(n) => Ok(n.to_string_lossy().into_owned()),
+        None => Err(UploadError::Failure(format!("Couldn't determine filename of: {}", path.to_string_lossy())))
+    }
+}
+
+/**
+Open a local file to download into, keeping what's in it up to `offset` and cutting off the rest.
+
+# Returns
+The file, positioned at `offset`.
+*/
+pub fn open_download(local_path: &Path, offset: u64) -> Result<fs::File, UploadError>
+{
+    let result = fs::OpenOptions::new().write(true).create(true).truncate(false).open(local_path).and_then(|mut f| {
+        f.set_len(offset)?;
+        f.seek(SeekFrom::Start(offset))?;
+        Ok(f)
+    });
+    result.map_err(|e| UploadError::Failure(format!("Couldn't open local file {} for download: {e}", local_path.to_string_lossy())))
+}
+
+/**
+Connect to a configured upload target.
+
+# Arguments
+* `name` - Name of the target in the upload_targets section of the config
+* `setup` - The target's configuration
+* `settings` - The whole settings object for the app.
+
+# Returns
+The connected target, or an error if it couldn't be reached or authenticated.
+*/
+pub fn connect(name: &str, setup: &UploadTargetSetup, settings: &Settings) -> Result<Box<dyn UploadTarget>, UploadError>
+{
+    Ok(match setup
+    {
+        UploadTargetSetup::Dropbox(config) => Box::new(dropbox::DropboxTarget::connect(name, config, settings)?),
+        UploadTargetSetup::GDrive(config) => Box::new(gdrive::GDriveTarget::connect(name, config, settings)?),
+        UploadTargetSetup::S3(config) => Box::new(s3::S3Target::connect(name, config)?),
+        UploadTargetSetup::Sftp(config) => Box::new(sftp::SftpTarget::connect(name, config)?)
+    })
+}
+
+/**
+Perform interactive authorization for a configured upload target, for the providers that need it.
+*/
+pub fn auth(name: &str, setup: &UploadTargetSetup, settings: &Settings)
+{
+    match setup
+    {
+        UploadTargetSetup::Dropbox(config) => dropbox::dropbox_auth(name, config, settings),
+        _ => info!("Upload target {} doesn't need interactive authorization.", name)
+    }
+}
+
+/**
+Get the upload targets an action applies to: the one named in the action, or all of them if none is named.
+*/
+pub fn selected_targets(settings: &Settings) -> Vec<(String, UploadTargetSetup)>
+{
+    let wanted = &settings.action.upload_target;
+    if wanted.is_empty()
+    {
+        let mut targets: Vec<(String, UploadTargetSetup)> = settings.upload_targets.clone().into_iter().collect();
+        targets.sort_by(|a, b| a.0.cmp(&b.0));
+        return targets;
+    }
+    match settings.upload_targets.get(wanted)
+    {
+        Some(t) => vec!((wanted.clone(), t.clone())),
+        None => {
+            let targets_list = settings.upload_targets.keys().cloned().collect::<Vec<String>>().join(",");
+            error!("upload target {} not found in upload targets list ({})", wanted, targets_list);
+            vec!()
+        }
+    }
+}
+
+/**
+Connect to the upload targets an action applies to. Targets that can't be connected to are logged and left out.
+*/
+pub fn connect_selected(settings: &Settings) -> Vec<(String, Box<dyn UploadTarget>)>
+{
+    let mut targets = Vec::new();
+    for (target_name, setup) in selected_targets(settings)
+    {
+        match connect(&target_name, &setup, settings)
+        {
+            Ok(t) => targets.push((target_name, t)),
+            Err(e) => {error!("Couldn't connect to upload target {}, skipping it -- Error: {}", target_name, e);}
+        }
+    }
+    targets
+}
+
+/**
+Upload the latest export of a source to a target.
+
+Files that are already at the target and match are skipped, so this can be run repeatedly until everything is up.
+The manifest is uploaded last, and only once all the parts are up, so an export with a manifest at the target is complete there.
+The copy at the target is then checked against the manifest.
+
+# Returns
+bool for whether uploading was found to be possible. The actual uploads may or may not have succeeded, but if this is false no more uploads should be attempted to this target.
+*/
+pub fn upload_source(target: &dyn UploadTarget, source_name: &str, settings: &Settings) -> bool
+{
+    info!("Starting upload of exports to {} for source: {}", target.name(), source_name);
+    let (files, manifest) = match list_files(source_name, settings)
+    {
+        Some(f) => f,
+        None => {return true;}
+    };
+    let mut uploaded = 0;
+    let mut failed = 0;
+    let size = |f: &String| fs::metadata(f).map(|m| m.len()).unwrap_or(0);
+    progress::start(&format!("upload to {} for {source_name}", target.name()), Some(files.iter().map(size).sum()));
+    for file_str in &files
+    {
+        let local_path = Path::new(file_str);
+        if cancel::cancelled()
+        {
+            warn!("Stopping uploads to {} after {}/{} files of source {} because the job was cancelled", target.name(), uploaded, files.len(), source_name);
+            progress::finish();
+            return false;
+        }
+        progress::next_file(&file_name(local_path).unwrap_or_else(|_| file_str.clone()));
+        if manifest.is_some() && failed > 0 && file_str.ends_with(".manifest.json")
+        {
+            warn!("Not uploading manifest to {} since {} parts failed: {}", target.name(), failed, file_str);
+            continue;
+        }
+        let result = match target.check(local_path)
+        {
+            Ok(RemoteState::Matches) => {
+                info!("File already uploaded to {}, skipping: {}", target.name(), file_str);
+                progress::file_position(size(file_str));
+                uploaded += 1;
+                continue;
+            },
+            Ok(_) => target.upload(local_path),
+            Err(e) => Err(e)
+        };
+        match result
+        {
+            Ok(()) => {
+                info!("Uploaded file to {}: {}", target.name(), file_str);
+                progress::file_position(size(file_str));
+                metrics::transferred(size(file_str));
+                uploaded += 1;
+            },
+            Err(UploadError::Failure(e)) => {
+                error!("Failed to upload file to {}: {} -- Error: {}", target.name(), file_str, e);
+                failed += 1;
+            },
+            Err(UploadError::Systemic(e)) => {
+                error!("Stopping uploads to {} after {}/{} files of source {} -- Error: {}", target.name(), uploaded, files.len(), source_name, e);
+                progress::finish();
+                return false;
+            }
+        }
+    }
+    progress::finish();
+    info!("Finished upload of exports to {} for source: {} -- {}/{} files up, {} failed", target.name(), source_name, uploaded, files.len(), failed);
+
+    match manifest
+    {
+        Some(m) => match verify_remote(target, &m, None)
+        {
+            Ok(problems) if problems.is_empty() => {info!("Copy of export {} at {} matches its manifest ({} parts)", m.timestamp, target.name(), m.parts.len());},
+            Ok(problems) => {
+                for problem in &problems
+                {
+                    error!("Copy of export {} of source {} at {} is incomplete: {}", m.timestamp, source_name, target.name(), problem);
+                }
+            },
+            Err(e) => {error!("Couldn't check copy of export {} of source {} at {} -- Error: {}", m.timestamp, source_name, target.name(), e);}
+        },
+        None => {warn!("Export of source {} has no manifest, so the copy at {} can't be checked", source_name, target.name());}
+    }
+    true
+}
+
+/**
+Check the copy of an export at a target against its manifest.
+
+# Arguments
+* `local_dir` - Where the export's parts are locally, if they should be hashed to compare against hashes the manifest doesn't have.
+  Otherwise only sizes and SHA-256 hashes are compared.
+
+# Returns
+A description of each problem found; empty if the copy is complete.
+*/
+pub fn verify_remote(target: &dyn UploadTarget, manifest: &Manifest, local_dir: Option<&Path>) -> Result<Vec<String>, UploadError>
+{
+    let mut problems = check_remote_parts(target, &manifest.parts, local_dir)?;
+    if target.stat(&Manifest::file_name(&manifest.source, manifest.timestamp))?.is_none()
+    {
+        problems.push(String::from("the manifest is missing"));
+    }
+    Ok(problems)
+}
+
+/**
+Check that files are at a target, with the given sizes and contents.
+
+# Arguments
+* `parts` - The files that should be there. Blank SHA-256 hashes aren't checked.
+* `local_dir` - Where local copies of the files are, if they should be hashed to compare against whatever hash the target reports
+
+# Returns
+A description of each problem found; empty if everything is there.
+*/
+pub fn check_remote_parts(target: &dyn UploadTarget, parts: &[ManifestPart], local_dir: Option<&Path>) -> Result<Vec<String>, UploadError>
+{
+    let remote: HashMap<String, RemoteFile> = target.list_remote()?.into_iter().map(|f| (f.name.clone(), f)).collect();
+    let mut problems = Vec::new();
+    for part in parts
+    {
+        let remote_file = match remote.get(&part.name)
+        {
+            Some(r) => r,
+            None => {
+                problems.push(format!("{} is missing", part.name));
+                continue;
+            }
+        };
+        if remote_file.size != part.size
+        {
+            problems.push(format!("{} is {} bytes but should be {}", part.name, remote_file.size, part.size));
+            continue;
+        }
+        match (&remote_file.hash, local_dir)
+        {
+            // the manifest already has the SHA-256, no need to hash the local file
+            (Some(RemoteHash::Sha256(h)), _) if !part.sha256.is_empty() && !h.eq_ignore_ascii_case(&part.sha256) => {
+                problems.push(format!("{} has SHA-256 {} but should have {}", part.name, h, part.sha256));
+            },
+            (Some(RemoteHash::Sha256(_)), _) if !part.sha256.is_empty() => {},
+            (Some(_), Some(dir)) => match target.hash_matches(&dir.join(&part.name), remote_file)
+            {
+                Ok(Some(false)) => problems.push(format!("{} has different contents than the local copy", part.name)),
+                Ok(_) => {},
+                Err(e) => problems.push(format!("{} couldn't be compared: {e}", part.name))
+            },
+            _ => {}
+        }
+    }
+    Ok(problems)
+}
+
+/**
+Get the files making up the latest export of a source: its parts in order, followed by its manifest if it has one.
+
+# Returns
+The files and the manifest, or None if there's nothing to upload. Problems are logged.
+*/
+pub fn list_files(source_name: &str, settings: &Settings) -> Option<(Vec<String>, Option<Manifest>)>
+{
+    let export_dir = &settings.startup.export_dir;
+    let target_timestamp = match latest_export_ts(source_name, export_dir)
     {
         Some(t) => t,
         None =>{
             info!("Nothing to upload.");
-            return vec!();
+            return None;
         }
     };
 
-    let glob_str = format!("{}/{}_{target_timestamp}.tar.zst.*", settings.startup.export_dir, &source_name);
-
-    match glob(&glob_str)
+    let mut files = export_files(source_name, target_timestamp, export_dir);
+    let manifest = match manifest::load(source_name, target_timestamp, export_dir)
+    {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Couldn't read manifest of export {} of source {} -- Error: {}", target_timestamp, source_name, e);
+            return None;
+        }
+    };
+    if let Some(m) = &manifest
     {
-        Ok(v) => v,
-        Err(e) =>
+        let missing: Vec<&str> = m.parts.iter().map(|p| p.name.as_str()).filter(|name| !files.iter().any(|f| Path::new(f).ends_with(name))).collect();
+        if !missing.is_empty() || files.len() != m.parts.len()
+        {
+            error!("Export {} of source {} doesn't match its manifest, not uploading it. {} parts listed, {} found, missing: {}", target_timestamp, source_name, m.parts.len(), files.len(), missing.join(", "));
+            return None;
+        }
+        if let Some(path) = manifest::manifest_path(source_name, target_timestamp, export_dir)
         {
-            error!("Failed to process glob: {} -- Error: {}", glob_str, e);
-            return vec!();
+            files.push(path.to_string_lossy().into_owned());
         }
-    }.filter_map(Result::ok).map(|f| f.display().to_string()).collect()
+    }
+    Some((files, manifest))
 }
 
 #[cfg(target_family = "unix")]
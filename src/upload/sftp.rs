@@ -0,0 +1,244 @@
+use log::{/*error,*/ warn, info/*, debug, trace, log, Level*/};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp as SftpChannel};
+use std::{fs::File, io::{Read, Seek, SeekFrom, Write}, net::TcpStream, path::{Path, PathBuf}};
+
+use crate::progress;
+use crate::settings::app_settings::{Sftp, SshCreds};
+use crate::upload::{file_name, open_download, RemoteFile, UploadError, UploadTarget};
+
+/** SFTP status code for a file that doesn't exist */
+const SFTP_NO_SUCH_FILE: i32 = 2;
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/**
+Upload target storing files in a directory on a server reachable over SFTP.
+
+A file that's at the target but shorter than the local one is taken to be a previous upload that was interrupted, and is continued from where it stopped.
+*/
+pub struct SftpTarget
+{
+    name: String,
+    // the sftp channel stops working if the session is dropped
+    _session: Session,
+    sftp: SftpChannel,
+    dest: PathBuf
+}
+
+impl SftpTarget
+{
+    /**
+    Connect and log in to the server, then make sure the destination directory exists.
+    */
+    pub fn connect(name: &str, config: &Sftp) -> Result<SftpTarget, UploadError>
+    {
+        let tcp = match TcpStream::connect((config.hostname.as_str(), config.port))
+        {
+            Ok(t) => t,
+            Err(e) => {return Err(UploadError::Systemic(format!("Couldn't connect to {}:{}: {e}", config.hostname, config.port)));}
+        };
+        let mut session = match Session::new()
+        {
+            Ok(s) => s,
+            Err(e) => {return Err(UploadError::Systemic(format!("Couldn't start SSH session: {e}")));}
+        };
+        session.set_tcp_stream(tcp);
+        session.set_timeout(60000);
+        if let Err(e) = session.handshake()
+        {
+            return Err(UploadError::Systemic(format!("SSH handshake with {} failed: {e}", config.hostname)));
+        }
+        check_host_key(&session, &config.hostname, config.port)?;
+
+        let auth_result = match &config.creds
+        {
+            SshCreds::Key(k) => session.userauth_pubkey_file(&k.username, None, Path::new(&k.keyfile_path), None),
+            SshCreds::Password(p) => session.userauth_password(&p.username, &p.password)
+        };
+        if let Err(e) = auth_result
+        {
+            return Err(UploadError::Systemic(format!("SSH authentication to {} failed: {e}", config.hostname)));
+        }
+        let sftp = match session.sftp()
+        {
+            Ok(s) => s,
+            Err(e) => {return Err(UploadError::Systemic(format!("Couldn't start SFTP on {}: {e}", config.hostname)));}
+        };
+
+        let dest = PathBuf::from(&config.dest_path);
+        let mut dir = PathBuf::new();
+        for component in dest.components()
+        {
+            dir.push(component);
+            if sftp.stat(&dir).is_err()
+            {
+                if let Err(e) = sftp.mkdir(&dir, 0o755)
+                {
+                    return Err(UploadError::Systemic(format!("Couldn't create destination directory {} on {}: {e}", dir.to_string_lossy(), config.hostname)));
+                }
+            }
+        }
+
+        Ok(SftpTarget{name: name.to_string(), _session: session, sftp, dest})
+    }
+
+    fn remote_path(&self, remote_name: &str) -> PathBuf
+    {
+        self.dest.join(remote_name)
+    }
+}
+
+impl UploadTarget for SftpTarget
+{
+    fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    fn list_remote(&self) -> Result<Vec<RemoteFile>, UploadError>
+    {
+        let entries = match self.sftp.readdir(&self.dest)
+        {
+            Ok(e) => e,
+            Err(e) => {return Err(UploadError::Failure(format!("Couldn't list {}: {e}", self.dest.to_string_lossy())));}
+        };
+        Ok(entries.into_iter()
+            .filter(|(_, stat)| stat.is_file())
+            .filter_map(|(path, stat)| Some(RemoteFile{
+                name: path.file_name()?.to_string_lossy().into_owned(),
+                size: stat.size.unwrap_or(0),
+                hash: None
+            }))
+            .collect())
+    }
+
+    fn stat(&self, remote_name: &str) -> Result<Option<RemoteFile>, UploadError>
+    {
+        match self.sftp.stat(&self.remote_path(remote_name))
+        {
+            Ok(stat) => Ok(Some(RemoteFile{name: remote_name.to_string(), size: stat.size.unwrap_or(0), hash: None})),
+            Err(e) if e.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE) => Ok(None),
+            Err(e) => Err(UploadError::Failure(format!("Couldn't look up {remote_name}: {e}")))
+        }
+    }
+
+    fn upload(&self, local_path: &Path) -> Result<(), UploadError>
+    {
+        let remote_name = file_name(local_path)?;
+        let remote_path = self.remote_path(&remote_name);
+        let mut local_file = match File::open(local_path)
+        {
+            Ok(f) => f,
+            Err(e) => {return Err(UploadError::Failure(format!("Failed to open file: {e}")));}
+        };
+        let local_size = match local_file.metadata()
+        {
+            Ok(m) => m.len(),
+            Err(e) => {return Err(UploadError::Failure(format!("Failed to get metadata of file: {e}")));}
+        };
+
+        // continue an interrupted upload if there is one, otherwise start over
+        let offset = match self.stat(&remote_name)?
+        {
+            Some(remote) if remote.size < local_size => remote.size,
+            Some(remote) => {
+                warn!("Replacing {} on {} which is {} bytes but should be {}", remote_name, self.name, remote.size, local_size);
+                0
+            },
+            None => 0
+        };
+        let flags = if offset > 0 {OpenFlags::WRITE} else {OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE};
+        let mut remote_file = match self.sftp.open_mode(&remote_path, flags, 0o644, OpenType::File)
+        {
+            Ok(f) => f,
+            Err(e) => {return Err(UploadError::Failure(format!("Couldn't open {} for writing: {e}", remote_path.to_string_lossy())));}
+        };
+        if offset > 0
+        {
+            info!("Resuming upload of {} at byte {} of {}", remote_name, offset, local_size);
+            if let Err(e) = local_file.seek(SeekFrom::Start(offset)).and_then(|_| remote_file.seek(SeekFrom::Start(offset)))
+            {
+                return Err(UploadError::Failure(format!("Couldn't seek to resume upload of {remote_name}: {e}")));
+            }
+        }
+
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut position = offset;
+        loop
+        {
+            let read = match local_file.read(&mut buffer)
+            {
+                Ok(0) => {break;},
+                Ok(n) => n,
+                Err(e) => {return Err(UploadError::Failure(format!("Failed to read file: {e}")));}
+            };
+            if let Err(e) = remote_file.write_all(&buffer[..read])
+            {
+                return Err(UploadError::Failure(format!("Upload of {remote_name} to {} interrupted: {e}", self.name)));
+            }
+            position += read as u64;
+            progress::file_position(position);
+        }
+        if let Err(e) = remote_file.close()
+        {
+            return Err(UploadError::Failure(format!("Couldn't finish writing {remote_name} to {}: {e}", self.name)));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, remote_name: &str) -> Result<(), UploadError>
+    {
+        match self.sftp.unlink(&self.remote_path(remote_name))
+        {
+            Ok(()) => Ok(()),
+            Err(e) => Err(UploadError::Failure(format!("Couldn't delete {remote_name} from {}: {e}", self.name)))
+        }
+    }
+
+    fn download(&self, remote_name: &str, local_path: &Path, offset: u64) -> Result<(), UploadError>
+    {
+        let mut remote_file = match self.sftp.open(self.remote_path(remote_name))
+        {
+            Ok(f) => f,
+            Err(e) => {return Err(UploadError::Failure(format!("Couldn't open {remote_name} on {}: {e}", self.name)));}
+        };
+        if let Err(e) = remote_file.seek(SeekFrom::Start(offset))
+        {
+            return Err(UploadError::Failure(format!("Couldn't seek to resume download of {remote_name}: {e}")));
+        }
+        let mut local_file = open_download(local_path, offset)?;
+        if let Err(e) = std::io::copy(&mut remote_file, &mut local_file)
+        {
+            return Err(UploadError::Failure(format!("Download of {remote_name} from {} interrupted: {e}", self.name)));
+        }
+        Ok(())
+    }
+}
+
+/**
+Make sure the server is the one we expect, according to ~/.ssh/known_hosts, the same file the ssh command uses for rsync.
+*/
+fn check_host_key(session: &Session, hostname: &str, port: u16) -> Result<(), UploadError>
+{
+    let (key, _) = match session.host_key()
+    {
+        Some(k) => k,
+        None => {return Err(UploadError::Systemic(format!("{hostname} didn't send a host key")));}
+    };
+    let mut known_hosts = match session.known_hosts()
+    {
+        Ok(k) => k,
+        Err(e) => {return Err(UploadError::Systemic(format!("Couldn't initialize known hosts: {e}")));}
+    };
+    let known_hosts_file = PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".ssh/known_hosts");
+    if let Err(e) = known_hosts.read_file(&known_hosts_file, KnownHostFileKind::OpenSSH)
+    {
+        warn!("Couldn't read {}: {e}", known_hosts_file.to_string_lossy());
+    }
+    match known_hosts.check_port(hostname, port, key)
+    {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(UploadError::Systemic(format!("Host key of {hostname} doesn't match the one in {}! Refusing to connect.", known_hosts_file.to_string_lossy()))),
+        CheckResult::NotFound => Err(UploadError::Systemic(format!("Host key of {hostname} is unknown. Add it to {} first, e.g. by connecting with ssh once.", known_hosts_file.to_string_lossy()))),
+        CheckResult::Failure => Err(UploadError::Systemic(format!("Couldn't check host key of {hostname}")))
+    }
+}
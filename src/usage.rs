@@ -0,0 +1,157 @@
+use serde::Serialize;
+use std::{collections::HashSet, fs, path::Path};
+
+use crate::{export_files, export_timestamps};
+use crate::manifest::manifest_path;
+use crate::settings::app_settings::Settings;
+use crate::snapshot::{list_snapshots, source_dir};
+
+/**
+How much space one source's backups take up.
+*/
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SourceUsage
+{
+    pub source: String,
+    pub snapshots: usize,
+    /** Size of everything stored for the source, counting files hard-linked between snapshots once */
+    pub snapshot_bytes: u64,
+    pub exports: usize,
+    /** Size of the parts and manifests of the source's exports */
+    pub export_bytes: u64
+}
+
+/**
+Space on the filesystem a directory is on.
+*/
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FilesystemSpace
+{
+    pub total_bytes: u64,
+    pub free_bytes: u64
+}
+
+/**
+Work out how much space a source's snapshots and exports take up. This reads through every snapshot, so it can take a while.
+*/
+pub fn source_usage(source_name: &str, settings: &Settings) -> SourceUsage
+{
+    let export_dir = &settings.startup.export_dir;
+    let timestamps = export_timestamps(source_name, export_dir);
+    let export_bytes = timestamps.iter().flat_map(|ts| {
+        let mut files = export_files(source_name, *ts, export_dir);
+        files.extend(manifest_path(source_name, *ts, export_dir).map(|p| p.to_string_lossy().into_owned()));
+        files
+    }).filter_map(|f| fs::metadata(f).ok()).map(|m| m.len()).sum();
+    SourceUsage{
+        source: source_name.to_string(),
+        snapshots: list_snapshots(&settings.startup.storage_dir, source_name).len(),
+        snapshot_bytes: disk_usage(&source_dir(&settings.startup.storage_dir, source_name)),
+        exports: timestamps.len(),
+        export_bytes
+    }
+}
+
+/**
+Total size of the files in a directory and everything under it, counting each hard-linked file once. Symlinks aren't followed.
+Anything that can't be read is left out.
+*/
+pub fn disk_usage(dir: &Path) -> u64
+{
+    tree_size(dir, true)
+}
+
+/**
+Total size of the files in a directory and everything under it, counting hard-linked files each time they turn up, as archiving them does.
+Symlinks aren't followed, and anything that can't be read is left out.
+*/
+pub fn content_size(dir: &Path) -> u64
+{
+    tree_size(dir, false)
+}
+
+fn tree_size(dir: &Path, links_once: bool) -> u64
+{
+    let mut seen = HashSet::new();
+    let mut total = 0;
+    let mut pending = vec!(dir.to_path_buf());
+    while let Some(d) = pending.pop()
+    {
+        let entries = match fs::read_dir(&d)
+        {
+            Ok(e) => e,
+            Err(_) => {continue;}
+        };
+        for entry in entries.filter_map(Result::ok)
+        {
+            let meta = match entry.path().symlink_metadata()
+            {
+                Ok(m) => m,
+                Err(_) => {continue;}
+            };
+            if meta.is_dir()
+            {
+                pending.push(entry.path());
+            }else if meta.is_file() && (!links_once || first_link(&meta, &mut seen))
+            {
+                total += meta.len();
+            }
+        }
+    }
+    total
+}
+
+#[cfg(target_family = "unix")]
+fn first_link(meta: &fs::Metadata, seen: &mut HashSet<(u64, u64)>) -> bool
+{
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink() < 2 || seen.insert((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(target_family = "unix"))]
+fn first_link(_meta: &fs::Metadata, _seen: &mut HashSet<(u64, u64)>) -> bool
+{
+    true
+}
+
+/**
+Get the size and free space of the filesystem a directory is on.
+
+# Returns
+The space, or None if it couldn't be determined.
+*/
+#[cfg(target_family = "unix")]
+pub fn filesystem_space(dir: &Path) -> Option<FilesystemSpace>
+{
+    let stats = nix::sys::statvfs::statvfs(dir).ok()?;
+    let fragment_size = stats.fragment_size() as u64;
+    Some(FilesystemSpace{
+        total_bytes: stats.blocks() as u64 * fragment_size,
+        free_bytes: stats.blocks_available() as u64 * fragment_size
+    })
+}
+
+#[cfg(not(target_family = "unix"))]
+pub fn filesystem_space(_dir: &Path) -> Option<FilesystemSpace>
+{
+    None
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn hard_links_count_once()
+    {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/one"), "12345").unwrap();
+        fs::write(dir.path().join("a/b/two"), "123").unwrap();
+        fs::hard_link(dir.path().join("a/one"), dir.path().join("one_again")).unwrap();
+        assert_eq!(disk_usage(dir.path()), 8);
+        assert_eq!(content_size(dir.path()), 13);
+        assert_eq!(disk_usage(&dir.path().join("missing")), 0);
+    }
+}
@@ -0,0 +1,177 @@
+use log::{error, warn, info/*, debug, trace, log, Level*/};
+use sha2::Sha256;
+use std::{fs, path::Path};
+
+use crate::{export_files, latest_export_ts};
+use crate::checksum::file_digest;
+use crate::encryption::{can_decrypt, starts_encrypted};
+use crate::export::test_archive;
+use crate::manifest::{self, Manifest, ManifestPart};
+use crate::settings::app_settings::Settings;
+use crate::upload::{self, UploadTarget};
+
+/**
+Check the latest export of a source: locally by comparing the parts to the manifest and test-decompressing them,
+then at each upload target by comparing the copies there to the local parts, using whatever hashes the target provides.
+
+Every problem found is logged.
+
+# Arguments
+* `targets` - Connected upload targets to check the copies at, with their names
+
+# Returns
+bool for whether everything checked out.
+*/
+pub fn verify(source_name: &str, settings: &Settings, targets: &[(String, Box<dyn UploadTarget>)]) -> bool
+{
+    info!("Beginning verify for source: {}", source_name);
+
+    let export_dir = &settings.startup.export_dir;
+    let timestamp = match latest_export_ts(source_name, export_dir)
+    {
+        Some(t) => t,
+        None => {
+            info!("Nothing to verify.");
+            return true;
+        }
+    };
+    let files = export_files(source_name, timestamp, export_dir);
+    let manifest = match manifest::load(source_name, timestamp, export_dir)
+    {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Verify of export {} of source {}: couldn't read manifest -- Error: {}", timestamp, source_name, e);
+            None
+        }
+    };
+    if manifest.is_none()
+    {
+        warn!("Export {} of source {} has no manifest, so only the parts that are there can be checked", timestamp, source_name);
+    }
+
+    let mut all_ok = report(source_name, timestamp, "locally", &verify_local(&files, manifest.as_ref(), settings));
+
+    // what the copies at the targets should match: the manifest, or else the local parts as they are
+    let parts = match &manifest
+    {
+        Some(m) => m.parts.clone(),
+        None => files.iter().filter_map(|f| Some(ManifestPart{
+            name: Path::new(f).file_name()?.to_string_lossy().into_owned(),
+            size: fs::metadata(f).ok()?.len(),
+            sha256: String::new()
+        })).collect()
+    };
+    for (target_name, target) in targets
+    {
+        let result = match &manifest
+        {
+            Some(m) => upload::verify_remote(target.as_ref(), m, Some(Path::new(export_dir))),
+            None => upload::check_remote_parts(target.as_ref(), &parts, Some(Path::new(export_dir)))
+        };
+        let problems = match result
+        {
+            Ok(p) => p,
+            Err(e) => vec!(format!("couldn't check the copy: {e}"))
+        };
+        all_ok &= report(source_name, timestamp, &format!("at {target_name}"), &problems);
+    }
+
+    info!("Completed verify for source: {} -- {}", source_name, if all_ok {"no problems found"} else {"problems found"});
+    all_ok
+}
+
+/**
+Check the parts of an export in the export dir against its manifest, and read through the whole archive.
+
+# Arguments
+* `files` - The export's part files, in order
+* `manifest` - The export's manifest, if it has one
+
+# Returns
+A description of each problem found.
+*/
+fn verify_local(files: &[String], manifest: Option<&Manifest>, settings: &Settings) -> Vec<String>
+{
+    let mut problems = Vec::new();
+    if let Some(m) = manifest
+    {
+        for part in &m.parts
+        {
+            let path = Path::new(&settings.startup.export_dir).join(&part.name);
+            match fs::metadata(&path)
+            {
+                Err(_) => {problems.push(format!("{} is missing", part.name)); continue;},
+                Ok(meta) if meta.len() != part.size => {problems.push(format!("{} is {} bytes but should be {}", part.name, meta.len(), part.size)); continue;},
+                Ok(_) => {}
+            }
+            match file_digest::<Sha256>(&path)
+            {
+                Ok(h) if h != part.sha256 => problems.push(format!("{} has SHA-256 {} but should have {}", part.name, h, part.sha256)),
+                Ok(_) => {},
+                Err(e) => problems.push(format!("{} couldn't be read: {e}", part.name))
+            }
+        }
+        for file in files
+        {
+            let name = Path::new(file).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            if m.part(&name).is_none()
+            {
+                problems.push(format!("{name} isn't in the manifest"));
+            }
+        }
+    }
+    if !problems.is_empty()
+    {
+        return problems;
+    }
+
+    let first = match files.first()
+    {
+        Some(f) => f,
+        None => {return vec!(String::from("there are no parts"));}
+    };
+    if starts_encrypted(Path::new(first)).unwrap_or(false) && !can_decrypt(&settings.export.encryption)
+    {
+        info!("Export is encrypted and no key to decrypt it is configured, skipping test decompression");
+        return problems;
+    }
+    match test_archive(files, &settings.export)
+    {
+        Ok((file_count, total_size)) => {
+            info!("Test decompression read {} files, {} bytes", file_count, total_size);
+            if let Some(m) = manifest
+            {
+                if file_count != m.file_count || total_size != m.uncompressed_size
+                {
+                    problems.push(format!("the archive has {file_count} files of {total_size} bytes but the manifest says {} files of {} bytes", m.file_count, m.uncompressed_size));
+                }
+            }
+        },
+        Err(e) => problems.push(format!("test decompression failed: {e}"))
+    }
+    problems
+}
+
+/**
+Log the outcome of checking one copy of an export.
+
+# Arguments
+* `place` - Where the copy is, to complete "export X of source Y is fine ..."
+
+# Returns
+bool for whether there were no problems.
+*/
+fn report(source_name: &str, timestamp: i64, place: &str, problems: &[String]) -> bool
+{
+    if problems.is_empty()
+    {
+        info!("Export {} of source {} is fine {}", timestamp, source_name, place);
+        return true;
+    }
+    for problem in problems
+    {
+        error!("Export {} of source {} has a problem {}: {}", timestamp, source_name, place, problem);
+    }
+    error!("Export {} of source {} has {} problems {}", timestamp, source_name, problems.len(), place);
+    false
+}